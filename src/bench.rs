@@ -0,0 +1,349 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context};
+use bytes::Bytes;
+use bytesize::ByteSize;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+use crate::{
+    core::{
+        file_tree::{FileTree, FileTreeNodeType, ScanOptions},
+        message::{FileChangeMessage, SenderMessage, SyncMessage},
+        policy::PolicyTable,
+        roots::Roots,
+        utils::quoted,
+    },
+    sender::{Sender, SenderOptions},
+};
+
+/// Subdirectory of the listener's output directory the synthetic tree is synced into, leaving
+/// the rest alone.
+pub const BENCH_DIR: &str = "caiman-bench";
+/// Files per generated directory.
+const FILES_PER_DIR: usize = 100;
+const WORDS: &[&str] = &[
+    "sync",
+    "caiman",
+    "directory",
+    "file",
+    "tree",
+    "change",
+    "watch",
+    "listener",
+    "sender",
+    "fn",
+    "let",
+    "match",
+    "return",
+    "struct",
+    "impl",
+    "pub",
+    "use",
+    "async",
+    "await",
+    "{",
+    "}",
+];
+
+/// The synthetic tree: how many files, and the range their sizes are spread over, evenly on a
+/// log scale so that small files dominate the count and large ones the bytes, as in most trees.
+#[derive(Debug, Clone, Copy)]
+pub struct TreeShape {
+    pub files: usize,
+    pub min_size: ByteSize,
+    pub max_size: ByteSize,
+    /// Share of text files, which compress well. The others are random bytes, which do not.
+    pub text_ratio: f64,
+}
+
+/// What `bench` generates and how it syncs it.
+#[derive(Debug)]
+pub struct Options {
+    pub to: String,
+    pub key: Option<String>,
+    pub shape: TreeShape,
+    pub jobs: usize,
+    pub policies: PolicyTable,
+    pub size_only: bool,
+    pub timeout: Duration,
+}
+
+/// How long each phase of a sync took.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub files: usize,
+    pub bytes: u64,
+    /// Walking the tree without reading files.
+    pub listing: Duration,
+    /// Walking the tree and hashing every file.
+    pub scan: Duration,
+    /// Compressing and serializing every file as the sender does, on one thread.
+    pub encoding: Duration,
+    pub encoded_bytes: u64,
+    /// The whole sync, the sender's own scan included.
+    pub sync: Duration,
+    pub sent_bytes: u64,
+}
+
+/// Writes the tree described by `shape` into `dir`, from `seed`. Returns the total size.
+pub fn generate(dir: &Path, shape: TreeShape, seed: u64) -> anyhow::Result<u64> {
+    ensure!(
+        shape.min_size <= shape.max_size,
+        "the minimum size is larger than the maximum size"
+    );
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (min, max) = (
+        shape.min_size.as_u64().max(1) as f64,
+        shape.max_size.as_u64().max(1) as f64,
+    );
+
+    let mut total = 0;
+    for i in 0..shape.files {
+        let subdir = dir.join(format!("dir_{:04}", i / FILES_PER_DIR));
+        if i % FILES_PER_DIR == 0 {
+            fs::create_dir_all(&subdir).with_context(|| format!("creating {}", quoted(&subdir)))?;
+        }
+
+        let size = (min.ln() + rng.gen::<f64>() * (max.ln() - min.ln())).exp() as usize;
+        let (name, contents) = match rng.gen_bool(shape.text_ratio.clamp(0.0, 1.0)) {
+            true => (format!("file_{:06}.txt", i), text(&mut rng, size)),
+            false => {
+                let mut contents = vec![0; size];
+                rng.fill(contents.as_mut_slice());
+                (format!("file_{:06}.bin", i), contents)
+            }
+        };
+        let path = subdir.join(name);
+        fs::write(&path, &contents).with_context(|| format!("writing {}", quoted(&path)))?;
+        total += size as u64;
+    }
+
+    Ok(total)
+}
+
+fn text(rng: &mut StdRng, size: usize) -> Vec<u8> {
+    let mut text = Vec::with_capacity(size + 16);
+    while text.len() < size {
+        let word = WORDS[rng.gen_range(0..WORDS.len())];
+        text.extend_from_slice(word.as_bytes());
+        text.push(match rng.gen_range(0..12) {
+            0 => b'\n',
+            _ => b' ',
+        });
+    }
+    text.truncate(size);
+
+    text
+}
+
+/// Generates a tree in a scratch directory, then times scanning and encoding it and syncing it
+/// to the listener, into its `BENCH_DIR`. The scratch directory is removed afterwards.
+pub async fn run(options: &Options) -> anyhow::Result<Report> {
+    let dir = std::env::temp_dir().join(format!("{}-{}", BENCH_DIR, std::process::id()));
+    let report = generate(&dir, options.shape, rand::random());
+    let report = match report {
+        Ok(bytes) => measure(&dir, options, bytes).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        eprintln!("WARNING: could not remove {}: {}", quoted(&dir), err);
+    }
+
+    report
+}
+
+async fn measure(dir: &Path, options: &Options, bytes: u64) -> anyhow::Result<Report> {
+    let mut report = Report {
+        files: options.shape.files,
+        bytes,
+        ..Default::default()
+    };
+    let sender_options = SenderOptions {
+        timeout: options.timeout,
+        jobs: options.jobs,
+        scan: ScanOptions {
+            size_only: options.size_only,
+            ..Default::default()
+        },
+        policies: options.policies,
+        key: options.key.clone(),
+        ..Default::default()
+    };
+
+    let listing = ScanOptions {
+        size_only: true,
+        ..sender_options.scan
+    };
+    let started = Instant::now();
+    FileTree::new_with(dir, listing).await?;
+    report.listing = started.elapsed();
+
+    let started = Instant::now();
+    let tree = FileTree::new_with(dir, sender_options.scan).await?;
+    report.scan = started.elapsed();
+
+    let started = Instant::now();
+    report.encoded_bytes = encode(dir, &tree, &options.policies)?;
+    report.encoding = started.elapsed();
+
+    let roots = Roots::single(dir).prefixed(Path::new(BENCH_DIR))?;
+    let sender = Sender::new(roots, &options.to, sender_options);
+    let started = Instant::now();
+    sender.start(false).await?;
+    report.sync = started.elapsed();
+    report.sent_bytes = sender.activity().bytes_transferred;
+
+    Ok(report)
+}
+
+/// Encodes every file of `tree` as the sender would, returning the size of the messages.
+fn encode(dir: &Path, tree: &FileTree, policies: &PolicyTable) -> anyhow::Result<u64> {
+    let mut encoded = 0;
+    for (id, node) in tree.iter().enumerate() {
+        let FileTreeNodeType::File { .. } = node.typ else {
+            continue;
+        };
+
+        let contents = fs::read(dir.join(&node.path))?;
+        let change = policies.encode(FileChangeMessage::FileEdited(
+            node.path.clone(),
+            Bytes::from(contents),
+            SystemTime::now(),
+        ))?;
+        let message = SenderMessage::Sync(SyncMessage {
+            id: id as u64,
+            depends_on: vec![],
+            change,
+        });
+        encoded += bincode::serialized_size(&message)?;
+    }
+
+    Ok(encoded)
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> ByteSize {
+    match elapsed.is_zero() {
+        true => ByteSize::b(0),
+        false => ByteSize::b((bytes as f64 / elapsed.as_secs_f64()) as u64),
+    }
+}
+
+fn secs(elapsed: Duration) -> String {
+    format!("{:.2}s", elapsed.as_secs_f64())
+}
+
+impl Report {
+    fn hashing(&self) -> Duration {
+        self.scan.saturating_sub(self.listing)
+    }
+
+    /// The sync without the sender's own scan.
+    fn transfer(&self) -> Duration {
+        self.sync.saturating_sub(self.scan)
+    }
+
+    /// Which flags could speed the sync up, from the slowest phase.
+    pub fn hints(&self) -> Vec<&'static str> {
+        let mut hints = vec![];
+        let encoding = rate(self.bytes, self.encoding);
+        let network = rate(self.sent_bytes, self.transfer());
+        let ratio = self.encoded_bytes as f64 / self.bytes.max(1) as f64;
+
+        if ratio > 0.9 {
+            hints.push("Compression saves little on these files: try --policy binary=raw, or --policy text=raw if they are mostly text");
+        }
+        if encoding < network {
+            hints.push("Compressing is slower than the network: raise --jobs to compress on more cores, or send some classes raw with --policy");
+        } else if ratio <= 0.9 {
+            hints.push(
+                "The network is slower than compressing: keep compression on, it sends fewer bytes",
+            );
+        }
+        if self.hashing() > self.transfer() {
+            hints.push("Hashing takes longer than transferring: --size-only on both ends skips it, at the cost of missing edits that keep a file's size");
+        }
+
+        hints
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Benchmark:")?;
+        writeln!(
+            f,
+            "  Tree: {} files, {}",
+            self.files,
+            ByteSize::b(self.bytes)
+        )?;
+        writeln!(
+            f,
+            "  Scan: {} listing, {} hashing ({}/s)",
+            secs(self.listing),
+            secs(self.hashing()),
+            rate(self.bytes, self.hashing())
+        )?;
+        writeln!(
+            f,
+            "  Encoding: {} on one thread ({}/s), {} serialized, {:.0}% of the contents",
+            secs(self.encoding),
+            rate(self.bytes, self.encoding),
+            ByteSize::b(self.encoded_bytes),
+            self.encoded_bytes as f64 * 100.0 / self.bytes.max(1) as f64
+        )?;
+        write!(
+            f,
+            "  Sync: {}, {} sent, {}/s over the network once scanned",
+            secs(self.sync),
+            ByteSize::b(self.sent_bytes),
+            rate(self.sent_bytes, self.transfer())
+        )?;
+        for hint in self.hints() {
+            write!(f, "\n  - {}", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_generated_tree_follows_shape() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let shape = TreeShape {
+            files: 150,
+            min_size: ByteSize::b(10),
+            max_size: ByteSize::kib(4),
+            text_ratio: 1.0,
+        };
+        let total = generate(dir.path(), shape, 7)?;
+
+        let sizes: Vec<u64> = walkdir::WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.metadata().map(|metadata| metadata.len()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(sizes.len(), 150);
+        assert_eq!(sizes.iter().sum::<u64>(), total);
+        assert!(sizes.iter().all(|&size| (10..=4096).contains(&size)));
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+
+        let tree = FileTree::new(dir.path()).await?;
+        let encoded = encode(dir.path(), &tree, &PolicyTable::default())?;
+        assert!(encoded < total, "text should compress");
+
+        Ok(())
+    }
+}
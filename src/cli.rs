@@ -1,62 +1,2333 @@
-use std::process;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
+use bytesize::ByteSize;
+use clap::{Args, Parser, Subcommand};
 
-use crate::{receiver, sender};
+use white_caiman::{
+    bench, conformance,
+    core::{
+        activity::Activity,
+        budget::MemoryBudget,
+        control::{self, Command, ControlAddr},
+        events::EventStream,
+        excludes::Excludes,
+        file_tree::ScanOptions,
+        identity::{Identities, Identity, Trust, TrustedPeers},
+        keepalive::KeepaliveConfig,
+        message::{DEFAULT_MAX_MESSAGE_SIZE, MIN_MAX_MESSAGE_SIZE},
+        policy::{Encoding, PolicyRule, PolicyTable},
+        profile,
+        proxy::Proxy,
+        roots::{Roots, SourceRoot},
+        tls::{ClientTls, ServerTls},
+        utils::quoted,
+        walk::DEFAULT_MAX_DEPTH,
+        webhook::Webhooks,
+    },
+    doctor, init, manifest,
+    mirror::Mirror,
+    receiver::{
+        self,
+        access::{AccessOptions, Cidr},
+        audit_log::AuditLog,
+        metrics::Metrics,
+        middleware::{ConvertEol, LineEnding, MiddlewareChain},
+        staging,
+        tenants::{Gateway, TenantsConfig},
+        versions,
+    },
+    relay::Relay,
+    sender::{self, hooks::SyncHooks, RequestHeader, WatchBackend, WatchmanOptions},
+    snapshot::{Snapshot, SnapshotDiff},
+    storage::{s3::Credentials, Target, S3},
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    #[command(name = "sync")]
+    Sync {
+        #[arg(
+            long,
+            short,
+            help = "Directory or file to sync (repeatable, each one is then synced into an entry named after it, as is a lone file)",
+            required_unless_present = "from_map",
+            env = "CAIMAN_FROM"
+        )]
+        from: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Directory or file to sync into a given path of the listener's directory, as <local>:<remote> (repeatable)"
+        )]
+        from_map: Vec<SourceRoot>,
+
+        #[arg(
+            long,
+            help = "Path of the listener's directory to sync into, e.g. deploy/current, instead of the directory itself"
+        )]
+        dest_prefix: Option<PathBuf>,
+
+        #[arg(
+            long,
+            short,
+            help = "Listener address (repeatable, to sync to several listeners while scanning and watching once)",
+            env = "CAIMAN_TO",
+            required = true
+        )]
+        to: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Proxy to reach the listener through, as http://[user:password@]host:port or socks5://[user:password@]host:port [default: from HTTPS_PROXY, HTTP_PROXY or ALL_PROXY]"
+        )]
+        proxy: Option<Proxy>,
+
+        #[arg(
+            long = "header",
+            help = "Header to add to the websocket handshake, as \"<name>: <value>\", e.g. for a reverse proxy in front of the listener (repeatable)"
+        )]
+        headers: Vec<RequestHeader>,
+
+        #[arg(
+            long,
+            help = "Key expected by the listener, or identifying the tenant to sync into for listeners serving several tenants",
+            env = "CAIMAN_KEY",
+            hide_env_values = true
+        )]
+        key: Option<String>,
+
+        #[arg(
+            long,
+            help = "PEM file of the CA the wss:// listener's certificate is signed by, instead of the public web's CAs"
+        )]
+        ca: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "PEM file of the client certificate to present to wss:// listeners requiring one",
+            requires = "client_key"
+        )]
+        client_cert: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "PEM file of the client certificate's private key",
+            requires = "client_cert"
+        )]
+        client_key: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of this side's identity, written by keygen, to authenticate the listener and to it",
+            env = "CAIMAN_IDENTITY"
+        )]
+        identity: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of the trusted listeners' keys, as <address> <key> lines [default: the identity's file, with a .peers extension]",
+            requires = "identity",
+            env = "CAIMAN_TRUSTED_PEERS"
+        )]
+        trusted_peers: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Trust listeners missing from the trusted peers on first use (tofu) or turn them away (pinned)",
+            default_value = "tofu",
+            requires = "identity"
+        )]
+        trust: Trust,
+
+        #[arg(
+            long, short, help = "Watch for changes",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        watch: bool,
+
+        #[arg(
+            long, help = "Interval between keepalive pings",
+            default_value = "15s", value_parser = humantime::parse_duration
+        )]
+        ping_interval: Duration,
+
+        #[arg(
+            long, help = "Time without messages from the listener before the connection is considered dead",
+            default_value = "45s", value_parser = humantime::parse_duration
+        )]
+        ping_timeout: Duration,
+
+        #[arg(
+            long, help = "Reconnect and resync when the connection is lost",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        reconnect: bool,
+
+        #[arg(
+            long, help = "Timeout for connecting, handshaking and sending messages",
+            default_value = "30s", value_parser = humantime::parse_duration
+        )]
+        timeout: Duration,
+
+        #[arg(
+            long, help = "Warn when the listener has not consumed a message for this long, until --timeout gives up on it (0s to disable)",
+            default_value = "5s", value_parser = humantime::parse_duration
+        )]
+        stall_warning: Duration,
+
+        #[arg(
+            long,
+            short,
+            help = "Maximum number of files read and compressed in parallel",
+            default_value_t = 8
+        )]
+        jobs: usize,
+
+        #[arg(
+            long, help = "In watch mode, periodically compare per-directory checksums with the listener and rescan divergent entries",
+            value_parser = humantime::parse_duration
+        )]
+        checksum_interval: Option<Duration>,
+
+        #[arg(
+            long, help = "In watch mode, periodically compare a hash of the whole tree with the listener and re-diff divergent entries on mismatch",
+            value_parser = humantime::parse_duration
+        )]
+        verify_interval: Option<Duration>,
+
+        #[arg(
+            long, help = "In watch mode, wait for changes to settle for this long and merge them per path before sending them",
+            default_value = "0s", value_parser = humantime::parse_duration
+        )]
+        debounce: Duration,
+
+        #[arg(
+            long, help = "Only send files whose size and mtime stay the same for this long, trying files still being written again, e.g. 500ms",
+            value_parser = humantime::parse_duration
+        )]
+        stability_window: Option<Duration>,
+
+        #[arg(
+            long,
+            help = "In watch mode, learn about changes from watchman (\"watchman\") or from the platform's own notifications (\"native\", the default on Windows)"
+        )]
+        watcher: Option<WatchBackend>,
+
+        #[arg(
+            long,
+            help = "In watch mode with watchman, save watchman's clock to this file as changes are sent, and on the next run also send the changes made since, as they happened"
+        )]
+        watch_clock: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "In watch mode with watchman, hold back changes while another watchman client asserts this state, e.g. hg.update, and send them as one batch once it ends (repeatable)"
+        )]
+        watch_defer: Vec<String>,
+
+        #[arg(
+            long, help = "In watch mode with watchman, only send changes once watchman reported none for this long, however long that takes, e.g. 1s",
+            default_value = "0s", value_parser = humantime::parse_duration
+        )]
+        watch_settle: Duration,
+
+        #[arg(
+            long,
+            help = "In watch mode, journal the changes seen and not sent yet to this file, and send them right after the next initial sync, should the sender die or lose the connection first"
+        )]
+        queue: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Skip files larger than this, as they would have to be held in memory whole",
+            default_value = "1GiB"
+        )]
+        max_file_size: ByteSize,
+
+        #[arg(
+            long,
+            help = "Pack files smaller than this into batches sent as a single message, 0 to send every file on its own",
+            default_value = "16KiB"
+        )]
+        batch_threshold: ByteSize,
+
+        #[arg(
+            long,
+            help = "Bound the bytes of files read, compressed and queued for sending at once, e.g. 256MB"
+        )]
+        memory_limit: Option<ByteSize>,
+
+        #[arg(
+            long,
+            help = "Transfer policy of edited files per class, as <text|binary|compressed>=<raw|gzip> for their wire encoding or <class>=chunk:<size> to send files over size in chunks of it (repeatable). Text and binary files are gzipped and compressed formats sent raw by default, files are only chunked when over the receiver's message limit"
+        )]
+        policy: Vec<PolicyRule>,
+
+        #[arg(
+            long, help = "Compare files by size only instead of hashing their contents. Should match the other side's setting",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        size_only: bool,
+
+        #[arg(
+            long, help = "Hash every file of the initial sync instead of taking files with the same size and mtime on both sides as unchanged",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        checksum: bool,
+
+        #[arg(
+            long, help = "Skip listing directories whose mtime did not change since the last scan. Faster rescans, but entries are missed on filesystems that do not update directory mtimes",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        trust_dir_mtime: bool,
+
+        #[arg(
+            long, help = "Send FIFOs for the listener to recreate, which it must be started with --specials to accept. Otherwise FIFOs, sockets and devices are skipped with a warning",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        specials: bool,
+
+        #[arg(
+            long, help = "Send what symlinks point to as if it was where the symlink is. Symlink loops fail the scan, naming the symlink",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        follow_symlinks: bool,
+
+        #[arg(
+            long, help = "Fail scans meeting entries nested deeper than this many directories",
+            default_value_t = DEFAULT_MAX_DEPTH
+        )]
+        max_depth: usize,
+
+        #[arg(
+            long, help = "Also sync editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store",
+            default_value_t = false, action = clap::ArgAction::SetTrue, env = "CAIMAN_NO_DEFAULT_EXCLUDES"
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave out files and directories whose name matches this glob, e.g. target or *.log (repeatable, or comma-separated)",
+            env = "CAIMAN_EXCLUDE",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Shell command run before scanning and sending the initial tree, e.g. a formatter or code generator"
+        )]
+        pre_sync: Option<String>,
+
+        #[arg(
+            long,
+            help = "Shell command run after the initial transfer and on graceful shutdown in watch mode, with $CAIMAN_EVENT set to sync or shutdown"
+        )]
+        post_sync: Option<String>,
+
+        #[arg(
+            long, help = "Abort the sync when a --pre-sync or --post-sync hook fails, instead of warning",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        abort_on_hook_failure: bool,
+
+        #[arg(
+            long,
+            help = "Write a chrome-tracing trace of the scan, hash, read, compress and send phases to this file, to open in Perfetto or chrome://tracing"
+        )]
+        profile: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Serve status, pause, resume, resync and shutdown commands, e.g. from `white-caiman ctl`, on this Unix socket path or loopback address"
+        )]
+        control: Option<ControlAddr>,
+
+        #[arg(
+            long, help = "Print the summary of the initial sync as a JSON line, for scripts",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        json: bool,
+
+        #[arg(
+            long,
+            help = "POST a JSON payload to this URL when a sync starts, completes, fails or disconnects (repeatable, or comma-separated)",
+            env = "CAIMAN_WEBHOOK",
+            value_delimiter = ','
+        )]
+        webhook: Vec<String>,
+
+        #[arg(
+            long, help = "Pop desktop notifications when the connection drops, the sync fails or a large initial sync completes",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        notify: bool,
+
+        #[arg(
+            long, help = "Show a live view of the session instead of its output: connection, changes sent, throughput and pending changes, with keys to pause, resync and quit",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        tui: bool,
+
+        #[arg(
+            long, help = "Print the session's events on stdout as JSON lines, for editor and IDE plugins, and everything else on stderr",
+            default_value_t = false, action = clap::ArgAction::SetTrue, conflicts_with = "tui"
+        )]
+        events_stdout: bool,
+    },
+
+    #[command(
+        name = "verify",
+        about = "Check that the listener's directory matches a local one, without modifying anything"
+    )]
+    Verify {
+        #[arg(
+            long,
+            short,
+            help = "Directory or file to compare (repeatable, like for sync)",
+            required_unless_present = "from_map"
+        )]
+        from: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Directory or file to compare with a given path of the listener's directory, as <local>:<remote> (repeatable)"
+        )]
+        from_map: Vec<SourceRoot>,
+
+        #[arg(
+            long,
+            help = "Path of the listener's directory to sync into, e.g. deploy/current, instead of the directory itself"
+        )]
+        dest_prefix: Option<PathBuf>,
+
+        #[arg(long, short, help = "Listener address", env = "CAIMAN_TO")]
+        to: String,
+
+        #[arg(
+            long,
+            help = "Proxy to reach the listener through, as http://[user:password@]host:port or socks5://[user:password@]host:port [default: from HTTPS_PROXY, HTTP_PROXY or ALL_PROXY]"
+        )]
+        proxy: Option<Proxy>,
+
+        #[arg(
+            long = "header",
+            help = "Header to add to the websocket handshake, as \"<name>: <value>\", e.g. for a reverse proxy in front of the listener (repeatable)"
+        )]
+        headers: Vec<RequestHeader>,
+
+        #[arg(
+            long,
+            help = "Key expected by the listener, or identifying the tenant to sync into for listeners serving several tenants",
+            env = "CAIMAN_KEY",
+            hide_env_values = true
+        )]
+        key: Option<String>,
+
+        #[arg(
+            long,
+            help = "PEM file of the CA the wss:// listener's certificate is signed by, instead of the public web's CAs"
+        )]
+        ca: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "PEM file of the client certificate to present to wss:// listeners requiring one",
+            requires = "client_key"
+        )]
+        client_cert: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "PEM file of the client certificate's private key",
+            requires = "client_cert"
+        )]
+        client_key: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of this side's identity, written by keygen, to authenticate the listener and to it",
+            env = "CAIMAN_IDENTITY"
+        )]
+        identity: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of the trusted listeners' keys, as <address> <key> lines [default: the identity's file, with a .peers extension]",
+            requires = "identity",
+            env = "CAIMAN_TRUSTED_PEERS"
+        )]
+        trusted_peers: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Trust listeners missing from the trusted peers on first use (tofu) or turn them away (pinned)",
+            default_value = "tofu",
+            requires = "identity"
+        )]
+        trust: Trust,
+
+        #[arg(
+            long, help = "Timeout for connecting, handshaking and exchanging directory states",
+            default_value = "30s", value_parser = humantime::parse_duration
+        )]
+        timeout: Duration,
+    },
+
+    #[command(name = "listen")]
+    Listen {
+        #[arg(long, short, help = "Port to listen on", env = "CAIMAN_PORT")]
+        port: u32,
+
+        #[arg(
+            long,
+            help = "Address to listen on, e.g. 0.0.0.0 to accept senders from other hosts",
+            default_value = "127.0.0.1",
+            env = "CAIMAN_BIND"
+        )]
+        bind: IpAddr,
+
+        #[arg(
+            long,
+            help = "Only accept connections from this network, e.g. 10.0.0.0/8 or 192.168.1.7 (repeatable, or comma-separated)",
+            value_delimiter = ','
+        )]
+        allow: Vec<Cidr>,
+
+        #[arg(
+            long,
+            help = "Only serve senders connecting to this URL path, e.g. /caiman behind a reverse proxy [default: any path]"
+        )]
+        path: Option<String>,
+
+        #[arg(
+            long,
+            help = "Refuse connections from an address that already connected this many times in the last minute"
+        )]
+        max_connections_per_minute: Option<u32>,
+
+        #[arg(
+            long,
+            help = "Refuse connections while this many are open, including those being turned away because a sync is in progress"
+        )]
+        max_connections: Option<usize>,
+
+        #[arg(
+            long,
+            short,
+            help = "Output directory path",
+            required_unless_present = "tenants",
+            conflicts_with = "tenants",
+            env = "CAIMAN_OUTPUT_DIR"
+        )]
+        output_dir: Option<String>,
+
+        #[arg(
+            long,
+            help = "Configuration file of the tenants to serve, each with its own key, directory, quota and policies, instead of a single output directory",
+            env = "CAIMAN_TENANTS"
+        )]
+        tenants: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Only serve senders passing this same key to sync or verify --key",
+            conflicts_with = "tenants",
+            env = "CAIMAN_KEY",
+            hide_env_values = true
+        )]
+        key: Option<String>,
+
+        #[arg(
+            long,
+            help = "PEM file of the listener's certificate chain, to serve senders over TLS at wss:// addresses",
+            requires = "tls_key"
+        )]
+        tls_cert: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "PEM file of the listener's private key",
+            requires = "tls_cert"
+        )]
+        tls_key: Option<PathBuf>,
+
+        #[arg(
+            long, help = "Only serve senders presenting a client certificate signed by --ca",
+            default_value_t = false, action = clap::ArgAction::SetTrue,
+            requires_all = ["tls_cert", "ca"]
+        )]
+        require_client_cert: bool,
+
+        #[arg(
+            long,
+            help = "PEM file of the CA client certificates must be signed by",
+            requires = "require_client_cert"
+        )]
+        ca: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of this side's identity, written by keygen, to only serve senders authenticating with a trusted one",
+            conflicts_with = "tenants",
+            env = "CAIMAN_IDENTITY"
+        )]
+        identity: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "File of the trusted senders' keys, as <name> <key> lines [default: the identity's file, with a .peers extension]",
+            requires = "identity",
+            env = "CAIMAN_TRUSTED_PEERS"
+        )]
+        trusted_peers: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Trust the first sender on first use while the trusted peers are empty (tofu), or only those listed (pinned)",
+            default_value = "tofu",
+            requires = "identity"
+        )]
+        trust: Trust,
+
+        #[arg(
+            long, help = "Interval between keepalive pings",
+            default_value = "15s", value_parser = humantime::parse_duration
+        )]
+        ping_interval: Duration,
+
+        #[arg(
+            long, help = "Time without messages from the sender before the connection is considered dead",
+            default_value = "45s", value_parser = humantime::parse_duration
+        )]
+        ping_timeout: Duration,
+
+        #[arg(
+            long, help = "Keep listening for the sender to reconnect after a dead connection",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        reconnect: bool,
+
+        #[arg(
+            long, help = "Timeout for connecting, handshaking and sending messages",
+            default_value = "30s", value_parser = humantime::parse_duration
+        )]
+        timeout: Duration,
+
+        #[arg(
+            long,
+            short,
+            help = "Maximum number of changes applied in parallel",
+            default_value_t = 8
+        )]
+        jobs: usize,
+
+        #[arg(
+            long,
+            help = "Line endings of written text files: native, lf or crlf. Binary files are left untouched"
+        )]
+        eol: Option<LineEnding>,
+
+        #[arg(
+            long,
+            help = "Convert line endings of written text files, as <native|lf|crlf> or <glob>=<native|lf|crlf> (repeatable, overrides --eol)"
+        )]
+        convert_eol: Vec<ConvertEol>,
+
+        #[arg(
+            long, help = "Keep local files whose modification time is newer than the incoming version",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        update_only: bool,
+
+        #[arg(
+            long, help = "Only create files missing from the output directory, never modify or delete existing ones",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        ignore_existing: bool,
+
+        #[arg(
+            long, help = "Allocate files to their final size before writing them, and refuse a sync that does not fit on disk before transferring anything",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        preallocate: bool,
+
+        #[arg(
+            long, help = "Compare files by size only instead of hashing their contents. Should match the other side's setting",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        size_only: bool,
+
+        #[arg(
+            long, help = "Skip listing directories whose mtime did not change since the last scan. Faster rescans, but entries are missed on filesystems that do not update directory mtimes",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        trust_dir_mtime: bool,
+
+        #[arg(
+            long, help = "Recreate the FIFOs senders started with --specials send",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        specials: bool,
+
+        #[arg(
+            long, help = "Fail scans of the output directory meeting entries nested deeper than this many directories",
+            default_value_t = DEFAULT_MAX_DEPTH
+        )]
+        max_depth: usize,
+
+        #[arg(
+            long, help = "Treat editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store like other files, deleting or replacing them to match the sender",
+            default_value_t = false, action = clap::ArgAction::SetTrue, env = "CAIMAN_NO_DEFAULT_EXCLUDES"
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave alone files and directories whose name matches this glob, never deleting or replacing them (repeatable, or comma-separated)",
+            env = "CAIMAN_EXCLUDE",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Audit mode: stage each session's changes in this directory instead of applying them, until approved",
+            env = "CAIMAN_STAGE_DIR"
+        )]
+        stage_dir: Option<PathBuf>,
+
+        #[arg(
+            long, help = "Discard staged sessions nobody approved or rejected for this long, e.g. 7d, including sessions abandoned mid-way",
+            requires = "stage_dir", value_parser = humantime::parse_duration
+        )]
+        staged_ttl: Option<Duration>,
+
+        #[arg(
+            long,
+            help = "Shell command run in the output directory once the initial sync is applied, with the changed paths in $CAIMAN_CHANGED_PATHS"
+        )]
+        on_sync: Option<String>,
+
+        #[arg(
+            long,
+            help = "Shell command run in the output directory after each later batch of changes is applied, with the changed paths in $CAIMAN_CHANGED_PATHS"
+        )]
+        on_change: Option<String>,
+
+        #[arg(
+            long,
+            help = "Serve status, pause, resume, resync and shutdown commands, e.g. from `white-caiman ctl`, on this Unix socket path or loopback address",
+            conflicts_with = "tenants"
+        )]
+        control: Option<ControlAddr>,
+
+        #[arg(
+            long,
+            help = "Serve a read-only HTTP API on this address, e.g. 0.0.0.0:9090, with /healthz, /status, /last-sync and /tree-hash for load balancers and deploy scripts",
+            conflicts_with = "tenants"
+        )]
+        status_addr: Option<SocketAddr>,
+
+        #[arg(
+            long, help = "Print the summary of each initial sync as a JSON line, for scripts",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        json: bool,
+
+        #[arg(
+            long,
+            help = "Append a JSON line to this file for every change applied, with its time, sender, type, path, size and hash"
+        )]
+        audit_log: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Keep the sender's tree as of the last completed sync in this file, and diff the next initial syncs against it too, so that changes made in the output directory are kept instead of undone, and changes made on both sides are reported as conflicts"
+        )]
+        sync_state: Option<PathBuf>,
+
+        #[arg(
+            long, help = "Hold deletions back for this long before applying them, e.g. 1h, unless `ctl apply-deletes` applies them earlier. Pending deletions are kept next to the output directory, across restarts",
+            value_parser = humantime::parse_duration
+        )]
+        delete_after: Option<Duration>,
+
+        #[arg(
+            long,
+            help = "Keep the last N versions of each file overwritten or deleted, in .caiman/versions inside the output directory, for the restore command"
+        )]
+        keep_versions: Option<usize>,
+
+        #[arg(
+            long,
+            help = "Record the changes being applied in .caiman/journal inside the output directory, and on startup roll back or finish those a crash left unfinished"
+        )]
+        journal: bool,
+
+        #[arg(
+            long,
+            help = "Refuse changes that would grow the output directory past this size, e.g. 10GiB, telling the sender instead of filling the disk",
+            conflicts_with = "tenants"
+        )]
+        max_disk_usage: Option<ByteSize>,
+
+        #[arg(
+            long,
+            help = "Close sessions sending a message larger than this. Senders learn it when connecting, and send larger files in chunks",
+            default_value = "256MiB"
+        )]
+        max_message_size: ByteSize,
+
+        #[arg(
+            long,
+            help = "Bound the bytes of changes received and not applied yet, across sessions, e.g. 256MB"
+        )]
+        memory_limit: Option<ByteSize>,
+
+        #[arg(
+            long,
+            help = "Move deleted files and directories to the platform's trash, or with `local` to .caiman-trash inside the output directory, instead of removing them",
+            num_args = 0..=1, default_missing_value = "system", value_name = "system|local"
+        )]
+        use_trash: Option<receiver::Trash>,
+
+        #[arg(
+            long,
+            help = "Back up the output directory periodically into a new directory of this one, hard linking the files unchanged since the previous backup"
+        )]
+        backup_dir: Option<PathBuf>,
+
+        #[arg(
+            long, help = "How often to back up the output directory, e.g. 30m",
+            default_value = "1h", value_parser = humantime::parse_duration, requires = "backup_dir"
+        )]
+        backup_interval: Duration,
+
+        #[arg(
+            long,
+            help = "Keep the latest backup of each of the last N hours",
+            default_value_t = 24,
+            requires = "backup_dir"
+        )]
+        keep_hourly: usize,
+
+        #[arg(
+            long,
+            help = "Keep the latest backup of each of the last N days",
+            default_value_t = 7,
+            requires = "backup_dir"
+        )]
+        keep_daily: usize,
+
+        #[arg(
+            long,
+            help = "Keep the latest backup of each of the last N weeks",
+            default_value_t = 4,
+            requires = "backup_dir"
+        )]
+        keep_weekly: usize,
+
+        #[arg(
+            long,
+            help = "POST a JSON payload to this URL when a sync starts, completes, fails or disconnects (repeatable, or comma-separated)",
+            env = "CAIMAN_WEBHOOK",
+            value_delimiter = ','
+        )]
+        webhook: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Send every change applied on to the listener at this address, e.g. ws://mirror:8080, catching it up on startup, so that listeners can be chained",
+            conflicts_with_all = ["tenants", "stage_dir"]
+        )]
+        forward: Option<String>,
+
+        #[arg(
+            long,
+            help = "Key the listener changes are sent on to expects",
+            requires = "forward",
+            env = "CAIMAN_FORWARD_KEY",
+            hide_env_values = true
+        )]
+        forward_key: Option<String>,
+
+        #[arg(
+            long,
+            help = "Mirror the output directory to this bucket, as s3://<bucket>/<prefix>, catching it up on startup then uploading every change applied. Credentials are read from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN",
+            conflicts_with_all = ["tenants", "stage_dir", "forward"]
+        )]
+        target: Option<String>,
+
+        #[arg(
+            long,
+            help = "Endpoint of the S3-compatible service to mirror to, e.g. http://minio:9000 [default: AWS's endpoint for --s3-region]",
+            requires = "target",
+            env = "CAIMAN_S3_ENDPOINT"
+        )]
+        s3_endpoint: Option<String>,
+
+        #[arg(
+            long,
+            help = "Region of the bucket to mirror to",
+            env = "AWS_REGION",
+            default_value = "us-east-1"
+        )]
+        s3_region: String,
+    },
+
+    #[command(
+        name = "mirror",
+        about = "Keep a local directory in sync with another one, running the sender and the receiver in this process"
+    )]
+    Mirror {
+        #[arg(
+            long,
+            short,
+            help = "Directory or file to mirror (repeatable, like for sync)",
+            required = true
+        )]
+        from: Vec<String>,
+
+        #[arg(long, help = "Directory to mirror into")]
+        to_dir: PathBuf,
+
+        #[arg(
+            long, short, help = "Watch for changes",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        watch: bool,
+
+        #[arg(
+            long,
+            short,
+            help = "Maximum number of files read, and of changes applied, in parallel",
+            default_value_t = 8
+        )]
+        jobs: usize,
+
+        #[arg(
+            long, help = "In watch mode, wait for changes to settle for this long and merge them per path before mirroring them",
+            default_value = "0s", value_parser = humantime::parse_duration
+        )]
+        debounce: Duration,
+
+        #[arg(
+            long,
+            help = "In watch mode, learn about changes from watchman (\"watchman\") or from the platform's own notifications (\"native\", the default on Windows)"
+        )]
+        watcher: Option<WatchBackend>,
+
+        #[arg(
+            long, help = "Also mirror editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store, and replace or delete them in the target",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave out files and directories whose name matches this glob, and leave them alone in the target (repeatable, or comma-separated)",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+    },
 
-#[derive(Parser, Debug)]
-#[command(author, version, about)]
-pub struct Cli {
-    #[command(subcommand)]
-    command: Commands,
-}
+    #[command(
+        name = "init",
+        about = "Set up syncing the current project interactively, writing the settings to caiman.env"
+    )]
+    Init {
+        #[arg(long, help = "File to write the settings to", default_value = init::ENV_FILE)]
+        output: PathBuf,
 
-#[derive(Subcommand, Debug)]
-enum Commands {
-    #[command(name = "sync")]
-    Sync {
-        #[arg(long, short, help = "Directory to sync")]
-        from: String,
+        #[arg(
+            long, help = "Overwrite the file if it exists",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        force: bool,
+    },
+
+    #[command(
+        name = "keygen",
+        about = "Generate an identity, which senders and listeners authenticate each other with"
+    )]
+    Keygen {
+        #[arg(long, help = "File to write the identity to", env = "CAIMAN_IDENTITY")]
+        identity: PathBuf,
+
+        #[arg(
+            long, help = "Replace the identity if it exists",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        force: bool,
+    },
+
+    #[command(
+        name = "id",
+        about = "Print the public key of an identity, to add to the trusted peers of the other side"
+    )]
+    Id {
+        #[arg(long, help = "File of the identity", env = "CAIMAN_IDENTITY")]
+        identity: PathBuf,
+    },
+
+    #[command(
+        name = "doctor",
+        about = "Check that syncing can work here: watchman, the directory and its filesystem, and the connection to a listener"
+    )]
+    Doctor {
+        #[arg(
+            long,
+            short,
+            help = "Directory to sync from",
+            default_value = ".",
+            env = "CAIMAN_FROM"
+        )]
+        from: PathBuf,
+
+        #[arg(
+            long,
+            short,
+            help = "Listener address to test the connection to",
+            env = "CAIMAN_TO"
+        )]
+        to: Option<String>,
+
+        #[arg(
+            long,
+            help = "Key expected by the listener",
+            env = "CAIMAN_KEY",
+            hide_env_values = true
+        )]
+        key: Option<String>,
+
+        #[arg(
+            long, help = "Timeout for connecting to the listener and getting its answer",
+            default_value = "10s", value_parser = humantime::parse_duration
+        )]
+        timeout: Duration,
+    },
 
-        #[arg(long, short, help = "Listener address")]
+    #[command(
+        name = "bench",
+        about = "Sync a synthetic tree to a listener and report how long scanning, hashing, encoding and transferring took"
+    )]
+    Bench {
+        #[arg(
+            long,
+            short,
+            help = "Listener address to sync to, into its caiman-bench directory",
+            env = "CAIMAN_TO"
+        )]
         to: String,
 
         #[arg(
-            long, short, help = "Watch for changes",
+            long,
+            help = "Key expected by the listener",
+            env = "CAIMAN_KEY",
+            hide_env_values = true
+        )]
+        key: Option<String>,
+
+        #[arg(long, help = "Number of files to generate", default_value_t = 1000)]
+        files: usize,
+
+        #[arg(
+            long,
+            help = "Size of the smallest files, sizes are spread evenly on a log scale",
+            default_value = "1KiB"
+        )]
+        min_size: ByteSize,
+
+        #[arg(long, help = "Size of the largest files", default_value = "1MiB")]
+        max_size: ByteSize,
+
+        #[arg(
+            long,
+            help = "Share of text files between 0 and 1, the others are random binary data",
+            default_value_t = 0.5
+        )]
+        text_ratio: f64,
+
+        #[arg(
+            long,
+            short,
+            help = "Maximum number of files read and compressed in parallel",
+            default_value_t = 8
+        )]
+        jobs: usize,
+
+        #[arg(
+            long,
+            help = "Transfer policy of edited files per class, as <text|binary|compressed>=<raw|gzip> or <class>=chunk:<size> (repeatable)"
+        )]
+        policy: Vec<PolicyRule>,
+
+        #[arg(
+            long, help = "Compare files by size only instead of hashing their contents. Should match the listener's setting",
             default_value_t = false, action = clap::ArgAction::SetTrue
         )]
-        watch: bool,
+        size_only: bool,
+
+        #[arg(
+            long, help = "Timeout for connecting, handshaking and sending messages",
+            default_value = "30s", value_parser = humantime::parse_duration
+        )]
+        timeout: Duration,
     },
 
-    #[command(name = "listen")]
-    Listen {
-        #[arg(long, short, help = "Port to listen on")]
-        port: u32,
+    #[command(
+        name = "snapshot",
+        about = "Save the state of a directory to a file, to compare it offline with diff-snapshots"
+    )]
+    Snapshot {
+        #[arg(long, short, help = "Directory to snapshot", default_value = ".")]
+        dir: PathBuf,
+
+        #[arg(long, short, help = "File to write the snapshot to")]
+        out: PathBuf,
+
+        #[arg(
+            long, help = "Record file sizes only instead of hashing their contents. Edits keeping a file's size then do not show in diffs",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        size_only: bool,
+
+        #[arg(
+            long, help = "Also record editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store",
+            default_value_t = false, action = clap::ArgAction::SetTrue, env = "CAIMAN_NO_DEFAULT_EXCLUDES"
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave out files and directories whose name matches this glob, e.g. target or *.log (repeatable, or comma-separated)",
+            env = "CAIMAN_EXCLUDE",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+    },
+
+    #[command(
+        name = "diff-snapshots",
+        about = "Show what was created, deleted and edited between two snapshots"
+    )]
+    DiffSnapshots {
+        #[arg(help = "Snapshot of the earlier state")]
+        before: PathBuf,
+
+        #[arg(help = "Snapshot of the later state")]
+        after: PathBuf,
+    },
+
+    #[command(
+        name = "manifest",
+        about = "Write a sha256sum-style manifest of every file of a directory, to check a mirror against later"
+    )]
+    Manifest {
+        #[arg(long, short, help = "Directory to list", default_value = ".")]
+        dir: PathBuf,
+
+        #[arg(long, short, help = "File to write the manifest to, instead of stdout")]
+        out: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Hash of the files, sha256 as sha256sum writes or sha1 as sha1sum writes",
+            default_value = "sha256"
+        )]
+        algo: manifest::Algo,
+
+        #[arg(
+            long, help = "Also include editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store",
+            default_value_t = false, action = clap::ArgAction::SetTrue, env = "CAIMAN_NO_DEFAULT_EXCLUDES"
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave out files and directories whose name matches this glob, e.g. target or *.log (repeatable, or comma-separated)",
+            env = "CAIMAN_EXCLUDE",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+    },
+
+    #[command(
+        name = "check",
+        about = "Check the files of a directory, e.g. a listener's output directory, against a manifest"
+    )]
+    Check {
+        #[arg(
+            long,
+            short,
+            help = "Manifest written by the manifest command, or by sha256sum or sha1sum"
+        )]
+        manifest: PathBuf,
+
+        #[arg(long, short, help = "Directory to check", default_value = ".")]
+        dir: PathBuf,
+
+        #[arg(
+            long, help = "Also include editor swap, lock and backup files (.*.swp, .#*, *~) and .DS_Store",
+            default_value_t = false, action = clap::ArgAction::SetTrue, env = "CAIMAN_NO_DEFAULT_EXCLUDES"
+        )]
+        no_default_excludes: bool,
+
+        #[arg(
+            long,
+            help = "Leave out files and directories whose name matches this glob, e.g. target or *.log (repeatable, or comma-separated)",
+            env = "CAIMAN_EXCLUDE",
+            value_delimiter = ','
+        )]
+        exclude: Vec<String>,
+    },
+
+    #[command(
+        name = "restore",
+        about = "Bring back a version of a file kept by a listener started with --keep-versions, or list them"
+    )]
+    Restore {
+        #[arg(help = "Path of the file, relative to the output directory")]
+        path: PathBuf,
+
+        #[arg(long, help = "Version to restore, as listed without this option")]
+        version: Option<String>,
+
+        #[arg(
+            long,
+            short,
+            help = "Output directory of the listener",
+            default_value = "."
+        )]
+        dir: PathBuf,
+    },
+
+    #[command(
+        name = "conformance",
+        about = "Check the wire encoding and apply outcomes against the protocol's golden test vectors"
+    )]
+    Conformance {
+        #[arg(
+            long,
+            help = "Directory of the vectors",
+            default_value = "conformance/vectors"
+        )]
+        vectors: PathBuf,
+
+        #[arg(
+            long, help = "Rewrite the bytes of every vector from its message, only when the protocol changes on purpose",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        bless: bool,
+    },
+
+    #[command(
+        name = "status",
+        about = "Show what a sender or listener started with --control is doing"
+    )]
+    Status {
+        #[arg(
+            long,
+            help = "Unix socket path or loopback address the sender or listener was given with --control",
+            env = "CAIMAN_CONTROL"
+        )]
+        control: ControlAddr,
+
+        #[arg(
+            long, help = "Print the status as JSON",
+            default_value_t = false, action = clap::ArgAction::SetTrue
+        )]
+        json: bool,
+    },
+
+    #[command(
+        name = "ctl",
+        about = "Send a command to a sender or listener started with --control, printing its status"
+    )]
+    Ctl {
+        #[arg(help = "status, pause, resume, resync, shutdown or apply-deletes")]
+        command: Command,
+
+        #[arg(
+            long,
+            help = "Unix socket path or loopback address the sender or listener was given with --control",
+            env = "CAIMAN_CONTROL"
+        )]
+        control: ControlAddr,
+    },
+
+    #[command(
+        name = "approve",
+        about = "Apply a session staged by a listener in audit mode, same as `staged approve`"
+    )]
+    Approve(ApproveArgs),
+
+    #[command(
+        name = "staged",
+        about = "Review the sessions staged by a listener in audit mode"
+    )]
+    Staged {
+        #[command(subcommand)]
+        command: StagedCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum StagedCommands {
+    #[command(name = "list", about = "List the staged sessions")]
+    List {
+        #[arg(long, help = "Directory the listener stages sessions in")]
+        stage_dir: PathBuf,
+    },
+
+    #[command(
+        name = "show",
+        about = "Show the diffs and changes of a staged session"
+    )]
+    Show {
+        #[arg(long, help = "Id of the staged session")]
+        session: String,
+
+        #[arg(long, help = "Directory the listener stages sessions in")]
+        stage_dir: PathBuf,
+    },
 
-        #[arg(long, short, help = "Output directory path")]
-        output_dir: String,
+    #[command(
+        name = "approve",
+        about = "Apply a staged session to its output directory, whole or not at all"
+    )]
+    Approve(ApproveArgs),
+
+    #[command(name = "reject", about = "Discard a staged session")]
+    Reject {
+        #[arg(long, help = "Id of the staged session")]
+        session: String,
+
+        #[arg(long, help = "Directory the listener stages sessions in")]
+        stage_dir: PathBuf,
     },
 }
 
+#[derive(Args, Debug)]
+struct ApproveArgs {
+    #[arg(long, help = "Id of the staged session")]
+    session: String,
+
+    #[arg(long, help = "Directory the listener stages sessions in")]
+    stage_dir: PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Maximum number of changes applied in parallel",
+        default_value_t = 8
+    )]
+    jobs: usize,
+
+    #[arg(
+        long,
+        help = "Line endings of written text files: native, lf or crlf. Binary files are left untouched"
+    )]
+    eol: Option<LineEnding>,
+
+    #[arg(
+        long,
+        help = "Convert line endings of written text files, as <native|lf|crlf> or <glob>=<native|lf|crlf> (repeatable, overrides --eol)"
+    )]
+    convert_eol: Vec<ConvertEol>,
+
+    #[arg(
+        long, help = "Keep local files whose modification time is newer than the incoming version",
+        default_value_t = false, action = clap::ArgAction::SetTrue
+    )]
+    update_only: bool,
+}
+
 impl Cli {
     pub async fn run(&self) {
         match &self.command {
-            Commands::Sync { from, to, watch } => {
-                let sender = sender::Sender::new(from, to.as_str());
-                let res = sender.start(*watch).await;
+            Commands::Sync {
+                from,
+                from_map,
+                dest_prefix,
+                to,
+                proxy,
+                headers,
+                key,
+                ca,
+                client_cert,
+                client_key,
+                identity,
+                trusted_peers,
+                trust,
+                watch,
+                ping_interval,
+                ping_timeout,
+                reconnect,
+                timeout,
+                stall_warning,
+                jobs,
+                checksum_interval,
+                verify_interval,
+                debounce,
+                stability_window,
+                watcher,
+                watch_clock,
+                watch_defer,
+                watch_settle,
+                queue,
+                max_file_size,
+                batch_threshold,
+                memory_limit,
+                policy,
+                size_only,
+                checksum,
+                trust_dir_mtime,
+                specials,
+                follow_symlinks,
+                max_depth,
+                no_default_excludes,
+                exclude,
+                pre_sync,
+                post_sync,
+                abort_on_hook_failure,
+                profile,
+                control,
+                json,
+                webhook,
+                notify,
+                tui,
+                events_stdout,
+            } => {
+                let several = [
+                    ("--tui", *tui),
+                    ("--control", control.is_some()),
+                    ("--watch-clock", watch_clock.is_some()),
+                    ("--queue", queue.is_some()),
+                    ("--events-stdout", *events_stdout),
+                ];
+                if let Some((flag, _)) = several.iter().find(|(_, set)| *set && to.len() > 1) {
+                    println!(
+                        "An error occurred:\n{} cannot be combined with several --to",
+                        flag
+                    );
+                    process::exit(1)
+                }
+                let tls_for = |to: &str| {
+                    client_tls(to, ca, client_cert, client_key).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(1)
+                    })
+                };
+                let identities =
+                    identities(identity, trusted_peers, *trust).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(1)
+                    });
+                let proxy_for = |to: &str| {
+                    proxy_for(to, proxy).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    })
+                };
+                let options = sender::SenderOptions {
+                    keepalive: KeepaliveConfig {
+                        interval: *ping_interval,
+                        timeout: *ping_timeout,
+                    },
+                    reconnect: *reconnect,
+                    timeout: *timeout,
+                    stall_warning: *stall_warning,
+                    jobs: *jobs,
+                    checksum_interval: *checksum_interval,
+                    verify_interval: *verify_interval,
+                    debounce: *debounce,
+                    stability_window: *stability_window,
+                    watcher: watcher.unwrap_or_default(),
+                    watchman: WatchmanOptions {
+                        // Watchman's client only takes static state names, parsed once.
+                        defer: watch_defer
+                            .iter()
+                            .map(|state| &*state.clone().leak())
+                            .collect(),
+                        settle: *watch_settle,
+                    },
+                    max_file_size: *max_file_size,
+                    batch_threshold: *batch_threshold,
+                    memory_limit: *memory_limit,
+                    scan: ScanOptions {
+                        size_only: *size_only,
+                        quick_check: false,
+                        default_excludes: !*no_default_excludes,
+                        trust_dir_mtime: *trust_dir_mtime,
+                        excludes: excludes(exclude),
+                        fifos: *specials,
+                        follow_links: *follow_symlinks,
+                        max_depth: *max_depth,
+                    },
+                    quick_check: !*checksum,
+                    policies: policy
+                        .iter()
+                        .copied()
+                        .fold(PolicyTable::default(), PolicyTable::with),
+                    middleware: Default::default(),
+                    key: key.clone(),
+                    hooks: SyncHooks {
+                        pre_sync: pre_sync.clone(),
+                        post_sync: post_sync.clone(),
+                        abort_on_failure: *abort_on_hook_failure,
+                    },
+                    control: control.clone(),
+                    json_summary: *json,
+                    webhooks: webhooks(webhook),
+                    notify: *notify,
+                    events: events_stdout.then(|| {
+                        EventStream::stdout().map(Arc::new).unwrap_or_else(|err| {
+                            println!("An error occurred:\n{}", err);
+                            process::exit(1)
+                        })
+                    }),
+                    tls: tls_for(&to[0]),
+                    identities,
+                    proxy: proxy_for(&to[0]),
+                    headers: headers.clone(),
+                    watch_clock: watch_clock.clone(),
+                    queue: queue.clone(),
+                };
+                if *notify && !cfg!(feature = "notify") {
+                    println!("An error occurred:\nthis build has no desktop notification support, rebuild it with the notify feature");
+                    process::exit(1)
+                }
+                if *tui && !cfg!(feature = "tui") {
+                    println!("An error occurred:\nthis build has no TUI support, rebuild it with the tui feature");
+                    process::exit(1)
+                }
+                let roots =
+                    source_roots(from, from_map, dest_prefix.as_deref()).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    });
+                if profile.is_some() {
+                    profile::enable();
+                }
+                let res = match to.as_slice() {
+                    [to] => {
+                        let sender = sender::Sender::new(roots, to.as_str(), options);
+                        match *tui {
+                            true => sender.start_tui(*watch).await,
+                            false => sender.start(*watch).await,
+                        }
+                    }
+                    to => {
+                        let targets: Vec<_> = to
+                            .iter()
+                            .map(|to| {
+                                let options = sender::SenderOptions {
+                                    tls: tls_for(to),
+                                    proxy: proxy_for(to),
+                                    ..options.clone()
+                                };
+                                (to.clone(), options)
+                            })
+                            .collect();
+                        sender::sync_all(roots, &targets, *watch).await
+                    }
+                };
+                if let Some(path) = profile {
+                    match profile::write(path) {
+                        Ok(()) => println!("Profile written to {}", path.display()),
+                        Err(err) => eprintln!("Could not write the profile: {:#}", err),
+                    }
+                }
+                if let Err(err) = res {
+                    println!("An error occurred:\n{}", err);
+                    process::exit(1)
+                }
+            }
+            Commands::Verify {
+                from,
+                from_map,
+                dest_prefix,
+                to,
+                proxy,
+                headers,
+                key,
+                ca,
+                client_cert,
+                client_key,
+                identity,
+                trusted_peers,
+                trust,
+                timeout,
+            } => {
+                let tls = client_tls(to, ca, client_cert, client_key).unwrap_or_else(|err| {
+                    println!("An error occurred:\n{:#}", err);
+                    process::exit(2)
+                });
+                let identities =
+                    identities(identity, trusted_peers, *trust).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(2)
+                    });
+                let proxy = proxy_for(to, proxy).unwrap_or_else(|err| {
+                    println!("An error occurred:\n{}", err);
+                    process::exit(2)
+                });
+                let options = sender::SenderOptions {
+                    timeout: *timeout,
+                    key: key.clone(),
+                    tls,
+                    identities,
+                    proxy,
+                    headers: headers.clone(),
+                    ..Default::default()
+                };
+                let roots =
+                    source_roots(from, from_map, dest_prefix.as_deref()).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(2)
+                    });
+                let sender = sender::Sender::new(roots, to.as_str(), options);
+                match sender.verify().await {
+                    Ok(true) => (),
+                    Ok(false) => process::exit(1),
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(2)
+                    }
+                }
+            }
+            Commands::Listen {
+                port,
+                bind,
+                allow,
+                path,
+                max_connections_per_minute,
+                max_connections,
+                output_dir,
+                tenants,
+                key,
+                tls_cert,
+                tls_key,
+                require_client_cert,
+                ca,
+                identity,
+                trusted_peers,
+                trust,
+                ping_interval,
+                ping_timeout,
+                reconnect,
+                timeout,
+                jobs,
+                eol,
+                convert_eol,
+                update_only,
+                ignore_existing,
+                preallocate,
+                size_only,
+                trust_dir_mtime,
+                specials,
+                max_depth,
+                no_default_excludes,
+                exclude,
+                stage_dir,
+                staged_ttl,
+                on_sync,
+                on_change,
+                control,
+                status_addr,
+                json,
+                audit_log,
+                webhook,
+                sync_state,
+                delete_after,
+                keep_versions,
+                journal,
+                max_disk_usage,
+                max_message_size,
+                memory_limit,
+                use_trash,
+                backup_dir,
+                backup_interval,
+                keep_hourly,
+                keep_daily,
+                keep_weekly,
+                forward,
+                forward_key,
+                target,
+                s3_endpoint,
+                s3_region,
+            } => {
+                let audit_log = audit_log.as_deref().map(|path| {
+                    AuditLog::open(path).map(Arc::new).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(1)
+                    })
+                });
+                let tls = tls_cert.as_ref().zip(tls_key.as_ref()).map(|(cert, key)| {
+                    let client_ca = ca.as_deref().filter(|_| *require_client_cert);
+                    ServerTls::load(cert, key, client_ca).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(1)
+                    })
+                });
+                let identities =
+                    identities(identity, trusted_peers, *trust).unwrap_or_else(|err| {
+                        println!("An error occurred:\n{:#}", err);
+                        process::exit(1)
+                    });
+                let mut options = receiver::ReceiverOptions {
+                    bind: *bind,
+                    access: AccessOptions {
+                        allow: allow.clone(),
+                        max_rate: *max_connections_per_minute,
+                        max_connections: *max_connections,
+                    },
+                    path: path.clone(),
+                    tls,
+                    identities,
+                    keepalive: KeepaliveConfig {
+                        interval: *ping_interval,
+                        timeout: *ping_timeout,
+                    },
+                    reconnect: *reconnect,
+                    timeout: *timeout,
+                    jobs: *jobs,
+                    scan: ScanOptions {
+                        size_only: *size_only,
+                        quick_check: false,
+                        default_excludes: !*no_default_excludes,
+                        trust_dir_mtime: *trust_dir_mtime,
+                        excludes: excludes(exclude),
+                        fifos: *specials,
+                        follow_links: false,
+                        max_depth: *max_depth,
+                    },
+                    apply: Arc::new(receiver::ApplyOptions {
+                        middleware: middleware(eol, convert_eol),
+                        update_only: *update_only,
+                        ignore_existing: *ignore_existing,
+                        quota: max_disk_usage.map(receiver::quota::Quota::new),
+                        preallocate: *preallocate,
+                        tombstones: delete_after.map(receiver::Tombstones::new),
+                        versions: keep_versions.map(receiver::Versions::new),
+                        trash: *use_trash,
+                        specials: *specials,
+                        blobs: Default::default(),
+                        converted: Default::default(),
+                        wal: journal.then(receiver::WriteAheadLog::default),
+                    }),
+                    metrics: Default::default(),
+                    stage_dir: stage_dir.clone(),
+                    staged_ttl: *staged_ttl,
+                    key: key.clone(),
+                    control: control.clone(),
+                    status_api: *status_addr,
+                    hooks: receiver::hooks::Hooks::new(on_sync.clone(), on_change.clone()),
+                    json_summary: *json,
+                    audit_log,
+                    webhooks: webhooks(webhook),
+                    sync_state: sync_state.clone(),
+                    backups: backup_dir
+                        .as_ref()
+                        .map(|dir| receiver::backups::BackupOptions {
+                            dir: dir.clone(),
+                            interval: *backup_interval,
+                            retention: receiver::backups::Retention {
+                                hourly: *keep_hourly,
+                                daily: *keep_daily,
+                                weekly: *keep_weekly,
+                            },
+                        }),
+                    max_message_size: max_message_size.as_u64(),
+                    memory: memory_limit.map(|limit| MemoryBudget::new(limit.as_u64())),
+                    applied: None,
+                };
+                if keep_versions.is_some() || *journal {
+                    options.scan = versions::excluding_versions(options.scan);
+                }
+                if let Some(trash) = use_trash {
+                    options.scan = trash.excluded_from(options.scan);
+                }
+                if max_message_size.as_u64() < MIN_MAX_MESSAGE_SIZE {
+                    println!(
+                        "An error occurred:\n--max-message-size must be at least {}, to fit the fragments of larger messages",
+                        ByteSize::b(MIN_MAX_MESSAGE_SIZE)
+                    );
+                    process::exit(1)
+                }
+                if *use_trash == Some(receiver::Trash::System) && !cfg!(feature = "trash") {
+                    println!("An error occurred:\nthis build has no trash support, rebuild it with the trash feature or use --use-trash local");
+                    process::exit(1)
+                }
+                let res = match (tenants, output_dir) {
+                    (Some(tenants), _) => match TenantsConfig::load(tenants) {
+                        Ok(config) => Gateway::new(*port, config, &options).start().await,
+                        Err(err) => Err(err),
+                    },
+                    (None, Some(output_dir)) => match (forward, target) {
+                        (Some(to), _) => {
+                            // The listener downstream may come up later, or go away for a while.
+                            let sender_options = sender::SenderOptions {
+                                keepalive: options.keepalive,
+                                reconnect: true,
+                                timeout: *timeout,
+                                jobs: *jobs,
+                                key: forward_key.clone(),
+                                ..Default::default()
+                            };
+                            Relay::new(*port, output_dir, to, options, sender_options)
+                                .start()
+                                .await
+                        }
+                        (None, Some(target)) => {
+                            match Credentials::from_env().and_then(|credentials| {
+                                S3::new(target, s3_endpoint.as_deref(), s3_region, credentials)
+                            }) {
+                                Ok(storage) => {
+                                    Target::new(*port, output_dir, storage, options)
+                                        .start()
+                                        .await
+                                }
+                                Err(err) => Err(err),
+                            }
+                        }
+                        (None, None) => {
+                            receiver::Receiver::new(*port, output_dir, options)
+                                .start()
+                                .await
+                        }
+                    },
+                    (None, None) => unreachable!("clap requires --output-dir without --tenants"),
+                };
+                if let Err(err) = res {
+                    println!("An error occurred:\n{}", err);
+                    process::exit(1)
+                }
+            }
+            Commands::Mirror {
+                from,
+                to_dir,
+                watch,
+                jobs,
+                debounce,
+                watcher,
+                no_default_excludes,
+                exclude,
+            } => {
+                let scan = ScanOptions {
+                    size_only: false,
+                    quick_check: false,
+                    default_excludes: !*no_default_excludes,
+                    trust_dir_mtime: false,
+                    excludes: excludes(exclude),
+                    fifos: false,
+                    ..Default::default()
+                };
+                let sender_options = sender::SenderOptions {
+                    jobs: *jobs,
+                    debounce: *debounce,
+                    watcher: watcher.unwrap_or_default(),
+                    scan,
+                    // Nothing goes over the network, compressing would be wasted work.
+                    policies: PolicyTable {
+                        text: Encoding::Raw,
+                        binary: Encoding::Raw,
+                        compressed: Encoding::Raw,
+                        archives: Encoding::Raw,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let receiver_options = receiver::ReceiverOptions {
+                    bind: Ipv4Addr::LOCALHOST.into(),
+                    access: Default::default(),
+                    path: None,
+                    tls: None,
+                    identities: None,
+                    keepalive: sender_options.keepalive,
+                    reconnect: false,
+                    timeout: sender_options.timeout,
+                    jobs: *jobs,
+                    scan,
+                    apply: Default::default(),
+                    metrics: Default::default(),
+                    stage_dir: None,
+                    staged_ttl: None,
+                    key: None,
+                    control: None,
+                    status_api: None,
+                    hooks: Default::default(),
+                    json_summary: false,
+                    audit_log: None,
+                    webhooks: Default::default(),
+                    sync_state: None,
+                    backups: None,
+                    max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                    memory: None,
+                    applied: None,
+                };
+                let roots = source_roots(from, &[], None).unwrap_or_else(|err| {
+                    println!("An error occurred:\n{}", err);
+                    process::exit(1)
+                });
+                let mirror = Mirror::new(roots, to_dir, sender_options, receiver_options);
+                let res = mirror.start(*watch).await;
                 if let Err(err) = res {
                     println!("An error occurred:\n{}", err);
                     process::exit(1)
                 }
             }
-            Commands::Listen { port, output_dir } => {
-                let receiver = receiver::Receiver::new(*port, output_dir);
-                let res = receiver.start().await;
+            Commands::Init { output, force } => {
+                if let Err(err) = init::run(output, *force) {
+                    println!("An error occurred:\n{:#}", err);
+                    process::exit(1)
+                }
+            }
+            Commands::Keygen { identity, force } => {
+                let generated = Identity::generate();
+                if let Err(err) = generated.save(identity, *force) {
+                    println!("An error occurred:\n{:#}", err);
+                    process::exit(1)
+                }
+                println!("Identity written to {}", quoted(identity));
+                println!("Public key: {}", generated.public_key());
+            }
+            Commands::Id { identity } => match Identity::load(identity) {
+                Ok(identity) => println!("{}", identity.public_key()),
+                Err(err) => {
+                    println!("An error occurred:\n{:#}", err);
+                    process::exit(1)
+                }
+            },
+            Commands::Doctor {
+                from,
+                to,
+                key,
+                timeout,
+            } => {
+                let options = doctor::Options {
+                    from: from.clone(),
+                    to: to.clone(),
+                    key: key.clone(),
+                    timeout: *timeout,
+                };
+                if !doctor::run(&options).await {
+                    process::exit(1)
+                }
+            }
+            Commands::Bench {
+                to,
+                key,
+                files,
+                min_size,
+                max_size,
+                text_ratio,
+                jobs,
+                policy,
+                size_only,
+                timeout,
+            } => {
+                let options = bench::Options {
+                    to: to.clone(),
+                    key: key.clone(),
+                    shape: bench::TreeShape {
+                        files: *files,
+                        min_size: *min_size,
+                        max_size: *max_size,
+                        text_ratio: *text_ratio,
+                    },
+                    jobs: *jobs,
+                    policies: policy
+                        .iter()
+                        .copied()
+                        .fold(PolicyTable::default(), PolicyTable::with),
+                    size_only: *size_only,
+                    timeout: *timeout,
+                };
+                match bench::run(&options).await {
+                    Ok(report) => println!("{}", report),
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+            }
+            Commands::Snapshot {
+                dir,
+                out,
+                size_only,
+                no_default_excludes,
+                exclude,
+            } => {
+                let options = ScanOptions {
+                    size_only: *size_only,
+                    quick_check: false,
+                    default_excludes: !*no_default_excludes,
+                    trust_dir_mtime: false,
+                    excludes: excludes(exclude),
+                    fifos: false,
+                    ..Default::default()
+                };
+                let res = match Snapshot::take(dir, options).await {
+                    Ok(snapshot) => snapshot.write(out).map(|_| snapshot.tree.len()),
+                    Err(err) => Err(err),
+                };
+                match res {
+                    Ok(entries) => println!("Saved {} entries to {}", entries, out.display()),
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+            }
+            Commands::DiffSnapshots { before, after } => {
+                match Snapshot::read(before).and_then(|before| Ok((before, Snapshot::read(after)?)))
+                {
+                    Ok((before, after)) => println!("{}", SnapshotDiff::new(&before, &after)),
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+            }
+            Commands::Manifest {
+                dir,
+                out,
+                algo,
+                no_default_excludes,
+                exclude,
+            } => {
+                let options = ScanOptions {
+                    default_excludes: !*no_default_excludes,
+                    excludes: excludes(exclude),
+                    ..Default::default()
+                };
+                let res = match manifest::generate(dir, *algo, options).await {
+                    Ok(manifest) => match out {
+                        Some(out) => std::fs::write(out, manifest).map_err(Into::into),
+                        None => {
+                            print!("{}", manifest);
+                            Ok(())
+                        }
+                    },
+                    Err(err) => Err(err),
+                };
                 if let Err(err) = res {
                     println!("An error occurred:\n{}", err);
                     process::exit(1)
                 }
             }
+            Commands::Check {
+                manifest,
+                dir,
+                no_default_excludes,
+                exclude,
+            } => {
+                let options = ScanOptions {
+                    default_excludes: !*no_default_excludes,
+                    excludes: excludes(exclude),
+                    ..Default::default()
+                };
+                match manifest::check(dir, manifest, options).await {
+                    Ok(report) => {
+                        println!("{}", report);
+                        if report.failed() > 0 {
+                            process::exit(1)
+                        }
+                    }
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+            }
+            Commands::Restore { path, version, dir } => {
+                let res = match version {
+                    Some(version) => versions::restore(dir, path, version)
+                        .map(|()| println!("Restored {} from version {}", quoted(path), version)),
+                    None => versions::list(dir, path).map(|versions| {
+                        match versions.is_empty() {
+                            true => println!("No versions of {} kept", quoted(path)),
+                            false => println!("Versions of {}, oldest first:", quoted(path)),
+                        }
+                        for (version, size) in versions {
+                            println!("  - {} ({})", version, ByteSize::b(size));
+                        }
+                    }),
+                };
+                if let Err(err) = res {
+                    println!(
+                        "An error occurred:
+{}",
+                        err
+                    );
+                    process::exit(1)
+                }
+            }
+            Commands::Conformance { vectors, bless } => {
+                if *bless {
+                    if let Err(err) = conformance::bless(vectors) {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+
+                match conformance::run(vectors).await {
+                    Ok(0) => println!("All vectors passed"),
+                    Ok(failed) => {
+                        println!("{} vectors failed", failed);
+                        process::exit(1)
+                    }
+                    Err(err) => {
+                        println!("An error occurred:\n{}", err);
+                        process::exit(1)
+                    }
+                }
+            }
+            Commands::Status { control, json } => show_status(control, *json).await,
+            Commands::Ctl { command, control } => match control::send(control, *command).await {
+                Ok(reply) => {
+                    println!("{:#}", reply.status);
+                    if let Some(error) = reply.error {
+                        println!("An error occurred:\n{}", error);
+                        process::exit(1)
+                    }
+                }
+                Err(err) => {
+                    println!("An error occurred:\n{}", err);
+                    process::exit(1)
+                }
+            },
+            Commands::Approve(args) => approve(args).await,
+            Commands::Staged { command } => match command {
+                StagedCommands::List { stage_dir } => list_staged(stage_dir).await,
+                StagedCommands::Show { session, stage_dir } => {
+                    show_staged(stage_dir, session).await
+                }
+                StagedCommands::Approve(args) => approve(args).await,
+                StagedCommands::Reject { session, stage_dir } => {
+                    match staging::reject(stage_dir, session).await {
+                        Ok(()) => println!("Session {} discarded", session),
+                        Err(err) => {
+                            println!("An error occurred:\n{}", err);
+                            process::exit(1)
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn approve(args: &ApproveArgs) {
+    let options = Arc::new(receiver::ApplyOptions {
+        middleware: middleware(&args.eol, &args.convert_eol),
+        update_only: args.update_only,
+        ..Default::default()
+    });
+    match staging::approve(&args.stage_dir, &args.session, args.jobs, options).await {
+        Ok(()) => println!("Session {} applied", args.session),
+        Err(err) => {
+            println!("An error occurred:\n{}", err);
+            process::exit(1)
+        }
+    }
+}
+
+async fn list_staged(stage_dir: &Path) {
+    let sessions = staging::list(stage_dir).await.unwrap_or_else(|err| {
+        println!("An error occurred:\n{}", err);
+        process::exit(1)
+    });
+
+    for session in sessions {
+        let state = match session.finished {
+            true => "ready",
+            false => "in progress",
+        };
+        println!(
+            "{}  {}  {}  {}",
+            session.id,
+            humantime::format_rfc3339_seconds(session.started),
+            state,
+            session.out_dir.display()
+        );
+    }
+}
+
+async fn show_staged(stage_dir: &Path, session: &str) {
+    let res = async {
+        let info = staging::read_info(stage_dir, session).await?;
+        let journal = staging::read_journal(stage_dir, session).await?;
+        anyhow::Ok((info, journal))
+    };
+    let (info, journal) = res.await.unwrap_or_else(|err| {
+        println!("An error occurred:\n{}", err);
+        process::exit(1)
+    });
+
+    println!("Session {} for {}", info.id, info.out_dir.display());
+    println!(
+        "Started {}{}",
+        humantime::format_rfc3339_seconds(info.started),
+        match info.finished {
+            true => "",
+            false => ", still in progress",
         }
+    );
+    for diff in info.diffs {
+        println!("{}\n", diff);
+    }
+
+    println!("Changes:");
+    for entry in journal {
+        println!("  - {}", entry);
+    }
+}
+
+async fn show_status(control: &ControlAddr, json: bool) {
+    let status = match control::send(control, Command::Status).await {
+        Ok(reply) => reply.status,
+        Err(err) => {
+            println!("An error occurred:\n{}", err);
+            process::exit(1)
+        }
+    };
+    if json {
+        println!("{:#}", status);
+        return;
+    }
+
+    let paused = match status["paused"].as_bool() {
+        Some(true) => ", paused",
+        _ => "",
+    };
+    match status["role"].as_str() {
+        Some("sender") => println!(
+            "Sender to {}, {}{}",
+            status["listener"].as_str().unwrap_or_default(),
+            status["state"].as_str().unwrap_or_default(),
+            paused
+        ),
+        _ => println!(
+            "Listener into {}{}",
+            status["output_dir"].as_str().unwrap_or_default(),
+            paused
+        ),
+    }
+    if let Ok(metrics) = serde_json::from_value::<Metrics>(status["metrics"].clone()) {
+        println!("{}", metrics);
+    }
+    let activity = serde_json::from_value::<Activity>(status["activity"].clone());
+    println!("{}", activity.unwrap_or_default());
+}
+
+fn middleware(eol: &Option<LineEnding>, convert_eol: &[ConvertEol]) -> MiddlewareChain {
+    eol.map(ConvertEol::from)
+        .into_iter()
+        .chain(convert_eol.iter().cloned())
+        .fold(MiddlewareChain::new(), MiddlewareChain::with)
+}
+
+/// A lone `--from` directory is synced into the listener's directory itself, as before roots
+/// could be combined. Otherwise each directory or file gets its own entry. Everything is then
+/// moved under `dest_prefix`.
+/// Compiles the `--exclude` patterns, exiting on invalid ones.
+fn excludes(patterns: &[String]) -> Option<&'static Excludes> {
+    Excludes::leak(patterns).unwrap_or_else(|err| {
+        println!("An error occurred:\n{:#}", err);
+        process::exit(1)
+    })
+}
+
+fn webhooks(urls: &[String]) -> Webhooks {
+    Webhooks::new(urls).unwrap_or_else(|err| {
+        println!("An error occurred:\n{:#}", err);
+        process::exit(1)
+    })
+}
+
+/// TLS for `wss://` listener addresses. Client certificates are only presented over TLS.
+fn client_tls(
+    to: &str,
+    ca: &Option<PathBuf>,
+    client_cert: &Option<PathBuf>,
+    client_key: &Option<PathBuf>,
+) -> anyhow::Result<Option<ClientTls>> {
+    if !to.starts_with("wss://") {
+        if ca.is_some() || client_cert.is_some() {
+            anyhow::bail!("--ca and --client-cert need a wss:// listener address")
+        }
+        return Ok(None);
+    }
+
+    let identity = client_cert.as_deref().zip(client_key.as_deref());
+    ClientTls::load(ca.as_deref(), identity).map(Some)
+}
+
+/// `--proxy`, or the proxy set in the environment for `to`.
+fn proxy_for(to: &str, proxy: &Option<Proxy>) -> Result<Option<Proxy>, String> {
+    match proxy {
+        Some(proxy) => Ok(Some(proxy.clone())),
+        None => Proxy::from_env(to),
+    }
+}
+
+/// Loads `--identity` and its trusted peers, if given.
+fn identities(
+    identity: &Option<PathBuf>,
+    trusted_peers: &Option<PathBuf>,
+    trust: Trust,
+) -> anyhow::Result<Option<Arc<Identities>>> {
+    let Some(identity) = identity else {
+        return Ok(None);
+    };
+
+    let trusted_peers = trusted_peers.clone().unwrap_or_else(|| {
+        let mut path = identity.as_os_str().to_owned();
+        path.push(".peers");
+        PathBuf::from(path)
+    });
+    Ok(Some(Arc::new(Identities {
+        identity: Identity::load(identity)?,
+        peers: TrustedPeers::load(&trusted_peers, trust)?,
+    })))
+}
+
+fn source_roots(
+    from: &[String],
+    from_map: &[SourceRoot],
+    dest_prefix: Option<&Path>,
+) -> anyhow::Result<Roots> {
+    let roots = match (from, from_map) {
+        ([dir], []) if !Path::new(dir).is_file() => Roots::single(dir),
+        _ => {
+            let named = from.iter().map(SourceRoot::named_after);
+            let roots = named
+                .chain(from_map.iter().cloned().map(Ok))
+                .collect::<anyhow::Result<_>>()?;
+
+            Roots::new(roots)?
+        }
+    };
+
+    match dest_prefix {
+        Some(prefix) => roots.prefixed(prefix),
+        None => Ok(roots),
     }
 }
@@ -0,0 +1,273 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    core::{
+        message::{Handshake, ReceiverMessage, SenderMessage, TreePage},
+        policy,
+        utils::quoted,
+    },
+    receiver::{apply_change, ApplyOptions},
+};
+
+/// A golden wire message: its encoding must not change unless the protocol does, so that
+/// alternative implementations and refactors can be checked against it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Vector {
+    pub description: String,
+    pub kind: MessageKind,
+    /// The message in serde's JSON representation, except that byte strings such as archives may
+    /// be written as `{"hex": "..."}` rather than as arrays of numbers.
+    pub message: serde_json::Value,
+    /// The bincode encoding of the message, as sent in a websocket binary frame, in hex.
+    pub bytes: String,
+    /// For changes, the directory before and after the receiver applies them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Handshake,
+    /// A `TreePage` following `Handshake::PagedSync`.
+    TreePage,
+    Sender,
+    Receiver,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Outcome {
+    pub before: DirState,
+    pub after: DirState,
+}
+
+/// Every directory and file below a directory, with the files' contents.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirState {
+    #[serde(default)]
+    pub dirs: Vec<PathBuf>,
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+impl Vector {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", quoted(path)))?;
+        serde_json::from_str(&contents).with_context(|| format!("parsing {}", quoted(path)))
+    }
+
+    /// Checks that the bytes decode to the message and the message encodes to the bytes, then
+    /// that applying the change gives the expected outcome.
+    pub async fn check(&self, scratch_dir: &Path) -> anyhow::Result<()> {
+        let bytes = hex::decode(&self.bytes).context("bytes are not valid hex")?;
+        match self.kind {
+            MessageKind::Handshake => self.check_encoding::<Handshake>(&bytes)?,
+            MessageKind::TreePage => self.check_encoding::<TreePage>(&bytes)?,
+            MessageKind::Sender => self.check_encoding::<SenderMessage>(&bytes)?,
+            MessageKind::Receiver => self.check_encoding::<ReceiverMessage>(&bytes)?,
+        }
+
+        let Some(outcome) = &self.outcome else {
+            return Ok(());
+        };
+        let SenderMessage::Sync(message) = bincode::deserialize(&bytes)? else {
+            bail!("only changes have an outcome")
+        };
+
+        outcome.before.create(scratch_dir)?;
+        let change = policy::decode(message.change).context("decoding the change")?;
+        let applied = apply_change(scratch_dir, change, &ApplyOptions::default()).await;
+        let state = DirState::read(scratch_dir)?;
+        match applied {
+            Ok(()) => ensure!(
+                state == outcome.after,
+                "applying gives {}",
+                serde_json::to_string(&state)?
+            ),
+            Err(err) => bail!("applying fails: {:#}", err),
+        }
+
+        Ok(())
+    }
+
+    /// Recomputes the bytes from the message, for when the protocol changes on purpose.
+    pub fn bless(&mut self) -> anyhow::Result<()> {
+        let bytes = match self.kind {
+            MessageKind::Handshake => encode::<Handshake>(&self.message)?,
+            MessageKind::TreePage => encode::<TreePage>(&self.message)?,
+            MessageKind::Sender => encode::<SenderMessage>(&self.message)?,
+            MessageKind::Receiver => encode::<ReceiverMessage>(&self.message)?,
+        };
+        self.bytes = hex::encode(bytes);
+
+        Ok(())
+    }
+
+    fn check_encoding<T: Serialize + DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let message = unhex(self.message.clone())?;
+        let decoded: T = bincode::deserialize(bytes).context("decoding the bytes")?;
+        ensure!(
+            bincode::serialized_size(&decoded)? == bytes.len() as u64,
+            "the bytes do not decode to exactly one message"
+        );
+        let decoded = serde_json::to_value(&decoded)?;
+        ensure!(
+            decoded == message,
+            "the bytes decode to {}",
+            serde_json::to_string(&decoded)?
+        );
+
+        let encoded = encode::<T>(&self.message)?;
+        ensure!(
+            encoded == bytes,
+            "the message encodes to {}",
+            hex::encode(encoded)
+        );
+
+        Ok(())
+    }
+}
+
+fn encode<T: Serialize + DeserializeOwned>(message: &serde_json::Value) -> anyhow::Result<Vec<u8>> {
+    let message: T =
+        serde_json::from_value(unhex(message.clone())?).context("parsing the message")?;
+    Ok(bincode::serialize(&message)?)
+}
+
+/// `message` with its `{"hex": "..."}` byte strings as the arrays of numbers serde expects.
+fn unhex(message: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    use serde_json::Value;
+
+    Ok(match message {
+        Value::Object(object) => match object.get("hex") {
+            Some(Value::String(hex)) if object.len() == 1 => {
+                let bytes = hex::decode(hex).context("byte string is not valid hex")?;
+                Value::from(bytes)
+            }
+            _ => Value::Object(
+                object
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, unhex(value)?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+        },
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(unhex)
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        value => value,
+    })
+}
+
+impl DirState {
+    fn create(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for path in &self.dirs {
+            std::fs::create_dir_all(dir.join(path))?;
+        }
+        for (path, contents) in &self.files {
+            std::fs::write(dir.join(path), contents)?;
+        }
+
+        Ok(())
+    }
+
+    fn read(dir: &Path) -> anyhow::Result<Self> {
+        let mut state = Self::default();
+        for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+            let entry = entry?;
+            let path = entry.path().strip_prefix(dir)?.to_owned();
+            if entry.file_type().is_dir() {
+                state.dirs.push(path);
+            } else {
+                let contents = std::fs::read(entry.path())?;
+                state
+                    .files
+                    .insert(path, String::from_utf8_lossy(&contents).into_owned());
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+/// Vectors found in `dir`, by file name.
+pub fn vectors(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", quoted(dir)))? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Checks every vector in `dir`, printing one line per vector. Returns the number of failures.
+pub async fn run(dir: &Path) -> anyhow::Result<usize> {
+    let scratch =
+        std::env::temp_dir().join(format!("white-caiman-conformance-{}", std::process::id()));
+    let mut failed = 0;
+    for (i, path) in vectors(dir)?.into_iter().enumerate() {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let scratch_dir = scratch.join(i.to_string());
+        let res = match Vector::load(&path) {
+            Ok(vector) => vector.check(&scratch_dir).await,
+            Err(err) => Err(err),
+        };
+
+        match res {
+            Ok(()) => println!("ok      {}", name),
+            Err(err) => {
+                failed += 1;
+                println!("FAILED  {}: {:#}", name, err);
+            }
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(&scratch);
+    Ok(failed)
+}
+
+/// Rewrites the bytes of every vector in `dir` from its message.
+pub fn bless(dir: &Path) -> anyhow::Result<()> {
+    for path in vectors(dir)? {
+        let mut vector = Vector::load(&path)?;
+        vector.bless()?;
+        let mut contents = serde_json::to_string_pretty(&vector)?;
+        contents.push('\n');
+        std::fs::write(&path, contents).with_context(|| format!("writing {}", quoted(&path)))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_conformance_vectors() -> anyhow::Result<()> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance/vectors");
+        assert!(!vectors(&dir)?.is_empty());
+        assert_eq!(run(&dir).await?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+
+use super::{message::FileChangeMessage, wire_path};
+
+/// Errors kept for `status`, the oldest ones being dropped first.
+const RECENT_ERRORS: usize = 10;
+/// Changes kept for `status` and the TUI, likewise.
+const RECENT_CHANGES: usize = 100;
+
+/// What a sender or listener is connected to and has done lately, reported by its control
+/// endpoint. Times are RFC 3339 timestamps.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Activity {
+    pub connected: bool,
+    /// The other end of the current or last connection.
+    pub peer: Option<String>,
+    /// Changes received or to send that are not done yet.
+    pub files_pending: usize,
+    /// Encoded messages sent or received, across sessions.
+    pub bytes_transferred: u64,
+    /// When the last batch of changes was done.
+    pub last_sync: Option<String>,
+    pub recent_errors: VecDeque<RecentError>,
+    /// The latest changes sent or applied.
+    #[serde(default)]
+    pub recent_changes: VecDeque<RecentChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentError {
+    pub at: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentChange {
+    pub at: String,
+    /// What happened to `path`, e.g. `edited`.
+    pub change: String,
+    #[serde(with = "wire_path")]
+    pub path: PathBuf,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+impl Activity {
+    pub fn connected(&mut self, peer: impl ToString) {
+        self.connected = true;
+        self.peer = Some(peer.to_string());
+    }
+
+    pub fn disconnected(&mut self) {
+        self.connected = false;
+        self.files_pending = 0;
+    }
+
+    pub fn synced(&mut self) {
+        self.last_sync = Some(now());
+    }
+
+    pub fn error(&mut self, error: impl ToString) {
+        if self.recent_errors.len() == RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+
+        self.recent_errors.push_back(RecentError {
+            at: now(),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn changed(&mut self, change: &FileChangeMessage) {
+        self.record_change(change.label(), change.path().to_owned());
+    }
+
+    /// Like `changed`, for changes already consumed, described by their label.
+    pub fn record_change(&mut self, change: &str, path: PathBuf) {
+        if self.recent_changes.len() == RECENT_CHANGES {
+            self.recent_changes.pop_front();
+        }
+
+        self.recent_changes.push_back(RecentChange {
+            at: now(),
+            change: change.to_owned(),
+            path,
+        });
+    }
+}
+
+impl Display for Activity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.connected, &self.peer) {
+            (true, Some(peer)) => writeln!(f, "Connected to {}", peer)?,
+            (false, Some(peer)) => writeln!(f, "Not connected, last connected to {}", peer)?,
+            (_, None) => writeln!(f, "Not connected")?,
+        }
+        writeln!(f, "Files pending: {}", self.files_pending)?;
+        writeln!(f, "Transferred: {}", ByteSize::b(self.bytes_transferred))?;
+        match &self.last_sync {
+            Some(last_sync) => {
+                let ago = humantime::parse_rfc3339(last_sync)
+                    .ok()
+                    .and_then(|last_sync| last_sync.elapsed().ok())
+                    .map(|ago| Duration::from_secs(ago.as_secs()));
+                match ago {
+                    Some(ago) => writeln!(
+                        f,
+                        "Last sync: {} ({} ago)",
+                        last_sync,
+                        humantime::format_duration(ago)
+                    )?,
+                    None => writeln!(f, "Last sync: {}", last_sync)?,
+                }
+            }
+            None => writeln!(f, "Last sync: never")?,
+        }
+
+        match self.recent_errors.is_empty() {
+            true => write!(f, "Recent errors: none"),
+            false => {
+                write!(f, "Recent errors:")?;
+                for error in &self.recent_errors {
+                    write!(f, "\n  - {}  {}", error.at, error.error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_recent_errors_are_kept() {
+        let mut activity = Activity::default();
+        for i in 0..RECENT_ERRORS + 2 {
+            activity.error(format!("error {}", i));
+        }
+
+        assert_eq!(activity.recent_errors.len(), RECENT_ERRORS);
+        assert_eq!(activity.recent_errors[0].error, "error 2");
+        assert_eq!(
+            activity.recent_errors.back().unwrap().error,
+            format!("error {}", RECENT_ERRORS + 1)
+        );
+    }
+}
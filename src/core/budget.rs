@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Permits count kibibytes, `Semaphore::acquire_many_owned` taking a `u32`.
+const UNIT: u64 = 1024;
+
+/// Bounds the bytes of payloads held in memory at once across a session's pipeline, shared by
+/// its tasks. Payloads larger than the whole budget wait for every other one to be released
+/// instead of waiting forever.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    permits: Arc<Semaphore>,
+    total: u32,
+}
+
+/// Bytes reserved from a `MemoryBudget`, released when dropped.
+#[derive(Debug, Default)]
+pub struct Reservation {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        let total = limit.div_ceil(UNIT).clamp(1, Semaphore::MAX_PERMITS as u64) as u32;
+        Self {
+            permits: Arc::new(Semaphore::new(total as usize)),
+            total,
+        }
+    }
+
+    /// Waits until `bytes` fit within the budget.
+    pub async fn reserve(&self, bytes: u64) -> Reservation {
+        let permits = self.units(bytes);
+        if permits == 0 {
+            return Reservation::default();
+        }
+
+        // The semaphore is never closed.
+        let permit = self.permits.clone().acquire_many_owned(permits).await.ok();
+        Reservation { _permit: permit }
+    }
+
+    /// Reserves `bytes` if they fit within the budget right away.
+    pub fn try_reserve(&self, bytes: u64) -> Option<Reservation> {
+        let permits = self.units(bytes);
+        if permits == 0 {
+            return Some(Reservation::default());
+        }
+
+        let permit = self.permits.clone().try_acquire_many_owned(permits).ok()?;
+        Some(Reservation {
+            _permit: Some(permit),
+        })
+    }
+
+    /// The bytes in the budget.
+    pub fn limit(&self) -> u64 {
+        self.total as u64 * UNIT
+    }
+
+    /// Bytes not reserved at the moment.
+    pub fn available(&self) -> u64 {
+        self.permits.available_permits() as u64 * UNIT
+    }
+
+    fn units(&self, bytes: u64) -> u32 {
+        bytes.div_ceil(UNIT).min(self.total as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{test, time::timeout};
+
+    #[test]
+    async fn test_reservations_wait_for_room() -> anyhow::Result<()> {
+        let budget = MemoryBudget::new(4 * UNIT);
+        let first = budget.reserve(3 * UNIT).await;
+        assert_eq!(budget.available(), UNIT);
+        assert!(budget.try_reserve(2 * UNIT).is_none());
+
+        let waiting = budget.clone();
+        let second = tokio::spawn(async move { waiting.reserve(2 * UNIT).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!second.is_finished());
+        drop(first);
+        let second = timeout(Duration::from_secs(1), second).await??;
+        assert_eq!(budget.available(), 2 * UNIT);
+        drop(second);
+
+        // Payloads larger than the budget take all of it.
+        let oversized = timeout(Duration::from_secs(1), budget.reserve(10 * UNIT)).await?;
+        assert_eq!(budget.available(), 0);
+        drop(oversized);
+        assert_eq!(budget.available(), 4 * UNIT);
+        assert!(budget.try_reserve(0).is_some());
+
+        Ok(())
+    }
+}
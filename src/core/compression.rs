@@ -1,20 +1,73 @@
-use std::path::Path;
-
-use anyhow::Context;
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    file_tree::ScanOptions,
+    specials::special_kind,
+    transfer::Oversized,
+    walk::{checked, walk},
+};
+use anyhow::{bail, Context};
 use bytes::Bytes;
+use futures::{AsyncReadExt, StreamExt};
+
+/// A file of a `FileBatch`, with its path relative to the batch's directory.
+#[derive(Debug)]
+pub struct PackedFile {
+    pub path: PathBuf,
+    pub contents: Bytes,
+    pub mtime: SystemTime,
+}
 
 pub async fn compress_dir(path: impl AsRef<Path>) -> anyhow::Result<Bytes> {
+    let (compressed, _) = compress_dir_with_limit(path, u64::MAX, ScanOptions::default()).await?;
+    Ok(compressed)
+}
+
+/// Archives the directory, leaving out the files larger than `max_file_size` bytes so the archive
+/// can be held in memory. The files left out are returned with paths relative to the directory.
+/// Entries excluded by `scan` and special files are skipped silently, symlinks are followed with
+/// `ScanOptions::follow_links`, and symlink loops fail, see `walk::WalkError`.
+pub async fn compress_dir_with_limit(
+    path: impl AsRef<Path>,
+    max_file_size: u64,
+    scan: ScanOptions,
+) -> anyhow::Result<(Bytes, Vec<Oversized>)> {
+    let root = path.as_ref();
     let mut tar = async_tar::Builder::new(Vec::new());
+    let mut oversized = vec![];
+
+    for entry in walk(root, scan)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| !scan.excludes(entry.path()))
+    {
+        let entry = checked(entry, scan).context("compressing dir")?;
+        let relative_path = entry.path().strip_prefix(root)?;
+        if special_kind(entry.file_type()).is_some() {
+            continue;
+        }
+        if entry.file_type().is_file() {
+            let size = entry.metadata().context("compressing dir")?.len();
+            if size > max_file_size {
+                oversized.push(Oversized {
+                    path: relative_path.to_owned(),
+                    size,
+                });
+                continue;
+            }
+        }
 
-    tar.append_dir_all(".", path.as_ref())
-        .await
-        .context("compressing dir")?;
-    let inner = tar.into_inner().await.context("finalzing archive")?;
-    let inner_bytes = Bytes::from(inner);
+        tar.append_path_with_name(entry.path(), relative_path)
+            .await
+            .context("compressing dir")?;
+    }
 
-    dbg!(path.as_ref(), &inner_bytes);
+    let inner = tar.into_inner().await.context("finalzing archive")?;
 
-    Ok(inner_bytes)
+    Ok((Bytes::from(inner), oversized))
 }
 
 pub async fn decompress_dir(path: impl AsRef<Path>, compressed: &[u8]) -> anyhow::Result<()> {
@@ -26,6 +79,74 @@ pub async fn decompress_dir(path: impl AsRef<Path>, compressed: &[u8]) -> anyhow
     Ok(())
 }
 
+/// The paths of the entries of a tar archive, relative to the directory it unpacks into.
+pub async fn archive_entries(compressed: &[u8]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = async_tar::Archive::new(compressed)
+        .entries()
+        .context("listing archive")?;
+
+    let mut paths = vec![];
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context("listing archive")?;
+        paths.push(entry.path()?.into_owned().into());
+    }
+
+    Ok(paths)
+}
+
+/// Packs files into a tar archive. Tar only keeps modification times to the second.
+pub async fn pack_files(files: &[PackedFile]) -> anyhow::Result<Bytes> {
+    let mut tar = async_tar::Builder::new(Vec::new());
+    for file in files {
+        let mut header = async_tar::Header::new_gnu();
+        header.set_size(file.contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(
+            file.mtime
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        tar.append_data(&mut header, &file.path, file.contents.as_ref())
+            .await
+            .context("packing files")?;
+    }
+    let archive = tar.into_inner().await.context("finalizing archive")?;
+
+    Ok(Bytes::from(archive))
+}
+
+/// Reverts `pack_files`. Only plain files with relative paths that stay inside the directory are
+/// accepted.
+pub async fn unpack_files(archive: &[u8]) -> anyhow::Result<Vec<PackedFile>> {
+    let mut entries = async_tar::Archive::new(archive)
+        .entries()
+        .context("unpacking files")?;
+
+    let mut files = vec![];
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("unpacking files")?;
+        let path: PathBuf = entry.path()?.into_owned().into();
+        let is_plain = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !entry.header().entry_type().is_file() || !is_plain || path.as_os_str().is_empty() {
+            bail!("unexpected entry {} in packed files", path.display());
+        }
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents).await?;
+        files.push(PackedFile {
+            path,
+            contents: Bytes::from(contents),
+            mtime,
+        });
+    }
+
+    Ok(files)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,6 +248,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    async fn test_files_over_the_limit_are_left_out() -> anyhow::Result<()> {
+        let source_dir = TempDir::new()?;
+        create_test_files(source_dir.path()).await?;
+        fs::write(source_dir.path().join("subdir/large.bin"), vec![0; 1024])?;
+
+        let (compressed, oversized) =
+            compress_dir_with_limit(source_dir.path(), 512, ScanOptions::default()).await?;
+        let output_dir = TempDir::new()?;
+        decompress_dir(output_dir.path(), &compressed).await?;
+
+        verify_files(output_dir.path()).await?;
+        assert!(!output_dir.path().join("subdir/large.bin").exists());
+        assert_eq!(oversized.len(), 1);
+        assert_eq!(oversized[0].path, Path::new("subdir/large.bin"));
+        assert_eq!(oversized[0].size, 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_pack_unpack_files() -> anyhow::Result<()> {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let files = vec![
+            PackedFile {
+                path: "a.txt".into(),
+                contents: Bytes::from("first"),
+                mtime,
+            },
+            PackedFile {
+                path: "nested/b.txt".into(),
+                contents: Bytes::new(),
+                mtime,
+            },
+        ];
+
+        let unpacked = unpack_files(&pack_files(&files).await?).await?;
+        assert_eq!(unpacked.len(), 2);
+        for (file, unpacked) in files.iter().zip(&unpacked) {
+            assert_eq!(unpacked.path, file.path);
+            assert_eq!(unpacked.contents, file.contents);
+            assert_eq!(unpacked.mtime, mtime);
+        }
+
+        Ok(())
+    }
+
     #[test]
     async fn test_invalid_compressed_data() {
         let output_dir = TempDir::new().unwrap();
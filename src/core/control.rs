@@ -0,0 +1,462 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinHandle,
+};
+
+use super::utils::quoted;
+
+/// Commands accepted by the control endpoint of a running sender or listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Command {
+    Status,
+    /// Hold changes back until resumed, the connection staying up meanwhile.
+    Pause,
+    Resume,
+    /// Compare the whole tree with the other end and resync what differs.
+    Resync,
+    /// Stop gracefully, as on Ctrl-C.
+    Shutdown,
+    /// Apply the deletions a listener holds back, without waiting for their grace period.
+    #[serde(rename = "apply-deletes")]
+    ApplyDeletes,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(Command::Status),
+            "pause" => Ok(Command::Pause),
+            "resume" => Ok(Command::Resume),
+            "resync" => Ok(Command::Resync),
+            "shutdown" => Ok(Command::Shutdown),
+            "apply-deletes" => Ok(Command::ApplyDeletes),
+            _ => Err(format!(
+                "unknown command '{}', expected status, pause, resume, resync, shutdown or apply-deletes",
+                s
+            )),
+        }
+    }
+}
+
+/// One line of JSON sent to the control endpoint, e.g. `{"command":"pause"}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub command: Command,
+}
+
+/// One line of JSON sent back for each request, with the status after the command ran.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub status: serde_json::Value,
+}
+
+/// Where a control endpoint listens: a loopback TCP address, or a Unix socket path.
+#[derive(Debug, Clone)]
+pub enum ControlAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ControlAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return match addr.ip().is_loopback() {
+                true => Ok(ControlAddr::Tcp(addr)),
+                false => Err(format!(
+                    "{} is not a loopback address, control endpoints are only reachable locally",
+                    addr
+                )),
+            };
+        }
+
+        #[cfg(unix)]
+        return Ok(ControlAddr::Unix(PathBuf::from(s)));
+        #[cfg(not(unix))]
+        Err(format!(
+            "expected a loopback address such as 127.0.0.1:7070, got '{}'",
+            s
+        ))
+    }
+}
+
+impl Display for ControlAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ControlAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// State shared between a running sender or listener and its control endpoint. Sessions follow
+/// it through `Controls::attach`.
+#[derive(Debug)]
+pub struct Controls {
+    paused: watch::Sender<bool>,
+    resyncs: watch::Sender<u64>,
+    shutdown: watch::Sender<bool>,
+    apply_deletes: watch::Sender<u64>,
+    sessions: AtomicUsize,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            paused: watch::Sender::new(false),
+            resyncs: watch::Sender::new(0),
+            shutdown: watch::Sender::new(false),
+            apply_deletes: watch::Sender::new(0),
+            sessions: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Controls {
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Completes once a shutdown is requested, right away if it already was.
+    pub async fn shutdown_requested(&self) {
+        let _ = self
+            .shutdown
+            .subscribe()
+            .wait_for(|shutdown| *shutdown)
+            .await;
+    }
+
+    /// Follows the controls for the duration of a session. Resyncs requested before are ignored.
+    pub fn attach(&self) -> SessionControls<'_> {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        let mut paused = self.paused.subscribe();
+        let is_paused = *paused.borrow_and_update();
+        SessionControls {
+            controls: self,
+            is_paused,
+            paused,
+            resyncs: self.resyncs.subscribe(),
+            shutdown: self.shutdown.subscribe(),
+        }
+    }
+
+    /// Changes with every `apply-deletes` request, for listeners holding deletions back.
+    pub fn apply_deletes_requests(&self) -> watch::Receiver<u64> {
+        self.apply_deletes.subscribe()
+    }
+
+    /// Carries out `command`, as if it came from the control endpoint.
+    pub fn run(&self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Status => (),
+            Command::Pause => {
+                self.paused.send_replace(true);
+            }
+            Command::Resume => {
+                self.paused.send_replace(false);
+            }
+            Command::Resync if self.sessions.load(Ordering::Relaxed) == 0 => {
+                return Err("no session is running".into())
+            }
+            Command::Resync if self.is_paused() => {
+                return Err("the session is paused, resume it first".into())
+            }
+            Command::Resync => {
+                self.resyncs.send_modify(|resyncs| *resyncs += 1);
+            }
+            Command::Shutdown => {
+                self.shutdown.send_replace(true);
+            }
+            Command::ApplyDeletes if self.apply_deletes.receiver_count() == 0 => {
+                return Err("deletions are not held back, see listen --delete-after".into())
+            }
+            Command::ApplyDeletes => {
+                self.apply_deletes.send_modify(|requests| *requests += 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "paused": self.is_paused(),
+            "active_sessions": self.sessions.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Adds the state of the controls to `status`, which describes the sender or listener.
+    pub fn describe(&self, mut status: serde_json::Value) -> serde_json::Value {
+        if let (Some(status), serde_json::Value::Object(state)) =
+            (status.as_object_mut(), self.status())
+        {
+            status.extend(state);
+        }
+        status
+    }
+}
+
+/// What a session is asked to do through the `Controls`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlEvent {
+    Paused,
+    Resumed,
+    Resync,
+    Shutdown,
+}
+
+/// A session's view of the `Controls`, counted as running until dropped.
+pub struct SessionControls<'a> {
+    controls: &'a Controls,
+    /// Whether the session is paused as of the last `ControlEvent` it got, so that it only acts
+    /// on a pause or resume once it announced it.
+    is_paused: bool,
+    paused: watch::Receiver<bool>,
+    resyncs: watch::Receiver<u64>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl SessionControls<'_> {
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    /// Waits for the next request. Once a shutdown is requested, it is all this returns.
+    pub async fn next(&mut self) -> ControlEvent {
+        let shutdown = async { self.shutdown.wait_for(|shutdown| *shutdown).await.map(drop) };
+        tokio::select! {
+            Ok(()) = shutdown => ControlEvent::Shutdown,
+            Ok(()) = self.paused.changed() => {
+                self.is_paused = *self.paused.borrow_and_update();
+                match self.is_paused {
+                    true => ControlEvent::Paused,
+                    false => ControlEvent::Resumed,
+                }
+            }
+            Ok(()) = self.resyncs.changed() => ControlEvent::Resync,
+            else => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for SessionControls<'_> {
+    fn drop(&mut self) {
+        self.controls.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves control requests until dropped. `status` describes the sender or listener, and is
+/// merged with the state of the `Controls` in every reply.
+pub struct ControlServer {
+    task: JoinHandle<()>,
+    addr: ControlAddr,
+}
+
+impl ControlServer {
+    pub async fn spawn<F>(
+        addr: ControlAddr,
+        controls: Arc<Controls>,
+        status: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        let state = controls.clone();
+        let status = Arc::new(move || state.describe(status()));
+
+        let (task, addr) = match addr {
+            ControlAddr::Tcp(tcp_addr) => {
+                let listener = TcpListener::bind(tcp_addr)
+                    .await
+                    .with_context(|| format!("binding the control endpoint to {}", tcp_addr))?;
+                let bound = ControlAddr::Tcp(listener.local_addr()?);
+                let task = tokio::spawn(async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        tokio::spawn(serve(stream, controls.clone(), status.clone()));
+                    }
+                });
+                (task, bound)
+            }
+            #[cfg(unix)]
+            ControlAddr::Unix(path) => {
+                let listener = bind_unix(&path).await?;
+                let task = tokio::spawn(async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        tokio::spawn(serve(stream, controls.clone(), status.clone()));
+                    }
+                });
+                (task, ControlAddr::Unix(path))
+            }
+        };
+        println!("Control endpoint listening on {}", addr);
+
+        Ok(Self { task, addr })
+    }
+
+    /// The address requests are served on, with the port picked by the system if it was 0.
+    pub fn addr(&self) -> &ControlAddr {
+        &self.addr
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        #[cfg(unix)]
+        if let ControlAddr::Unix(path) = &self.addr {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Binds a Unix socket, replacing the one left over by a process that did not exit cleanly but
+/// not the one of a process still running.
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path) -> anyhow::Result<tokio::net::UnixListener> {
+    if path.exists() {
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            bail!("{} is in use by another process", quoted(path))
+        }
+        tokio::fs::remove_file(path)
+            .await
+            .with_context(|| format!("removing the stale socket {}", quoted(path)))?;
+    }
+
+    tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("binding the control endpoint to {}", quoted(path)))
+}
+
+async fn serve<S, F>(stream: S, controls: Arc<Controls>, status: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn() -> serde_json::Value + ?Sized,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let res = serde_json::from_str::<Request>(&line)
+            .map_err(|err| format!("invalid request: {}", err))
+            .and_then(|request| controls.run(request.command));
+        let reply = Reply {
+            ok: res.is_ok(),
+            error: res.err(),
+            status: status(),
+        };
+
+        let Ok(mut encoded) = serde_json::to_vec(&reply) else {
+            break;
+        };
+        encoded.push(b'\n');
+        if write.write_all(&encoded).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends `command` to the control endpoint at `addr`, returning its reply.
+pub async fn send(addr: &ControlAddr, command: Command) -> anyhow::Result<Reply> {
+    match addr {
+        ControlAddr::Tcp(tcp_addr) => {
+            let stream = TcpStream::connect(tcp_addr)
+                .await
+                .with_context(|| format!("connecting to {}", addr))?;
+            request(stream, command).await
+        }
+        #[cfg(unix)]
+        ControlAddr::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .with_context(|| format!("connecting to {}", addr))?;
+            request(stream, command).await
+        }
+    }
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    command: Command,
+) -> anyhow::Result<Reply> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut encoded = serde_json::to_vec(&Request { command })?;
+    encoded.push(b'\n');
+    write.write_all(&encoded).await?;
+
+    let Some(line) = BufReader::new(read).lines().next_line().await? else {
+        bail!("the control endpoint closed the connection without replying")
+    };
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_control_commands() -> anyhow::Result<()> {
+        let controls = Arc::new(Controls::default());
+        let addr = "127.0.0.1:0".parse().map_err(anyhow::Error::msg)?;
+        let server = ControlServer::spawn(
+            addr,
+            controls.clone(),
+            || serde_json::json!({ "role": "test" }),
+        )
+        .await?;
+        let addr = server.addr();
+
+        let reply = send(addr, Command::Resync).await?;
+        assert!(!reply.ok);
+        assert_eq!(reply.status["role"], "test");
+
+        let mut session = controls.attach();
+        assert!(send(addr, Command::Pause).await?.ok);
+        // Sessions act on a pause once they got it, not while busy with something else.
+        assert!(!session.is_paused());
+        assert_eq!(session.next().await, ControlEvent::Paused);
+        assert!(session.is_paused());
+        assert!(!send(addr, Command::Resync).await?.ok);
+
+        let reply = send(addr, Command::Resume).await?;
+        assert_eq!(reply.status["paused"], false);
+        assert_eq!(reply.status["active_sessions"], 1);
+        assert_eq!(session.next().await, ControlEvent::Resumed);
+        assert!(send(addr, Command::Resync).await?.ok);
+        assert_eq!(session.next().await, ControlEvent::Resync);
+
+        assert!(send(addr, Command::Shutdown).await?.ok);
+        assert_eq!(session.next().await, ControlEvent::Shutdown);
+        controls.shutdown_requested().await;
+        assert!("0.0.0.0:7070".parse::<ControlAddr>().is_err());
+
+        Ok(())
+    }
+}
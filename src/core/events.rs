@@ -0,0 +1,153 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use super::{specials::SpecialKind, wire_path};
+
+/// What `--events-stdout` reports, one JSON object per line with the event name in `event`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        peer: String,
+    },
+    /// A change was sent, `change` says what happened to `path`, e.g. `edited`.
+    FileSynced {
+        change: String,
+        #[serde(with = "wire_path")]
+        path: PathBuf,
+    },
+    Error {
+        error: String,
+    },
+    Disconnected,
+    /// The whole tree is compared with the other end again.
+    ResyncStarted {
+        reason: String,
+    },
+    /// A FIFO, socket or device was left out of the sync, see `specials`.
+    SpecialFileSkipped {
+        #[serde(with = "wire_path")]
+        path: PathBuf,
+        kind: SpecialKind,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// RFC 3339 timestamp.
+    at: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Events written as JSON lines to the process's stdout, for editors and IDEs to follow a sync.
+/// Everything else the process prints goes to stderr instead, so that stdout only has events.
+pub struct EventStream {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream").finish_non_exhaustive()
+    }
+}
+
+impl EventStream {
+    /// Takes over stdout, pointing it to stderr for the rest of the process's output.
+    #[cfg(unix)]
+    pub fn stdout() -> io::Result<Self> {
+        use std::os::fd::AsFd;
+
+        io::stdout().flush()?;
+        let events = io::stdout().as_fd().try_clone_to_owned()?;
+        if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self::to(File::from(events)))
+    }
+
+    /// Other output cannot be moved away from stdout on this platform, so events are mixed with
+    /// it, though they are the only lines starting with `{`.
+    #[cfg(not(unix))]
+    pub fn stdout() -> io::Result<Self> {
+        Ok(Self::to(io::stdout()))
+    }
+
+    pub fn to(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Writes `event` as a single line, flushed right away. Consumers that went away are not an
+    /// error of the sync.
+    pub fn emit(&self, event: &Event) {
+        let record = Record {
+            at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        };
+        let Ok(mut line) = serde_json::to_vec(&record) else {
+            return;
+        };
+        line.push(b'\n');
+
+        let mut out = self.out.lock().unwrap();
+        let _ = out.write_all(&line).and_then(|_| out.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_are_json_lines() -> anyhow::Result<()> {
+        let out = Shared::default();
+        let events = EventStream::to(out.clone());
+        events.emit(&Event::Connected {
+            peer: "127.0.0.1:8080".to_owned(),
+        });
+        events.emit(&Event::FileSynced {
+            change: "edited".to_owned(),
+            path: PathBuf::from("src/main.rs"),
+        });
+        events.emit(&Event::Disconnected);
+
+        let written = String::from_utf8(out.0.lock().unwrap().clone())?;
+        let lines = written
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["event"], "connected");
+        assert_eq!(lines[0]["peer"], "127.0.0.1:8080");
+        assert_eq!(lines[1]["event"], "file_synced");
+        assert_eq!(lines[1]["path"], "src/main.rs");
+        assert_eq!(lines[2]["event"], "disconnected");
+        assert!(lines[2]["at"].is_string());
+
+        Ok(())
+    }
+}
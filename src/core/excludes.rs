@@ -0,0 +1,114 @@
+use std::{path::Path, sync::OnceLock};
+
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// File names of temporary, lock and backup files left around by editors and file managers,
+/// which are never worth syncing: vim swap files, emacs lock files, `~` backups and `.DS_Store`.
+pub const DEFAULT_EXCLUDES: &[&str] = &[".*.sw[a-p]", ".#*", "*~", ".DS_Store"];
+
+/// Whether the last component of `path` matches one of the `DEFAULT_EXCLUDES`.
+pub fn is_default_excluded(path: &Path) -> bool {
+    static GLOBS: OnceLock<GlobSet> = OnceLock::new();
+    let globs = GLOBS.get_or_init(|| {
+        DEFAULT_EXCLUDES
+            .iter()
+            .fold(GlobSetBuilder::new(), |mut builder, glob| {
+                builder.add(Glob::new(glob).unwrap());
+                builder
+            })
+            .build()
+            .unwrap()
+    });
+
+    path.file_name().is_some_and(|name| globs.is_match(name))
+}
+
+/// Glob patterns given with `--exclude`, matched against the names of files and directories like
+/// the `DEFAULT_EXCLUDES`. Everything inside an excluded directory is left out too.
+#[derive(Debug)]
+pub struct Excludes {
+    patterns: Vec<String>,
+    globs: GlobSet,
+}
+
+impl Excludes {
+    /// Compiles `patterns`, if any. They are kept for the life of the process, so that the
+    /// `ScanOptions` referring to them stay `Copy`.
+    pub fn leak(patterns: &[String]) -> anyhow::Result<Option<&'static Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = Glob::new(pattern)
+                .with_context(|| format!("invalid exclude pattern `{}`", pattern))?;
+            builder.add(glob);
+        }
+        let excludes = Self {
+            patterns: patterns.to_vec(),
+            globs: builder.build()?,
+        };
+
+        Ok(Some(Box::leak(Box::new(excludes))))
+    }
+
+    /// `excludes` with `pattern` added.
+    pub fn with(excludes: Option<&Self>, pattern: &str) -> anyhow::Result<&'static Self> {
+        let mut patterns = excludes.map_or(vec![], |excludes| excludes.patterns.clone());
+        patterns.push(pattern.to_owned());
+
+        Ok(Self::leak(&patterns)?.expect("at least one pattern"))
+    }
+
+    /// Whether the last component of `path` matches one of the patterns.
+    pub fn matches(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.globs.is_match(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_excludes() {
+        for excluded in [
+            "src/.main.rs.swp",
+            ".notes.txt.swo",
+            "src/.#main.rs",
+            "README.md~",
+            "assets/.DS_Store",
+        ] {
+            assert!(is_default_excluded(Path::new(excluded)), "{}", excluded);
+        }
+
+        for kept in [
+            "src/main.rs",
+            "main.swp.rs",
+            ".gitignore",
+            "docs/#notes#",
+            ".swp/file",
+        ] {
+            assert!(!is_default_excluded(Path::new(kept)), "{}", kept);
+        }
+    }
+
+    #[test]
+    fn test_excludes() -> anyhow::Result<()> {
+        let patterns = ["target".to_owned(), "*.log".to_owned()];
+        let excludes = Excludes::leak(&patterns)?.unwrap();
+        assert!(excludes.matches(Path::new("target")));
+        assert!(excludes.matches(Path::new("crates/core/target")));
+        assert!(excludes.matches(Path::new("logs/build.log")));
+        assert!(!excludes.matches(Path::new("target/debug")));
+        assert!(!excludes.matches(Path::new("src/target.rs")));
+
+        assert!(Excludes::leak(&[])?.is_none());
+        assert!(Excludes::leak(&["[".to_owned()]).is_err());
+
+        Ok(())
+    }
+}
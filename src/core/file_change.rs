@@ -2,16 +2,15 @@
 
 use std::{
     cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Context;
-use bytes::Bytes;
 use serde::Deserialize;
 use watchman_client::prelude::*;
 
-use super::{compression::compress_dir, message::FileChangeMessage, utils::is_dir_empty};
+use super::{message::FileChangeMessage, transfer::TransferJob};
 
 query_result_type! {
     pub struct FileChange {
@@ -25,10 +24,291 @@ query_result_type! {
     }
 }
 
+/// Merges the events reported for each path within a batch. Watchman reports the
+/// current state of a file, so the latest event wins, except that a file created during the
+/// window stays new, and one created then removed again is dropped altogether. A path that held
+/// another inode earlier in the window, found at another path by its end, is also reported gone
+/// with that inode, so that the move away from it can be paired.
+fn coalesce(changes: Vec<FileChange>) -> Vec<FileChange> {
+    let mut latest = BTreeMap::<PathBuf, FileChange>::new();
+    let mut replaced = BTreeMap::<PathBuf, FileChange>::new();
+    for mut change in changes {
+        let path = change.name.to_path_buf();
+        if let Some(previous) = latest.remove(&path) {
+            *change.is_new |= *previous.is_new;
+            if *previous.ino != *change.ino && !*previous.is_new {
+                replaced.insert(path.clone(), previous);
+            }
+        }
+
+        latest.insert(path, change);
+    }
+
+    let moved_to: HashMap<_, _> = latest
+        .values()
+        .filter(|change| *change.exists)
+        .map(|change| (*change.ino, change.name.to_path_buf()))
+        .collect();
+    let moved_away: Vec<_> = replaced
+        .into_iter()
+        .filter_map(|(path, mut previous)| {
+            let moved = moved_to.get(&*previous.ino).is_some_and(|to| *to != path)
+                && *latest[&path].ino != *previous.ino;
+            *previous.exists = false;
+            moved.then_some(previous)
+        })
+        .collect();
+
+    let mut coalesced: Vec<_> = latest
+        .into_values()
+        .filter(|change| *change.exists || !*change.is_new)
+        .collect();
+    coalesced.extend(moved_away);
+    coalesced
+}
+
+/// Pairs the directories moved within the watched root, which Watchman reports as the old path
+/// gone and a new one created with the same inode, along with every entry below both. The entries
+/// the move explains are dropped: those gone from below the old path that are below the new one
+/// with the same inode, and those below the new path unless edited since. Entries gone from below
+/// the old path and not below the new one are deleted from the new one instead, once moved.
+/// Directories moved into a directory created in the same window are sent with it.
+fn pair_renamed_dirs(changes: Vec<FileChange>) -> (Vec<FileChange>, Vec<(PathBuf, PathBuf)>) {
+    let is_dir =
+        |change: &FileChange| matches!(change.typ.clone().into_inner(), FileType::Directory);
+    let mut deleted_dirs = HashMap::new();
+    let mut created_dirs = BTreeSet::new();
+    for change in changes.iter().filter(|change| is_dir(change)) {
+        match (*change.exists, *change.is_new) {
+            (false, _) => {
+                deleted_dirs.insert(*change.ino, change.name.to_path_buf());
+            }
+            (true, true) => {
+                created_dirs.insert(change.name.to_path_buf());
+            }
+            (true, false) => (),
+        }
+    }
+
+    // Sorted by the old path, so that a directory is paired before those it holds.
+    let mut pairs: Vec<_> = changes
+        .iter()
+        .filter(|change| is_dir(change) && *change.exists)
+        .filter_map(|change| {
+            let from = deleted_dirs.get(&*change.ino)?;
+            let to = change.name.to_path_buf();
+            (*from != to).then(|| (from.clone(), to))
+        })
+        .collect();
+    pairs.sort();
+    let mut renamed_dirs: Vec<(PathBuf, PathBuf)> = vec![];
+    for (from, to) in pairs {
+        if !renamed_dirs
+            .iter()
+            .any(|(outer, _)| from.starts_with(outer))
+        {
+            renamed_dirs.push((from, to));
+        }
+    }
+    // Moved into a directory that is not moved there itself, and sent whole after the moves.
+    loop {
+        let targets: BTreeSet<_> = renamed_dirs.iter().map(|(_, to)| to.clone()).collect();
+        let created = |dir: &Path| created_dirs.contains(dir) && !targets.contains(dir);
+        let before = renamed_dirs.len();
+        renamed_dirs.retain(|(_, to)| !to.ancestors().skip(1).any(created));
+        if renamed_dirs.len() == before {
+            break;
+        }
+    }
+    if renamed_dirs.is_empty() {
+        return (changes, renamed_dirs);
+    }
+
+    let existing: HashMap<_, _> = changes
+        .iter()
+        .filter(|change| *change.exists)
+        .map(|change| (change.name.to_path_buf(), *change.ino))
+        .collect();
+    // The entries moved along, by their new path, with their mtime before the move.
+    let mut moved = HashMap::new();
+    let mut kept = vec![];
+    for mut change in changes {
+        let path = change.name.to_path_buf();
+        let renamed = renamed_dirs.iter().find(|(from, _)| path.starts_with(from));
+        let Some((from, to)) = renamed.filter(|_| !*change.exists) else {
+            kept.push(change);
+            continue;
+        };
+
+        let new_path = to.join(path.strip_prefix(from).unwrap());
+        match existing.get(&new_path) {
+            Some(&ino) if ino == *change.ino => {
+                moved.insert(new_path, *change.mtime);
+            }
+            // Replaced by another entry, which overwrites it once moved.
+            Some(_) => (),
+            None => {
+                change.name = NameField::new(new_path);
+                kept.push(change);
+            }
+        }
+    }
+
+    kept.retain_mut(|change| {
+        let path = change.name.to_path_buf();
+        if renamed_dirs.iter().any(|(_, to)| *to == path) {
+            return false;
+        }
+        match moved.get(&path) {
+            Some(&mtime) if mtime == *change.mtime => false,
+            Some(_) => {
+                *change.is_new = false;
+                true
+            }
+            None => true,
+        }
+    });
+
+    (kept, renamed_dirs)
+}
+
+/// Pairs the files moved within the watched root, indexing the inodes gone from a path to find
+/// them at another, wherever they were moved to. Files moved into a directory created in the same
+/// window are sent with it.
+fn pair_renamed_files(changes: Vec<FileChange>) -> (Vec<FileChange>, Vec<(PathBuf, PathBuf)>) {
+    let is_dir =
+        |change: &FileChange| matches!(change.typ.clone().into_inner(), FileType::Directory);
+    let created_dirs: BTreeSet<_> = changes
+        .iter()
+        .filter(|change| is_dir(change) && *change.exists && *change.is_new)
+        .map(|change| change.name.to_path_buf())
+        .collect();
+    let mut gone = HashMap::new();
+    for (index, change) in changes.iter().enumerate() {
+        if !*change.exists && !is_dir(change) {
+            gone.entry(*change.ino).or_insert(index);
+        }
+    }
+
+    let mut paired = vec![false; changes.len()];
+    let mut renamed_files = vec![];
+    for (index, change) in changes.iter().enumerate() {
+        if !*change.exists || is_dir(change) {
+            continue;
+        }
+        let Some(&from) = gone.get(&*change.ino) else {
+            continue;
+        };
+        let (from_path, to_path) = (changes[from].name.to_path_buf(), change.name.to_path_buf());
+        let in_created_dir = to_path
+            .ancestors()
+            .skip(1)
+            .any(|dir| created_dirs.contains(dir));
+        if paired[from] || from_path == to_path || in_created_dir {
+            continue;
+        }
+
+        paired[from] = true;
+        paired[index] = true;
+        renamed_files.push((from_path, to_path));
+    }
+
+    let kept = changes
+        .into_iter()
+        .zip(paired)
+        .filter_map(|(change, paired)| (!paired).then_some(change))
+        .collect();
+    (kept, renamed_files)
+}
+
+/// Orders renames so that each finds its source and a free target once applied in turn: after the
+/// renames moving away what is at its target, and after those moving its source or the directory
+/// of its target in place. Renames waiting on each other in a cycle, like two files swapped, are
+/// broken up by moving one source aside first.
+fn in_rename_order(mut pending: Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, PathBuf)> {
+    let waits_on = |(from, to): &(PathBuf, PathBuf),
+                    (other_from, other_to): &(PathBuf, PathBuf)| {
+        to == other_from || below(from, other_to) || below(to, other_to)
+    };
+
+    let mut ordered = vec![];
+    while !pending.is_empty() {
+        let ready = (0..pending.len()).find(|&index| {
+            (pending.iter().enumerate())
+                .all(|(other, rename)| other == index || !waits_on(&pending[index], rename))
+        });
+        if let Some(index) = ready {
+            ordered.push(pending.remove(index));
+            continue;
+        }
+
+        let (from, to) = pending.remove(0);
+        let mut aside = from.clone().into_os_string();
+        aside.push(".caiman-rename");
+        let aside = PathBuf::from(aside);
+        for (other_from, _) in pending.iter_mut() {
+            if below(other_from, &from) {
+                *other_from = aside.join(other_from.strip_prefix(&from).unwrap());
+            }
+        }
+        ordered.push((from, aside.clone()));
+        pending.push((aside, to));
+    }
+
+    ordered
+}
+
+/// Keeps the final state of each path once moves are paired: a path reported gone with an inode
+/// moved elsewhere that was not paired, but holding another inode by the end of the batch, is only
+/// sent as it ends up, moved there or not. Entries created or edited below a directory created in
+/// the batch are left to it, as it is sent whole.
+fn final_states(changes: Vec<FileChange>, renames: &[(PathBuf, PathBuf)]) -> Vec<FileChange> {
+    let targets: BTreeSet<_> = renames.iter().map(|(_, to)| to).collect();
+    let created_dirs: BTreeSet<_> = changes
+        .iter()
+        .filter(|change| *change.exists && *change.is_new)
+        .filter(|change| matches!(change.typ.clone().into_inner(), FileType::Directory))
+        .map(|change| change.name.to_path_buf())
+        .collect();
+    let mut latest = BTreeMap::<PathBuf, FileChange>::new();
+    for change in changes {
+        let path = change.name.to_path_buf();
+        let in_created_dir = path
+            .ancestors()
+            .skip(1)
+            .any(|dir| created_dirs.contains(dir));
+        let superseded = match *change.exists {
+            true => in_created_dir,
+            false => targets.contains(&path),
+        };
+        if superseded {
+            continue;
+        }
+        match latest.entry(path) {
+            Entry::Vacant(entry) => {
+                entry.insert(change);
+            }
+            Entry::Occupied(mut entry) if *change.exists => {
+                entry.insert(change);
+            }
+            Entry::Occupied(_) => (),
+        }
+    }
+
+    latest.into_values().collect()
+}
+
+/// Whether `path` is strictly below `dir`.
+fn below(path: &Path, dir: &Path) -> bool {
+    path != dir && path.starts_with(dir)
+}
+
 #[derive(Debug)]
 pub struct SortedFileChanges {
-    pub root_path: PathBuf,
     inner: Vec<FileChange>,
+    /// Entries moved within the watched root, from and to, renamed before the other changes are
+    /// sent, in reverse.
+    renames: Vec<(PathBuf, PathBuf)>,
 }
 
 impl Deref for SortedFileChanges {
@@ -46,7 +326,16 @@ impl DerefMut for SortedFileChanges {
 }
 
 impl SortedFileChanges {
-    pub fn from(root_path: PathBuf, mut inner: Vec<FileChange>) -> Self {
+    /// Sorts a batch of changes, sending each path once in the state it ends up in, however many
+    /// times it was created, edited or deleted within the batch.
+    pub fn from(changes: Vec<FileChange>) -> Self {
+        let (inner, mut renames) = pair_renamed_dirs(coalesce(changes));
+        let (inner, renamed_files) = pair_renamed_files(inner);
+        renames.extend(renamed_files);
+        let mut inner = final_states(inner, &renames);
+        // Popped from the end.
+        let mut renames = in_rename_order(renames);
+        renames.reverse();
         inner.sort_unstable_by(|change1, change2| {
             let ino1 = change1.ino.clone().into_inner();
             let ino2 = change2.ino.clone().into_inner();
@@ -67,62 +356,341 @@ impl SortedFileChanges {
             }
         });
 
-        Self { root_path, inner }
+        Self { inner, renames }
     }
 
-    pub async fn next_message(&mut self) -> Option<FileChangeMessage> {
+    /// Turns the next change into a transfer job. Watchman reports names relative to the watched
+    /// root, and so are the jobs: their payload is read by `TransferJob::load` against the root.
+    pub fn next_job(&mut self) -> Option<TransferJob> {
+        if let Some((from, to)) = self.renames.pop() {
+            return Some(TransferJob::Ready(FileChangeMessage::Rename(from, to)));
+        }
+
         let this_change = self.pop()?;
         let this_path = this_change.name.to_path_buf();
-        let this_ino = this_change.ino.into_inner();
 
         let is_dir = matches!(this_change.typ.into_inner(), FileType::Directory);
         let is_new = this_change.is_new.into_inner();
 
         let exists = this_change.exists.into_inner();
         if exists {
-            let message = match (is_dir, is_new) {
-                (true, false) => FileChangeMessage::DirectoryContentsEdited(this_path),
-                (false, true) => FileChangeMessage::FileCreated(this_path),
-                (false, false) => {
-                    let file_path = self.root_path.join(&this_path);
-                    let contents = tokio::fs::read(file_path).await.unwrap(); // TODO: handle this
-                    FileChangeMessage::FileEdited(this_path, Bytes::from(contents))
-                }
-                (true, true) => {
-                    if is_dir_empty(this_path.as_path()) {
-                        FileChangeMessage::EmptyDirectoryCreated(this_path)
-                    } else {
-                        let contents = compress_dir(this_path.as_path())
-                            .await
-                            .context("compressing dir")
-                            .unwrap();
-
-                        FileChangeMessage::DirectoryCreated(this_path, contents)
-                    }
+            let job = match (is_dir, is_new) {
+                (true, false) => {
+                    TransferJob::Ready(FileChangeMessage::DirectoryContentsEdited(this_path))
                 }
+                (false, true) => TransferJob::Ready(FileChangeMessage::FileCreated(this_path)),
+                (false, false) => TransferJob::File(this_path),
+                (true, true) => TransferJob::Directory(this_path),
             };
 
-            return Some(message);
+            return Some(job);
         }
 
-        let next_ino_reached = !self
-            .last()
-            .is_some_and(|change| change.ino.abs_diff(this_ino) == 0);
+        let message = match is_dir {
+            true => FileChangeMessage::DirectoryDeleted(this_path),
+            false => FileChangeMessage::FileDeleted(this_path),
+        };
+        Some(TransferJob::Ready(message))
+    }
+}
 
-        if next_ino_reached {
-            let message = match is_dir {
-                true => FileChangeMessage::DirectoryDeleted(this_path),
-                false => FileChangeMessage::FileDeleted(this_path),
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{compression::decompress_dir, file_tree::ScanOptions, roots::Roots};
+    use std::{fs, path::Path};
+    use tempfile::TempDir;
+    use tokio::test;
 
-            return Some(message);
+    fn change(
+        name: &str,
+        exists: bool,
+        is_new: bool,
+        typ: FileType,
+        ino: u64,
+        ctime: i64,
+    ) -> FileChange {
+        FileChange {
+            name: NameField::new(name.into()),
+            exists: ExistsField::new(exists),
+            is_new: NewField::new(is_new),
+            ctime: CTimeField::new(ctime),
+            mtime: MTimeField::new(ctime),
+            typ: FileTypeField::new(typ),
+            ino: InodeNumberField::new(ino),
         }
+    }
 
-        let next_change = self.pop().unwrap();
-        Some(FileChangeMessage::Rename(
-            this_path,
-            next_change.name.to_path_buf(),
-        ))
+    async fn load_all(
+        changes: Vec<FileChange>,
+        root: &Path,
+    ) -> anyhow::Result<Vec<FileChangeMessage>> {
+        let mut changes = SortedFileChanges::from(changes);
+        let mut messages = vec![];
+        while let Some(job) = changes.next_job() {
+            messages.push(
+                job.load(&Roots::single(root), u64::MAX, ScanOptions::default())
+                    .await?
+                    .0,
+            );
+        }
+
+        Ok(messages)
     }
-}
 
+    #[test]
+    async fn test_coalesce_keeps_the_latest_state_per_path() {
+        let changes = coalesce(vec![
+            change("saved.txt", true, true, FileType::Regular, 1, 0),
+            change("saved.txt", true, false, FileType::Regular, 1, 1),
+            change("edited.txt", true, false, FileType::Regular, 2, 0),
+            change("edited.txt", false, false, FileType::Regular, 2, 1),
+            change("scratch.swp", true, true, FileType::Regular, 3, 0),
+            change("scratch.swp", false, false, FileType::Regular, 3, 1),
+        ]);
+
+        let states: Vec<_> = changes
+            .iter()
+            .map(|change| {
+                (
+                    change.name.to_str().unwrap(),
+                    *change.exists,
+                    *change.is_new,
+                )
+            })
+            .collect();
+        assert_eq!(
+            states,
+            vec![("edited.txt", false, false), ("saved.txt", true, true)]
+        );
+    }
+
+    // The watched root is a temporary directory while the tests run from the crate root, so
+    // none of the relative paths below resolve unless they are joined with the root.
+
+    #[test]
+    async fn test_created_directories_resolve_against_root() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("watched_new_dir/nested"))?;
+        fs::write(
+            root.path().join("watched_new_dir/nested/file.txt"),
+            "contents",
+        )?;
+        fs::create_dir(root.path().join("watched_empty_dir"))?;
+
+        let messages = load_all(
+            vec![
+                change("watched_new_dir", true, true, FileType::Directory, 1, 0),
+                change("watched_empty_dir", true, true, FileType::Directory, 2, 0),
+            ],
+            root.path(),
+        )
+        .await?;
+
+        let FileChangeMessage::DirectoryCreated(path, archive) = &messages[0] else {
+            panic!("expected a created directory, got {:?}", messages[0]);
+        };
+        assert_eq!(path, Path::new("watched_new_dir"));
+
+        let unpacked = TempDir::new()?;
+        decompress_dir(unpacked.path(), archive.as_ref()).await?;
+        assert_eq!(
+            fs::read_to_string(unpacked.path().join("nested/file.txt"))?,
+            "contents"
+        );
+
+        assert!(matches!(
+            &messages[1],
+            FileChangeMessage::EmptyDirectoryCreated(path) if path == Path::new("watched_empty_dir")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_edits_and_renames_resolve_against_root() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir(root.path().join("watched_dir"))?;
+        fs::write(root.path().join("watched_dir/edited.txt"), "edited")?;
+
+        let messages = load_all(
+            vec![
+                change(
+                    "watched_dir/edited.txt",
+                    true,
+                    false,
+                    FileType::Regular,
+                    1,
+                    0,
+                ),
+                change(
+                    "watched_dir/renamed.txt",
+                    true,
+                    false,
+                    FileType::Regular,
+                    2,
+                    20,
+                ),
+                change(
+                    "watched_dir/original.txt",
+                    false,
+                    false,
+                    FileType::Regular,
+                    2,
+                    10,
+                ),
+            ],
+            root.path(),
+        )
+        .await?;
+
+        // Renames are sent first.
+        assert!(matches!(
+            &messages[0],
+            FileChangeMessage::Rename(from, to)
+                if from == Path::new("watched_dir/original.txt")
+                    && to == Path::new("watched_dir/renamed.txt")
+        ));
+        assert!(matches!(
+            &messages[1],
+            FileChangeMessage::FileEdited(path, contents, _)
+                if path == Path::new("watched_dir/edited.txt") && contents == "edited"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_chained_and_swapped_renames_are_ordered() -> anyhow::Result<()> {
+        // Renames are ready as they are, nothing is read from the watched root.
+        let renames = |changes| async move {
+            let messages = load_all(changes, Path::new("")).await?;
+            let renames = messages.into_iter().map(|message| match message {
+                FileChangeMessage::Rename(from, to) => Ok((from, to)),
+                message => Err(anyhow::anyhow!("expected a rename, got {:?}", message)),
+            });
+            renames.collect::<anyhow::Result<Vec<_>>>()
+        };
+        let path = |path: &str| PathBuf::from(path);
+
+        // `mv b.txt c/b.txt && mv a.txt b.txt`, reported across two notifications.
+        let moved = renames(vec![
+            change("b.txt", true, false, FileType::Regular, 2, 0),
+            change("c/b.txt", true, true, FileType::Regular, 2, 1),
+            change("a.txt", false, false, FileType::Regular, 1, 1),
+            change("b.txt", true, false, FileType::Regular, 1, 1),
+        ])
+        .await?;
+        assert_eq!(
+            moved,
+            [
+                (path("b.txt"), path("c/b.txt")),
+                (path("a.txt"), path("b.txt"))
+            ]
+        );
+
+        // `mv a.txt tmp && mv b.txt a.txt && mv tmp b.txt`, whose steps are lost.
+        let swapped = renames(vec![
+            change("a.txt", true, false, FileType::Regular, 1, 0),
+            change("b.txt", true, false, FileType::Regular, 2, 0),
+            change("a.txt", true, false, FileType::Regular, 2, 1),
+            change("b.txt", true, false, FileType::Regular, 1, 1),
+        ])
+        .await?;
+        assert_eq!(
+            swapped,
+            [
+                (path("b.txt"), path("b.txt.caiman-rename")),
+                (path("a.txt"), path("b.txt")),
+                (path("b.txt.caiman-rename"), path("a.txt"))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_moved_directories_are_renamed_whole() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("moved/nested"))?;
+        fs::write(root.path().join("moved/kept.txt"), "kept")?;
+        fs::write(root.path().join("moved/edited.txt"), "edited")?;
+
+        let messages = load_all(
+            vec![
+                change("docs", false, false, FileType::Directory, 1, 10),
+                change("docs/kept.txt", false, false, FileType::Regular, 2, 10),
+                change("docs/edited.txt", false, false, FileType::Regular, 3, 10),
+                change("docs/removed.txt", false, false, FileType::Regular, 4, 10),
+                change("docs/nested", false, false, FileType::Directory, 5, 10),
+                change("moved", true, true, FileType::Directory, 1, 20),
+                change("moved/kept.txt", true, true, FileType::Regular, 2, 10),
+                change("moved/edited.txt", true, true, FileType::Regular, 3, 20),
+                change("moved/nested", true, true, FileType::Directory, 5, 10),
+            ],
+            root.path(),
+        )
+        .await?;
+
+        // The move first, then the edit and the deletion of what was moved along.
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(matches!(
+            &messages[0],
+            FileChangeMessage::Rename(from, to)
+                if from == Path::new("docs") && to == Path::new("moved")
+        ));
+        assert!(messages[1..].iter().any(|message| matches!(
+            message,
+            FileChangeMessage::FileEdited(path, contents, _)
+                if path == Path::new("moved/edited.txt") && contents == "edited"
+        )));
+        assert!(messages[1..].iter().any(|message| matches!(
+            message,
+            FileChangeMessage::FileDeleted(path) if path == Path::new("moved/removed.txt")
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_batches_send_each_path_once() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("build.log"), "done")?;
+        fs::create_dir(root.path().join("archive"))?;
+        fs::write(root.path().join("archive/b.txt"), "b")?;
+
+        // A build writing its log over and over, and `mv b.txt archive/b.txt && mv a.txt b.txt`
+        // into a new directory, which is sent whole.
+        let messages = load_all(
+            vec![
+                change("build.log", true, false, FileType::Regular, 1, 0),
+                change("b.txt", true, false, FileType::Regular, 2, 0),
+                change("build.log", true, false, FileType::Regular, 1, 1),
+                change("archive", true, true, FileType::Directory, 3, 1),
+                change("archive/b.txt", true, true, FileType::Regular, 2, 1),
+                change("a.txt", false, false, FileType::Regular, 4, 1),
+                change("b.txt", true, false, FileType::Regular, 4, 1),
+                change("build.log", true, false, FileType::Regular, 1, 2),
+            ],
+            root.path(),
+        )
+        .await?;
+
+        let summary: Vec<_> = messages
+            .iter()
+            .map(|message| (message.label(), message.paths()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("renamed", vec![Path::new("a.txt"), Path::new("b.txt")]),
+                ("edited", vec![Path::new("build.log")]),
+                ("created directory", vec![Path::new("archive")]),
+            ],
+            "{:?}",
+            messages
+        );
+
+        Ok(())
+    }
+}
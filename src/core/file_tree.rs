@@ -1,29 +1,358 @@
 use anyhow::bail;
 use sha1::{Digest, Sha1};
 use std::{
-    fs,
+    collections::{BTreeMap, HashMap},
+    fs::{self, Metadata},
     ops::Deref,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    excludes::{is_default_excluded, Excludes},
+    profile,
+    scan_cache::{self, Found},
+    specials::{special_kind, SkippedSpecial, SpecialKind},
+    walk::{checked, walk, WalkError, DEFAULT_MAX_DEPTH},
+    wire_path,
 };
-use walkdir::WalkDir;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileTreeNode {
+    #[serde(with = "wire_path")]
     pub path: PathBuf,
     pub typ: FileTreeNodeType,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum FileTreeNodeType {
-    File { sha1: [u8; 20] },
+    /// `sha1` is left out of trees scanned with `ScanOptions::size_only`.
+    File {
+        size: u64,
+        sha1: Option<[u8; 20]>,
+    },
     Dir,
+    /// A `Dir` with the Merkle hash of its contents and the number of nodes below it, see
+    /// `subtree_hashes`, for receivers that say they accept it, see `SUBTREE_HASHES_HEADER`.
+    HashedDir {
+        sha1: [u8; 20],
+        descendants: u64,
+    },
+    /// A `File` scanned with `ScanOptions::quick_check`, with its mtime in whole seconds since
+    /// the epoch instead of a hash, for receivers that say they accept it, see
+    /// `QUICK_CHECK_HEADER`.
+    TimedFile {
+        size: u64,
+        mtime: u64,
+    },
+    /// A FIFO, in trees scanned with `ScanOptions::fifos`, for receivers that say they recreate
+    /// them, see `SPECIALS_HEADER`.
+    Fifo,
+}
+
+impl FileTreeNodeType {
+    pub fn is_dir(&self) -> bool {
+        matches!(
+            self,
+            FileTreeNodeType::Dir | FileTreeNodeType::HashedDir { .. }
+        )
+    }
+
+    /// Whether two files have the same contents as far as their scans tell: by hash when both
+    /// were hashed, by size and mtime when both were quick checked, and by size otherwise. `None`
+    /// unless both are files. FIFOs have no contents, so two of them are always the same.
+    pub fn same_file(&self, other: &FileTreeNodeType) -> Option<bool> {
+        use FileTreeNodeType::*;
+
+        match (self, other) {
+            (
+                File { size, sha1 },
+                File {
+                    size: other_size,
+                    sha1: other_sha1,
+                },
+            ) => Some(
+                size == other_size
+                    && sha1
+                        .zip(*other_sha1)
+                        .is_none_or(|(sha1, other_sha1)| sha1 == other_sha1),
+            ),
+            (
+                TimedFile { size, mtime },
+                TimedFile {
+                    size: other_size,
+                    mtime: other_mtime,
+                },
+            ) => Some(size == other_size && mtime == other_mtime),
+            (
+                TimedFile { size, .. },
+                File {
+                    size: other_size, ..
+                },
+            )
+            | (
+                File { size, .. },
+                TimedFile {
+                    size: other_size, ..
+                },
+            ) => Some(size == other_size),
+            (Fifo, Fifo) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// The device and inode numbers of a file with other hard links, `None` for files with a single
+/// one and on platforms without inodes.
+#[cfg(unix)]
+pub fn linked_inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn linked_inode(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether both paths are links to the same file.
+pub fn is_same_file(path1: &Path, path2: &Path) -> bool {
+    match (fs::metadata(path1), fs::metadata(path2)) {
+        (Ok(metadata1), Ok(metadata2)) => {
+            linked_inode(&metadata1).is_some_and(|inode| Some(inode) == linked_inode(&metadata2))
+        }
+        _ => false,
+    }
+}
+
+/// Whole seconds since the epoch, as `TimedFile` records mtimes: archives and batches only keep
+/// mtimes to the second.
+pub fn unix_secs(mtime: SystemTime) -> u64 {
+    mtime
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The Merkle hash of a directory's contents and the number of nodes below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubtreeHash {
+    pub sha1: [u8; 20],
+    pub descendants: u64,
+}
+
+/// Hashes the contents of every directory of sorted `nodes`, from the names, sizes and hashes of
+/// its files and the hashes of its subdirectories, so that identical subtrees can be told apart
+/// without comparing their entries. Directories of trees scanned by size only or quick checked
+/// are only hashed from sizes and mtimes, and never match those of hashed trees. Files have no
+/// subtree hash.
+pub fn subtree_hashes(nodes: &[FileTreeNode]) -> Vec<Option<SubtreeHash>> {
+    let mut hashes = vec![None; nodes.len()];
+    // The directories the current node is below, innermost last.
+    let mut open: Vec<(usize, Sha1)> = vec![];
+    let close = |open: &mut Vec<(usize, Sha1)>, hashes: &mut Vec<_>, end: usize| {
+        let (index, hasher) = open.pop().expect("an open directory");
+        let sha1 = hasher.finalize().into();
+        hashes[index] = Some(SubtreeHash {
+            sha1,
+            descendants: (end - index - 1) as u64,
+        });
+        if let Some((parent, hasher)) = open.last_mut() {
+            entry(hasher, &nodes[*parent].path, &nodes[index].path);
+            hasher.update([1]);
+            hasher.update(sha1);
+        }
+    };
+
+    for (index, node) in nodes.iter().enumerate() {
+        while open
+            .last()
+            .is_some_and(|&(dir, _)| !node.path.starts_with(&nodes[dir].path))
+        {
+            close(&mut open, &mut hashes, index);
+        }
+
+        match &node.typ {
+            FileTreeNodeType::File { size, sha1 } => {
+                if let Some((parent, hasher)) = open.last_mut() {
+                    entry(hasher, &nodes[*parent].path, &node.path);
+                    hasher.update([0]);
+                    hasher.update(size.to_le_bytes());
+                    if let Some(sha1) = sha1 {
+                        hasher.update(sha1);
+                    }
+                }
+            }
+            FileTreeNodeType::TimedFile { size, mtime } => {
+                if let Some((parent, hasher)) = open.last_mut() {
+                    entry(hasher, &nodes[*parent].path, &node.path);
+                    hasher.update([2]);
+                    hasher.update(size.to_le_bytes());
+                    hasher.update(mtime.to_le_bytes());
+                }
+            }
+            FileTreeNodeType::Fifo => {
+                if let Some((parent, hasher)) = open.last_mut() {
+                    entry(hasher, &nodes[*parent].path, &node.path);
+                    hasher.update([3]);
+                }
+            }
+            FileTreeNodeType::Dir | FileTreeNodeType::HashedDir { .. } => {
+                open.push((index, Sha1::new()))
+            }
+        }
+    }
+
+    while !open.is_empty() {
+        close(&mut open, &mut hashes, nodes.len());
+    }
+
+    hashes
+}
+
+/// Hashes the name of the entry at `path` below `dir`.
+fn entry(hasher: &mut Sha1, dir: &Path, path: &Path) {
+    let name = path.strip_prefix(dir).unwrap_or(path);
+    hasher.update(name.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+}
+
+/// How a directory is scanned into a `FileTree`.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanOptions {
+    /// Only record file sizes instead of hashing contents, so that files are compared by size.
+    pub size_only: bool,
+    /// Record file mtimes instead of hashing contents, so that files of the same size and mtime
+    /// are taken as unchanged, like rsync's quick check. Files are `TimedFile`s, unless
+    /// `size_only` is set too.
+    pub quick_check: bool,
+    /// Leave out editor swap, lock and backup files, see `excludes::DEFAULT_EXCLUDES`.
+    pub default_excludes: bool,
+    /// Skip listing directories whose mtime did not change since the last scan, see
+    /// `scan_cache`.
+    pub trust_dir_mtime: bool,
+    /// Leave out entries matching these patterns too.
+    pub excludes: Option<&'static Excludes>,
+    /// Keep FIFOs in trees as `FileTreeNodeType::Fifo` nodes, instead of leaving them out like
+    /// other special files, see `specials`.
+    pub fifos: bool,
+    /// Scan what symlinks point to, as if it was where the symlink is. Symlink loops fail scans.
+    pub follow_links: bool,
+    /// Fail scans meeting entries nested deeper than this, see `walk::WalkError`.
+    pub max_depth: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            size_only: false,
+            quick_check: false,
+            default_excludes: true,
+            trust_dir_mtime: false,
+            excludes: None,
+            fifos: false,
+            follow_links: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Whether `path` is left out of scans, archives and watched changes.
+    pub fn excludes(&self, path: &Path) -> bool {
+        (self.default_excludes && is_default_excluded(path))
+            || self.excludes.is_some_and(|excludes| excludes.matches(path))
+    }
+
+    /// Whether `path` or one of its parent directories is left out. Scans never descend into
+    /// excluded directories, while watched changes are reported by full path.
+    pub fn excludes_within(&self, path: &Path) -> bool {
+        path.ancestors().any(|ancestor| self.excludes(ancestor))
+    }
+
+    /// Also leaves out entries named `name`, e.g. a directory the receiver keeps its own files in.
+    pub fn excluding(self, name: &str) -> Self {
+        Self {
+            excludes: Some(Excludes::with(self.excludes, name).expect("a valid pattern")),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SubtreeChecksum {
+    #[serde(with = "wire_path")]
+    pub path: PathBuf,
+    pub sha1: [u8; 20],
+}
+
+/// Returns the top-level entries whose checksums differ or that only exist on one side.
+pub fn divergent_subtrees(local: &[SubtreeChecksum], remote: &[SubtreeChecksum]) -> Vec<PathBuf> {
+    let mut checksums = BTreeMap::<&Path, (Option<&[u8; 20]>, Option<&[u8; 20]>)>::new();
+    for checksum in local {
+        checksums.entry(&checksum.path).or_default().0 = Some(&checksum.sha1);
+    }
+
+    for checksum in remote {
+        checksums.entry(&checksum.path).or_default().1 = Some(&checksum.sha1);
+    }
+
+    checksums
+        .into_iter()
+        .filter(|(_, (local, remote))| local != remote)
+        .map(|(path, _)| path.to_owned())
+        .collect()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Combines the checksums of the top-level entries into a single hash for the whole tree, the
+/// root of a two-level Merkle tree.
+pub fn root_checksum(checksums: &[SubtreeChecksum]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for checksum in checksums {
+        hasher.update(checksum.path.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(checksum.sha1);
+    }
+
+    hasher.finalize().into()
+}
+
+/// Collects the links of a tree as its files are found, in order.
+#[derive(Default)]
+struct Links {
+    first: HashMap<(u64, u64), PathBuf>,
+    links: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Links {
+    fn found(&mut self, path: &Path, inode: Option<(u64, u64)>) {
+        let Some(inode) = inode else {
+            return;
+        };
+        match self.first.get(&inode) {
+            Some(target) => {
+                self.links.insert(path.to_owned(), target.clone());
+            }
+            None => {
+                self.first.insert(inode, path.to_owned());
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct FileTree {
     nodes: Vec<FileTreeNode>,
+    /// The hard links of the files linked more than once within the tree, each to the first of
+    /// their paths. Only known to the side that scanned the tree.
+    #[serde(skip)]
+    links: BTreeMap<PathBuf, PathBuf>,
+    /// The special files left out of the tree, see `specials`. Only known to the side that
+    /// scanned the tree.
+    #[serde(skip)]
+    specials: BTreeMap<PathBuf, SpecialKind>,
 }
 
 impl Deref for FileTree {
@@ -34,8 +363,26 @@ impl Deref for FileTree {
     }
 }
 
+/// Collects nodes already in the tree's order, e.g. from `TreePage`s.
+impl FromIterator<FileTreeNode> for FileTree {
+    fn from_iter<I: IntoIterator<Item = FileTreeNode>>(nodes: I) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+            links: BTreeMap::new(),
+            specials: BTreeMap::new(),
+        }
+    }
+}
+
 impl FileTree {
     pub async fn new(base_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new_with(base_path, ScanOptions::default()).await
+    }
+
+    pub async fn new_with(
+        base_path: impl AsRef<Path>,
+        options: ScanOptions,
+    ) -> anyhow::Result<Self> {
         let base_path = base_path.as_ref();
         if !base_path.try_exists().is_ok_and(|exists| exists) {
             fs::create_dir(base_path)?;
@@ -45,36 +392,117 @@ impl FileTree {
             bail!("provided path is not a directory")
         }
 
+        Self::scan(base_path, base_path, options).await
+    }
+
+    /// A tree of a single file, at the empty path like the root of a directory's tree.
+    pub async fn new_file_with(
+        path: impl AsRef<Path>,
+        options: ScanOptions,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            bail!("provided path is not a file")
+        }
+
+        Self::scan(path, path, options).await
+    }
+
+    /// Scans only the entry at `subtree` (relative to `base_path`), keeping node paths relative
+    /// to `base_path`. A missing entry yields an empty tree.
+    pub async fn new_subtree(
+        base_path: impl AsRef<Path>,
+        subtree: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        Self::new_subtree_with(base_path, subtree, ScanOptions::default()).await
+    }
+
+    pub async fn new_subtree_with(
+        base_path: impl AsRef<Path>,
+        subtree: impl AsRef<Path>,
+        options: ScanOptions,
+    ) -> anyhow::Result<Self> {
+        let base_path = base_path.as_ref();
+        let start_path = base_path.join(subtree);
+        if !start_path.try_exists().is_ok_and(|exists| exists) {
+            return Ok(Self::default());
+        }
+
+        Self::scan(base_path, &start_path, options).await
+    }
+
+    async fn scan(
+        base_path: &Path,
+        start_path: &Path,
+        options: ScanOptions,
+    ) -> anyhow::Result<Self> {
+        // The cache knows nothing of symlinks, which it does not follow.
+        if options.trust_dir_mtime && !options.follow_links && start_path.is_dir() {
+            return Self::scan_cached(base_path, start_path, options).await;
+        }
+
+        let _span = profile::span("scan", Some(start_path));
         let mut nodes = vec![];
+        let mut links = Links::default();
+        let mut specials = BTreeMap::new();
 
         let mut handles = vec![];
-        for entry in WalkDir::new(base_path)
+        for entry in walk(start_path, options)
             .sort_by(|entry1, entry2| entry1.path().cmp(entry2.path()))
             .into_iter()
-            .filter_map(|e| e.ok())
+            .filter_entry(|entry| !options.excludes(entry.path()))
         {
+            // Entries that cannot be read are left out, but a walk that would not end fails.
+            let entry = match checked(entry, options) {
+                Ok(entry) => entry,
+                Err(err) if err.is::<WalkError>() => return Err(err),
+                Err(_) => continue,
+            };
             let meta = entry.metadata();
             if meta.is_err() {
                 continue;
             }
 
-            let is_file = meta.unwrap().is_file();
+            let meta = meta.unwrap();
+            let is_file = meta.is_file();
 
             if is_file {
                 let full_path = entry.path().to_owned();
                 let truncated_path = entry.path().strip_prefix(base_path).unwrap().to_owned();
-                handles.push(tokio::spawn(async {
-                    let file = tokio::fs::read(full_path).await.unwrap();
-
-                    let mut hasher = Sha1::new();
-                    hasher.update(&file);
-                    let sha1: [u8; 20] = hasher.finalize().into();
+                links.found(&truncated_path, linked_inode(&meta));
+                let size = meta.len();
+                let mtime = meta.modified().ok();
+                handles.push(tokio::spawn(async move {
+                    let typ = match (options.size_only, options.quick_check, mtime) {
+                        (true, ..) => FileTreeNodeType::File { size, sha1: None },
+                        (false, true, Some(mtime)) => FileTreeNodeType::TimedFile {
+                            size,
+                            mtime: unix_secs(mtime),
+                        },
+                        _ => FileTreeNodeType::File {
+                            size,
+                            sha1: Some(hash(&full_path, &truncated_path).await),
+                        },
+                    };
 
                     FileTreeNode {
                         path: truncated_path,
-                        typ: FileTreeNodeType::File { sha1 },
+                        typ,
                     }
                 }));
+            } else if let Some(kind) = special_kind(meta.file_type()) {
+                let path = entry.path().strip_prefix(base_path).unwrap().to_owned();
+                match (kind, options.fifos) {
+                    (SpecialKind::Fifo, true) => handles.push(tokio::spawn(async {
+                        FileTreeNode {
+                            path,
+                            typ: FileTreeNodeType::Fifo,
+                        }
+                    })),
+                    _ => {
+                        specials.insert(path, kind);
+                    }
+                }
             } else {
                 let path = entry.path().strip_prefix(base_path).unwrap().to_owned();
                 handles.push(tokio::spawn(async {
@@ -91,7 +519,281 @@ impl FileTree {
             nodes.push(handle.await.unwrap());
         }
 
-        Ok(Self { nodes })
+        Ok(Self {
+            nodes,
+            links: links.links,
+            specials,
+        })
+    }
+
+    /// Like `scan`, reusing the listings and hashes of what did not change since the last scan.
+    async fn scan_cached(
+        base_path: &Path,
+        start_path: &Path,
+        options: ScanOptions,
+    ) -> anyhow::Result<Self> {
+        let _span = profile::span("scan", Some(start_path));
+
+        let mut links = Links::default();
+        let mut specials = BTreeMap::new();
+        let mut handles = vec![];
+        for found in scan_cache::walk(start_path, options) {
+            let truncated_path = found.path().strip_prefix(base_path).unwrap().to_owned();
+            match found {
+                Found::File { inode, .. } => links.found(&truncated_path, inode),
+                Found::Special { kind, .. } if kind != SpecialKind::Fifo || !options.fifos => {
+                    specials.insert(truncated_path, kind);
+                    continue;
+                }
+                _ => (),
+            }
+            handles.push(tokio::spawn(async move {
+                let typ = match found {
+                    Found::Dir(_) => FileTreeNodeType::Dir,
+                    Found::Special { .. } => FileTreeNodeType::Fifo,
+                    Found::File {
+                        path,
+                        size,
+                        mtime,
+                        sha1,
+                        ..
+                    } => match (options.size_only, options.quick_check, sha1) {
+                        (true, ..) => FileTreeNodeType::File { size, sha1: None },
+                        (false, true, _) => FileTreeNodeType::TimedFile {
+                            size,
+                            mtime: unix_secs(mtime),
+                        },
+                        (false, false, Some(sha1)) => FileTreeNodeType::File {
+                            size,
+                            sha1: Some(sha1),
+                        },
+                        (false, false, None) => {
+                            let sha1 = hash(&path, &truncated_path).await;
+                            scan_cache::remember(&path, size, mtime, sha1);
+                            FileTreeNodeType::File {
+                                size,
+                                sha1: Some(sha1),
+                            }
+                        }
+                    },
+                };
+
+                FileTreeNode {
+                    path: truncated_path,
+                    typ,
+                }
+            }));
+        }
+
+        let mut nodes = Vec::with_capacity(handles.len());
+        for handle in handles {
+            nodes.push(handle.await.unwrap());
+        }
+
+        Ok(Self {
+            nodes,
+            links: links.links,
+            specials,
+        })
+    }
+
+    /// Moves every node under `dest`, the scanned directory itself becoming `dest`.
+    pub fn rebased(self, dest: &Path) -> Self {
+        if dest.as_os_str().is_empty() {
+            return self;
+        }
+
+        let rebase = |path: PathBuf| match path.as_os_str().is_empty() {
+            true => dest.to_owned(),
+            false => dest.join(path),
+        };
+        let nodes = self
+            .nodes
+            .into_iter()
+            .map(|node| FileTreeNode {
+                path: rebase(node.path),
+                ..node
+            })
+            .collect();
+        let links = self
+            .links
+            .into_iter()
+            .map(|(link, target)| (rebase(link), rebase(target)))
+            .collect();
+        let specials = self
+            .specials
+            .into_iter()
+            .map(|(path, kind)| (rebase(path), kind))
+            .collect();
+
+        Self {
+            nodes,
+            links,
+            specials,
+        }
+    }
+
+    /// The first path of the file at `path` if it is another hard link to it.
+    pub fn link_target(&self, path: &Path) -> Option<&Path> {
+        self.links.get(path).map(PathBuf::as_path)
+    }
+
+    /// The hard links to files with another path in the tree, and the first of those paths.
+    pub fn links(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.links
+            .iter()
+            .map(|(link, target)| (link.as_path(), target.as_path()))
+    }
+
+    /// The special files left out of the tree.
+    pub fn specials(&self) -> impl Iterator<Item = SkippedSpecial> + '_ {
+        self.specials.iter().map(|(path, &kind)| SkippedSpecial {
+            path: path.clone(),
+            kind,
+        })
+    }
+
+    /// Keeps only the nodes whose path `keep` returns true for.
+    pub fn filtered(mut self, keep: impl Fn(&Path) -> bool) -> Self {
+        self.nodes.retain(|node| keep(&node.path));
+        self.links.retain(|link, target| keep(link) && keep(target));
+        self.specials.retain(|path, _| keep(path));
+        self
+    }
+
+    /// Replaces the type of the nodes `map` returns a new one for.
+    pub fn mapped(mut self, map: impl Fn(&FileTreeNode) -> Option<FileTreeNodeType>) -> Self {
+        for node in self.nodes.iter_mut() {
+            if let Some(typ) = map(node) {
+                node.typ = typ;
+            }
+        }
+        self
+    }
+
+    /// The node at `path` and every node below it.
+    pub fn subtree(&self, path: &Path) -> &[FileTreeNode] {
+        let start = self
+            .nodes
+            .partition_point(|node| node.path.as_path() < path);
+        let len = self.nodes[start..]
+            .iter()
+            .position(|node| !node.path.starts_with(path))
+            .unwrap_or(self.nodes.len() - start);
+
+        &self.nodes[start..start + len]
+    }
+
+    /// Splits the nodes into runs of about `page_size` bytes once encoded, in order. Empty trees
+    /// still make one empty page.
+    pub fn into_pages(self, page_size: usize) -> impl Iterator<Item = Vec<FileTreeNode>> {
+        let mut nodes = self.nodes.into_iter().peekable();
+        let mut started = false;
+        std::iter::from_fn(move || {
+            if started && nodes.peek().is_none() {
+                return None;
+            }
+
+            started = true;
+            let (mut page, mut size) = (vec![], 0);
+            while size < page_size {
+                let Some(node) = nodes.next() else {
+                    break;
+                };
+                // The path's length, the node's type, a size and a hash.
+                size += node.path.as_os_str().len() + 48;
+                page.push(node);
+            }
+            Some(page)
+        })
+    }
+
+    /// Turns every directory into a `HashedDir`.
+    pub fn with_subtree_hashes(mut self) -> Self {
+        let hashes = subtree_hashes(&self.nodes);
+        for (node, hash) in self.nodes.iter_mut().zip(hashes) {
+            if let Some(SubtreeHash { sha1, descendants }) = hash {
+                node.typ = FileTreeNodeType::HashedDir { sha1, descendants };
+            }
+        }
+
+        self
+    }
+
+    /// Combines trees with disjoint paths into one sorted tree.
+    pub fn merged(trees: impl IntoIterator<Item = FileTree>) -> Self {
+        let (mut nodes, mut links, mut specials) = (vec![], BTreeMap::new(), BTreeMap::new());
+        for tree in trees {
+            nodes.extend(tree.nodes);
+            links.extend(tree.links);
+            specials.extend(tree.specials);
+        }
+        nodes.sort_by(|node1, node2| node1.path.cmp(&node2.path));
+
+        Self {
+            nodes,
+            links,
+            specials,
+        }
+    }
+
+    /// Hashes the nodes below each top-level entry, so that two trees can be compared cheaply
+    /// and only the divergent entries rescanned.
+    pub fn top_level_checksums(&self) -> Vec<SubtreeChecksum> {
+        let mut checksums = vec![];
+        let mut current: Option<(PathBuf, Sha1)> = None;
+
+        for node in self.nodes.iter() {
+            let Some(top_level) = node.path.components().next() else {
+                continue;
+            };
+
+            let top_level = Path::new(top_level.as_os_str());
+            if current.as_ref().is_none_or(|(path, _)| path != top_level) {
+                if let Some((path, hasher)) = current.take() {
+                    checksums.push(SubtreeChecksum {
+                        path,
+                        sha1: hasher.finalize().into(),
+                    });
+                }
+
+                current = Some((top_level.to_owned(), Sha1::new()));
+            }
+
+            let (_, hasher) = current.as_mut().unwrap();
+            // Named as on the wire, for peers on other platforms to get the same checksums.
+            hasher.update(
+                wire_path::to_wire(&node.path)
+                    .as_os_str()
+                    .as_encoded_bytes(),
+            );
+            hasher.update([0]);
+            match &node.typ {
+                FileTreeNodeType::File { size, sha1 } => {
+                    hasher.update([0]);
+                    hasher.update(size.to_le_bytes());
+                    if let Some(sha1) = sha1 {
+                        hasher.update(sha1);
+                    }
+                }
+                FileTreeNodeType::TimedFile { size, mtime } => {
+                    hasher.update([2]);
+                    hasher.update(size.to_le_bytes());
+                    hasher.update(mtime.to_le_bytes());
+                }
+                FileTreeNodeType::Dir | FileTreeNodeType::HashedDir { .. } => hasher.update([1]),
+                FileTreeNodeType::Fifo => hasher.update([3]),
+            }
+        }
+
+        if let Some((path, hasher)) = current {
+            checksums.push(SubtreeChecksum {
+                path,
+                sha1: hasher.finalize().into(),
+            });
+        }
+
+        checksums
     }
 
     pub fn is_valid(&self) -> bool {
@@ -108,3 +810,471 @@ impl FileTree {
         true
     }
 }
+
+async fn hash(full_path: &Path, truncated_path: &Path) -> [u8; 20] {
+    let _span = profile::span("hash", Some(truncated_path));
+    let file = tokio::fs::read(full_path).await.unwrap();
+
+    let mut hasher = Sha1::new();
+    hasher.update(&file);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        file_tree_diff::{PagedDiff, TreeDiff},
+        message::RequestMessage,
+    };
+    use tempfile::TempDir;
+    use tokio::test;
+    use walkdir::WalkDir;
+
+    fn create_test_files(dir: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(dir.join("src/nested"))?;
+        fs::create_dir_all(dir.join("assets"))?;
+
+        fs::write(dir.join("README.md"), "readme")?;
+        fs::write(dir.join("src/main.rs"), "fn main() {}")?;
+        fs::write(dir.join("src/nested/lib.rs"), "pub fn lib() {}")?;
+        fs::write(dir.join("assets/logo.svg"), "<svg/>")?;
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_identical_trees_have_identical_checksums() -> anyhow::Result<()> {
+        let (dir1, dir2) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(dir1.path())?;
+        create_test_files(dir2.path())?;
+
+        let checksums1 = FileTree::new(dir1.path()).await?.top_level_checksums();
+        let checksums2 = FileTree::new(dir2.path()).await?.top_level_checksums();
+
+        assert_eq!(checksums1.len(), 3);
+        assert_eq!(checksums1, checksums2);
+        assert!(divergent_subtrees(&checksums1, &checksums2).is_empty());
+        assert_eq!(root_checksum(&checksums1), root_checksum(&checksums2));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_divergent_subtrees() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+
+        fs::write(
+            remote.path().join("src/nested/lib.rs"),
+            "pub fn changed() {}",
+        )?;
+        fs::remove_dir_all(remote.path().join("assets"))?;
+        fs::write(remote.path().join("LICENSE"), "MIT")?;
+
+        let local_checksums = FileTree::new(local.path()).await?.top_level_checksums();
+        let remote_checksums = FileTree::new(remote.path()).await?.top_level_checksums();
+
+        assert_ne!(
+            root_checksum(&local_checksums),
+            root_checksum(&remote_checksums)
+        );
+
+        let divergent = divergent_subtrees(&local_checksums, &remote_checksums);
+        assert_eq!(
+            divergent,
+            vec![
+                PathBuf::from("LICENSE"),
+                PathBuf::from("assets"),
+                PathBuf::from("src")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_subtree_paths_are_relative_to_base() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        create_test_files(dir.path())?;
+
+        let subtree = FileTree::new_subtree(dir.path(), "src").await?;
+        let paths: Vec<_> = subtree.iter().map(|node| node.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("src"),
+                Path::new("src/main.rs"),
+                Path::new("src/nested"),
+                Path::new("src/nested/lib.rs"),
+            ]
+        );
+
+        let missing = FileTree::new_subtree(dir.path(), "missing").await?;
+        assert!(missing.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_size_only_compares_sizes() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::write(remote.path().join("README.md"), "README")?;
+
+        let size_only = ScanOptions {
+            size_only: true,
+            ..Default::default()
+        };
+        let local_tree = FileTree::new_with(local.path(), size_only).await?;
+        let remote_tree = FileTree::new_with(remote.path(), size_only).await?;
+        assert!(local_tree.iter().all(|node| matches!(
+            node.typ,
+            FileTreeNodeType::Dir | FileTreeNodeType::File { sha1: None, .. }
+        )));
+        assert!(TreeDiff::from(&local_tree, &remote_tree).is_empty());
+
+        let hashed_remote_tree = FileTree::new(remote.path()).await?;
+        assert!(TreeDiff::from(&local_tree, &hashed_remote_tree).is_empty());
+        let hashed_local_tree = FileTree::new(local.path()).await?;
+        assert!(!TreeDiff::from(&hashed_local_tree, &hashed_remote_tree).is_empty());
+
+        fs::write(remote.path().join("README.md"), "longer readme")?;
+        let remote_tree = FileTree::new_with(remote.path(), size_only).await?;
+        assert!(!TreeDiff::from(&local_tree, &remote_tree).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_quick_check_compares_mtimes() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::write(remote.path().join("README.md"), "README")?;
+        let synced_at = SystemTime::now() - std::time::Duration::from_secs(3600);
+        for dir in [local.path(), remote.path()] {
+            for path in [
+                "README.md",
+                "src/main.rs",
+                "src/nested/lib.rs",
+                "assets/logo.svg",
+            ] {
+                fs::File::open(dir.join(path))?.set_modified(synced_at)?;
+            }
+        }
+
+        let quick_check = ScanOptions {
+            quick_check: true,
+            ..Default::default()
+        };
+        let local_tree = FileTree::new_with(local.path(), quick_check).await?;
+        let remote_tree = FileTree::new_with(remote.path(), quick_check).await?;
+        assert!(local_tree.iter().all(|node| matches!(
+            node.typ,
+            FileTreeNodeType::Dir | FileTreeNodeType::TimedFile { .. }
+        )));
+        // Files of the same size and mtime are taken as unchanged, whatever their contents.
+        assert!(TreeDiff::from(&local_tree, &remote_tree).is_empty());
+        let hashed_remote_tree = FileTree::new(remote.path()).await?;
+        assert!(TreeDiff::from(&local_tree, &hashed_remote_tree).is_empty());
+
+        fs::File::open(remote.path().join("README.md"))?.set_modified(SystemTime::now())?;
+        let remote_tree = FileTree::new_with(remote.path(), quick_check).await?;
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.edited_files(), [Path::new("README.md")]);
+        // Directories of identical contents keep matching.
+        let (local_tree, remote_tree) = (
+            local_tree.with_subtree_hashes(),
+            remote_tree.with_subtree_hashes(),
+        );
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.edited_files(), [Path::new("README.md")]);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    async fn test_special_files_are_left_out() -> anyhow::Result<()> {
+        use crate::core::specials::create_fifo;
+
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        create_fifo(&remote.path().join("src/events"))?;
+        let _socket = std::os::unix::net::UnixListener::bind(remote.path().join("agent.sock"))?;
+
+        let remote_tree = FileTree::new(remote.path()).await?;
+        let local_tree = FileTree::new(local.path()).await?;
+        assert!(TreeDiff::from(&local_tree, &remote_tree).is_empty());
+        let specials: Vec<_> = remote_tree
+            .specials()
+            .map(|special| special.to_string())
+            .collect();
+        assert_eq!(specials, ["agent.sock (socket)", "src/events (FIFO)"]);
+
+        // With `fifos`, FIFOs are kept and diffed like empty files.
+        let fifos = ScanOptions {
+            fifos: true,
+            ..Default::default()
+        };
+        let remote_tree = FileTree::new_with(remote.path(), fifos).await?;
+        assert!(remote_tree
+            .iter()
+            .any(|node| matches!(node.typ, FileTreeNodeType::Fifo)));
+        assert_eq!(remote_tree.specials().count(), 1);
+        let cached = ScanOptions {
+            trust_dir_mtime: true,
+            ..fifos
+        };
+        let cached_tree = FileTree::new_with(remote.path(), cached).await?;
+        assert_eq!(cached_tree.len(), remote_tree.len());
+        assert_eq!(cached_tree.specials().count(), 1);
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.created_files(), [Path::new("src/events")]);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    async fn test_symlink_loops_fail_followed_scans() -> anyhow::Result<()> {
+        use crate::core::{compression::compress_dir_with_limit, utils::quoted};
+
+        let dir = TempDir::new()?;
+        create_test_files(dir.path())?;
+        std::os::unix::fs::symlink(dir.path().join("src"), dir.path().join("src/nested/up"))?;
+        std::os::unix::fs::symlink(dir.path().join("README.md"), dir.path().join("docs.md"))?;
+
+        let follow = ScanOptions {
+            follow_links: true,
+            ..Default::default()
+        };
+        let err = FileTree::new_with(dir.path(), follow).await.unwrap_err();
+        let loop_path = dir.path().join("src/nested/up");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "symlink loop: {} leads back to {}, which it is in",
+                quoted(&loop_path),
+                quoted(&dir.path().join("src"))
+            )
+        );
+        let err = compress_dir_with_limit(dir.path(), u64::MAX, follow)
+            .await
+            .unwrap_err();
+        assert!(err.root_cause().is::<WalkError>());
+
+        fs::remove_file(&loop_path)?;
+        let tree = FileTree::new_with(dir.path(), follow).await?;
+        assert!(tree.iter().any(|node| node.path == Path::new("docs.md")
+            && matches!(node.typ, FileTreeNodeType::File { size: 6, .. })));
+
+        let shallow = ScanOptions {
+            max_depth: 1,
+            ..follow
+        };
+        let err = FileTree::new_with(dir.path(), shallow).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WalkError>(),
+            Some(WalkError::TooDeep { path, max_depth: 1 }) if path.ends_with("assets/logo.svg")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_diff_across_files_and_dirs() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::write(local.path().join("build.sh"), "cargo build")?;
+        fs::write(remote.path().join("build.sh"), "cargo build")?;
+        fs::remove_dir_all(remote.path().join("assets"))?;
+        fs::write(remote.path().join("LICENSE"), "license")?;
+        fs::create_dir(remote.path().join("docs"))?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?;
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.deleted_dirs(), [Path::new("assets")]);
+        assert_eq!(diff.created_files(), [Path::new("LICENSE")]);
+        assert_eq!(diff.created_dirs(), [Path::new("docs")]);
+        assert!(diff.deleted_files().is_empty() && diff.edited_files().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_paged_diff_matches_the_whole_diff() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::remove_dir_all(local.path().join("src"))?;
+        fs::write(local.path().join("src"), "now a file")?;
+        fs::remove_dir_all(remote.path().join("assets"))?;
+        fs::create_dir_all(remote.path().join("docs/guide"))?;
+        fs::write(remote.path().join("docs/guide/intro.md"), "intro")?;
+        fs::write(remote.path().join("README.md"), "edited readme")?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?;
+        let whole = TreeDiff::from(&local_tree, &remote_tree);
+        let whole = (whole.requests(), whole.deletions());
+
+        // One node per page splits every directory from its entries, and from the nodes skipped
+        // along with it.
+        let pages: Vec<_> = remote_tree.with_subtree_hashes().into_pages(1).collect();
+        assert!(pages.len() > 1 && pages.iter().all(|page| page.len() == 1));
+        let mut paged = PagedDiff::new(&local_tree);
+        let (mut requests, mut deletions) = (vec![], vec![]);
+        for (i, page) in pages.iter().enumerate() {
+            let diff = paged.page(page, i == pages.len() - 1);
+            requests.extend(diff.requests());
+            deletions.extend(diff.deletions());
+        }
+
+        // Pages report requests and deletions interleaved, in the tree's order.
+        fn sorted(items: &[impl std::fmt::Debug]) -> Vec<String> {
+            let mut items: Vec<_> = items.iter().map(|item| format!("{:?}", item)).collect();
+            items.sort();
+            items
+        }
+        assert_eq!(sorted(&requests), sorted(&whole.0));
+        assert_eq!(sorted(&deletions), sorted(&whole.1));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_subtree_hashes_skip_identical_subtrees() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::write(
+            remote.path().join("src/nested/lib.rs"),
+            "pub fn edited() {}",
+        )?;
+        fs::create_dir(remote.path().join("docs"))?;
+        fs::write(remote.path().join("docs/intro.md"), "intro")?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?.with_subtree_hashes();
+        let hash = |tree: &FileTree, path: &str| {
+            let index = tree.iter().position(|node| node.path == Path::new(path));
+            subtree_hashes(tree)[index.unwrap()].unwrap()
+        };
+        assert_eq!(hash(&local_tree, "assets"), hash(&remote_tree, "assets"));
+        assert_ne!(hash(&local_tree, "src"), hash(&remote_tree, "src"));
+        assert_eq!(
+            hash(&remote_tree, "").descendants,
+            remote_tree.len() as u64 - 1
+        );
+        assert!(matches!(
+            remote_tree.subtree(Path::new("assets"))[0].typ,
+            FileTreeNodeType::HashedDir { descendants: 1, .. }
+        ));
+
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.edited_files(), [Path::new("src/nested/lib.rs")]);
+        assert_eq!(diff.created_dirs(), [Path::new("docs")]);
+        assert!(diff.created_files().is_empty() && diff.deleted_files().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_moved_directories_are_renamed() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        create_test_files(local.path())?;
+        create_test_files(remote.path())?;
+        fs::rename(remote.path().join("src"), remote.path().join("lib"))?;
+        // Moved and edited, its contents are sent again.
+        fs::rename(remote.path().join("assets"), remote.path().join("static"))?;
+        fs::write(remote.path().join("static/logo.svg"), "<svg></svg>")?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?;
+        // Without subtree hashes, moves cannot be told from deletions and creations.
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert!(diff.renamed_dirs().is_empty());
+        assert_eq!(diff.deleted_dirs(), [Path::new("assets"), Path::new("src")]);
+
+        let remote_tree = remote_tree.with_subtree_hashes();
+        let diff = TreeDiff::from(&local_tree, &remote_tree);
+        assert_eq!(diff.renamed_dirs(), [(Path::new("src"), Path::new("lib"))]);
+        assert_eq!(diff.deleted_dirs(), [Path::new("assets")]);
+        assert_eq!(diff.created_dirs(), [Path::new("static")]);
+
+        let requests = diff.apply(local.path()).await;
+        assert!(matches!(
+            &requests[..],
+            [RequestMessage::Dir(path)] if path == Path::new("static")
+        ));
+        assert!(!local.path().join("src").exists());
+        assert_eq!(
+            fs::read_to_string(local.path().join("lib/nested/lib.rs"))?,
+            "pub fn lib() {}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_default_excludes_are_left_out() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        create_test_files(dir.path())?;
+        fs::write(dir.path().join("src/.main.rs.swp"), "swap")?;
+        fs::write(dir.path().join("README.md~"), "backup")?;
+        fs::write(dir.path().join("assets/.DS_Store"), "")?;
+
+        let tree = FileTree::new(dir.path()).await?;
+        assert_eq!(tree.len(), 8);
+        assert!(tree.iter().all(|node| !is_default_excluded(&node.path)));
+
+        let options = ScanOptions {
+            default_excludes: false,
+            ..Default::default()
+        };
+        let full_tree = FileTree::new_with(dir.path(), options).await?;
+        assert_eq!(full_tree.len(), tree.len() + 3);
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_trusted_dir_mtimes_skip_unchanged_dirs() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        create_test_files(dir.path())?;
+        // Only entries left alone for a while are remembered.
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        for entry in WalkDir::new(dir.path()) {
+            fs::File::open(entry?.path())?.set_modified(an_hour_ago)?;
+        }
+
+        let trusted = ScanOptions {
+            trust_dir_mtime: true,
+            ..Default::default()
+        };
+        let tree = FileTree::new_with(dir.path(), trusted).await?;
+        assert!(TreeDiff::from(&tree, &FileTree::new(dir.path()).await?).is_empty());
+
+        // Edits are noticed, files being checked one by one.
+        fs::write(dir.path().join("src/main.rs"), "fn main() { edited() }")?;
+        let tree = FileTree::new_with(dir.path(), trusted).await?;
+        assert!(TreeDiff::from(&tree, &FileTree::new(dir.path()).await?).is_empty());
+
+        // A directory that looks unchanged is not listed again.
+        fs::write(dir.path().join("assets/icon.svg"), "<svg/>")?;
+        fs::File::open(dir.path().join("assets"))?.set_modified(an_hour_ago)?;
+        let tree = FileTree::new_with(dir.path(), trusted).await?;
+        assert!(!tree.iter().any(|node| node.path.ends_with("icon.svg")));
+        let tree = FileTree::new(dir.path()).await?;
+        assert!(tree.iter().any(|node| node.path.ends_with("icon.svg")));
+
+        Ok(())
+    }
+}
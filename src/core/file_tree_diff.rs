@@ -1,14 +1,243 @@
-use std::{fmt::Display, path::Path};
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
 use super::{
-    file_tree::{FileTree, FileTreeNodeType},
-    message::RequestMessage,
+    file_tree::{subtree_hashes, FileTree, FileTreeNode, FileTreeNodeType, SubtreeHash},
+    message::{FileChangeMessage, RequestMessage},
+    utils::quoted,
 };
 
+/// Which list of a `TreeDiff` an entry is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEntry {
+    CreatedDir,
+    DeletedDir,
+    CreatedFile,
+    DeletedFile,
+    EditedFile,
+}
+
+/// Diffs a local tree against a remote one arriving in pages, both sorted by path, each page
+/// giving the diff of its own nodes. Local nodes are held until the remote nodes they could match
+/// have been seen, and are only reported deleted once the last page is in. Remote `HashedDir`s
+/// matching the subtree hash of the local directory are skipped whole, and a deleted local
+/// directory with the subtree hash of a created `HashedDir` of the same page is reported renamed
+/// to it.
+pub struct PagedDiff<'local> {
+    local: &'local [FileTreeNode],
+    /// Position of the next local node to diff.
+    next: usize,
+    /// The subtree hashes of `local`, computed once a remote directory has one.
+    local_hashes: Option<Vec<Option<SubtreeHash>>>,
+    /// A directory missing locally and requested whole, whose remote descendants are skipped,
+    /// possibly in the next pages.
+    created_dir: Option<PathBuf>,
+    /// Remote nodes left to skip below an identical or requested directory, possibly in the next
+    /// pages.
+    skipped: u64,
+}
+
+impl<'local> PagedDiff<'local> {
+    pub fn new(local_tree: &'local FileTree) -> Self {
+        Self {
+            local: local_tree,
+            next: 0,
+            local_hashes: None,
+            created_dir: None,
+            skipped: 0,
+        }
+    }
+
+    /// Diffs the next page of remote nodes, which must follow the previous ones in order.
+    pub fn page<'tree>(&mut self, remote: &'tree [FileTreeNode], last: bool) -> TreeDiff<'tree>
+    where
+        'local: 'tree,
+    {
+        let mut diff = TreeDiff::empty();
+        // The indices of the local directories deleted, and the hashes of the remote ones created.
+        let mut deleted_dirs = vec![];
+        let mut created_hashes = vec![];
+        let mut remote = remote.iter().peekable();
+        loop {
+            let skipped = self.skipped.min(remote.len() as u64);
+            if skipped > 0 {
+                remote.nth(skipped as usize - 1);
+                self.skipped -= skipped;
+            }
+            if let Some(created_dir) = &self.created_dir {
+                while remote
+                    .next_if(|node| node.path.starts_with(created_dir))
+                    .is_some()
+                {}
+                if remote.peek().is_some() {
+                    self.created_dir = None;
+                }
+            }
+
+            let Some(&remote_node) = remote.peek() else {
+                break;
+            };
+            let Some(local_node) = self.local.get(self.next) else {
+                remote.next();
+                self.created(&mut diff, remote_node, &mut created_hashes);
+                continue;
+            };
+
+            let order = local_node.path.cmp(&remote_node.path);
+            let same_file = local_node.typ.same_file(&remote_node.typ);
+            match (&local_node.typ, &remote_node.typ, order) {
+                (_, _, Ordering::Equal) if same_file.is_some() => {
+                    if same_file == Some(false) {
+                        diff.edited_files.push(&local_node.path)
+                    }
+
+                    self.next += 1;
+                    remote.next();
+                }
+                (local_typ, remote_typ, Ordering::Equal)
+                    if local_typ.is_dir() && remote_typ.is_dir() =>
+                {
+                    remote.next();
+                    self.same_dir(remote_typ);
+                }
+                // Entries of different types are only the same entry replaced when their paths
+                // are equal, otherwise the one with the smaller path is missing on the other side.
+                // A replaced file is deleted, while a replaced directory is only deleted once the
+                // remote entries sorting before it are through.
+                (
+                    _,
+                    FileTreeNodeType::File { .. }
+                    | FileTreeNodeType::TimedFile { .. }
+                    | FileTreeNodeType::Fifo,
+                    Ordering::Equal,
+                )
+                | (_, _, Ordering::Greater) => {
+                    remote.next();
+                    self.created(&mut diff, remote_node, &mut created_hashes);
+                }
+                (_, _, Ordering::Equal | Ordering::Less) => {
+                    self.deleted(&mut diff, &mut deleted_dirs)
+                }
+            }
+        }
+
+        if last {
+            while self.next < self.local.len() {
+                self.deleted(&mut diff, &mut deleted_dirs);
+            }
+        }
+
+        if !deleted_dirs.is_empty() && !created_hashes.is_empty() {
+            self.pair_renamed(&mut diff, &deleted_dirs, &created_hashes);
+        }
+        diff
+    }
+
+    /// Turns the deletion of a local directory and the creation of a remote one with the same
+    /// subtree hash into a rename, each directory being paired at most once.
+    fn pair_renamed<'tree>(
+        &mut self,
+        diff: &mut TreeDiff<'tree>,
+        deleted_dirs: &[usize],
+        created_hashes: &[(&'tree Path, [u8; 20])],
+    ) where
+        'local: 'tree,
+    {
+        let local = self.local;
+        let local_hashes = self
+            .local_hashes
+            .get_or_insert_with(|| subtree_hashes(local));
+        let mut deleted: Vec<_> = deleted_dirs
+            .iter()
+            .filter_map(|&index| Some((local[index].path.as_path(), local_hashes[index]?.sha1)))
+            .collect();
+
+        for &(to, sha1) in created_hashes {
+            let Some(position) = deleted.iter().position(|&(_, hash)| hash == sha1) else {
+                continue;
+            };
+            let (from, _) = deleted.swap_remove(position);
+            diff.deleted_dirs.retain(|&path| path != from);
+            diff.created_dirs.retain(|&path| path != to);
+            diff.renamed_dirs.push((from, to));
+        }
+    }
+
+    /// Moves past the local directory at the same path as a remote one, and past both their
+    /// contents if the remote one has the same subtree hash.
+    fn same_dir(&mut self, remote: &FileTreeNodeType) {
+        let index = self.next;
+        self.next += 1;
+        let FileTreeNodeType::HashedDir { sha1, descendants } = *remote else {
+            return;
+        };
+
+        let local = self.local;
+        let local_hashes = self
+            .local_hashes
+            .get_or_insert_with(|| subtree_hashes(local));
+        if let Some(hash) = local_hashes[index].filter(|hash| hash.sha1 == sha1) {
+            self.next += hash.descendants as usize;
+            self.skipped = descendants;
+        }
+    }
+
+    /// Requests `node`, and directories with everything below them.
+    fn created<'tree>(
+        &mut self,
+        diff: &mut TreeDiff<'tree>,
+        node: &'tree FileTreeNode,
+        created_hashes: &mut Vec<(&'tree Path, [u8; 20])>,
+    ) {
+        match node.typ {
+            FileTreeNodeType::File { .. }
+            | FileTreeNodeType::TimedFile { .. }
+            | FileTreeNodeType::Fifo => diff.created_files.push(&node.path),
+            FileTreeNodeType::Dir => {
+                diff.created_dirs.push(&node.path);
+                self.created_dir = Some(node.path.clone());
+            }
+            FileTreeNodeType::HashedDir { sha1, descendants } => {
+                diff.created_dirs.push(&node.path);
+                created_hashes.push((&node.path, sha1));
+                self.skipped = descendants;
+            }
+        }
+    }
+
+    /// Deletes the next local node, and directories with everything below them.
+    fn deleted<'tree>(&mut self, diff: &mut TreeDiff<'tree>, deleted_dirs: &mut Vec<usize>)
+    where
+        'local: 'tree,
+    {
+        let node = &self.local[self.next];
+        deleted_dirs.extend(node.typ.is_dir().then_some(self.next));
+        self.next += 1;
+        match node.typ.is_dir() {
+            false => diff.deleted_files.push(&node.path),
+            true => {
+                diff.deleted_dirs.push(&node.path);
+                while self
+                    .local
+                    .get(self.next)
+                    .is_some_and(|other| other.path.starts_with(&node.path))
+                {
+                    self.next += 1;
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TreeDiff<'message> {
     created_dirs: Vec<&'message Path>,
     deleted_dirs: Vec<&'message Path>,
+    /// Local directories moved to where the remote tree has the same contents, from and to.
+    renamed_dirs: Vec<(&'message Path, &'message Path)>,
     created_files: Vec<&'message Path>,
     deleted_files: Vec<&'message Path>,
     edited_files: Vec<&'message Path>,
@@ -19,30 +248,37 @@ impl Display for TreeDiff<'_> {
         f.write_str("\nDeleted Directories:")?;
         for &deleted_dir in self.deleted_dirs.iter() {
             f.write_str("\n  - ")?;
-            f.write_str(deleted_dir.to_str().unwrap())?;
+            write!(f, "{}", quoted(deleted_dir))?;
         }
 
         f.write_str("\nDeleted Files:")?;
         for &deleted_file in self.deleted_files.iter() {
             f.write_str("\n  - ")?;
-            f.write_str(deleted_file.to_str().unwrap())?;
+            write!(f, "{}", quoted(deleted_file))?;
+        }
+
+        f.write_str("\nRenamed Directories:")?;
+        for &(from, to) in self.renamed_dirs.iter() {
+            f.write_str("\n  - ")?;
+            write!(f, "{} -> {}", quoted(from), quoted(to))?;
         }
 
         f.write_str("\nRequested Directories from Sender:")?;
         for &created_dir in self.created_dirs.iter() {
             f.write_str("\n  - ")?;
-            f.write_str(created_dir.to_str().unwrap())?;
+            write!(f, "{}", quoted(created_dir))?;
         }
 
         f.write_str("\nRequested Files from Sender:")?;
         for &created_file in self.created_files.iter() {
             f.write_str("\n  - ")?;
-            f.write_str(created_file.to_str().unwrap())?;
+            write!(f, "{}", quoted(created_file))?;
         }
 
+        f.write_str("\nEdited Files (checksum mismatch):")?;
         for &edited_file in self.edited_files.iter() {
             f.write_str("\n  - ")?;
-            f.write_str(edited_file.to_str().unwrap())?;
+            write!(f, "{}", quoted(edited_file))?;
         }
 
         Ok(())
@@ -51,122 +287,98 @@ impl Display for TreeDiff<'_> {
 
 impl<'tree> TreeDiff<'tree> {
     pub fn from(local_tree: &'tree FileTree, remote_tree: &'tree FileTree) -> Self {
-        let (mut local_idx, mut remote_idx) = (0, 0);
-        let mut diff = Self {
+        PagedDiff::new(local_tree).page(remote_tree, true)
+    }
+
+    fn empty() -> Self {
+        Self {
             created_dirs: vec![],
             deleted_dirs: vec![],
+            renamed_dirs: vec![],
             created_files: vec![],
             deleted_files: vec![],
             edited_files: vec![],
-        };
-
-        while local_idx < local_tree.len() && remote_idx < remote_tree.len() {
-            let local_node = local_tree.get(local_idx).unwrap();
-            let remote_node = remote_tree.get(remote_idx).unwrap();
-
-            match (&local_node.typ, &remote_node.typ) {
-                (
-                    FileTreeNodeType::File { sha1: local_sha },
-                    FileTreeNodeType::File { sha1: remote_sha },
-                ) => match local_node.path.cmp(&remote_node.path) {
-                    std::cmp::Ordering::Greater => {
-                        diff.created_files.push(&remote_node.path);
-                        remote_idx += 1;
-                    }
-                    std::cmp::Ordering::Less => {
-                        diff.deleted_files.push(&local_node.path);
-                        local_idx += 1;
-                    }
-                    std::cmp::Ordering::Equal => {
-                        if local_sha != remote_sha {
-                            diff.edited_files.push(&local_node.path)
-                        }
-
-                        local_idx += 1;
-                        remote_idx += 1;
-                    }
-                },
-                (FileTreeNodeType::File { sha1: _ }, FileTreeNodeType::Dir) => {
-                    diff.deleted_files.push(&local_node.path);
-                    local_idx += 1;
-                }
-                (FileTreeNodeType::Dir, FileTreeNodeType::File { sha1: _ }) => {
-                    diff.created_files.push(&remote_node.path);
-                    remote_idx += 1;
-                }
-                (FileTreeNodeType::Dir, FileTreeNodeType::Dir) => {
-                    match local_node.path.cmp(&remote_node.path) {
-                        std::cmp::Ordering::Less => {
-                            diff.deleted_dirs.push(&local_node.path);
-                            let local_idx_offset = local_tree
-                                .get(local_idx..)
-                                .unwrap()
-                                .iter()
-                                .position(|node| !node.path.starts_with(&local_node.path))
-                                .unwrap_or(local_tree.len() - local_idx);
-                            local_idx += local_idx_offset;
-                        }
-                        std::cmp::Ordering::Greater => {
-                            diff.created_dirs.push(&remote_node.path);
-                            let remote_idx_offset = remote_tree
-                                .get(remote_idx..)
-                                .unwrap()
-                                .iter()
-                                .position(|node| !node.path.starts_with(&remote_node.path))
-                                .unwrap_or(remote_tree.len() - remote_idx);
-                            remote_idx += remote_idx_offset;
-                        }
-                        std::cmp::Ordering::Equal => {
-                            local_idx += 1;
-                            remote_idx += 1;
-                        }
-                    }
-                }
-            }
         }
+    }
 
-        while let Some(node) = local_tree.get(local_idx) {
-            match &node.typ {
-                FileTreeNodeType::File { sha1: _ } => {
-                    diff.deleted_files.push(&node.path);
-                    local_idx += 1;
-                }
-                FileTreeNodeType::Dir => {
-                    diff.deleted_dirs.push(&node.path);
-                    let local_idx_offset = local_tree
-                        .get(local_idx..)
-                        .unwrap()
-                        .iter()
-                        .position(|node| !node.path.starts_with(&node.path))
-                        .unwrap_or(local_tree.len() - local_idx);
-                    local_idx += local_idx_offset;
-                }
-            }
+    /// Drops the deletions and edits, so that applying the diff only creates missing entries.
+    pub fn without_existing(self) -> Self {
+        let Self {
+            mut created_dirs,
+            renamed_dirs,
+            created_files,
+            ..
+        } = self;
+        created_dirs.extend(renamed_dirs.into_iter().map(|(_, to)| to));
+        Self {
+            created_dirs,
+            deleted_dirs: vec![],
+            renamed_dirs: vec![],
+            created_files,
+            deleted_files: vec![],
+            edited_files: vec![],
         }
+    }
 
-        while let Some(node) = remote_tree.get(remote_idx) {
-            match &node.typ {
-                FileTreeNodeType::File { sha1: _ } => {
-                    diff.created_files.push(&node.path);
-                    remote_idx += 1;
-                }
-                FileTreeNodeType::Dir => {
-                    diff.created_dirs.push(&node.path);
-                    let remote_idx_offset = remote_tree
-                        .get(remote_idx..)
-                        .unwrap()
-                        .iter()
-                        .position(|node| !node.path.starts_with(&node.path))
-                        .unwrap_or(remote_tree.len() - remote_idx);
-                    remote_idx += remote_idx_offset;
-                }
-            }
+    /// Turns renamed directories back into a deletion and a creation, for receivers that must
+    /// not move what they hold.
+    pub fn without_renames(mut self) -> Self {
+        for (from, to) in std::mem::take(&mut self.renamed_dirs) {
+            self.deleted_dirs.push(from);
+            self.created_dirs.push(to);
         }
+        self
+    }
 
-        diff
+    /// Keeps only the entries `keep` returns true for.
+    pub fn retain(&mut self, mut keep: impl FnMut(DiffEntry, &'tree Path) -> bool) {
+        self.created_dirs
+            .retain(|&path| keep(DiffEntry::CreatedDir, path));
+        self.deleted_dirs
+            .retain(|&path| keep(DiffEntry::DeletedDir, path));
+        self.created_files
+            .retain(|&path| keep(DiffEntry::CreatedFile, path));
+        self.deleted_files
+            .retain(|&path| keep(DiffEntry::DeletedFile, path));
+        self.edited_files
+            .retain(|&path| keep(DiffEntry::EditedFile, path));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created_dirs.is_empty()
+            && self.deleted_dirs.is_empty()
+            && self.renamed_dirs.is_empty()
+            && self.created_files.is_empty()
+            && self.deleted_files.is_empty()
+            && self.edited_files.is_empty()
+    }
+
+    pub fn created_dirs(&self) -> &[&Path] {
+        &self.created_dirs
+    }
+
+    pub fn created_files(&self) -> &[&Path] {
+        &self.created_files
+    }
+
+    pub fn edited_files(&self) -> &[&Path] {
+        &self.edited_files
+    }
+
+    pub fn deleted_files(&self) -> &[&Path] {
+        &self.deleted_files
+    }
+
+    pub fn deleted_dirs(&self) -> &[&Path] {
+        &self.deleted_dirs
+    }
+
+    pub fn renamed_dirs(&self) -> &[(&Path, &Path)] {
+        &self.renamed_dirs
     }
 
     pub async fn apply(&self, root_path: &Path) -> Vec<RequestMessage> {
+        let mut requests = self.rename_dirs(root_path).await;
         for deleted_dir in self.deleted_dirs.iter() {
             let path = root_path.join(deleted_dir);
             let _ = tokio::fs::remove_dir_all(path).await;
@@ -177,6 +389,31 @@ impl<'tree> TreeDiff<'tree> {
             let _ = tokio::fs::remove_file(path).await;
         }
 
+        requests.extend(self.requests());
+        requests
+    }
+
+    /// Moves the renamed directories, returning the requests for those that could not be moved
+    /// and must be sent instead. The directories left behind are deleted by the next diff.
+    pub async fn rename_dirs(&self, root_path: &Path) -> Vec<RequestMessage> {
+        let mut requests = vec![];
+        for &(from, to) in self.renamed_dirs.iter() {
+            if let Err(err) = tokio::fs::rename(root_path.join(from), root_path.join(to)).await {
+                eprintln!(
+                    "Could not rename {} to {}, requesting it instead: {}",
+                    quoted(from),
+                    quoted(to),
+                    err
+                );
+                requests.push(RequestMessage::Dir(to.to_owned()));
+            }
+        }
+
+        requests
+    }
+
+    /// The entries to request from the sender, without applying anything.
+    pub fn requests(&self) -> Vec<RequestMessage> {
         let mut requests = Vec::<RequestMessage>::with_capacity(
             self.created_dirs.len() + self.created_files.len() + self.edited_files.len(),
         );
@@ -195,4 +432,26 @@ impl<'tree> TreeDiff<'tree> {
 
         requests
     }
+
+    /// The renames `apply` performs, as changes to be applied later, before the deletions.
+    pub fn renames(&self) -> Vec<FileChangeMessage> {
+        self.renamed_dirs
+            .iter()
+            .map(|&(from, to)| FileChangeMessage::Rename(from.to_owned(), to.to_owned()))
+            .collect()
+    }
+
+    /// The deletions `apply` performs, as changes to be applied later.
+    pub fn deletions(&self) -> Vec<FileChangeMessage> {
+        let dirs = self
+            .deleted_dirs
+            .iter()
+            .map(|&path| FileChangeMessage::DirectoryDeleted(path.to_owned()));
+        let files = self
+            .deleted_files
+            .iter()
+            .map(|&path| FileChangeMessage::FileDeleted(path.to_owned()));
+
+        dirs.chain(files).collect()
+    }
 }
@@ -0,0 +1,493 @@
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Context};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use tungstenite::http::{HeaderMap, HeaderValue};
+
+use super::utils::quoted;
+
+/// Each side's public key, in the websocket handshake's request and response.
+pub const IDENTITY_HEADER: &str = "x-caiman-identity";
+
+/// Each side's random challenge, which the other side signs along with its own.
+pub const CHALLENGE_HEADER: &str = "x-caiman-challenge";
+
+/// The listener's signature, in the handshake's response. The sender's follows as the first
+/// websocket message, a binary one holding the signature's bytes.
+pub const SIGNATURE_HEADER: &str = "x-caiman-signature";
+
+const KEY_PREFIX: &str = "ed25519:";
+
+/// A random value signed to prove a key is held, fresh for each session.
+pub type Challenge = [u8; 32];
+
+/// Which side signs, so that a signature is never valid for the other one.
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Sender,
+    Listener,
+}
+
+/// The bytes signed by `role`: the signatures cover both sides' challenges, so they are only
+/// valid for the session they were made in.
+fn transcript(role: Role, sender: &Challenge, listener: &Challenge) -> Vec<u8> {
+    let role: &[u8] = match role {
+        Role::Sender => b"white-caiman sender",
+        Role::Listener => b"white-caiman listener",
+    };
+    [role, sender, listener].concat()
+}
+
+pub fn challenge() -> Challenge {
+    rand::random()
+}
+
+/// An ed25519 key pair identifying a sender or a listener. Its file holds the hex-encoded secret
+/// key.
+pub struct Identity(SigningKey);
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Identity").field(&self.public_key()).finish()
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading the identity {}", quoted(path)))?;
+        let secret = hex::decode(contents.trim())
+            .ok()
+            .and_then(|secret| secret.try_into().ok())
+            .ok_or_else(|| anyhow!("{} is not an identity", quoted(path)))?;
+
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Writes the secret key to `path`, readable by its owner only. Existing identities are only
+    /// replaced with `force`, as the peers trusting them would no longer recognize this side.
+    pub fn save(&self, path: &Path, force: bool) -> anyhow::Result<()> {
+        if path.exists() && !force {
+            bail!(
+                "{} already exists, pass --force to replace it",
+                quoted(path)
+            )
+        }
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", hex::encode(self.0.to_bytes())))
+            .with_context(|| format!("writing {}", quoted(path)))?;
+
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    pub fn sign(&self, role: Role, sender: &Challenge, listener: &Challenge) -> Signature {
+        self.0.sign(&transcript(role, sender, listener))
+    }
+}
+
+/// The public half of an `Identity`, written as `ed25519:<hex>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(VerifyingKey);
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", KEY_PREFIX, hex::encode(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix(KEY_PREFIX)
+            .and_then(|key| hex::decode(key).ok()?.try_into().ok())
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .map(Self)
+            .ok_or_else(|| format!("invalid public key '{}', expected {}<hex>", s, KEY_PREFIX))
+    }
+}
+
+impl PublicKey {
+    /// Checks that `role` holding this key signed the session's challenges.
+    pub fn verify(
+        &self,
+        role: Role,
+        sender: &Challenge,
+        listener: &Challenge,
+        signature: &[u8],
+    ) -> anyhow::Result<()> {
+        let signature = Signature::from_slice(signature).context("malformed signature")?;
+        self.0
+            .verify(&transcript(role, sender, listener), &signature)
+            .map_err(|_| anyhow!("the signature does not match {}", self))
+    }
+}
+
+/// What to do about peers whose key is not in the trusted peers file yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// Trust them and add them to the file, on a sender for each new listener address, and on a
+    /// listener for the first sender only, while the file lists none.
+    Tofu,
+    /// Turn them away: their keys must be added to the file beforehand.
+    Pinned,
+}
+
+impl FromStr for Trust {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tofu" => Ok(Trust::Tofu),
+            "pinned" => Ok(Trust::Pinned),
+            _ => Err(format!("unknown trust '{}', expected tofu or pinned", s)),
+        }
+    }
+}
+
+/// The peers a side trusts, as `<name> <public key>` lines of a file, `#` starting comments.
+/// Senders name listeners after their address, listeners name senders as they see fit.
+#[derive(Debug)]
+pub struct TrustedPeers {
+    path: PathBuf,
+    trust: Trust,
+    peers: Mutex<Vec<(String, PublicKey)>>,
+}
+
+impl TrustedPeers {
+    /// Reads the peers trusted in `path`, none if it does not exist yet.
+    pub fn load(path: &Path, trust: Trust) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(path))),
+        };
+
+        let mut peers = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let peer = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| "expected <name> <public key>".to_string())
+                .and_then(|(name, key)| Ok((name.to_owned(), key.trim().parse()?)))
+                .map_err(|err| anyhow!("{} line {}: {}", quoted(path), i + 1, err))?;
+            peers.push(peer);
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            trust,
+            peers: Mutex::new(peers),
+        })
+    }
+
+    /// Checks the key presented by the listener at `addr`, trusting it on first use unless
+    /// pinned.
+    pub fn check_listener(&self, addr: &str, key: PublicKey) -> anyhow::Result<()> {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.iter().find(|(name, _)| name == addr) {
+            Some((_, trusted)) if *trusted == key => Ok(()),
+            Some((_, trusted)) => bail!(
+                "the listener at {} presented {} instead of {}, remove it from {} if it got a new identity",
+                addr,
+                key,
+                trusted,
+                quoted(&self.path)
+            ),
+            None if self.trust == Trust::Tofu => {
+                println!("Trusting the listener at {} with key {}", addr, key);
+                self.add(&mut peers, addr, key)
+            }
+            None => bail!(
+                "the listener at {} is not trusted, add `{} {}` to {} if it is expected",
+                addr,
+                addr,
+                key,
+                quoted(&self.path)
+            ),
+        }
+    }
+
+    /// Whether a sender presenting `key` may connect. It still has to prove it holds the key
+    /// before it is `admit`ted.
+    pub fn may_admit(&self, key: PublicKey) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.iter().any(|(_, trusted)| *trusted == key)
+            || (self.trust == Trust::Tofu && peers.is_empty())
+    }
+
+    /// Returns the name of the sender presenting `key`, which proved it holds the key, trusting
+    /// it on first use. `addr` names it then.
+    pub fn admit(&self, key: PublicKey, addr: &str) -> anyhow::Result<String> {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some((name, _)) = peers.iter().find(|(_, trusted)| *trusted == key) {
+            return Ok(name.clone());
+        }
+        if self.trust == Trust::Pinned || !peers.is_empty() {
+            bail!("{} is not a trusted sender", key)
+        }
+
+        println!("Trusting the sender at {} with key {}", addr, key);
+        self.add(&mut peers, addr, key)?;
+        Ok(addr.to_owned())
+    }
+
+    fn add(
+        &self,
+        peers: &mut Vec<(String, PublicKey)>,
+        name: &str,
+        key: PublicKey,
+    ) -> anyhow::Result<()> {
+        if let Some(parent) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{} {}", name, key))
+            .with_context(|| format!("writing {}", quoted(&self.path)))?;
+        peers.push((name.to_owned(), key));
+
+        Ok(())
+    }
+}
+
+/// This side's identity and the peers it trusts: senders and listeners configured with them
+/// authenticate each other during the websocket handshake.
+#[derive(Debug)]
+pub struct Identities {
+    pub identity: Identity,
+    pub peers: TrustedPeers,
+}
+
+/// A sender's side of the identity exchange, between its request and the listener's response.
+pub struct SenderExchange<'a> {
+    identities: &'a Identities,
+    challenge: Challenge,
+}
+
+impl Identities {
+    /// Presents the sender's key and challenge in its handshake request.
+    pub fn request(&self, headers: &mut HeaderMap) -> SenderExchange<'_> {
+        let challenge = challenge();
+        headers.insert(IDENTITY_HEADER, header(self.identity.public_key()));
+        headers.insert(CHALLENGE_HEADER, header(hex::encode(challenge)));
+        SenderExchange {
+            identities: self,
+            challenge,
+        }
+    }
+
+    /// Checks the sender presenting itself in `request` may connect, and answers its challenge
+    /// in `response`. The exchange completes once the sender's signature is `verify`ed.
+    pub fn answer(
+        &self,
+        request: &HeaderMap,
+        response: &mut HeaderMap,
+    ) -> Result<ListenerExchange, String> {
+        let key: PublicKey = header_value(request, IDENTITY_HEADER)
+            .ok_or("the sender did not present an identity")?
+            .parse()?;
+        let sender_challenge = header_value(request, CHALLENGE_HEADER)
+            .and_then(parse_challenge)
+            .ok_or("the sender did not present a challenge")?;
+        if !self.peers.may_admit(key) {
+            return Err(format!("{} is not a trusted sender", key));
+        }
+
+        let challenge = challenge();
+        let signature = self
+            .identity
+            .sign(Role::Listener, &sender_challenge, &challenge);
+        response.insert(IDENTITY_HEADER, header(self.identity.public_key()));
+        response.insert(CHALLENGE_HEADER, header(hex::encode(challenge)));
+        response.insert(SIGNATURE_HEADER, header(hex::encode(signature.to_bytes())));
+
+        Ok(ListenerExchange {
+            key,
+            sender_challenge,
+            challenge,
+        })
+    }
+}
+
+impl SenderExchange<'_> {
+    /// Checks the listener at `addr` answered the challenge with a trusted key, and returns the
+    /// sender's own signature, to send as its first message.
+    pub fn complete(self, addr: &str, response: &HeaderMap) -> anyhow::Result<Vec<u8>> {
+        let key: PublicKey = header_value(response, IDENTITY_HEADER)
+            .ok_or_else(|| {
+                anyhow!(
+                    "the listener at {} has no identity, start it with --identity",
+                    addr
+                )
+            })?
+            .parse()
+            .map_err(|err: String| anyhow!(err))?;
+        let challenge = header_value(response, CHALLENGE_HEADER)
+            .and_then(parse_challenge)
+            .context("the listener did not present a challenge")?;
+        let signature = header_value(response, SIGNATURE_HEADER)
+            .and_then(|signature| hex::decode(signature).ok())
+            .context("the listener did not sign the challenge")?;
+        key.verify(Role::Listener, &self.challenge, &challenge, &signature)
+            .with_context(|| format!("authenticating the listener at {}", addr))?;
+        self.identities.peers.check_listener(addr, key)?;
+
+        let signature = self
+            .identities
+            .identity
+            .sign(Role::Sender, &self.challenge, &challenge);
+        Ok(signature.to_bytes().to_vec())
+    }
+}
+
+/// A listener's side of the identity exchange, waiting for the sender's signature.
+pub struct ListenerExchange {
+    key: PublicKey,
+    sender_challenge: Challenge,
+    challenge: Challenge,
+}
+
+impl ListenerExchange {
+    /// Checks the sender's `signature` and returns its name among the trusted peers.
+    pub fn verify(
+        self,
+        peers: &TrustedPeers,
+        signature: &[u8],
+        addr: &str,
+    ) -> anyhow::Result<String> {
+        self.key.verify(
+            Role::Sender,
+            &self.sender_challenge,
+            &self.challenge,
+            signature,
+        )?;
+        peers.admit(self.key, addr)
+    }
+}
+
+fn header(value: impl ToString) -> HeaderValue {
+    // Keys and hex strings are always valid header values.
+    HeaderValue::from_str(&value.to_string()).unwrap()
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+fn parse_challenge(challenge: &str) -> Option<Challenge> {
+    hex::decode(challenge).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sender_and_listener_authenticate_each_other() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let sender = Identities {
+            identity: Identity::generate(),
+            peers: TrustedPeers::load(&dir.path().join("sender-peers"), Trust::Tofu)?,
+        };
+        let listener = Identities {
+            identity: Identity::generate(),
+            peers: TrustedPeers::load(&dir.path().join("listener-peers"), Trust::Tofu)?,
+        };
+
+        let (mut request, mut response) = (HeaderMap::new(), HeaderMap::new());
+        let exchange = sender.request(&mut request);
+        let pending = listener.answer(&request, &mut response).unwrap();
+        let signature = exchange.complete("127.0.0.1:8080", &response)?;
+        assert_eq!(
+            pending.verify(&listener.peers, &signature, "laptop")?,
+            "laptop"
+        );
+
+        // Both sides trusted each other on first use, and remember it.
+        let reloaded = TrustedPeers::load(&dir.path().join("listener-peers"), Trust::Pinned)?;
+        assert!(reloaded.may_admit(sender.identity.public_key()));
+        assert!(!reloaded.may_admit(Identity::generate().public_key()));
+        let listener_key = listener.identity.public_key();
+        sender
+            .peers
+            .check_listener("127.0.0.1:8080", listener_key)?;
+        assert!(sender
+            .peers
+            .check_listener("127.0.0.1:8080", Identity::generate().public_key())
+            .is_err());
+
+        // Listeners only trust their first sender on first use.
+        let stranger = Identities {
+            identity: Identity::generate(),
+            peers: TrustedPeers::load(&dir.path().join("stranger-peers"), Trust::Tofu)?,
+        };
+        let (mut request, mut response) = (HeaderMap::new(), HeaderMap::new());
+        stranger.request(&mut request);
+        assert!(listener.answer(&request, &mut response).is_err());
+
+        // A signature from another session is refused.
+        let (mut request, mut response) = (HeaderMap::new(), HeaderMap::new());
+        sender.request(&mut request);
+        let pending = listener.answer(&request, &mut response).unwrap();
+        assert!(pending
+            .verify(&listener.peers, &signature, "laptop")
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_identity_files() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("keys/identity");
+        let identity = Identity::generate();
+        identity.save(&path, false)?;
+        assert!(Identity::generate().save(&path, false).is_err());
+        assert_eq!(Identity::load(&path)?.public_key(), identity.public_key());
+
+        let key = identity.public_key().to_string();
+        assert_eq!(key.parse::<PublicKey>(), Ok(identity.public_key()));
+        assert!("ed25519:1234".parse::<PublicKey>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::{fmt::Display, time::Duration};
+
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct DeadConnection {
+    pub idle: Duration,
+}
+
+impl Display for DeadConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no message received from peer in {}, connection considered dead",
+            humantime::format_duration(self.idle)
+        )
+    }
+}
+
+impl std::error::Error for DeadConnection {}
+
+/// Tracks when the peer was last heard from and paces outgoing pings.
+pub struct Keepalive {
+    timeout: Duration,
+    ticker: Interval,
+    last_seen: Instant,
+}
+
+impl Keepalive {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        let mut ticker =
+            tokio::time::interval_at(Instant::now() + config.interval, config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            timeout: config.timeout,
+            ticker,
+            last_seen: Instant::now(),
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Waits until the next ping is due, failing if the peer has been silent for longer than the
+    /// configured timeout. Cancel safe, so it can be used as a `tokio::select!` branch.
+    pub async fn tick(&mut self) -> Result<(), DeadConnection> {
+        self.ticker.tick().await;
+
+        let idle = self.last_seen.elapsed();
+        if idle > self.timeout {
+            return Err(DeadConnection { idle });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_silent_peers_are_dead_after_the_timeout() {
+        tokio::time::pause();
+        let mut keepalive = Keepalive::new(KeepaliveConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(25),
+        });
+
+        // Pings are due every interval while the peer answers them.
+        for _ in 0..3 {
+            keepalive.tick().await.unwrap();
+            keepalive.seen();
+        }
+
+        // Then it stops answering.
+        let started = Instant::now();
+        keepalive.tick().await.unwrap();
+        keepalive.tick().await.unwrap();
+        let dead = keepalive.tick().await.unwrap_err();
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+        assert_eq!(dead.idle, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,217 @@
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use super::{
+    file_tree::{FileTree, FileTreeNode, FileTreeNodeType},
+    file_tree_diff::{DiffEntry, TreeDiff},
+    utils::quoted,
+};
+
+/// Suffix of the copies kept of receiver files whose edits conflict with the sender's.
+pub const CONFLICT_SUFFIX: &str = ".caiman-conflict";
+
+/// Both sides changed an entry since the last sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// The sender's version wins, the receiver's is kept next to it with `CONFLICT_SUFFIX`.
+    BothEdited,
+    /// The receiver edited what the sender deleted, so it is not deleted.
+    EditedDeleted,
+    /// The receiver deleted what the sender edited, so it is restored.
+    DeletedEdited,
+}
+
+/// What a three-way diff did differently from a two-way one.
+#[derive(Debug, Default)]
+pub struct MergeReport<'tree> {
+    /// Entries created, edited or deleted on the receiver only, left as they are.
+    pub kept: Vec<&'tree Path>,
+    pub conflicts: Vec<(&'tree Path, Conflict)>,
+}
+
+impl MergeReport<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.kept.is_empty() && self.conflicts.is_empty()
+    }
+}
+
+impl Display for MergeReport<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\nKept Receiver Changes:")?;
+        for path in self.kept.iter() {
+            write!(f, "\n  - {}", quoted(path))?;
+        }
+
+        f.write_str("\nConflicts:")?;
+        for (path, conflict) in self.conflicts.iter() {
+            let resolution = match conflict {
+                Conflict::BothEdited => {
+                    format!(
+                        "edited on both sides, the receiver's version is kept as *{}",
+                        CONFLICT_SUFFIX
+                    )
+                }
+                Conflict::EditedDeleted => "deleted by the sender but edited here, kept".to_owned(),
+                Conflict::DeletedEdited => {
+                    "deleted here but edited by the sender, restored".to_owned()
+                }
+            };
+            write!(f, "\n  - {}: {}", quoted(path), resolution)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Diffs `local` against `remote` knowing `ancestor`, the tree both had after the last sync, so
+/// that changes made on the receiver are told apart from the sender's. The receiver's own
+/// changes are left alone, where a two-way diff would undo them, and entries changed on both
+/// sides are reported as conflicts.
+pub fn three_way<'tree>(
+    local: &'tree FileTree,
+    remote: &'tree FileTree,
+    ancestor: &FileTree,
+) -> (TreeDiff<'tree>, MergeReport<'tree>) {
+    let ancestor_nodes: HashMap<&Path, &FileTreeNodeType> = ancestor
+        .iter()
+        .map(|node| (node.path.as_path(), &node.typ))
+        .collect();
+    let unchanged = |nodes: &[FileTreeNode]| {
+        nodes.iter().all(|node| {
+            ancestor_nodes
+                .get(node.path.as_path())
+                .is_some_and(|typ| same(typ, &node.typ))
+        })
+    };
+
+    let mut diff = TreeDiff::from(local, remote);
+    let mut report = MergeReport::default();
+    diff.retain(|entry, path| {
+        if !ancestor_nodes.contains_key(path) {
+            // Created on one side since the last sync: the sender's entries are synced, the
+            // receiver's are kept. Files created on both sides conflict.
+            return match entry {
+                DiffEntry::CreatedDir | DiffEntry::CreatedFile => true,
+                DiffEntry::DeletedDir | DiffEntry::DeletedFile => {
+                    report.kept.push(path);
+                    false
+                }
+                DiffEntry::EditedFile => {
+                    report.conflicts.push((path, Conflict::BothEdited));
+                    true
+                }
+            };
+        }
+
+        match entry {
+            DiffEntry::DeletedDir | DiffEntry::DeletedFile => {
+                let keep = !unchanged(local.subtree(path));
+                if keep {
+                    report.conflicts.push((path, Conflict::EditedDeleted));
+                }
+                !keep
+            }
+            DiffEntry::CreatedDir | DiffEntry::CreatedFile => {
+                let sender_unchanged = unchanged(remote.subtree(path))
+                    && ancestor.subtree(path).len() == remote.subtree(path).len();
+                match sender_unchanged {
+                    true => report.kept.push(path),
+                    false => report.conflicts.push((path, Conflict::DeletedEdited)),
+                }
+                !sender_unchanged
+            }
+            DiffEntry::EditedFile => {
+                let ancestor_typ = ancestor_nodes[path];
+                let local_unchanged = node(local, path).is_some_and(|typ| same(ancestor_typ, typ));
+                let remote_unchanged =
+                    node(remote, path).is_some_and(|typ| same(ancestor_typ, typ));
+                if remote_unchanged {
+                    report.kept.push(path);
+                } else if !local_unchanged {
+                    report.conflicts.push((path, Conflict::BothEdited));
+                }
+                !remote_unchanged
+            }
+        }
+    });
+    report.kept.sort();
+    report.conflicts.sort_by_key(|&(path, _)| path);
+
+    (diff, report)
+}
+
+fn node<'tree>(tree: &'tree FileTree, path: &Path) -> Option<&'tree FileTreeNodeType> {
+    tree.subtree(path)
+        .first()
+        .filter(|node| node.path == path)
+        .map(|node| &node.typ)
+}
+
+/// Whether two nodes have the same contents, as far as their scans tell.
+fn same(typ1: &FileTreeNodeType, typ2: &FileTreeNodeType) -> bool {
+    (typ1.is_dir() && typ2.is_dir()) || typ1.same_file(typ2).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_three_way_keeps_receiver_changes() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        for dir in [local.path(), remote.path()] {
+            fs::create_dir(dir.join("docs"))?;
+            fs::write(dir.join("docs/guide.md"), "guide")?;
+            fs::write(dir.join("edited_here.txt"), "v1")?;
+            fs::write(dir.join("edited_there.txt"), "v1")?;
+            fs::write(dir.join("edited_both.txt"), "v1")?;
+            fs::write(dir.join("deleted_here.txt"), "v1")?;
+            fs::write(dir.join("deleted_there.txt"), "v1")?;
+        }
+        let ancestor = FileTree::new(remote.path()).await?;
+
+        fs::write(local.path().join("created_here.txt"), "local")?;
+        fs::write(local.path().join("edited_here.txt"), "local")?;
+        fs::write(local.path().join("edited_both.txt"), "local")?;
+        fs::remove_file(local.path().join("deleted_here.txt"))?;
+        fs::write(local.path().join("docs/notes.md"), "notes")?;
+
+        fs::write(remote.path().join("created_there.txt"), "remote")?;
+        fs::write(remote.path().join("edited_there.txt"), "remote!")?;
+        fs::write(remote.path().join("edited_both.txt"), "remote!")?;
+        fs::remove_file(remote.path().join("deleted_there.txt"))?;
+        fs::remove_dir_all(remote.path().join("docs"))?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?;
+        let (diff, report) = three_way(&local_tree, &remote_tree, &ancestor);
+
+        assert_eq!(diff.created_files(), [Path::new("created_there.txt")]);
+        assert_eq!(
+            diff.edited_files(),
+            [Path::new("edited_both.txt"), Path::new("edited_there.txt")]
+        );
+        assert_eq!(diff.deleted_files(), [Path::new("deleted_there.txt")]);
+        // The receiver added a file to the directory the sender deleted.
+        assert!(diff.deleted_dirs().is_empty());
+        assert_eq!(
+            report.kept,
+            [
+                Path::new("created_here.txt"),
+                Path::new("deleted_here.txt"),
+                Path::new("edited_here.txt")
+            ]
+        );
+        assert_eq!(
+            report.conflicts,
+            [
+                (Path::new("docs"), Conflict::EditedDeleted),
+                (Path::new("edited_both.txt"), Conflict::BothEdited)
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -1,25 +1,383 @@
-use std::path::PathBuf;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use bytes::Bytes;
+use bytesize::ByteSize;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::{
+    file_tree::{FileTree, FileTreeNode, SubtreeChecksum},
+    wire_path,
+};
 
 type OldPath = PathBuf;
 type NewPath = PathBuf;
 
+/// A change to the sender's tree. New variants go last, see `PROTOCOL_VERSION`.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FileChangeMessage {
-    FileCreated(PathBuf),
-    FileDeleted(PathBuf),
-    FileEdited(PathBuf, Bytes),
-    EmptyDirectoryCreated(PathBuf),
-    DirectoryCreated(PathBuf, Bytes),
-    DirectoryDeleted(PathBuf),
-    Rename(OldPath, NewPath),
-    DirectoryContentsEdited(PathBuf),
+    FileCreated(#[serde(with = "wire_path")] PathBuf),
+    FileDeleted(#[serde(with = "wire_path")] PathBuf),
+    /// Contents and modification time of a file on the sender.
+    FileEdited(#[serde(with = "wire_path")] PathBuf, Bytes, SystemTime),
+    GzippedFileEdited(#[serde(with = "wire_path")] PathBuf, Bytes, SystemTime),
+    EmptyDirectoryCreated(#[serde(with = "wire_path")] PathBuf),
+    DirectoryCreated(#[serde(with = "wire_path")] PathBuf, Bytes),
+    DirectoryDeleted(#[serde(with = "wire_path")] PathBuf),
+    Rename(
+        #[serde(with = "wire_path")] OldPath,
+        #[serde(with = "wire_path")] NewPath,
+    ),
+    DirectoryContentsEdited(#[serde(with = "wire_path")] PathBuf),
+    /// Part of a file too large to fit in one message, see `MAX_MESSAGE_SIZE_HEADER`.
+    FileChunk(#[serde(with = "wire_path")] PathBuf, Chunk),
+    /// A file with the same contents as one already sent during the session, by their SHA-1: the
+    /// receiver copies it instead of having the contents sent again. Only files of at least
+    /// `MIN_DEDUP_SIZE` bytes are deduplicated.
+    FileFromHash(#[serde(with = "wire_path")] PathBuf, [u8; 20], SystemTime),
+    /// Small files below the directory at the path, packed into one message instead of one
+    /// each. Other entries of the directory are left alone, unlike with `DirectoryCreated`.
+    FileBatch(#[serde(with = "wire_path")] PathBuf, PackedFiles),
+    /// `DirectoryCreated` with a gzipped archive, for receivers that say they accept it, see
+    /// `GZIP_ARCHIVES_HEADER`.
+    GzippedDirectoryCreated(#[serde(with = "wire_path")] PathBuf, Bytes),
+    /// A hard link at the second path to the file at the first one, sent during the session, for
+    /// receivers that say they accept it, see `HARDLINKS_HEADER`.
+    HardlinkCreated(
+        #[serde(with = "wire_path")] PathBuf,
+        #[serde(with = "wire_path")] PathBuf,
+    ),
+    /// A FIFO, for receivers that say they recreate them, see `SPECIALS_HEADER`. FIFOs have no
+    /// contents, only the path is sent.
+    FifoCreated(#[serde(with = "wire_path")] PathBuf),
+    /// A `FileEdited` or a `FileChunk` with the SHA-1 of its contents or data, as sent before
+    /// being encoded, for receivers that say they verify it, see `CHECKSUMS_HEADER`. Receivers
+    /// check it once decoded and again once written, and reject the change with
+    /// `Rejection::ChecksumMismatch` when they differ.
+    Checksummed(Box<FileChangeMessage>, [u8; 20]),
+}
+
+/// Files smaller than this are always sent, deduplicating them would not save much.
+pub const MIN_DEDUP_SIZE: u64 = 64 * 1024;
+
+/// `data` goes at `offset` of a file of `size` bytes. Chunks of a file are sent in order, the
+/// first one replacing whatever was received of the file before. Chunks of sparse files leave
+/// out their holes, the first one being at offset 0 and the last one ending the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    pub offset: u64,
+    pub size: u64,
+    pub data: Bytes,
+    pub mtime: SystemTime,
+}
+
+/// A tar archive of `files` files of `size` bytes in all, with paths relative to the directory of
+/// the `FileBatch`, see `compression::pack_files`. The archive is gzipped unless it looked
+/// incompressible.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackedFiles {
+    pub files: u64,
+    pub size: u64,
+    pub gzip: bool,
+    pub archive: Bytes,
+}
+
+impl Chunk {
+    pub fn is_last(&self) -> bool {
+        self.offset + self.data.len() as u64 >= self.size
+    }
+}
+
+impl FileChangeMessage {
+    /// The path the change applies to, or the source path for renames.
+    pub fn path(&self) -> &Path {
+        match self {
+            FileChangeMessage::FileCreated(path)
+            | FileChangeMessage::FileDeleted(path)
+            | FileChangeMessage::FileEdited(path, ..)
+            | FileChangeMessage::GzippedFileEdited(path, ..)
+            | FileChangeMessage::EmptyDirectoryCreated(path)
+            | FileChangeMessage::DirectoryCreated(path, _)
+            | FileChangeMessage::GzippedDirectoryCreated(path, _)
+            | FileChangeMessage::DirectoryDeleted(path)
+            | FileChangeMessage::Rename(path, _)
+            | FileChangeMessage::DirectoryContentsEdited(path)
+            | FileChangeMessage::FileChunk(path, _)
+            | FileChangeMessage::FileFromHash(path, ..)
+            | FileChangeMessage::FileBatch(path, _)
+            | FileChangeMessage::HardlinkCreated(_, path)
+            | FileChangeMessage::FifoCreated(path) => path,
+            FileChangeMessage::Checksummed(change, _) => change.path(),
+        }
+    }
+
+    /// Every path the change touches, including the destination of renames.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            FileChangeMessage::Rename(old_path, new_path) => vec![old_path, new_path],
+            FileChangeMessage::HardlinkCreated(target, link) => vec![link, target],
+            FileChangeMessage::Checksummed(change, _) => change.paths(),
+            message => vec![message.path()],
+        }
+    }
+
+    /// Bytes of contents the change carries, as received.
+    pub fn payload_len(&self) -> u64 {
+        match self {
+            FileChangeMessage::FileEdited(_, contents, _)
+            | FileChangeMessage::GzippedFileEdited(_, contents, _)
+            | FileChangeMessage::DirectoryCreated(_, contents)
+            | FileChangeMessage::GzippedDirectoryCreated(_, contents) => contents.len() as u64,
+            FileChangeMessage::FileChunk(_, chunk) => chunk.data.len() as u64,
+            FileChangeMessage::FileBatch(_, packed) => packed.archive.len() as u64,
+            FileChangeMessage::Checksummed(change, _) => change.payload_len(),
+            _ => 0,
+        }
+    }
+
+    /// What happens to the path, e.g. `edited`, for people.
+    pub fn label(&self) -> &'static str {
+        match self {
+            FileChangeMessage::FileCreated(_) | FileChangeMessage::FifoCreated(_) => "created",
+            FileChangeMessage::FileDeleted(_) => "deleted",
+            FileChangeMessage::FileEdited(..)
+            | FileChangeMessage::GzippedFileEdited(..)
+            | FileChangeMessage::FileChunk(..)
+            | FileChangeMessage::FileFromHash(..) => "edited",
+            FileChangeMessage::FileBatch(..) => "edited files",
+            FileChangeMessage::EmptyDirectoryCreated(_)
+            | FileChangeMessage::DirectoryCreated(..)
+            | FileChangeMessage::GzippedDirectoryCreated(..) => "created directory",
+            FileChangeMessage::DirectoryDeleted(_) => "deleted directory",
+            FileChangeMessage::Rename(..) => "renamed",
+            FileChangeMessage::DirectoryContentsEdited(_) => "rescanned directory",
+            FileChangeMessage::HardlinkCreated(..) => "linked",
+            FileChangeMessage::Checksummed(change, _) => change.label(),
+        }
+    }
+
+    /// The change as `Checksummed`, for the changes carrying a file's contents or part of them.
+    /// Others are returned as they are.
+    pub fn checksummed(self) -> Self {
+        let sha1 = match &self {
+            FileChangeMessage::FileEdited(_, contents, _) => Sha1::digest(contents),
+            FileChangeMessage::FileChunk(_, chunk) => Sha1::digest(&chunk.data),
+            _ => return self,
+        };
+        FileChangeMessage::Checksummed(Box::new(self), sha1.into())
+    }
+}
+
+/// Envelope for every change sent after the initial tree exchange. Ids are sequence numbers
+/// assigned in scheduling order, which may differ from the order in which messages hit the wire.
+/// `depends_on` lists the latest earlier messages touching overlapping paths: the receiver must
+/// not apply the change before them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncMessage {
+    pub id: u64,
+    pub depends_on: Vec<u64>,
+    pub change: FileChangeMessage,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RequestMessage {
-    File(PathBuf),
-    Dir(PathBuf)
+    File(#[serde(with = "wire_path")] PathBuf),
+    Dir(#[serde(with = "wire_path")] PathBuf),
+}
+
+/// Encoded messages larger than this are sent as `SenderMessage::Fragment`s.
+pub const MAX_FRAGMENT_SIZE: usize = 1 << 20;
+
+/// Version of the encoding of the messages exchanged during sessions, bumped whenever it changes,
+/// e.g. when a variant is inserted before others. Peers of another version are refused during the
+/// websocket handshake rather than misread, see `PROTOCOL_HEADER`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Header of the websocket handshake with the sender's `PROTOCOL_VERSION`, and in the response
+/// with the receiver's. Receivers refuse senders of another version, and senders receivers of
+/// another version.
+pub const PROTOCOL_HEADER: &str = "x-caiman-protocol";
+
+/// Response header of the websocket handshake with the largest message the receiver accepts, in
+/// bytes, once reassembled from its fragments. Senders split the payloads that would not fit:
+/// files into `FileChunk`s, and directory archives into their entries.
+pub const MAX_MESSAGE_SIZE_HEADER: &str = "x-caiman-max-message-size";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `FileChangeMessage::FileFromHash`, which only receivers writing files as sent can.
+pub const DEDUP_HEADER: &str = "x-caiman-dedup";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `FileChangeMessage::FileBatch`.
+pub const FILE_BATCH_HEADER: &str = "x-caiman-file-batch";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `FileChangeMessage::GzippedDirectoryCreated`.
+pub const GZIP_ARCHIVES_HEADER: &str = "x-caiman-gzip-archives";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `Handshake::PagedSync`, with the tree following in `TreePage`s.
+pub const TREE_PAGES_HEADER: &str = "x-caiman-tree-pages";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `FileTreeNodeType::HashedDir` nodes in the sender's tree.
+pub const SUBTREE_HASHES_HEADER: &str = "x-caiman-subtree-hashes";
+
+/// Response header of the websocket handshake set by receivers accepting
+/// `FileChangeMessage::HardlinkCreated`.
+pub const HARDLINKS_HEADER: &str = "x-caiman-hardlinks";
+
+/// Response header of the websocket handshake set by receivers recreating the FIFOs of senders
+/// that keep them in their tree, as `FileTreeNodeType::Fifo` nodes and `FifoCreated` changes.
+pub const SPECIALS_HEADER: &str = "x-caiman-specials";
+
+/// Response header of the websocket handshake set by receivers recreating the holes of sparse
+/// files, sent as `FileChunk`s of their data only.
+pub const SPARSE_FILES_HEADER: &str = "x-caiman-sparse-files";
+
+/// Response header of the websocket handshake telling where the receiver writes, see
+/// `DirIdentity`, for senders to refuse syncing into their own source.
+pub const OUT_DIR_HEADER: &str = "x-caiman-out-dir";
+
+/// Response header of the websocket handshake set by receivers verifying
+/// `FileChangeMessage::Checksummed` changes.
+pub const CHECKSUMS_HEADER: &str = "x-caiman-checksums";
+
+/// Header of the websocket handshake set by senders whose tree has
+/// `FileTreeNodeType::TimedFile` nodes, and in the response by receivers accepting them.
+pub const QUICK_CHECK_HEADER: &str = "x-caiman-quick-check";
+
+/// Encoded `TreePage`s hold about this many bytes of nodes, well below `MIN_MAX_MESSAGE_SIZE`.
+pub const TREE_PAGE_SIZE: usize = 512 * 1024;
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 256 << 20;
+
+/// Smaller limits would not leave room for a fragment and its envelope.
+pub const MIN_MAX_MESSAGE_SIZE: u64 = 2 * MAX_FRAGMENT_SIZE as u64;
+
+/// First message of every session, telling the receiver what the sender wants.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Handshake {
+    /// Mirror the sender's tree into the receiver's directory. `dests` are the subdirectories the
+    /// sender's roots are mounted under: the receiver leaves everything else alone.
+    Sync {
+        #[serde(with = "wire_path::vec")]
+        dests: Vec<PathBuf>,
+        tree: FileTree,
+    },
+    /// Only report the receiver's tree below `dests`, without modifying anything.
+    Verify {
+        #[serde(with = "wire_path::vec")]
+        dests: Vec<PathBuf>,
+    },
+    /// `Sync` with the tree sent right after it in `TreePage`s, for receivers that say they
+    /// accept it, see `TREE_PAGES_HEADER`.
+    PagedSync {
+        #[serde(with = "wire_path::vec")]
+        dests: Vec<PathBuf>,
+    },
+}
+
+/// A run of nodes of the sender's tree following `Handshake::PagedSync`, in the tree's order, so
+/// that the receiver can diff each page as it arrives instead of holding the whole tree. The
+/// `last` one ends the tree.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreePage {
+    pub nodes: Vec<FileTreeNode>,
+    pub last: bool,
+}
+
+/// Messages sent by the sender once the initial `FileTree` has been exchanged. New variants go
+/// last, see `PROTOCOL_VERSION`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SenderMessage {
+    Sync(SyncMessage),
+    /// The message with this id was dropped before being sent, nothing depends on it anymore.
+    Skipped(u64),
+    /// Hash of the whole tree, see `file_tree::root_checksum`.
+    RootChecksum([u8; 20]),
+    Checksums(Vec<SubtreeChecksum>),
+    Subtree(#[serde(with = "wire_path")] PathBuf, FileTree),
+    /// The whole tree, sent when watch mode may have missed events and the receiver must diff
+    /// everything again.
+    FullTree(FileTree),
+    /// Part of an encoded message larger than `MAX_FRAGMENT_SIZE`, to be reassembled by the
+    /// receiver once the `last` part arrives. Splitting lets pings and control messages through
+    /// in between.
+    Fragment {
+        data: Bytes,
+        last: bool,
+    },
+    /// Everything requested or changed so far has been sent: the initial sync, a reply to
+    /// requests, or a batch of watched changes.
+    BatchEnd,
+}
+
+impl SenderMessage {
+    /// Control messages may overtake the messages sent before them. Only those that the receiver
+    /// handles regardless of order qualify: checksums must stay behind the changes they cover.
+    pub fn is_control(&self) -> bool {
+        matches!(self, SenderMessage::Skipped(_))
+    }
+}
+
+/// Messages sent by the receiver, starting with the reply to the initial `FileTree`. New variants
+/// go last, see `PROTOCOL_VERSION`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReceiverMessage {
+    Requests(Vec<RequestMessage>),
+    /// The root checksums differ, the sender should send its per-entry checksums.
+    ChecksumsRequested,
+    SubtreesRequested(#[serde(with = "wire_path::vec")] Vec<PathBuf>),
+    /// The receiver's tree, in reply to `Handshake::Verify`.
+    Tree(FileTree),
+    /// The change to this path was not applied, the sender may retry it later.
+    ChangeRejected(#[serde(with = "wire_path")] PathBuf, Rejection),
+}
+
+/// Why the receiver refused to apply a change, leaving the path as it was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    /// Applying it would grow the output directory past its quota.
+    QuotaExceeded { limit: u64, used: u64, needed: u64 },
+    /// The output directory's filesystem does not have enough free space.
+    DiskFull { available: u64, needed: u64 },
+    /// The receiver no longer has a file with the contents of a `FileChangeMessage::FileFromHash`,
+    /// or the target of a `FileChangeMessage::HardlinkCreated`, which must be sent in full.
+    MissingContents,
+    /// The contents of a `FileChangeMessage::Checksummed` change did not match its checksum,
+    /// as received or as written, and must be sent again.
+    ChecksumMismatch,
 }
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Rejection::QuotaExceeded {
+                limit,
+                used,
+                needed,
+            } => write!(
+                f,
+                "quota of {} exceeded, {} used and {} more needed",
+                ByteSize::b(limit),
+                ByteSize::b(used),
+                ByteSize::b(needed)
+            ),
+            Rejection::DiskFull { available, needed } => write!(
+                f,
+                "not enough disk space, {} needed and {} available",
+                ByteSize::b(needed),
+                ByteSize::b(available)
+            ),
+            Rejection::MissingContents => write!(f, "no file with the same contents was received"),
+            Rejection::ChecksumMismatch => write!(f, "the contents did not match their checksum"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
@@ -1,7 +1,31 @@
-pub mod message;
+pub mod activity;
+pub mod budget;
+pub mod compression;
+pub mod control;
+pub mod events;
+pub mod excludes;
 pub mod file_change;
-pub mod file_tree_diff;
 pub mod file_tree;
-pub mod compression;
+pub mod file_tree_diff;
+pub mod identity;
+pub mod keepalive;
+pub mod merge;
+pub mod message;
+pub mod overlap;
+pub mod policy;
+pub mod profile;
+pub mod proxy;
+pub mod records;
+pub mod roots;
+pub mod scan_cache;
+pub mod sparse;
+pub mod specials;
+pub mod summary;
+pub mod timeout;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
 pub mod utils;
-
+pub mod walk;
+pub mod webhook;
+pub mod wire_path;
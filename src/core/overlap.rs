@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use super::{file_tree::ScanOptions, utils::quoted};
+
+/// Where a receiver writes, as told to senders in `OUT_DIR_HEADER`: the name of its host, and
+/// the device and inode numbers of its output directory and of each of its ancestors, innermost
+/// first. Senders on the same host find out whether they would sync into their own source
+/// without either side revealing paths. Only known on Unix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirIdentity {
+    host: String,
+    chain: Vec<(u64, u64)>,
+}
+
+/// How a source directory overlaps with the receiver's output directory.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Overlap {
+    /// The source is the output directory, or is inside it.
+    SourceInside,
+    /// The output directory is inside the source, at this path below it.
+    DestinationInside(PathBuf),
+}
+
+impl DirIdentity {
+    #[cfg(unix)]
+    pub fn of(dir: &Path) -> Option<Self> {
+        Some(Self {
+            host: host_name()?,
+            chain: inode_chain(&dir.canonicalize().ok()?),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_dir: &Path) -> Option<Self> {
+        None
+    }
+
+    pub fn to_header(&self) -> String {
+        let chain = self
+            .chain
+            .iter()
+            .map(|(dev, ino)| format!("{}:{}", dev, ino));
+        std::iter::once(self.host.clone())
+            .chain(chain)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn from_header(value: &str) -> Option<Self> {
+        let mut parts = value.split(' ');
+        let host = parts.next()?.to_owned();
+        let chain = parts
+            .map(|part| {
+                let (dev, ino) = part.split_once(':')?;
+                Some((dev.parse().ok()?, ino.parse().ok()?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { host, chain })
+    }
+
+    /// How the source at `path`, on this host, overlaps with the directory, leaving out the
+    /// directories `scan` excludes: an excluded output directory is never synced.
+    pub fn overlap(&self, path: &Path, scan: ScanOptions) -> Option<Overlap> {
+        let (Some(host), Some(&dest)) = (host_name(), self.chain.first()) else {
+            return None;
+        };
+        if host != self.host {
+            return None;
+        }
+        let canonical = path.canonicalize().ok()?;
+        let source = inode_chain(&canonical);
+        if source.contains(&dest) {
+            return Some(Overlap::SourceInside);
+        }
+
+        // Followed down from the source, one directory of the output directory's ancestors at a
+        // time.
+        let depth = self
+            .chain
+            .iter()
+            .position(|inode| Some(inode) == source.first())?;
+        let below = &self.chain[..depth];
+        let found = WalkDir::new(&canonical)
+            .min_depth(depth)
+            .max_depth(depth)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0 || inode(entry.path()).is_some_and(|ino| below.contains(&ino))
+            })
+            .filter_map(Result::ok)
+            .find(|entry| inode(entry.path()) == Some(dest))?;
+        let relative = found.path().strip_prefix(&canonical).ok()?;
+        let excluded = relative
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| scan.excludes(&path.join(ancestor)));
+
+        (!excluded).then(|| Overlap::DestinationInside(relative.to_owned()))
+    }
+}
+
+impl Overlap {
+    /// Why syncing the source at `path` is refused.
+    pub fn describe(&self, path: &Path) -> String {
+        match self {
+            Overlap::SourceInside => format!(
+                "{} is the listener's output directory or inside it, syncing it would overwrite itself",
+                quoted(path)
+            ),
+            Overlap::DestinationInside(relative) => format!(
+                "the listener's output directory is {} inside {}, syncing would send its own writes back to it, exclude it with --exclude {}",
+                quoted(relative),
+                quoted(path),
+                relative
+                    .file_name()
+                    .map_or_else(Default::default, |name| name.to_string_lossy())
+            ),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// The inodes of the canonical `path` and of its ancestors, innermost first.
+fn inode_chain(path: &Path) -> Vec<(u64, u64)> {
+    path.ancestors().map_while(inode).collect()
+}
+
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&byte| byte == 0)?;
+    let name = std::str::from_utf8(&name[..len]).ok()?;
+    (!name.is_empty() && !name.contains(' ')).then(|| name.to_owned())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::core::excludes::Excludes;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_overlapping_directories_are_found() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let (src, out) = (dir.path().join("src"), dir.path().join("src/build/out"));
+        fs::create_dir_all(&out)?;
+        fs::create_dir_all(dir.path().join("src/docs"))?;
+        fs::create_dir_all(dir.path().join("elsewhere"))?;
+
+        let identity = DirIdentity::of(&out).expect("an identity");
+        assert_eq!(
+            DirIdentity::from_header(&identity.to_header()),
+            Some(identity.clone())
+        );
+        let scan = ScanOptions::default();
+        assert_eq!(identity.overlap(&out, scan), Some(Overlap::SourceInside));
+        assert_eq!(
+            identity.overlap(&out.join("."), scan),
+            Some(Overlap::SourceInside)
+        );
+        assert_eq!(
+            identity.overlap(&src, scan),
+            Some(Overlap::DestinationInside("build/out".into()))
+        );
+        assert_eq!(identity.overlap(&dir.path().join("elsewhere"), scan), None);
+
+        // Excluding the output directory, or a directory it is in, breaks the loop.
+        let scan = ScanOptions {
+            excludes: Excludes::leak(&["build".to_owned()])?,
+            ..scan
+        };
+        assert_eq!(identity.overlap(&src, scan), None);
+
+        let other_host = DirIdentity {
+            host: "other-host".into(),
+            ..identity
+        };
+        assert_eq!(other_host.overlap(&out, ScanOptions::default()), None);
+
+        Ok(())
+    }
+}
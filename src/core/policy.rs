@@ -0,0 +1,529 @@
+use std::{
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use bytesize::ByteSize;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use super::{
+    message::{FileChangeMessage, PackedFiles},
+    profile,
+};
+
+/// Extensions of formats that are already compressed, compressing them again is wasted work.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4",
+    "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "pdf", "png", "pptx", "rar", "tgz", "webm", "webp",
+    "whl", "xlsx", "xz", "zip", "zst",
+];
+
+/// Leading bytes of compressed formats, for files whose extension does not tell.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\x1f\x8b",           // gzip
+    b"\x28\xb5\x2f\xfd",   // zstd
+    b"\xfd7zXZ\x00",       // xz
+    b"BZh",                // bzip2
+    b"\x04\x22\x4d\x18",   // lz4
+    b"PK\x03\x04",         // zip, jar, docx...
+    b"7z\xbc\xaf\x27\x1c", // 7z
+    b"Rar!",               // rar
+    b"\x89PNG",            // png
+    b"\xff\xd8\xff",       // jpeg
+    b"GIF8",               // gif
+    b"OggS",               // ogg
+    b"fLaC",               // flac
+];
+
+/// Contents sampled for their entropy are read in windows of this size, spread over the contents.
+const ENTROPY_WINDOW: usize = 4096;
+const ENTROPY_WINDOWS: usize = 16;
+
+/// Samples with more bits of entropy per byte than this would not shrink when compressed.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileClass {
+    Text,
+    Binary,
+    Compressed,
+}
+
+impl FromStr for FileClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(FileClass::Text),
+            "binary" => Ok(FileClass::Binary),
+            "compressed" => Ok(FileClass::Compressed),
+            _ => Err(format!(
+                "unknown file class '{}', expected text, binary or compressed",
+                s
+            )),
+        }
+    }
+}
+
+impl FileClass {
+    /// Classifies a file by its extension first, then by sniffing its contents.
+    pub fn of(path: &Path, contents: &[u8]) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        if extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+            || looks_compressed(contents)
+        {
+            FileClass::Compressed
+        } else if is_binary(contents) {
+            FileClass::Binary
+        } else {
+            FileClass::Text
+        }
+    }
+
+    /// Classifies the file at `path` from its first bytes, for files too large to be read whole.
+    pub fn sniff(path: &Path) -> std::io::Result<Self> {
+        let mut head = Vec::new();
+        std::fs::File::open(path)?
+            .take((ENTROPY_WINDOW * ENTROPY_WINDOWS) as u64)
+            .read_to_end(&mut head)?;
+
+        Ok(Self::of(path, &head))
+    }
+}
+
+/// Guesses whether `contents` are binary the way git does, by looking for a NUL byte close to the
+/// start of the file.
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|&byte| byte == 0)
+}
+
+/// Guesses whether `contents` are already compressed or encrypted, from their leading bytes or
+/// from the entropy of a sample of them, so that compressing them is not even attempted.
+pub fn looks_compressed(contents: &[u8]) -> bool {
+    COMPRESSED_MAGIC
+        .iter()
+        .any(|magic| contents.starts_with(magic))
+        || is_mp4_family(contents)
+        || sampled_entropy(contents).is_some_and(|entropy| entropy > INCOMPRESSIBLE_ENTROPY)
+}
+
+/// MP4, MOV, HEIC and AVIF files start with an `ftyp` box.
+fn is_mp4_family(contents: &[u8]) -> bool {
+    contents.get(4..8) == Some(b"ftyp")
+}
+
+/// Shannon entropy, in bits per byte, of up to `ENTROPY_WINDOWS` windows spread over `contents`.
+/// Contents smaller than a window are too short to tell.
+fn sampled_entropy(contents: &[u8]) -> Option<f64> {
+    if contents.len() < ENTROPY_WINDOW {
+        return None;
+    }
+
+    let windows = ENTROPY_WINDOWS.min(contents.len() / ENTROPY_WINDOW);
+    let stride = (contents.len() - ENTROPY_WINDOW) / windows.max(2).saturating_sub(1);
+    let mut counts = [0u64; 256];
+    for window in 0..windows {
+        let start = window * stride;
+        for &byte in &contents[start..start + ENTROPY_WINDOW] {
+            counts[byte as usize] += 1;
+        }
+    }
+
+    let total = (windows * ENTROPY_WINDOW) as f64;
+    let entropy = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    Some(entropy)
+}
+
+/// How the contents of an edited file are encoded on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Raw,
+    Gzip,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Encoding::Raw),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(format!("unknown encoding '{}', expected raw or gzip", s)),
+        }
+    }
+}
+
+/// What a rule sets for its class of files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Encode(Encoding),
+    /// Files larger than this many bytes are sent in chunks of this size.
+    Chunk(u64),
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("chunk", size)) => match size.parse::<ByteSize>() {
+                Ok(size) if size.as_u64() > 0 => Ok(RuleAction::Chunk(size.as_u64())),
+                _ => Err(format!("invalid chunk size '{}'", size)),
+            },
+            _ => match s.parse() {
+                Ok(encoding) => Ok(RuleAction::Encode(encoding)),
+                Err(_) => Err(format!(
+                    "unknown policy '{}', expected raw, gzip or chunk:<size>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Overrides the policy of one class of files. Parsed from `<class>=<raw|gzip>` for its
+/// encoding, or from `<class>=chunk:<size>` to send its files over `size` in chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyRule {
+    pub class: FileClass,
+    pub action: RuleAction,
+}
+
+impl FromStr for PolicyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((class, action)) = s.split_once('=') else {
+            return Err(format!("expected <class>=<policy>, got '{}'", s));
+        };
+
+        Ok(Self {
+            class: class.parse()?,
+            action: action.parse()?,
+        })
+    }
+}
+
+/// Per-class transfer policy for the contents of edited files, and for directory archives and
+/// batches of small files.
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyTable {
+    pub text: Encoding,
+    pub binary: Encoding,
+    pub compressed: Encoding,
+    pub archives: Encoding,
+    /// Chunk sizes of the classes whose large files are sent in chunks even when they would fit
+    /// in a message, see `RuleAction::Chunk`.
+    pub text_chunks: Option<u64>,
+    pub binary_chunks: Option<u64>,
+    pub compressed_chunks: Option<u64>,
+}
+
+impl Default for PolicyTable {
+    fn default() -> Self {
+        Self {
+            text: Encoding::Gzip,
+            binary: Encoding::Gzip,
+            compressed: Encoding::Raw,
+            archives: Encoding::Gzip,
+            text_chunks: None,
+            binary_chunks: None,
+            compressed_chunks: None,
+        }
+    }
+}
+
+impl PolicyTable {
+    pub fn with(mut self, rule: PolicyRule) -> Self {
+        match rule.action {
+            RuleAction::Encode(encoding) => {
+                *match rule.class {
+                    FileClass::Text => &mut self.text,
+                    FileClass::Binary => &mut self.binary,
+                    FileClass::Compressed => &mut self.compressed,
+                } = encoding
+            }
+            RuleAction::Chunk(size) => {
+                *match rule.class {
+                    FileClass::Text => &mut self.text_chunks,
+                    FileClass::Binary => &mut self.binary_chunks,
+                    FileClass::Compressed => &mut self.compressed_chunks,
+                } = Some(size)
+            }
+        }
+
+        self
+    }
+
+    pub fn encoding(&self, class: FileClass) -> Encoding {
+        match class {
+            FileClass::Text => self.text,
+            FileClass::Binary => self.binary,
+            FileClass::Compressed => self.compressed,
+        }
+    }
+
+    /// The smallest chunk size of any class, files no larger than it are never chunked by policy.
+    pub fn min_chunk_size(&self) -> Option<u64> {
+        [self.text_chunks, self.binary_chunks, self.compressed_chunks]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// The size of the chunks the file at `path`, of `size` bytes, is sent in, if its class is
+    /// chunked and it is larger than the chunks. Files are only read to be classified when they
+    /// are larger than `min_chunk_size`.
+    pub fn chunk_size(&self, path: &Path, size: u64) -> Option<u64> {
+        if self
+            .min_chunk_size()
+            .is_none_or(|chunk_size| size <= chunk_size)
+        {
+            return None;
+        }
+
+        let chunk_size = match FileClass::sniff(path).ok()? {
+            FileClass::Text => self.text_chunks,
+            FileClass::Binary => self.binary_chunks,
+            FileClass::Compressed => self.compressed_chunks,
+        };
+        chunk_size.filter(|&chunk_size| size > chunk_size)
+    }
+
+    /// Encodes the contents of edited files according to their class, and archives unless they
+    /// look incompressible. Contents that gzip would not shrink are sent raw. Other changes are
+    /// returned as is.
+    pub fn encode(&self, change: FileChangeMessage) -> anyhow::Result<FileChangeMessage> {
+        let change = match change {
+            FileChangeMessage::FileEdited(path, contents, mtime) => {
+                let encoding = self.encoding(FileClass::of(&path, &contents));
+                match gzip(&path, &contents, encoding)? {
+                    Some(compressed) => {
+                        FileChangeMessage::GzippedFileEdited(path, compressed, mtime)
+                    }
+                    None => FileChangeMessage::FileEdited(path, contents, mtime),
+                }
+            }
+            FileChangeMessage::DirectoryCreated(path, archive) => {
+                let encoding = archive_encoding(self.archives, &archive);
+                match gzip(&path, &archive, encoding)? {
+                    Some(compressed) => {
+                        FileChangeMessage::GzippedDirectoryCreated(path, compressed)
+                    }
+                    None => FileChangeMessage::DirectoryCreated(path, archive),
+                }
+            }
+            FileChangeMessage::FileBatch(path, packed) if !packed.gzip => {
+                let encoding = archive_encoding(self.archives, &packed.archive);
+                let packed = match gzip(&path, &packed.archive, encoding)? {
+                    Some(archive) => PackedFiles {
+                        gzip: true,
+                        archive,
+                        ..packed
+                    },
+                    None => packed,
+                };
+                FileChangeMessage::FileBatch(path, packed)
+            }
+            FileChangeMessage::Checksummed(change, sha1) => {
+                FileChangeMessage::Checksummed(Box::new(self.encode(*change)?), sha1)
+            }
+            change => change,
+        };
+
+        Ok(change)
+    }
+}
+
+fn archive_encoding(encoding: Encoding, archive: &[u8]) -> Encoding {
+    match looks_compressed(archive) {
+        true => Encoding::Raw,
+        false => encoding,
+    }
+}
+
+/// The gzipped `contents` with `encoding` gzip, unless compressing did not make them smaller.
+fn gzip(path: &Path, contents: &[u8], encoding: Encoding) -> anyhow::Result<Option<Bytes>> {
+    if encoding == Encoding::Raw {
+        return Ok(None);
+    }
+
+    let _span = profile::span("compress", Some(path));
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(contents)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() < contents.len()).then(|| Bytes::from(compressed)))
+}
+
+/// The size of the contents of a gzip stream, as recorded in its trailer. Gzip only keeps it
+/// modulo 2^32, so it is off for contents of 4 GiB and more.
+pub fn decoded_len(compressed: &[u8]) -> u64 {
+    match compressed.len().checked_sub(4) {
+        Some(start) => {
+            u32::from_le_bytes(compressed[start..].try_into().unwrap_or_default()) as u64
+        }
+        None => 0,
+    }
+}
+
+/// Reverts `PolicyTable::encode`, so that edited files carry their plain contents and archives
+/// are plain tar archives.
+pub fn decode(change: FileChangeMessage) -> anyhow::Result<FileChangeMessage> {
+    let change = match change {
+        FileChangeMessage::GzippedFileEdited(path, compressed, mtime) => {
+            FileChangeMessage::FileEdited(path, Bytes::from(decompress(&compressed)?), mtime)
+        }
+        FileChangeMessage::GzippedDirectoryCreated(path, compressed) => {
+            FileChangeMessage::DirectoryCreated(path, Bytes::from(decompress(&compressed)?))
+        }
+        FileChangeMessage::FileBatch(path, packed) if packed.gzip => {
+            let archive = Bytes::from(decompress(&packed.archive)?);
+            FileChangeMessage::FileBatch(
+                path,
+                PackedFiles {
+                    gzip: false,
+                    archive,
+                    ..packed
+                },
+            )
+        }
+        FileChangeMessage::Checksummed(change, sha1) => {
+            FileChangeMessage::Checksummed(Box::new(decode(*change)?), sha1)
+        }
+        change => change,
+    };
+
+    Ok(change)
+}
+
+pub fn decompress(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    GzDecoder::new(compressed).read_to_end(&mut contents)?;
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_classification() {
+        assert_eq!(
+            FileClass::of(Path::new("a.rs"), b"fn main() {}"),
+            FileClass::Text
+        );
+        assert_eq!(
+            FileClass::of(Path::new("a.bin"), b"\x7fELF\0\0"),
+            FileClass::Binary
+        );
+        assert_eq!(
+            FileClass::of(Path::new("a.PNG"), b"\x89PNG"),
+            FileClass::Compressed
+        );
+        assert_eq!(
+            FileClass::of(Path::new("a.zip"), b"text"),
+            FileClass::Compressed
+        );
+    }
+
+    #[test]
+    fn test_policy_table_round_trip() -> anyhow::Result<()> {
+        let table = PolicyTable::default().with("binary=raw".parse().unwrap());
+        assert!("text=zstd".parse::<PolicyRule>().is_err());
+        assert!("binary=chunk:0".parse::<PolicyRule>().is_err());
+        let chunked = table.with("binary=chunk:4KiB".parse().unwrap());
+        assert_eq!(chunked.binary_chunks, Some(4096));
+        assert_eq!(chunked.min_chunk_size(), Some(4096));
+        assert_eq!(chunked.binary, Encoding::Raw);
+
+        let text = Bytes::from("line\n".repeat(1000));
+        let encoded = table.encode(FileChangeMessage::FileEdited(
+            "a.txt".into(),
+            text.clone(),
+            SystemTime::UNIX_EPOCH,
+        ))?;
+        let FileChangeMessage::GzippedFileEdited(_, compressed, _) = &encoded else {
+            panic!("expected gzipped contents, got {:?}", encoded);
+        };
+        assert!(compressed.len() < text.len());
+        assert!(matches!(
+            decode(encoded)?,
+            FileChangeMessage::FileEdited(_, contents, _) if contents == text
+        ));
+
+        let binary = Bytes::from_static(b"\0\x01\x02");
+        assert!(matches!(
+            table.encode(FileChangeMessage::FileEdited(
+                "a.bin".into(),
+                binary,
+                SystemTime::UNIX_EPOCH
+            ))?,
+            FileChangeMessage::FileEdited(..)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_incompressible_contents_are_sent_raw() -> anyhow::Result<()> {
+        assert!(looks_compressed(b"\x1f\x8b\x08\0"));
+        assert!(looks_compressed(b"\0\0\0\x18ftypmp42"));
+        assert!(!looks_compressed(&b"line\n".repeat(2000)));
+        let noise: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert!(looks_compressed(&noise));
+
+        let table = PolicyTable::default();
+        let encoded = table.encode(FileChangeMessage::FileEdited(
+            "noise.bin".into(),
+            Bytes::from(noise.clone()),
+            SystemTime::UNIX_EPOCH,
+        ))?;
+        assert!(matches!(encoded, FileChangeMessage::FileEdited(..)));
+        let encoded = table.encode(FileChangeMessage::DirectoryCreated(
+            "dir".into(),
+            Bytes::from(noise),
+        ))?;
+        assert!(matches!(encoded, FileChangeMessage::DirectoryCreated(..)));
+
+        // Tiny contents that gzip would only grow are sent raw too.
+        let encoded = table.encode(FileChangeMessage::FileEdited(
+            "a.txt".into(),
+            Bytes::from("a"),
+            SystemTime::UNIX_EPOCH,
+        ))?;
+        assert!(matches!(encoded, FileChangeMessage::FileEdited(..)));
+
+        let archive = Bytes::from("entry\0".repeat(1000));
+        let encoded = table.encode(FileChangeMessage::DirectoryCreated(
+            "dir".into(),
+            archive.clone(),
+        ))?;
+        assert!(matches!(
+            encoded,
+            FileChangeMessage::GzippedDirectoryCreated(_, ref compressed) if compressed.len() < archive.len()
+        ));
+        assert!(matches!(
+            decode(encoded)?,
+            FileChangeMessage::DirectoryCreated(_, contents) if contents == archive
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use super::utils::quoted;
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+/// Timed spans of the sync phases, written as a chrome-tracing trace that Perfetto or
+/// `chrome://tracing` can open. Recording is off until `enable` is called, spans being no-ops.
+struct Profile {
+    start: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    events: Vec<Event>,
+    /// Whether each lane has a span running. Tasks move between threads, so spans are laid out
+    /// on the first free lane instead, which keeps spans of a lane from overlapping.
+    lanes: Vec<bool>,
+}
+
+impl State {
+    fn take_lane(&mut self) -> usize {
+        let lane = match self.lanes.iter().position(|busy| !busy) {
+            Some(lane) => lane,
+            None => {
+                self.lanes.push(false);
+                self.lanes.len() - 1
+            }
+        };
+        self.lanes[lane] = true;
+        lane
+    }
+}
+
+#[derive(Serialize)]
+struct Event {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    ts: f64,
+    dur: f64,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Args>,
+}
+
+#[derive(Serialize)]
+struct Args {
+    path: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events: &'a [Event],
+    display_time_unit: &'static str,
+}
+
+/// Starts recording spans for the rest of the process.
+pub fn enable() {
+    PROFILE.get_or_init(|| Profile {
+        start: Instant::now(),
+        state: Default::default(),
+    });
+}
+
+/// Times a phase until the returned span is dropped. `path`, when the phase works on one, shows
+/// up in the span's arguments.
+pub fn span(name: &'static str, path: Option<&Path>) -> Span {
+    let Some(profile) = PROFILE.get() else {
+        return Span(None);
+    };
+
+    Span(Some(Running {
+        name,
+        path: path.map(|path| path.to_string_lossy().into_owned()),
+        lane: profile.state.lock().unwrap().take_lane(),
+        start: Instant::now(),
+    }))
+}
+
+/// Writes the spans recorded so far to `path`, if recording was enabled.
+pub fn write(path: &Path) -> anyhow::Result<()> {
+    let Some(profile) = PROFILE.get() else {
+        return Ok(());
+    };
+
+    let state = profile.state.lock().unwrap();
+    let trace = Trace {
+        trace_events: &state.events,
+        display_time_unit: "ms",
+    };
+    let file = std::fs::File::create(path).with_context(|| format!("creating {}", quoted(path)))?;
+    serde_json::to_writer(std::io::BufWriter::new(file), &trace)
+        .with_context(|| format!("writing {}", quoted(path)))?;
+
+    Ok(())
+}
+
+pub struct Span(Option<Running>);
+
+struct Running {
+    name: &'static str,
+    path: Option<String>,
+    lane: usize,
+    start: Instant,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let (Some(running), Some(profile)) = (self.0.take(), PROFILE.get()) else {
+            return;
+        };
+
+        let micros = |duration: Duration| duration.as_secs_f64() * 1e6;
+        let mut state = profile.state.lock().unwrap();
+        state.lanes[running.lane] = false;
+        state.events.push(Event {
+            name: running.name,
+            cat: "sync",
+            ph: "X",
+            ts: micros(running.start - profile.start),
+            dur: micros(running.start.elapsed()),
+            pid: std::process::id(),
+            tid: running.lane,
+            args: running.path.map(|path| Args { path }),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlapping_spans_get_their_own_lanes() {
+        let mut state = State::default();
+        let scan = state.take_lane();
+        let hash = state.take_lane();
+        assert_ne!(scan, hash);
+
+        state.lanes[hash] = false;
+        assert_eq!(state.take_lane(), hash);
+        state.lanes[scan] = false;
+        assert_eq!(state.take_lane(), scan);
+        assert_eq!(state.lanes.len(), 2);
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Appends `record` to `buf`, length-prefixed. Journals write their records at once, so that a
+/// record cut short by a crash is the last one and is left out by `read_records`.
+pub fn push_record<T: Serialize>(buf: &mut Vec<u8>, record: &T) -> bincode::Result<()> {
+    let encoded = bincode::serialize(record)?;
+    buf.extend((encoded.len() as u64).to_be_bytes());
+    buf.extend(encoded);
+    Ok(())
+}
+
+/// The records written with `push_record`, up to the first one cut short or unreadable.
+pub fn read_records<T: DeserializeOwned>(mut contents: &[u8]) -> Vec<T> {
+    let mut records = vec![];
+    while let Some((len, rest)) = contents.split_first_chunk::<8>() {
+        let len = u64::from_be_bytes(*len) as usize;
+        let Some(record) = rest
+            .get(..len)
+            .and_then(|encoded| bincode::deserialize(encoded).ok())
+        else {
+            break;
+        };
+        records.push(record);
+        contents = &rest[len..];
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_cut_short_are_left_out() -> anyhow::Result<()> {
+        let mut buf = vec![];
+        push_record(&mut buf, &(1u32, "a".to_string()))?;
+        push_record(&mut buf, &(2u32, "b".to_string()))?;
+        let records: Vec<(u32, String)> = read_records(&buf);
+        assert_eq!(records, [(1, "a".into()), (2, "b".into())]);
+
+        let records: Vec<(u32, String)> = read_records(&buf[..buf.len() - 1]);
+        assert_eq!(records, [(1, "a".into())]);
+
+        Ok(())
+    }
+}
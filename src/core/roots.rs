@@ -0,0 +1,359 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use super::{
+    file_tree::{FileTree, ScanOptions},
+    utils::quoted,
+};
+
+/// A local directory synced under `dest`, relative to the receiver's output directory. An empty
+/// `dest` maps the directory onto the output directory itself.
+#[derive(Debug, Clone)]
+pub struct SourceRoot {
+    pub path: PathBuf,
+    pub dest: PathBuf,
+}
+
+impl SourceRoot {
+    /// Syncs `path` under a subdirectory of the output directory named after it.
+    pub fn named_after(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let dest = std::path::absolute(&path)?
+            .components()
+            .next_back()
+            .and_then(|name| match name {
+                Component::Normal(name) => Some(PathBuf::from(name)),
+                _ => None,
+            })
+            .with_context(|| format!("{} has no name to sync it under", quoted(&path)))?;
+
+        Ok(Self { path, dest })
+    }
+}
+
+impl FromStr for SourceRoot {
+    type Err = String;
+
+    /// Parses `<local>:<remote>`, splitting at the last colon so that Windows drive letters work.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, dest) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <local>:<remote>, got {}", s))?;
+        if path.is_empty() {
+            return Err(format!("missing local directory in {}", s));
+        }
+
+        Ok(Self {
+            path: path.into(),
+            dest: dest.into(),
+        })
+    }
+}
+
+/// Roots can be single files, synced like a directory's only entry.
+async fn scan(root: &SourceRoot, options: ScanOptions) -> anyhow::Result<FileTree> {
+    match root.path.is_file() {
+        true => FileTree::new_file_with(&root.path, options).await,
+        false => FileTree::new_with(&root.path, options).await,
+    }
+}
+
+/// Whether `path` stays inside the directory it is joined to.
+fn is_plain(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn validate_dest(dest: &Path) -> anyhow::Result<()> {
+    if !is_plain(dest) {
+        bail!(
+            "destination {} must be a relative path without `..`",
+            quoted(dest)
+        )
+    }
+
+    Ok(())
+}
+
+/// The directories making up one synced tree, each mounted under its own destination. Paths in
+/// messages and trees are relative to the mounted tree, and `resolve` maps them back to local
+/// paths.
+#[derive(Debug, Clone)]
+pub struct Roots {
+    roots: Vec<SourceRoot>,
+}
+
+impl Roots {
+    pub fn new(roots: Vec<SourceRoot>) -> anyhow::Result<Self> {
+        if roots.is_empty() {
+            bail!("at least one directory is required")
+        }
+
+        for (i, root) in roots.iter().enumerate() {
+            validate_dest(&root.dest)?;
+            if root.dest.as_os_str().is_empty() && root.path.is_file() {
+                bail!(
+                    "{} is a file, it needs a name in the output directory",
+                    quoted(&root.path)
+                )
+            }
+
+            if let Some(other) = roots[..i].iter().find(|other| {
+                root.dest.starts_with(&other.dest) || other.dest.starts_with(&root.dest)
+            }) {
+                bail!(
+                    "{} and {} overlap on the receiver, as {} and {}",
+                    quoted(&other.path),
+                    quoted(&root.path),
+                    quoted(&other.dest),
+                    quoted(&root.dest)
+                )
+            }
+        }
+
+        Ok(Self { roots })
+    }
+
+    /// A single directory mapped onto the whole output directory.
+    pub fn single(path: impl Into<PathBuf>) -> Self {
+        Self {
+            roots: vec![SourceRoot {
+                path: path.into(),
+                dest: PathBuf::new(),
+            }],
+        }
+    }
+
+    /// The receiver's side of a layout: each destination below `base`.
+    pub fn under(base: &Path, dests: Vec<PathBuf>) -> anyhow::Result<Self> {
+        Self::new(
+            dests
+                .into_iter()
+                .map(|dest| SourceRoot {
+                    path: base.join(&dest),
+                    dest,
+                })
+                .collect(),
+        )
+    }
+
+    /// Moves every root under `prefix`.
+    pub fn prefixed(self, prefix: &Path) -> anyhow::Result<Self> {
+        let roots = self
+            .roots
+            .into_iter()
+            .map(|root| SourceRoot {
+                dest: match root.dest.as_os_str().is_empty() {
+                    true => prefix.to_owned(),
+                    false => prefix.join(root.dest),
+                },
+                ..root
+            })
+            .collect();
+
+        Self::new(roots)
+    }
+
+    pub fn dests(&self) -> Vec<PathBuf> {
+        self.roots.iter().map(|root| root.dest.clone()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SourceRoot> {
+        self.roots.iter()
+    }
+
+    /// The local path of a path in the mounted tree, if it falls under one of the roots.
+    pub fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        self.roots.iter().find_map(|root| {
+            let relative = path.strip_prefix(&root.dest).ok()?;
+            Some(match relative.as_os_str().is_empty() {
+                true => root.path.clone(),
+                false => root.path.join(relative),
+            })
+        })
+    }
+
+    /// Whether `path` falls under one of the roots without escaping it through `..` or by being
+    /// absolute, so that a peer cannot touch anything else.
+    pub fn contains(&self, path: &Path) -> bool {
+        is_plain(path) && self.resolve(path).is_some()
+    }
+
+    /// Creates the directories containing the roots, so that the roots themselves can be
+    /// created like any other entry, be they files or directories.
+    pub async fn create_parents(&self) -> anyhow::Result<()> {
+        for root in self.roots.iter() {
+            let Some(parent) = root.path.parent() else {
+                continue;
+            };
+
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("creating {}", quoted(parent)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Scans every root. Missing roots are left out, unless the root is the whole tree.
+    pub async fn tree(&self, options: ScanOptions) -> anyhow::Result<FileTree> {
+        let mut trees = vec![];
+        for root in self.roots.iter() {
+            let is_mounted = !root.dest.as_os_str().is_empty();
+            if is_mounted && !root.path.try_exists().is_ok_and(|exists| exists) {
+                continue;
+            }
+
+            trees.push(scan(root, options).await?.rebased(&root.dest));
+        }
+
+        Ok(FileTree::merged(trees))
+    }
+
+    /// Scans the part of the mounted tree below `subtree`, which may span several roots.
+    pub async fn subtree(&self, subtree: &Path, options: ScanOptions) -> anyhow::Result<FileTree> {
+        let mut trees = vec![];
+        for root in self.roots.iter() {
+            let tree = if root.path.is_file() {
+                match subtree.starts_with(&root.dest) || root.dest.starts_with(subtree) {
+                    true => FileTree::new_file_with(&root.path, options).await?,
+                    false => continue,
+                }
+            } else if let Ok(relative) = subtree.strip_prefix(&root.dest) {
+                FileTree::new_subtree_with(&root.path, relative, options).await?
+            } else if root.dest.starts_with(subtree) && root.path.is_dir() {
+                FileTree::new_with(&root.path, options).await?
+            } else {
+                continue;
+            };
+
+            trees.push(tree.rebased(&root.dest));
+        }
+
+        Ok(FileTree::merged(trees))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::file_tree::FileTreeNodeType;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    fn root(path: &Path, dest: &str) -> SourceRoot {
+        SourceRoot {
+            path: path.to_owned(),
+            dest: dest.into(),
+        }
+    }
+
+    #[test]
+    async fn test_roots_are_mounted_under_their_dests() -> anyhow::Result<()> {
+        let (src, config) = (TempDir::new()?, TempDir::new()?);
+        fs::create_dir(src.path().join("nested"))?;
+        fs::write(src.path().join("nested/main.rs"), "fn main() {}")?;
+        fs::write(config.path().join("app.toml"), "debug = true")?;
+
+        let roots = Roots::new(vec![
+            root(src.path(), "code/src"),
+            root(config.path(), "config"),
+        ])?;
+        let tree = roots.tree(ScanOptions::default()).await?;
+        let paths: Vec<_> = tree.iter().map(|node| node.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("code/src"),
+                Path::new("code/src/nested"),
+                Path::new("code/src/nested/main.rs"),
+                Path::new("config"),
+                Path::new("config/app.toml"),
+            ]
+        );
+
+        let subtree = roots
+            .subtree(Path::new("code"), ScanOptions::default())
+            .await?;
+        assert_eq!(subtree.len(), 3);
+        let subtree = roots
+            .subtree(Path::new("code/src/nested"), ScanOptions::default())
+            .await?;
+        assert_eq!(subtree.len(), 2);
+
+        assert_eq!(
+            roots.resolve(Path::new("config/app.toml")),
+            Some(config.path().join("app.toml"))
+        );
+        assert_eq!(
+            roots.resolve(Path::new("code/src")),
+            Some(src.path().to_owned())
+        );
+        assert_eq!(roots.resolve(Path::new("other")), None);
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_file_roots() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let db = dir.path().join("app.db");
+        fs::write(&db, "data")?;
+
+        let roots = Roots::new(vec![SourceRoot::named_after(&db)?])?;
+        let tree = roots.tree(ScanOptions::default()).await?;
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].path, Path::new("app.db"));
+        assert!(matches!(
+            tree[0].typ,
+            FileTreeNodeType::File { size: 4, .. }
+        ));
+
+        let subtree = roots
+            .subtree(Path::new("app.db"), ScanOptions::default())
+            .await?;
+        assert_eq!(subtree.len(), 1);
+        assert_eq!(roots.resolve(Path::new("app.db")), Some(db.clone()));
+        assert!(Roots::new(vec![root(&db, "")]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_invalid_layouts() {
+        let dir = Path::new("dir");
+        assert!(Roots::new(vec![]).is_err());
+        assert!(Roots::new(vec![root(dir, "a"), root(dir, "a/b")]).is_err());
+        assert!(Roots::new(vec![root(dir, ""), root(dir, "a")]).is_err());
+        assert!(Roots::new(vec![root(dir, "../a")]).is_err());
+        assert!(Roots::new(vec![root(dir, "/a")]).is_err());
+        assert!(Roots::new(vec![root(dir, "a"), root(dir, "ab")]).is_ok());
+    }
+
+    #[test]
+    async fn test_prefixed_roots_contain_only_their_dests() -> anyhow::Result<()> {
+        let dir = Path::new("dir");
+        let roots = Roots::single(dir).prefixed(Path::new("deploy/current"))?;
+        assert_eq!(roots.dests(), vec![PathBuf::from("deploy/current")]);
+        assert!(roots.contains(Path::new("deploy/current")));
+        assert!(roots.contains(Path::new("deploy/current/app/main.rs")));
+        assert!(!roots.contains(Path::new("deploy")));
+        assert!(!roots.contains(Path::new("deploy/current/../../etc/passwd")));
+        assert!(!roots.contains(Path::new("/deploy/current")));
+
+        let roots =
+            Roots::new(vec![root(dir, "src"), root(dir, "config")])?.prefixed(Path::new("app"))?;
+        assert_eq!(
+            roots.dests(),
+            vec![PathBuf::from("app/src"), PathBuf::from("app/config")]
+        );
+        assert!(Roots::single(dir).prefixed(Path::new("../up")).is_err());
+
+        Ok(())
+    }
+}
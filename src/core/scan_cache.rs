@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    file_tree::{linked_inode, ScanOptions},
+    specials::{special_kind, SpecialKind},
+};
+
+/// How long a directory or file must have been left alone before its listing or hash is
+/// remembered. Changes made within the same mtime tick as the scan would otherwise go unnoticed.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Directory listings and file hashes kept between scans of `ScanOptions::trust_dir_mtime`
+/// trees, by directory. A directory whose mtime is unchanged is assumed to have the same
+/// entries, so it is not listed again, and its files are only hashed again when their own size
+/// or mtime changed. Filesystems that do not update directory mtimes on every entry change, like
+/// some network or FUSE ones, make scans miss created and deleted entries.
+static LISTINGS: OnceLock<Mutex<HashMap<PathBuf, Listing>>> = OnceLock::new();
+
+struct Listing {
+    mtime: SystemTime,
+    /// Sorted by name.
+    entries: Vec<Entry>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    name: OsString,
+    kind: Kind,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Dir,
+    File {
+        size: u64,
+        mtime: SystemTime,
+        sha1: Option<[u8; 20]>,
+    },
+    Special(SpecialKind),
+}
+
+/// An entry found by `walk`.
+pub enum Found {
+    Dir(PathBuf),
+    /// `sha1` is known when the file has not changed since it was last hashed. `inode` is set
+    /// for files with other hard links, see `linked_inode`.
+    File {
+        path: PathBuf,
+        size: u64,
+        mtime: SystemTime,
+        sha1: Option<[u8; 20]>,
+        inode: Option<(u64, u64)>,
+    },
+    Special {
+        path: PathBuf,
+        kind: SpecialKind,
+    },
+}
+
+impl Found {
+    pub fn path(&self) -> &Path {
+        match self {
+            Found::Dir(path) | Found::File { path, .. } | Found::Special { path, .. } => path,
+        }
+    }
+}
+
+fn listings() -> &'static Mutex<HashMap<PathBuf, Listing>> {
+    LISTINGS.get_or_init(Default::default)
+}
+
+fn settled(mtime: SystemTime) -> bool {
+    mtime.elapsed().is_ok_and(|age| age >= SETTLE)
+}
+
+/// Every entry below `start_path` and `start_path` itself, in the order of a `WalkDir` sorted by
+/// path, listing only the directories that changed since the last walk.
+pub fn walk(start_path: &Path, options: ScanOptions) -> Vec<Found> {
+    let mut found = vec![];
+    if !options.excludes(start_path) {
+        found.push(Found::Dir(start_path.to_owned()));
+        visit(start_path, options, &mut found);
+    }
+
+    found
+}
+
+fn visit(dir: &Path, options: ScanOptions, found: &mut Vec<Found>) {
+    let Ok(meta) = fs::symlink_metadata(dir) else {
+        return;
+    };
+    // Like `WalkDir`, symlinks to directories are not followed.
+    if !meta.is_dir() {
+        return;
+    }
+
+    let mtime = meta.modified().ok();
+    let entries = {
+        let mut listings = listings().lock().unwrap();
+        let previous = listings.get(dir);
+        match (previous, mtime) {
+            (Some(previous), Some(mtime)) if previous.mtime == mtime => previous.entries.clone(),
+            _ => {
+                let entries = list(dir, previous);
+                match mtime {
+                    Some(mtime) if settled(mtime) => {
+                        let listing = Listing {
+                            mtime,
+                            entries: entries.clone(),
+                        };
+                        listings.insert(dir.to_owned(), listing);
+                    }
+                    _ => {
+                        listings.remove(dir);
+                    }
+                }
+                entries
+            }
+        }
+    };
+
+    for entry in entries {
+        let path = dir.join(&entry.name);
+        if options.excludes(&path) {
+            continue;
+        }
+
+        match entry.kind {
+            Kind::Dir => {
+                found.push(Found::Dir(path.clone()));
+                visit(&path, options, found);
+            }
+            Kind::File { size, mtime, sha1 } => {
+                // The listing may be trusted, the files in it are still checked for edits.
+                let Ok(meta) = fs::metadata(&path) else {
+                    continue;
+                };
+                let Ok(modified) = meta.modified() else {
+                    continue;
+                };
+                let unchanged = meta.len() == size && modified == mtime;
+                found.push(Found::File {
+                    path,
+                    size: meta.len(),
+                    mtime: modified,
+                    sha1: sha1.filter(|_| unchanged),
+                    inode: linked_inode(&meta),
+                });
+            }
+            Kind::Special(kind) => found.push(Found::Special { path, kind }),
+        }
+    }
+}
+
+/// Lists `dir`, keeping the hashes `previous` had for unchanged files.
+fn list(dir: &Path, previous: Option<&Listing>) -> Vec<Entry> {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut entries = vec![];
+    for entry in read_dir.filter_map(|entry| entry.ok()) {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+
+        let name = entry.file_name();
+        let kind = match (meta.is_file(), meta.modified()) {
+            (true, Ok(mtime)) => {
+                let size = meta.len();
+                let sha1 = previous
+                    .and_then(|previous| find(&previous.entries, &name))
+                    .and_then(|previous| match previous.kind {
+                        Kind::File {
+                            size: previous_size,
+                            mtime: previous_mtime,
+                            sha1,
+                        } if previous_size == size && previous_mtime == mtime => sha1,
+                        _ => None,
+                    });
+                Kind::File { size, mtime, sha1 }
+            }
+            (true, Err(_)) => continue,
+            (false, _) => match special_kind(meta.file_type()) {
+                Some(kind) => Kind::Special(kind),
+                None => Kind::Dir,
+            },
+        };
+        entries.push(Entry { name, kind });
+    }
+    entries.sort_by(|entry1, entry2| entry1.name.cmp(&entry2.name));
+
+    entries
+}
+
+fn find<'a>(entries: &'a [Entry], name: &OsString) -> Option<&'a Entry> {
+    entries
+        .binary_search_by(|entry| entry.name.cmp(name))
+        .ok()
+        .map(|i| &entries[i])
+}
+
+/// Remembers the hash of the file at `path`, as it was when `walk` found it.
+pub fn remember(path: &Path, size: u64, mtime: SystemTime, sha1: [u8; 20]) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    if !settled(mtime) {
+        return;
+    }
+
+    let mut listings = listings().lock().unwrap();
+    let Some(listing) = listings.get_mut(dir) else {
+        return;
+    };
+    let Ok(i) = listing
+        .entries
+        .binary_search_by(|entry| entry.name.as_os_str().cmp(name))
+    else {
+        return;
+    };
+
+    if let Kind::File {
+        size: listed_size,
+        mtime: listed_mtime,
+        sha1: listed_sha1,
+    } = &mut listing.entries[i].kind
+    {
+        if *listed_size == size && *listed_mtime == mtime {
+            *listed_sha1 = Some(sha1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn names(found: &[Found]) -> Vec<&Path> {
+        found.iter().skip(1).map(Found::path).collect()
+    }
+
+    #[test]
+    fn test_directories_are_listed_again_once_their_mtime_changes() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        let dir = root.path();
+        fs::write(dir.join("a.txt"), "a")?;
+        let set_mtime = |ago: u64| {
+            fs::File::open(dir)?.set_modified(SystemTime::now() - Duration::from_secs(ago))
+        };
+        set_mtime(60)?;
+        let mtime = fs::metadata(dir)?.modified()?;
+        let options = ScanOptions {
+            trust_dir_mtime: true,
+            ..Default::default()
+        };
+        assert_eq!(names(&walk(dir, options)), [dir.join("a.txt")]);
+
+        // An entry created behind the directory's mtime is not seen, the listing being trusted.
+        fs::write(dir.join("b.txt"), "b")?;
+        fs::File::open(dir)?.set_modified(mtime)?;
+        assert_eq!(names(&walk(dir, options)), [dir.join("a.txt")]);
+
+        set_mtime(30)?;
+        assert_eq!(
+            names(&walk(dir, options)),
+            [dir.join("a.txt"), dir.join("b.txt")]
+        );
+
+        Ok(())
+    }
+}
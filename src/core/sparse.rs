@@ -0,0 +1,100 @@
+use std::{fs::Metadata, ops::Range, path::Path};
+
+use super::{file_tree::ScanOptions, walk::walk};
+
+/// Whether the file takes less room on disk than its size, i.e. has holes.
+#[cfg(unix)]
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Whether a file below `dir` that `scan` does not leave out is sparse.
+pub fn holds_sparse_file(dir: &Path, scan: ScanOptions) -> bool {
+    walk(dir, scan)
+        .into_iter()
+        .filter_entry(|entry| !scan.excludes(entry.path()))
+        .filter_map(Result::ok)
+        .any(|entry| entry.metadata().is_ok_and(|metadata| is_sparse(&metadata)))
+}
+
+/// The ranges of a sparse file holding data, in order, everything else being holes. `None` when
+/// the file is not sparse, or the platform cannot tell where its holes are.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_regions(path: &Path) -> Option<Vec<Range<u64>>> {
+    use std::os::fd::AsRawFd;
+
+    let file = std::fs::File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    if !is_sparse(&metadata) {
+        return None;
+    }
+
+    let size = metadata.len() as libc::off_t;
+    let fd = file.as_raw_fd();
+    let mut regions = vec![];
+    let mut offset = 0;
+    while offset < size {
+        // Fails with ENXIO past the last data region.
+        let start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if start < 0 {
+            break;
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return None;
+        }
+
+        let end = end.min(size);
+        regions.push(start as u64..end as u64);
+        offset = end;
+    }
+
+    Some(regions)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_regions(_path: &Path) -> Option<Vec<Range<u64>>> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::{Seek, SeekFrom, Write},
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_data_regions_skip_holes() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("disk.img");
+        let mut file = fs::File::create(&path)?;
+        file.write_all(&[1; 4096])?;
+        file.seek(SeekFrom::Start(1 << 20))?;
+        file.write_all(&[2; 4096])?;
+        file.set_len(4 << 20)?;
+        drop(file);
+
+        // Filesystems without holes store every byte.
+        if !is_sparse(&fs::metadata(&path)?) {
+            assert!(data_regions(&path).is_none());
+            return Ok(());
+        }
+        assert!(holds_sparse_file(dir.path(), ScanOptions::default()));
+        let regions = data_regions(&path).expect("a sparse file");
+        assert_eq!(regions.first().map(|region| region.start), Some(0));
+        assert!(regions.iter().any(|region| region.contains(&(1 << 20))));
+        assert!(regions.iter().all(|region| region.end <= 2 << 20));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    fmt::Display,
+    fs::FileType,
+    path::{Path, PathBuf},
+};
+
+use super::{file_tree::ScanOptions, utils::quoted, walk::walk};
+use serde::Serialize;
+
+/// A file with no contents to send: reading a FIFO blocks until something writes to it, and
+/// sockets and devices cannot be read as files at all. Special files are left out of trees and
+/// transfers, except FIFOs with `ScanOptions::fifos`, which receivers recreate empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl Display for SpecialKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SpecialKind::Fifo => "FIFO",
+            SpecialKind::Socket => "socket",
+            SpecialKind::BlockDevice => "block device",
+            SpecialKind::CharDevice => "character device",
+        })
+    }
+}
+
+/// What kind of special file an entry of this type is, `None` for regular files, directories and
+/// symlinks. Only Unix has special files.
+#[cfg(unix)]
+pub fn special_kind(file_type: FileType) -> Option<SpecialKind> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    } else if file_type.is_block_device() {
+        Some(SpecialKind::BlockDevice)
+    } else if file_type.is_char_device() {
+        Some(SpecialKind::CharDevice)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn special_kind(_file_type: FileType) -> Option<SpecialKind> {
+    None
+}
+
+/// A special file left out of a tree or a transfer.
+#[derive(Debug, Clone)]
+pub struct SkippedSpecial {
+    pub path: PathBuf,
+    pub kind: SpecialKind,
+}
+
+impl Display for SkippedSpecial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", quoted(&self.path), self.kind)
+    }
+}
+
+impl std::error::Error for SkippedSpecial {}
+
+/// Whether a FIFO is below `dir`, leaving out what `scan` excludes.
+pub fn holds_fifo(dir: &Path, scan: ScanOptions) -> bool {
+    walk(dir, scan)
+        .into_iter()
+        .filter_entry(|entry| !scan.excludes(entry.path()))
+        .filter_map(Result::ok)
+        .any(|entry| special_kind(entry.file_type()) == Some(SpecialKind::Fifo))
+}
+
+/// Creates a FIFO at `path`, readable and writable by its owner and readable by everyone else.
+#[cfg(unix)]
+pub fn create_fifo(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mkfifo(path.as_ptr(), 0o644) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn create_fifo(_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "FIFOs cannot be created on this platform",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_fifos_are_special() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("file.txt"), "file")?;
+        assert!(!holds_fifo(dir.path(), ScanOptions::default()));
+
+        create_fifo(&dir.path().join("events"))?;
+        let file_type = fs::symlink_metadata(dir.path().join("events"))?.file_type();
+        assert_eq!(special_kind(file_type), Some(SpecialKind::Fifo));
+        let file_type = fs::symlink_metadata(dir.path().join("file.txt"))?.file_type();
+        assert_eq!(special_kind(file_type), None);
+        assert!(holds_fifo(dir.path(), ScanOptions::default()));
+
+        Ok(())
+    }
+}
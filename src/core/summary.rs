@@ -0,0 +1,221 @@
+use std::{fmt::Display, time::Duration};
+
+use bytesize::ByteSize;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use super::{file_tree_diff::TreeDiff, message::FileChangeMessage, policy};
+
+/// Totals of an initial sync, printed by both ends once it is done.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct TransferSummary {
+    /// Only the receiver knows which of the files it had already, these are `None` for senders.
+    pub files_created: Option<usize>,
+    pub files_edited: Option<usize>,
+    pub files_deleted: Option<usize>,
+    pub dirs_deleted: Option<usize>,
+    /// Directories moved by the receiver to where the sender has the same contents.
+    pub dirs_renamed: Option<usize>,
+    /// Files sent on their own, not as part of a directory.
+    pub files_transferred: u64,
+    /// Directories sent as a whole, empty or as an archive.
+    pub dirs_transferred: u64,
+    /// Files copied by the receiver from identical ones, instead of being sent.
+    pub files_deduplicated: u64,
+    /// Files hard linked by the receiver to another one, instead of being sent.
+    pub files_linked: u64,
+    /// Contents of the files and directory archives, uncompressed.
+    pub bytes: u64,
+    /// The same contents as they went over the connection.
+    pub compressed_bytes: u64,
+    pub elapsed_secs: f64,
+    /// Uncompressed bytes per second.
+    pub throughput: f64,
+}
+
+/// Counts the changes of an initial sync as they are sent or received.
+pub struct Transfer {
+    started: Instant,
+    summary: TransferSummary,
+}
+
+impl Transfer {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            summary: TransferSummary::default(),
+        }
+    }
+
+    /// Counts the entries the receiver's diff creates, edits, renames and deletes, adding up the
+    /// diffs of a tree received in pages.
+    pub fn diffed(&mut self, diff: &TreeDiff) {
+        let summary = &mut self.summary;
+        *summary.files_created.get_or_insert(0) += diff.created_files().len();
+        *summary.files_edited.get_or_insert(0) += diff.edited_files().len();
+        *summary.files_deleted.get_or_insert(0) += diff.deleted_files().len();
+        *summary.dirs_deleted.get_or_insert(0) += diff.deleted_dirs().len();
+        *summary.dirs_renamed.get_or_insert(0) += diff.renamed_dirs().len();
+    }
+
+    pub fn count(&mut self, change: &FileChangeMessage) {
+        let summary = &mut self.summary;
+        match change {
+            FileChangeMessage::FileCreated(_) | FileChangeMessage::FifoCreated(_) => {
+                summary.files_transferred += 1
+            }
+            FileChangeMessage::FileEdited(_, contents, _) => {
+                summary.files_transferred += 1;
+                summary.bytes += contents.len() as u64;
+                summary.compressed_bytes += contents.len() as u64;
+            }
+            FileChangeMessage::GzippedFileEdited(_, compressed, _) => {
+                summary.files_transferred += 1;
+                summary.bytes += policy::decoded_len(compressed);
+                summary.compressed_bytes += compressed.len() as u64;
+            }
+            FileChangeMessage::EmptyDirectoryCreated(_) => summary.dirs_transferred += 1,
+            FileChangeMessage::DirectoryCreated(_, contents) => {
+                summary.dirs_transferred += 1;
+                summary.bytes += contents.len() as u64;
+                summary.compressed_bytes += contents.len() as u64;
+            }
+            FileChangeMessage::GzippedDirectoryCreated(_, compressed) => {
+                summary.dirs_transferred += 1;
+                summary.bytes += policy::decoded_len(compressed);
+                summary.compressed_bytes += compressed.len() as u64;
+            }
+            FileChangeMessage::FileChunk(_, chunk) => {
+                if chunk.offset == 0 {
+                    summary.files_transferred += 1;
+                }
+                summary.bytes += chunk.data.len() as u64;
+                summary.compressed_bytes += chunk.data.len() as u64;
+            }
+            FileChangeMessage::FileFromHash(..) => summary.files_deduplicated += 1,
+            FileChangeMessage::HardlinkCreated(..) => summary.files_linked += 1,
+            FileChangeMessage::FileBatch(_, packed) => {
+                summary.files_transferred += packed.files;
+                summary.bytes += packed.size;
+                summary.compressed_bytes += packed.archive.len() as u64;
+            }
+            FileChangeMessage::Checksummed(change, _) => self.count(change),
+            FileChangeMessage::FileDeleted(_)
+            | FileChangeMessage::DirectoryDeleted(_)
+            | FileChangeMessage::Rename(..)
+            | FileChangeMessage::DirectoryContentsEdited(_) => (),
+        }
+    }
+
+    pub fn finish(self) -> TransferSummary {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = match elapsed > 0.0 {
+            true => self.summary.bytes as f64 / elapsed,
+            false => 0.0,
+        };
+
+        TransferSummary {
+            elapsed_secs: elapsed,
+            throughput,
+            ..self.summary
+        }
+    }
+}
+
+impl TransferSummary {
+    /// Prints the summary, as a single JSON line for scripts when `json` is set.
+    pub fn print(&self, json: bool) {
+        match json {
+            true => match serde_json::to_string(self) {
+                Ok(json) => println!("{}", json),
+                Err(err) => eprintln!("Could not serialize the transfer summary: {}", err),
+            },
+            false => println!("{}", self),
+        }
+    }
+}
+
+impl Display for TransferSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Transfer summary:")?;
+        if let (Some(created), Some(edited), Some(deleted)) =
+            (self.files_created, self.files_edited, self.files_deleted)
+        {
+            writeln!(
+                f,
+                "  Files: {} created, {} edited, {} deleted",
+                created, edited, deleted
+            )?;
+        }
+        if let Some(deleted) = self.dirs_deleted {
+            writeln!(f, "  Directories deleted: {}", deleted)?;
+        }
+        if let Some(renamed) = self.dirs_renamed.filter(|&renamed| renamed > 0) {
+            writeln!(f, "  Directories renamed: {}", renamed)?;
+        }
+        writeln!(
+            f,
+            "  Transferred: {} files, {} directories",
+            self.files_transferred, self.dirs_transferred
+        )?;
+        if self.files_deduplicated > 0 {
+            writeln!(
+                f,
+                "  Deduplicated: {} files copied from identical ones",
+                self.files_deduplicated
+            )?;
+        }
+        if self.files_linked > 0 {
+            writeln!(f, "  Linked: {} files hard linked", self.files_linked)?;
+        }
+        writeln!(
+            f,
+            "  Bytes: {} ({} compressed)",
+            ByteSize::b(self.bytes),
+            ByteSize::b(self.compressed_bytes)
+        )?;
+        let elapsed = Duration::from_millis((self.elapsed_secs * 1000.0) as u64);
+        writeln!(f, "  Elapsed: {}", humantime::format_duration(elapsed))?;
+        write!(f, "  Throughput: {}/s", ByteSize::b(self.throughput as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::policy::PolicyTable;
+    use bytes::Bytes;
+    use std::{path::PathBuf, time::SystemTime};
+
+    #[test]
+    fn test_transfer_counts_changes() -> anyhow::Result<()> {
+        let mut transfer = Transfer::start();
+        let contents = Bytes::from("fn main() {}\n".repeat(100));
+        let gzipped = PolicyTable::default().encode(FileChangeMessage::FileEdited(
+            PathBuf::from("main.rs"),
+            contents.clone(),
+            SystemTime::now(),
+        ))?;
+        assert!(matches!(gzipped, FileChangeMessage::GzippedFileEdited(..)));
+
+        transfer.count(&gzipped);
+        transfer.count(&FileChangeMessage::FileEdited(
+            PathBuf::from("logo.png"),
+            Bytes::from_static(b"0123456789"),
+            SystemTime::now(),
+        ));
+        transfer.count(&FileChangeMessage::EmptyDirectoryCreated(PathBuf::from(
+            "empty",
+        )));
+        transfer.count(&FileChangeMessage::FileDeleted(PathBuf::from("old.rs")));
+        let summary = transfer.finish();
+
+        assert_eq!(summary.files_transferred, 2);
+        assert_eq!(summary.dirs_transferred, 1);
+        assert_eq!(summary.bytes, contents.len() as u64 + 10);
+        assert!(summary.compressed_bytes < summary.bytes);
+        assert_eq!(summary.files_created, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+use tokio::time::{Instant, MissedTickBehavior};
+
+#[derive(Debug)]
+pub struct TimedOut {
+    pub operation: &'static str,
+    pub after: Duration,
+}
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} timed out after {}, the peer may be unreachable or overloaded (see --timeout)",
+            self.operation,
+            humantime::format_duration(self.after)
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+pub async fn with_timeout<F: Future>(
+    after: Duration,
+    operation: &'static str,
+    fut: F,
+) -> Result<F::Output, TimedOut> {
+    tokio::time::timeout(after, fut)
+        .await
+        .map_err(|_| TimedOut { operation, after })
+}
+
+/// Like `with_timeout`, but calls `on_stall` with the time spent so far every `warn_every` while
+/// `fut` is still pending, so that an operation stuck on the peer is reported before it is given
+/// up on. A zero `warn_every` never warns.
+pub async fn with_watchdog<F: Future>(
+    after: Duration,
+    warn_every: Duration,
+    operation: &'static str,
+    mut on_stall: impl FnMut(Duration),
+    fut: F,
+) -> Result<F::Output, TimedOut> {
+    if warn_every.is_zero() {
+        return with_timeout(after, operation, fut).await;
+    }
+
+    let started = Instant::now();
+    let mut warnings = tokio::time::interval_at(started + warn_every, warn_every);
+    warnings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let fut = with_timeout(after, operation, fut);
+    tokio::pin!(fut);
+
+    loop {
+        tokio::select! {
+            res = &mut fut => break res,
+            _ = warnings.tick() => on_stall(started.elapsed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_watchdog_warns_until_timeout() {
+        let mut stalls = vec![];
+        let res = with_watchdog(
+            Duration::from_millis(250),
+            Duration::from_millis(100),
+            "sending a message",
+            |elapsed| stalls.push(elapsed),
+            std::future::pending::<()>(),
+        )
+        .await;
+
+        assert!(res.is_err_and(|err| err.after == Duration::from_millis(250)));
+        assert_eq!(stalls.len(), 2);
+        assert!(stalls[0] >= Duration::from_millis(100));
+    }
+
+    #[test]
+    async fn test_watchdog_is_quiet_when_ready() {
+        let mut stalls = 0;
+        let res = with_watchdog(
+            Duration::from_millis(250),
+            Duration::from_millis(100),
+            "sending a message",
+            |_| stalls += 1,
+            async { 42 },
+        )
+        .await;
+
+        assert_eq!(res.ok(), Some(42));
+        assert_eq!(stalls, 0);
+    }
+}
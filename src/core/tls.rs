@@ -0,0 +1,150 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{bail, Context};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::{transport::BoxedTransport, utils::quoted};
+
+/// The listener's side of TLS: its certificate, and the CA senders' certificates must be signed
+/// by when client certificates are required.
+#[derive(Clone)]
+pub struct ServerTls(TlsAcceptor);
+
+impl std::fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ServerTls")
+    }
+}
+
+impl ServerTls {
+    /// Reads the PEM files of the listener's certificate chain and key, and of the CA to verify
+    /// client certificates with, if any.
+    pub fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> anyhow::Result<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider())
+                        .build()
+                        .with_context(|| format!("using {} as a CA", quoted(ca)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs(cert)?, private_key(key)?)
+            .with_context(|| format!("using {} and {}", quoted(cert), quoted(key)))?;
+
+        Ok(Self(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> anyhow::Result<BoxedTransport> {
+        let stream = self.0.accept(stream).await.context("TLS handshake")?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// The sender's side of TLS: the CAs the listener's certificate is verified with, and the
+/// certificate it presents itself, if any.
+#[derive(Clone)]
+pub struct ClientTls(TlsConnector);
+
+impl std::fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientTls")
+    }
+}
+
+impl ClientTls {
+    /// Reads the PEM files of the CA to verify the listener with, the public web's CAs being
+    /// trusted without one, and of the client certificate chain and key to present.
+    pub fn load(ca: Option<&Path>, identity: Option<(&Path, &Path)>) -> anyhow::Result<Self> {
+        let roots = match ca {
+            Some(ca) => roots(ca)?,
+            None => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+        };
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certs(cert)?, private_key(key)?)
+                .with_context(|| format!("using {} and {}", quoted(cert), quoted(key)))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Self(TlsConnector::from(Arc::new(config))))
+    }
+
+    /// Runs the TLS handshake with the listener at `host`, which its certificate must be for.
+    pub async fn connect(&self, host: &str, stream: TcpStream) -> std::io::Result<BoxedTransport> {
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let stream = self.0.connect(server_name, stream).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", quoted(path)))?;
+    if certs.is_empty() {
+        bail!("{} holds no certificates", quoted(path))
+    }
+
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("reading a private key from {}", quoted(path)))
+}
+
+fn roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("using {} as a CA", quoted(path)))?;
+    }
+
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_loading_reports_unusable_files() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let empty = dir.path().join("empty.pem");
+        fs::write(&empty, "")?;
+        let missing = dir.path().join("missing.pem");
+
+        assert!(ClientTls::load(None, None).is_ok());
+        let err = ClientTls::load(Some(&empty), None).unwrap_err();
+        assert!(err.to_string().contains("holds no certificates"));
+        assert!(ClientTls::load(None, Some((&missing, &missing))).is_err());
+        assert!(ServerTls::load(&empty, &empty, None).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,941 @@
+use std::{
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use bytesize::ByteSize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{
+    compression::{compress_dir_with_limit, pack_files, PackedFile},
+    file_tree::{is_same_file, ScanOptions},
+    message::{Chunk, FileChangeMessage, PackedFiles, RequestMessage},
+    policy::PolicyTable,
+    profile,
+    roots::Roots,
+    sparse::{data_regions, holds_sparse_file},
+    specials::{holds_fifo, special_kind, SkippedSpecial, SpecialKind},
+    utils::{hash_file, is_dir_empty, quoted},
+    walk::walk,
+};
+
+/// Files sent in chunks are read this much at a time, so that loading several chunks at once
+/// stays cheap.
+const MAX_CHUNK_SIZE: u64 = 16 << 20;
+
+/// Small files are packed together until they add up to this much, so that a batch fits in a
+/// fragment and batches are loaded in parallel.
+const MAX_BATCH_SIZE: u64 = 1 << 20;
+
+/// A file left out of a transfer because it does not fit the in-memory limit.
+#[derive(Debug, Clone)]
+pub struct Oversized {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Display for Oversized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", quoted(&self.path), ByteSize(self.size))
+    }
+}
+
+impl std::error::Error for Oversized {}
+
+/// A file left out of a transfer because its size or mtime changed while waiting for it to
+/// settle, most likely as it is still being written, see `TransferJob::settled`.
+#[derive(Debug, Clone)]
+pub struct Unsettled {
+    pub path: PathBuf,
+}
+
+impl Display for Unsettled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", quoted(&self.path))
+    }
+}
+
+/// A pending outgoing message whose payload, if any, has not been read from disk yet.
+#[derive(Debug)]
+pub enum TransferJob {
+    File(PathBuf),
+    Directory(PathBuf),
+    /// `len` bytes at `offset` of a file of `size` bytes, too large to be sent whole.
+    FileChunk {
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        size: u64,
+    },
+    /// A file whose contents, with `sha1` as of the last scan, were already sent as `source`.
+    FromHash {
+        path: PathBuf,
+        sha1: [u8; 20],
+        source: PathBuf,
+    },
+    /// A hard link to the file already sent as `target`.
+    Hardlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    /// Small files below `dir`, sent together as a `FileBatch`.
+    Batch {
+        dir: PathBuf,
+        files: Vec<PathBuf>,
+    },
+    Ready(FileChangeMessage),
+}
+
+impl From<RequestMessage> for TransferJob {
+    fn from(request: RequestMessage) -> Self {
+        match request {
+            RequestMessage::File(path) => TransferJob::File(path),
+            RequestMessage::Dir(path) => TransferJob::Directory(path),
+        }
+    }
+}
+
+impl TransferJob {
+    pub fn path(&self) -> &Path {
+        match self {
+            TransferJob::File(path)
+            | TransferJob::Directory(path)
+            | TransferJob::FileChunk { path, .. }
+            | TransferJob::FromHash { path, .. }
+            | TransferJob::Hardlink { path, .. }
+            | TransferJob::Batch { dir: path, .. } => path,
+            TransferJob::Ready(message) => message.path(),
+        }
+    }
+
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            TransferJob::File(path)
+            | TransferJob::Directory(path)
+            | TransferJob::FileChunk { path, .. } => vec![path],
+            // The copy must be applied after the source is written, and before it changes again.
+            TransferJob::FromHash { path, source, .. } => vec![path, source],
+            TransferJob::Hardlink { path, target } => vec![path, target],
+            TransferJob::Batch { files, .. } => files.iter().map(PathBuf::as_path).collect(),
+            TransferJob::Ready(message) => message.paths(),
+        }
+    }
+
+    /// Waits `window`, then leaves out the files whose contents the job sends that changed size
+    /// or mtime meanwhile, so that files are not sent half-written. A job left with no file to
+    /// send is dropped. Directories are archived as they are, and files missing before or after
+    /// are left for `load` to report.
+    pub async fn settled(
+        self,
+        roots: &Roots,
+        window: Duration,
+    ) -> (Option<TransferJob>, Vec<Unsettled>) {
+        let files: Vec<&Path> = match &self {
+            TransferJob::File(path)
+            | TransferJob::FileChunk { path, .. }
+            | TransferJob::FromHash { path, .. } => vec![path],
+            TransferJob::Batch { files, .. } => files.iter().map(PathBuf::as_path).collect(),
+            TransferJob::Directory(_) | TransferJob::Hardlink { .. } | TransferJob::Ready(_) => {
+                return (Some(self), vec![])
+            }
+        };
+
+        let before = stat_all(roots, &files).await;
+        tokio::time::sleep(window).await;
+        let after = stat_all(roots, &files).await;
+        let unsettled: Vec<_> = files
+            .iter()
+            .zip(before.iter().zip(&after))
+            .filter(|(_, (before, after))| before.is_some() && after.is_some() && before != after)
+            .map(|(path, _)| Unsettled {
+                path: path.to_path_buf(),
+            })
+            .collect();
+
+        let job = match self {
+            _ if unsettled.is_empty() => Some(self),
+            TransferJob::Batch { dir, files } => {
+                let files: Vec<_> = files
+                    .into_iter()
+                    .filter(|path| !unsettled.iter().any(|file| &file.path == path))
+                    .collect();
+                (!files.is_empty()).then_some(TransferJob::Batch { dir, files })
+            }
+            _ => None,
+        };
+
+        (job, unsettled)
+    }
+
+    /// Splits the job when its message could be larger than `max_message_size`: files into
+    /// chunks, and directories into an empty one followed by jobs for their entries. Files over
+    /// `max_file_size` stay whole, to be reported as oversized. With `sparse`, sparse files are
+    /// always sent in chunks of their data only, see `sparse_chunks`, and directories holding
+    /// one are split. So are directories holding a FIFO with `ScanOptions::fifos`, archives
+    /// leaving them out. Files that `policies` chunk by class are sent in chunks too, and
+    /// directories holding a file larger than its smallest chunk size are split.
+    pub fn split(
+        self,
+        roots: &Roots,
+        max_message_size: u64,
+        max_file_size: u64,
+        scan: ScanOptions,
+        sparse: bool,
+        policies: &PolicyTable,
+    ) -> Vec<TransferJob> {
+        // Leaves room for the envelope, and for what gzip adds to incompressible files.
+        let max_payload = max_message_size / 2;
+        let Ok(source) = resolve(roots, self.path()) else {
+            return vec![self];
+        };
+
+        match self {
+            TransferJob::File(path) => {
+                let metadata = std::fs::metadata(&source).ok();
+                // Opening a FIFO blocks, special files are left to `load`.
+                if metadata
+                    .as_ref()
+                    .is_some_and(|metadata| !metadata.is_file())
+                {
+                    return vec![TransferJob::File(path)];
+                }
+                let size = metadata.map_or(0, |metadata| metadata.len());
+                if size > max_file_size {
+                    return vec![TransferJob::File(path)];
+                }
+                let by_policy = policies.chunk_size(&source, size);
+                let chunk_size = max_payload
+                    .min(MAX_CHUNK_SIZE)
+                    .min(by_policy.unwrap_or(u64::MAX));
+                if let Some(regions) = sparse.then(|| data_regions(&source)).flatten() {
+                    return sparse_chunks(&path, size, regions, chunk_size);
+                }
+                if size <= max_payload && by_policy.is_none() {
+                    return vec![TransferJob::File(path)];
+                }
+
+                (0..size)
+                    .step_by(chunk_size as usize)
+                    .map(|offset| TransferJob::FileChunk {
+                        path: path.clone(),
+                        offset,
+                        len: chunk_size.min(size - offset),
+                        size,
+                    })
+                    .collect()
+            }
+            TransferJob::Directory(path)
+                if archive_size(&source, max_payload, max_file_size, scan) > max_payload
+                    || (sparse && holds_sparse_file(&source, scan))
+                    || (scan.fifos && holds_fifo(&source, scan))
+                    || policies.min_chunk_size().is_some_and(|size| {
+                        holds_file_over(&source, size, max_file_size, scan)
+                    }) =>
+            {
+                unpacked(&path, &source, scan)
+                    .into_iter()
+                    .flat_map(|job| {
+                        job.split(
+                            roots,
+                            max_message_size,
+                            max_file_size,
+                            scan,
+                            sparse,
+                            policies,
+                        )
+                    })
+                    .collect()
+            }
+            job => vec![job],
+        }
+    }
+
+    /// Splits a directory job into an empty directory followed by jobs for its entries, so that
+    /// they are sent one by one instead of as an archive. Other jobs are left as they are.
+    pub fn unpack(self, roots: &Roots, scan: ScanOptions) -> Vec<TransferJob> {
+        match self {
+            TransferJob::Directory(path) => match resolve(roots, &path) {
+                Ok(source) => unpacked(&path, &source, scan),
+                Err(_) => vec![TransferJob::Directory(path)],
+            },
+            job => vec![job],
+        }
+    }
+
+    /// Roughly how many bytes the job's payload takes in memory once loaded, counting no more
+    /// than `limit` of them.
+    pub fn estimated_size(
+        &self,
+        roots: &Roots,
+        limit: u64,
+        max_file_size: u64,
+        scan: ScanOptions,
+    ) -> u64 {
+        let file_size = |path: &Path| {
+            resolve(roots, path)
+                .ok()
+                .and_then(|source| std::fs::metadata(source).ok())
+                .map_or(0, |metadata| metadata.len())
+                .min(max_file_size)
+        };
+
+        let size = match self {
+            TransferJob::File(path) => file_size(path),
+            TransferJob::FileChunk { len, .. } => *len,
+            TransferJob::Batch { files, .. } => files.iter().map(|path| file_size(path)).sum(),
+            TransferJob::Directory(path) => resolve(roots, path).map_or(0, |source| {
+                archive_size(&source, limit, max_file_size, scan)
+            }),
+            TransferJob::FromHash { .. } | TransferJob::Hardlink { .. } | TransferJob::Ready(_) => {
+                0
+            }
+        };
+        size.min(limit)
+    }
+
+    /// Reads the job's payload from the root its path falls under, producing the message to send. Files
+    /// larger than `max_file_size` bytes are never read: a file job fails with `Oversized`, while
+    /// directory archives leave them out and list them next to the message. Archives also leave
+    /// out the entries excluded by `scan`. Files that changed since they were deduplicated, or are
+    /// not linked to their hard link's target anymore, are loaded whole. Special files are never
+    /// read either: a file job fails with `SkippedSpecial`, unless it is a FIFO kept by
+    /// `ScanOptions::fifos`, sent as `FifoCreated`.
+    pub async fn load(
+        self,
+        roots: &Roots,
+        max_file_size: u64,
+        scan: ScanOptions,
+    ) -> anyhow::Result<(FileChangeMessage, Vec<Oversized>)> {
+        let mut oversized = vec![];
+        let message = match self {
+            TransferJob::File(path) => {
+                let file_path = resolve(roots, &path)?;
+                let metadata = tokio::fs::metadata(&file_path)
+                    .await
+                    .with_context(|| format!("reading {}", quoted(&path)))?;
+                match special_kind(metadata.file_type()) {
+                    Some(SpecialKind::Fifo) if scan.fifos => {
+                        return Ok((FileChangeMessage::FifoCreated(path), oversized))
+                    }
+                    Some(kind) => return Err(SkippedSpecial { path, kind }.into()),
+                    None => (),
+                }
+                let size = metadata.len();
+                if size > max_file_size {
+                    return Err(Oversized { path, size }.into());
+                }
+
+                let span = profile::span("read", Some(&path));
+                let contents = tokio::fs::read(file_path)
+                    .await
+                    .with_context(|| format!("reading {}", quoted(&path)))?;
+                drop(span);
+
+                FileChangeMessage::FileEdited(path, Bytes::from(contents), metadata.modified()?)
+            }
+            TransferJob::Directory(path) => {
+                let dir_path = resolve(roots, &path)?;
+                if is_dir_empty(&dir_path) {
+                    FileChangeMessage::EmptyDirectoryCreated(path)
+                } else {
+                    let _span = profile::span("compress", Some(&path));
+                    let (contents, left_out) =
+                        compress_dir_with_limit(&dir_path, max_file_size, scan)
+                            .await
+                            .with_context(|| format!("compressing {}", quoted(&path)))?;
+                    oversized.extend(left_out.into_iter().map(|file| Oversized {
+                        path: path.join(file.path),
+                        ..file
+                    }));
+
+                    FileChangeMessage::DirectoryCreated(path, contents)
+                }
+            }
+            TransferJob::FileChunk {
+                path,
+                offset,
+                len,
+                size,
+            } => {
+                let file_path = resolve(roots, &path)?;
+                let mut file = tokio::fs::File::open(file_path)
+                    .await
+                    .with_context(|| format!("reading {}", quoted(&path)))?;
+                let metadata = file.metadata().await?;
+                if metadata.len() != size {
+                    bail!("{} changed while being sent in chunks", quoted(&path))
+                }
+
+                let span = profile::span("read", Some(&path));
+                let mut data = vec![0; len as usize];
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
+                file.read_exact(&mut data)
+                    .await
+                    .with_context(|| format!("reading {}", quoted(&path)))?;
+                drop(span);
+
+                let chunk = Chunk {
+                    offset,
+                    size,
+                    data: Bytes::from(data),
+                    mtime: metadata.modified()?,
+                };
+                FileChangeMessage::FileChunk(path, chunk)
+            }
+            TransferJob::FromHash { path, sha1, .. } => {
+                let file_path = resolve(roots, &path)?;
+                let _span = profile::span("hash", Some(&path));
+                if !hash_file(&file_path).await.is_ok_and(|hash| hash == sha1) {
+                    return Box::pin(TransferJob::File(path).load(roots, max_file_size, scan))
+                        .await;
+                }
+
+                let mtime = tokio::fs::metadata(&file_path)
+                    .await
+                    .with_context(|| format!("reading {}", quoted(&path)))?
+                    .modified()?;
+                FileChangeMessage::FileFromHash(path, sha1, mtime)
+            }
+            TransferJob::Hardlink { path, target } => {
+                let (file_path, target_path) = (resolve(roots, &path)?, resolve(roots, &target)?);
+                if !is_same_file(&file_path, &target_path) {
+                    return Box::pin(TransferJob::File(path).load(roots, max_file_size, scan))
+                        .await;
+                }
+
+                FileChangeMessage::HardlinkCreated(target, path)
+            }
+            TransferJob::Batch { dir, files } => {
+                let mut packed = vec![];
+                for path in files {
+                    match read_small_file(roots, &path, max_file_size).await {
+                        Ok(file) => packed.push(PackedFile {
+                            path: path.strip_prefix(&dir)?.to_owned(),
+                            ..file
+                        }),
+                        Err(err) => match err.downcast::<Oversized>() {
+                            Ok(file) => oversized.push(file),
+                            // Most likely deleted since, which the deletion's change will say.
+                            Err(err) => eprintln!("Leaving a file out of a batch: {:#}", err),
+                        },
+                    }
+                }
+
+                let _span = profile::span("compress", Some(&dir));
+                let size = packed.iter().map(|file| file.contents.len() as u64).sum();
+                let archive = pack_files(&packed)
+                    .await
+                    .with_context(|| format!("packing files of {}", quoted(&dir)))?;
+                let packed = PackedFiles {
+                    files: packed.len() as u64,
+                    size,
+                    gzip: false,
+                    archive,
+                };
+                FileChangeMessage::FileBatch(dir, packed)
+            }
+            TransferJob::Ready(message) => message,
+        };
+
+        Ok((message, oversized))
+    }
+}
+
+/// The sizes and mtimes of files, `None` for those that cannot be read.
+async fn stat_all(roots: &Roots, paths: &[&Path]) -> Vec<Option<(u64, SystemTime)>> {
+    let mut stats = Vec::with_capacity(paths.len());
+    for path in paths {
+        let stat = match resolve(roots, path) {
+            Ok(file_path) => tokio::fs::metadata(file_path)
+                .await
+                .ok()
+                .and_then(|metadata| Some((metadata.len(), metadata.modified().ok()?))),
+            Err(_) => None,
+        };
+        stats.push(stat);
+    }
+    stats
+}
+
+/// Reads a file of a batch, failing with `Oversized` if it grew past `max_file_size` since.
+async fn read_small_file(
+    roots: &Roots,
+    path: &Path,
+    max_file_size: u64,
+) -> anyhow::Result<PackedFile> {
+    let file_path = resolve(roots, path)?;
+    let metadata = tokio::fs::metadata(&file_path)
+        .await
+        .with_context(|| format!("reading {}", quoted(path)))?;
+    if !metadata.is_file() {
+        bail!("{} is not a regular file anymore", quoted(path));
+    }
+    if metadata.len() > max_file_size {
+        return Err(Oversized {
+            path: path.to_owned(),
+            size: metadata.len(),
+        }
+        .into());
+    }
+
+    let _span = profile::span("read", Some(path));
+    let contents = tokio::fs::read(file_path)
+        .await
+        .with_context(|| format!("reading {}", quoted(path)))?;
+    Ok(PackedFile {
+        path: path.to_owned(),
+        contents: Bytes::from(contents),
+        mtime: metadata.modified()?,
+    })
+}
+
+/// Packs runs of consecutive jobs of files smaller than `threshold` bytes into
+/// `TransferJob::Batch`es, below the deepest directory they share within a root. Other jobs
+/// pass through in order, and all of them do with a `threshold` of 0.
+pub struct SmallFilePacker<I> {
+    jobs: I,
+    roots: Arc<Roots>,
+    threshold: u64,
+    batch: Vec<PathBuf>,
+    batch_dir: PathBuf,
+    batch_size: u64,
+    held: Option<TransferJob>,
+}
+
+impl<I: Iterator<Item = TransferJob>> SmallFilePacker<I> {
+    pub fn new(jobs: I, roots: Arc<Roots>, threshold: u64) -> Self {
+        Self {
+            jobs,
+            roots,
+            threshold,
+            batch: vec![],
+            batch_dir: PathBuf::new(),
+            batch_size: 0,
+            held: None,
+        }
+    }
+
+    /// The size of the file of a file job, if it is small enough to be packed.
+    fn small_file_size(&self, job: &TransferJob) -> Option<u64> {
+        let TransferJob::File(path) = job else {
+            return None;
+        };
+        if self.threshold == 0 {
+            return None;
+        }
+        let metadata = std::fs::metadata(self.roots.resolve(path)?).ok()?;
+        (metadata.is_file() && metadata.len() < self.threshold).then_some(metadata.len())
+    }
+
+    /// Whether a file of `size` bytes in the directory `parent` can join the current batch.
+    fn fits(&self, parent: &Path, size: u64) -> bool {
+        let dir = common_ancestor(&self.batch_dir, parent);
+        self.batch_size + size <= MAX_BATCH_SIZE && self.roots.contains(&dir)
+    }
+
+    fn flush(&mut self) -> Option<TransferJob> {
+        let mut files = std::mem::take(&mut self.batch);
+        self.batch_size = 0;
+        match files.len() {
+            0 => None,
+            1 => files.pop().map(TransferJob::File),
+            _ => Some(TransferJob::Batch {
+                dir: std::mem::take(&mut self.batch_dir),
+                files,
+            }),
+        }
+    }
+}
+
+impl<I: Iterator<Item = TransferJob>> Iterator for SmallFilePacker<I> {
+    type Item = TransferJob;
+
+    fn next(&mut self) -> Option<TransferJob> {
+        loop {
+            let Some(job) = self.held.take().or_else(|| self.jobs.next()) else {
+                return self.flush();
+            };
+            let Some(size) = self.small_file_size(&job) else {
+                return match self.flush() {
+                    Some(batch) => {
+                        self.held = Some(job);
+                        Some(batch)
+                    }
+                    None => Some(job),
+                };
+            };
+
+            let path = job.path().to_owned();
+            let parent = path.parent().unwrap_or(Path::new(""));
+            if self.batch.is_empty() {
+                self.batch_dir = parent.to_owned();
+            } else if self.fits(parent, size) {
+                self.batch_dir = common_ancestor(&self.batch_dir, parent);
+            } else {
+                self.held = Some(job);
+                return self.flush();
+            }
+            self.batch_size += size;
+            self.batch.push(path);
+        }
+    }
+}
+
+fn common_ancestor(path1: &Path, path2: &Path) -> PathBuf {
+    path1
+        .components()
+        .zip(path2.components())
+        .take_while(|(component1, component2)| component1 == component2)
+        .map(|(component, _)| component)
+        .collect()
+}
+
+/// Chunks of the data `regions` of a sparse file, holes being the ranges no chunk covers. The
+/// first chunk is always at offset 0 and the last one always ends the file, empty if need be, so
+/// that the receiver starts the file and sets its size.
+fn sparse_chunks(
+    path: &Path,
+    size: u64,
+    regions: Vec<Range<u64>>,
+    chunk_size: u64,
+) -> Vec<TransferJob> {
+    let chunk = |offset, len| TransferJob::FileChunk {
+        path: path.to_owned(),
+        offset,
+        len,
+        size,
+    };
+
+    let mut chunks = vec![];
+    if regions.first().is_none_or(|region| region.start > 0) {
+        chunks.push(chunk(0, 0));
+    }
+    for region in &regions {
+        for offset in region.clone().step_by(chunk_size as usize) {
+            chunks.push(chunk(offset, chunk_size.min(region.end - offset)));
+        }
+    }
+    if regions.last().is_none_or(|region| region.end < size) {
+        chunks.push(chunk(size, 0));
+    }
+
+    chunks
+}
+
+/// The jobs creating the directory at `path`, found at `source`, entry by entry.
+fn unpacked(path: &Path, source: &Path, scan: ScanOptions) -> Vec<TransferJob> {
+    let mut entries: Vec<_> = std::fs::read_dir(source)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| !scan.excludes(&entry.path()))
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut jobs = vec![TransferJob::Ready(
+        FileChangeMessage::EmptyDirectoryCreated(path.to_owned()),
+    )];
+    for entry in entries {
+        let entry_path = path.join(entry.file_name());
+        jobs.push(match entry.path().is_dir() {
+            true => TransferJob::Directory(entry_path),
+            false => TransferJob::File(entry_path),
+        });
+    }
+    jobs
+}
+
+/// The size of the archive of `dir`, tar adding a 512 bytes header to every entry and padding
+/// contents to 512 bytes. Stops counting once it is over `limit`.
+fn archive_size(dir: &Path, limit: u64, max_file_size: u64, scan: ScanOptions) -> u64 {
+    let entries = walk(dir, scan)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| !scan.excludes(entry.path()))
+        .filter_map(Result::ok);
+
+    let mut size = 0;
+    for entry in entries {
+        // Archives follow symbolic links.
+        let len = std::fs::metadata(entry.path())
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map_or(0, |metadata| metadata.len());
+        if len <= max_file_size {
+            size += 512 + len.next_multiple_of(512);
+        }
+        if size > limit {
+            break;
+        }
+    }
+
+    size
+}
+
+/// Whether `dir` holds a file larger than `size` bytes that is not left out for being over
+/// `max_file_size`.
+fn holds_file_over(dir: &Path, size: u64, max_file_size: u64, scan: ScanOptions) -> bool {
+    walk(dir, scan)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| !scan.excludes(entry.path()))
+        .filter_map(Result::ok)
+        .filter_map(|entry| std::fs::metadata(entry.path()).ok())
+        .any(|metadata| metadata.is_file() && (size + 1..=max_file_size).contains(&metadata.len()))
+}
+
+fn resolve(roots: &Roots, path: &Path) -> anyhow::Result<PathBuf> {
+    roots
+        .resolve(path)
+        .with_context(|| format!("{} is outside of the synced directories", quoted(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::{Seek, Write},
+    };
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_split_keeps_messages_under_the_limit() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("assets/icons"))?;
+        fs::write(root.path().join("assets/icons/logo.svg"), "<svg/>")?;
+        let large: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        fs::write(root.path().join("assets/video.mp4"), &large)?;
+        let roots = Roots::single(root.path());
+        let scan = ScanOptions::default();
+        let policies = PolicyTable::default();
+
+        let small = TransferJob::Directory("assets/icons".into()).split(
+            &roots,
+            4096,
+            u64::MAX,
+            scan,
+            false,
+            &policies,
+        );
+        assert!(matches!(&small[..], [TransferJob::Directory(_)]));
+
+        let jobs = TransferJob::Directory("assets".into()).split(
+            &roots,
+            4096,
+            u64::MAX,
+            scan,
+            false,
+            &policies,
+        );
+        assert!(matches!(
+            &jobs[0],
+            TransferJob::Ready(FileChangeMessage::EmptyDirectoryCreated(path)) if path == Path::new("assets")
+        ));
+        assert!(
+            matches!(&jobs[1], TransferJob::Directory(path) if path == Path::new("assets/icons"))
+        );
+
+        let mut received = vec![];
+        for job in jobs.into_iter().skip(2) {
+            let (message, _) = job.load(&roots, u64::MAX, scan).await?;
+            let FileChangeMessage::FileChunk(_, chunk) = message else {
+                panic!("expected a chunk, got {:?}", message)
+            };
+            assert!(chunk.data.len() <= 2048);
+            assert_eq!(chunk.offset, received.len() as u64);
+            received.extend_from_slice(&chunk.data);
+        }
+        assert_eq!(received, large);
+
+        // Files over the in-memory limit are left for `load` to report.
+        let oversized = TransferJob::File("assets/video.mp4".into())
+            .split(&roots, 4096, 1000, scan, false, &policies);
+        assert!(matches!(&oversized[..], [TransferJob::File(_)]));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_policies_chunk_large_files_of_their_class() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("assets"))?;
+        let binary: Vec<u8> = (0..5000u32).map(|i| (i % 7) as u8).collect();
+        fs::write(root.path().join("assets/model.bin"), &binary)?;
+        fs::write(root.path().join("assets/notes.txt"), "note\n".repeat(1000))?;
+        let roots = Roots::single(root.path());
+        let scan = ScanOptions::default();
+        let policies = PolicyTable::default().with("binary=chunk:2KiB".parse().unwrap());
+
+        let jobs = TransferJob::Directory("assets".into()).split(
+            &roots,
+            u64::MAX,
+            u64::MAX,
+            scan,
+            false,
+            &policies,
+        );
+        let chunks: Vec<_> = jobs
+            .iter()
+            .filter_map(|job| match job {
+                TransferJob::FileChunk { path, len, .. } => Some((path.as_path(), *len)),
+                _ => None,
+            })
+            .collect();
+        let model = Path::new("assets/model.bin");
+        assert_eq!(chunks, [(model, 2048), (model, 2048), (model, 904)]);
+        assert!(
+            matches!(&jobs[..], [.., TransferJob::File(path)] if path == Path::new("assets/notes.txt"))
+        );
+
+        let jobs = TransferJob::Directory("assets".into()).split(
+            &roots,
+            u64::MAX,
+            u64::MAX,
+            scan,
+            false,
+            &PolicyTable::default(),
+        );
+        assert!(matches!(&jobs[..], [TransferJob::Directory(_)]));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_sparse_files_are_sent_without_their_holes() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        let path = root.path().join("disk.img");
+        let mut file = fs::File::create(&path)?;
+        file.set_len(8 << 20)?;
+        file.seek(std::io::SeekFrom::Start(1 << 20))?;
+        file.write_all(&[7; 4096])?;
+        drop(file);
+        // Filesystems without holes store every byte.
+        if data_regions(&path).is_none() {
+            return Ok(());
+        }
+
+        let roots = Roots::single(root.path());
+        let scan = ScanOptions::default();
+        let policies = PolicyTable::default();
+        let jobs = TransferJob::File("disk.img".into()).split(
+            &roots,
+            4 << 20,
+            u64::MAX,
+            scan,
+            true,
+            &policies,
+        );
+        let mut received = vec![0; 8 << 20];
+        let mut sent = 0;
+        for job in jobs {
+            let (message, _) = job.load(&roots, u64::MAX, scan).await?;
+            let FileChangeMessage::FileChunk(_, chunk) = message else {
+                panic!("expected a chunk, got {:?}", message)
+            };
+            let offset = chunk.offset as usize;
+            received[offset..offset + chunk.data.len()].copy_from_slice(&chunk.data);
+            sent += chunk.data.len();
+        }
+        assert_eq!(received, fs::read(&path)?);
+        assert!(sent < 1 << 20);
+
+        // Without `sparse`, files under the limit are sent whole.
+        let whole = TransferJob::File("disk.img".into()).split(
+            &roots,
+            32 << 20,
+            u64::MAX,
+            scan,
+            false,
+            &policies,
+        );
+        assert!(matches!(&whole[..], [TransferJob::File(_)]));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_small_files_are_packed() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("node_modules/a"))?;
+        fs::create_dir_all(root.path().join("node_modules/b"))?;
+        fs::write(root.path().join("node_modules/a/index.js"), "a")?;
+        fs::write(root.path().join("node_modules/b/index.js"), "b")?;
+        fs::write(root.path().join("large.bin"), vec![0; 4096])?;
+        fs::write(root.path().join("README.md"), "readme")?;
+        let roots = Arc::new(Roots::single(root.path()));
+
+        let jobs = vec![
+            TransferJob::File("node_modules/a/index.js".into()),
+            TransferJob::File("node_modules/b/index.js".into()),
+            TransferJob::File("large.bin".into()),
+            TransferJob::File("README.md".into()),
+        ];
+        let packed: Vec<_> = SmallFilePacker::new(jobs.into_iter(), roots.clone(), 1024).collect();
+        let [TransferJob::Batch { dir, files }, TransferJob::File(large), TransferJob::File(readme)] =
+            &packed[..]
+        else {
+            panic!("expected a batch and two files, got {:?}", packed)
+        };
+        assert_eq!(dir, Path::new("node_modules"));
+        assert_eq!(files.len(), 2);
+        assert_eq!(large, Path::new("large.bin"));
+        assert_eq!(readme, Path::new("README.md"));
+
+        let batch = TransferJob::Batch {
+            dir: dir.clone(),
+            files: files.clone(),
+        };
+        let (message, _) = batch.load(&roots, u64::MAX, ScanOptions::default()).await?;
+        let FileChangeMessage::FileBatch(_, packed) = message else {
+            panic!("expected a batch, got {:?}", message)
+        };
+        assert_eq!((packed.files, packed.size), (2, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_files_being_written_are_left_out() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("logs"))?;
+        fs::write(root.path().join("logs/old.log"), "done")?;
+        fs::write(root.path().join("logs/app.log"), "starting")?;
+        let roots = Roots::single(root.path());
+
+        let app = root.path().join("logs/app.log");
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            fs::OpenOptions::new()
+                .append(true)
+                .open(app)?
+                .write_all(b", still going")
+        });
+        let batch = TransferJob::Batch {
+            dir: "logs".into(),
+            files: vec!["logs/app.log".into(), "logs/old.log".into()],
+        };
+        let (job, unsettled) = batch.settled(&roots, Duration::from_millis(200)).await;
+        writer.await??;
+        assert!(matches!(
+            job,
+            Some(TransferJob::Batch { files, .. }) if files == [Path::new("logs/old.log")]
+        ));
+        assert_eq!(unsettled.len(), 1);
+        assert_eq!(unsettled[0].path, Path::new("logs/app.log"));
+
+        let (job, unsettled) = TransferJob::File("logs/app.log".into())
+            .settled(&roots, Duration::from_millis(10))
+            .await;
+        assert!(matches!(job, Some(TransferJob::File(_))));
+        assert!(unsettled.is_empty());
+
+        Ok(())
+    }
+}
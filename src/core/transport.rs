@@ -0,0 +1,54 @@
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+
+/// Buffered bytes in each direction of a loopback connection before writes wait for reads.
+const LOOPBACK_BUFFER: usize = 1 << 20;
+
+/// A byte stream websocket sessions run over, either a TCP connection or an in-memory one.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type BoxedTransport = Box<dyn Transport>;
+
+/// Connects a sender to a receiver running in the same process, in place of a TCP connection.
+#[derive(Debug)]
+pub struct Loopback {
+    connections: mpsc::UnboundedSender<BoxedTransport>,
+}
+
+/// The receiver's end of a `Loopback`, yielding a connection each time the sender connects.
+pub struct LoopbackListener {
+    connections: mpsc::UnboundedReceiver<BoxedTransport>,
+}
+
+pub fn loopback() -> (Loopback, LoopbackListener) {
+    let (connections, incoming) = mpsc::unbounded_channel();
+    (
+        Loopback { connections },
+        LoopbackListener {
+            connections: incoming,
+        },
+    )
+}
+
+impl Loopback {
+    pub fn connect(&self) -> anyhow::Result<BoxedTransport> {
+        let (client, server) = tokio::io::duplex(LOOPBACK_BUFFER);
+        self.connections
+            .send(Box::new(server))
+            .map_err(|_| anyhow!("the receiver is no longer running"))?;
+
+        Ok(Box::new(client))
+    }
+}
+
+impl LoopbackListener {
+    /// The next connection, or `None` once the `Loopback` is dropped.
+    pub async fn accept(&mut self) -> Option<BoxedTransport> {
+        self.connections.recv().await
+    }
+}
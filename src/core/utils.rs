@@ -1,4 +1,7 @@
-use std::path::Path;
+use std::{fmt::Display, path::Path};
+
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncReadExt;
 
 pub fn is_dir_empty(path: &Path) -> bool {
     path.read_dir()
@@ -6,4 +9,133 @@ pub fn is_dir_empty(path: &Path) -> bool {
         .unwrap_or(true)
 }
 
+/// Displays a path with C-style escapes for whitespace, control characters and invalid UTF-8,
+/// like `ls -b`, so odd names neither garble the output nor get lost in it.
+pub struct Quoted<'a>(pub &'a Path);
+
+impl Display for Quoted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.0.as_os_str().as_encoded_bytes().utf8_chunks() {
+            for char in chunk.valid().chars() {
+                match char {
+                    '\\' => f.write_str("\\\\")?,
+                    ' ' => f.write_str("\\ ")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\r' => f.write_str("\\r")?,
+                    '\t' => f.write_str("\\t")?,
+                    char if char.is_control() => {
+                        let mut buf = [0; 4];
+                        for byte in char.encode_utf8(&mut buf).bytes() {
+                            write!(f, "\\{:03o}", byte)?;
+                        }
+                    }
+                    char => write!(f, "{}", char)?,
+                }
+            }
+
+            for byte in chunk.invalid() {
+                write!(f, "\\{:03o}", byte)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn quoted(path: &Path) -> Quoted<'_> {
+    Quoted(path)
+}
+
+/// Copies a file into `to`, which must not exist yet. On filesystems with copy-on-write clones
+/// (btrfs, XFS, APFS, ReFS) the copy shares the original's blocks, which is instant and takes no
+/// space until either file changes. Other filesystems get a byte copy.
+pub fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    reflink_copy::reflink_or_copy(from, to)?;
+    Ok(())
+}
+
+/// The SHA-1 of the file at `path`, read a block at a time since it may not fit in memory.
+pub async fn hash_file(path: &Path) -> std::io::Result<[u8; 20]> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut block = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut block).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&block[..read]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// The SHA-256 of a file, for manifests checked with `sha256sum`.
+pub async fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut block = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut block).await?;
+        if read == 0 {
+            break;
+        }
+        context.update(&block[..read]);
+    }
 
+    let mut sha256 = [0; 32];
+    sha256.copy_from_slice(context.finish().as_ref());
+    Ok(sha256)
+}
+
+/// Runs `command` through the platform's shell, for user-provided hooks.
+pub fn shell_command(command: &str) -> tokio::process::Command {
+    let (program, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut shell = tokio::process::Command::new(program);
+    shell.args([flag, command]);
+
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quoted_paths() {
+        let quote = |path: &str| quoted(Path::new(path)).to_string();
+
+        assert_eq!(quote("dir/file.txt"), "dir/file.txt");
+        assert_eq!(quote("my file.txt"), "my\\ file.txt");
+        assert_eq!(quote("line\nbreak\t"), "line\\nbreak\\t");
+        assert_eq!(quote("bell\x07"), "bell\\007");
+        assert_eq!(quote("back\\slash"), "back\\\\slash");
+        assert_eq!(quote("résumé.pdf"), "résumé.pdf");
+    }
+
+    #[test]
+    fn test_clone_file() -> std::io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let (from, to) = (dir.path().join("from.bin"), dir.path().join("to.bin"));
+        std::fs::write(&from, "contents")?;
+
+        clone_file(&from, &to)?;
+        std::fs::write(&from, "changed")?;
+        assert_eq!(std::fs::read_to_string(&to)?, "contents");
+        assert!(clone_file(&from, &to).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_invalid_utf8_is_escaped() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9.txt"));
+        assert_eq!(quoted(path).to_string(), "caf\\351.txt");
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use walkdir::{DirEntry, WalkDir};
+
+use super::{file_tree::ScanOptions, utils::quoted};
+
+/// Directories nested deeper than this below a scanned directory fail scans, unless
+/// `ScanOptions::max_depth` says otherwise.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Why a scan or an archive of a directory gave up, naming the entry at fault. Unlike unreadable
+/// entries, which are skipped, these would make the walk endless or the tree unusable.
+#[derive(Debug)]
+pub enum WalkError {
+    /// A followed symlink leads back to a directory it is in.
+    Loop { path: PathBuf, ancestor: PathBuf },
+    /// An entry nested deeper than `ScanOptions::max_depth` below the walked directory.
+    TooDeep { path: PathBuf, max_depth: usize },
+}
+
+impl Display for WalkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalkError::Loop { path, ancestor } => write!(
+                f,
+                "symlink loop: {} leads back to {}, which it is in",
+                quoted(path),
+                quoted(ancestor)
+            ),
+            WalkError::TooDeep { path, max_depth } => write!(
+                f,
+                "{} is nested more than {} directories deep, raise --max-depth to sync it",
+                quoted(path),
+                max_depth
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WalkError {}
+
+/// Walks `dir` like scans do, following symlinks with `ScanOptions::follow_links`, and going
+/// one level past `ScanOptions::max_depth` for `checked` to report it.
+pub fn walk(dir: &Path, scan: ScanOptions) -> WalkDir {
+    WalkDir::new(dir)
+        .follow_links(scan.follow_links)
+        .max_depth(scan.max_depth.saturating_add(1))
+}
+
+/// The entry of a `walk`, or a `WalkError` for symlink loops and entries nested too deep.
+pub fn checked(entry: walkdir::Result<DirEntry>, scan: ScanOptions) -> anyhow::Result<DirEntry> {
+    match entry {
+        Ok(entry) if entry.depth() > scan.max_depth => Err(WalkError::TooDeep {
+            path: entry.into_path(),
+            max_depth: scan.max_depth,
+        }
+        .into()),
+        Ok(entry) => Ok(entry),
+        Err(err) => match (err.path(), err.loop_ancestor()) {
+            (Some(path), Some(ancestor)) => Err(WalkError::Loop {
+                path: path.to_owned(),
+                ancestor: ancestor.to_owned(),
+            }
+            .into()),
+            _ => Err(err.into()),
+        },
+    }
+}
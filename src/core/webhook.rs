@@ -0,0 +1,234 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use futures::future::join_all;
+use serde::Serialize;
+
+use super::summary::TransferSummary;
+
+/// How long a webhook may take to answer before it is given up on, since posting holds the
+/// session back.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum WebhookEvent {
+    /// Connected, about to exchange the directory states.
+    SyncStarted,
+    /// The initial sync was transferred.
+    SyncCompleted(TransferSummary),
+    /// The session failed.
+    Error(String),
+    /// The session ended, on error or not.
+    Disconnected,
+}
+
+/// JSON body of every webhook request.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub event: &'static str,
+    /// `sender` or `receiver`.
+    pub role: &'static str,
+    pub peer: Option<String>,
+    /// When the event happened, as an RFC 3339 timestamp.
+    pub at: String,
+    /// A one-line description, which chat services such as Slack display as the message.
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<TransferSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// URLs receiving a POST request with a JSON `WebhookPayload` on each session event, e.g. to
+/// notify a chat or a CI pipeline. Failing webhooks are warned about and never fail the sync.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    urls: Vec<reqwest::Url>,
+    client: reqwest::Client,
+}
+
+impl WebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::SyncStarted => "sync_started",
+            WebhookEvent::SyncCompleted(_) => "sync_completed",
+            WebhookEvent::Error(_) => "error",
+            WebhookEvent::Disconnected => "disconnected",
+        }
+    }
+}
+
+impl WebhookPayload {
+    pub fn new(event: WebhookEvent, role: &'static str, peer: Option<String>) -> Self {
+        let peer_after = |preposition: &str| match &peer {
+            Some(peer) => format!(" {} {}", preposition, peer),
+            None => String::new(),
+        };
+        let text = match &event {
+            WebhookEvent::SyncStarted => {
+                format!(
+                    "white-caiman {} started syncing{}",
+                    role,
+                    peer_after("with")
+                )
+            }
+            WebhookEvent::SyncCompleted(summary) => format!(
+                "white-caiman {} completed the initial sync{}: {} files, {} directories in {:.1}s",
+                role,
+                peer_after("with"),
+                summary.files_transferred,
+                summary.dirs_transferred,
+                summary.elapsed_secs
+            ),
+            WebhookEvent::Error(error) => {
+                format!(
+                    "white-caiman {} failed{}: {}",
+                    role,
+                    peer_after("with"),
+                    error
+                )
+            }
+            WebhookEvent::Disconnected => {
+                format!("white-caiman {} disconnected{}", role, peer_after("from"))
+            }
+        };
+
+        let name = event.name();
+        let (summary, error) = match event {
+            WebhookEvent::SyncCompleted(summary) => (Some(summary), None),
+            WebhookEvent::Error(error) => (None, Some(error)),
+            WebhookEvent::SyncStarted | WebhookEvent::Disconnected => (None, None),
+        };
+
+        Self {
+            event: name,
+            role,
+            peer,
+            at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            text,
+            summary,
+            error,
+        }
+    }
+}
+
+impl Webhooks {
+    pub fn new(urls: &[String]) -> anyhow::Result<Self> {
+        let urls = urls
+            .iter()
+            .map(|url| {
+                let parsed = reqwest::Url::parse(url)
+                    .with_context(|| format!("invalid webhook URL {:?}", url))?;
+                match parsed.scheme() {
+                    "http" | "https" => Ok(parsed),
+                    scheme => bail!(
+                        "invalid webhook URL {:?}: {} is not http or https",
+                        url,
+                        scheme
+                    ),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+
+        Ok(Self { urls, client })
+    }
+
+    /// Posts `event` to every URL at once, waiting for them to answer.
+    pub async fn post(&self, event: WebhookEvent, role: &'static str, peer: Option<String>) {
+        if self.urls.is_empty() {
+            return;
+        }
+
+        let payload = &WebhookPayload::new(event, role, peer);
+        let requests = self.urls.iter().map(|url| async move {
+            let res = self
+                .client
+                .post(url.clone())
+                .json(payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = res {
+                eprintln!(
+                    "WARNING: {} webhook to {} failed: {}",
+                    payload.event, url, err
+                );
+            }
+        });
+        join_all(requests).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        test,
+    };
+
+    #[test]
+    async fn test_events_are_posted_as_json() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            // Reads until the whole body announced by the headers is in.
+            let body = loop {
+                let read = stream.read(&mut buf).await?;
+                anyhow::ensure!(read > 0, "request cut short");
+                request.extend_from_slice(&buf[..read]);
+                let request = String::from_utf8_lossy(&request);
+                let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_owned)
+                    })
+                    .context("no content length")?
+                    .parse()?;
+                if body.len() >= length {
+                    break format!("{}\n{}", headers.lines().next().unwrap_or_default(), body);
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await?;
+            anyhow::Ok(body)
+        });
+
+        let webhooks = Webhooks::new(&[url])?;
+        let summary = TransferSummary {
+            files_transferred: 3,
+            ..Default::default()
+        };
+        let peer = Some("127.0.0.1:8080".to_owned());
+        webhooks
+            .post(WebhookEvent::SyncCompleted(summary), "sender", peer)
+            .await;
+
+        let request = server.await??;
+        let (request_line, body) = request.split_once('\n').unwrap();
+        assert_eq!(request_line, "POST /hook HTTP/1.1");
+        let payload: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(payload["event"], "sync_completed");
+        assert_eq!(payload["role"], "sender");
+        assert_eq!(payload["peer"], "127.0.0.1:8080");
+        assert_eq!(payload["summary"]["files_transferred"], 3);
+        assert!(payload.get("error").is_none());
+
+        assert!(Webhooks::new(&["ftp://example.com".to_owned()]).is_err());
+
+        Ok(())
+    }
+}
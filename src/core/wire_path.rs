@@ -0,0 +1,245 @@
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// The path as sent to peers, with `/` separators whatever the platform, so that Windows and
+/// Unix peers name the same entries the same way. Paths on the wire are relative to the synced
+/// directories.
+#[cfg(windows)]
+pub fn to_wire(path: &Path) -> Cow<'_, Path> {
+    match path.to_str() {
+        Some(path) if path.contains('\\') => Cow::Owned(path.replace('\\', "/").into()),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn to_wire(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// A path received from a peer, with the platform's separators. On Windows, a `\` in a name sent
+/// by a Unix peer becomes a separator.
+#[cfg(windows)]
+pub fn from_wire(path: PathBuf) -> PathBuf {
+    match path.to_str() {
+        Some(wire) if wire.contains('/') => wire.replace('/', "\\").into(),
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn from_wire(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Strips the `\\?\` prefix Windows gives canonical paths, which lifts the length limit on paths
+/// but makes them compare unequal to the same path without it, as watchers and users name them.
+/// Paths that cannot go without it, being too long or not valid otherwise, keep it.
+pub fn without_verbatim_prefix(path: &Path) -> PathBuf {
+    let Some(name) = path.to_str() else {
+        return path.to_owned();
+    };
+    let stripped = match (name.strip_prefix(r"\\?\UNC\"), name.strip_prefix(r"\\?\")) {
+        (Some(unc), _) => format!(r"\\{}", unc),
+        (None, Some(disk)) if disk.as_bytes().get(1) == Some(&b':') => disk.to_owned(),
+        _ => return path.to_owned(),
+    };
+    match stripped.len() < 260 && !stripped.contains('/') {
+        true => stripped.into(),
+        false => path.to_owned(),
+    }
+}
+
+/// The bytes of a path on the wire: its name as is on Unix, where names may be any bytes, and
+/// UTF-8 elsewhere. Valid UTF-8 names are encoded exactly like a `PathBuf`, which older peers
+/// expect. Human-readable formats get a string, or an array of bytes for other names.
+struct Encoded<'a>(&'a Path);
+
+impl Serialize for Encoded<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = to_wire(self.0);
+        let bytes = path.as_os_str().as_encoded_bytes();
+        match (serializer.is_human_readable(), std::str::from_utf8(bytes)) {
+            (true, Ok(name)) => serializer.serialize_str(name),
+            _ => serializer.serialize_bytes(bytes),
+        }
+    }
+}
+
+struct Decoded(PathBuf);
+
+impl<'de> Deserialize<'de> for Decoded {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(PathVisitor),
+            false => deserializer.deserialize_byte_buf(PathVisitor),
+        }
+    }
+}
+
+struct PathVisitor;
+
+impl<'de> Visitor<'de> for PathVisitor {
+    type Value = Decoded;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a path, as a string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Decoded, E> {
+        Ok(Decoded(from_wire(name.into())))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Decoded, E> {
+        self.visit_byte_buf(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Decoded, E> {
+        Ok(Decoded(from_wire(from_bytes(bytes))))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Decoded, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        self.visit_byte_buf(bytes)
+    }
+}
+
+/// A path from the bytes of its name. Platforms whose names must be Unicode get the invalid
+/// parts replaced with U+FFFD.
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    std::ffi::OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    match String::from_utf8(bytes) {
+        Ok(name) => name.into(),
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned().into(),
+    }
+}
+
+/// `#[serde(with = "wire_path")]` for `PathBuf` fields of messages, see `Encoded`.
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    Encoded(path).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Decoded::deserialize(deserializer).map(|decoded| decoded.0)
+}
+
+/// `#[serde(with = "wire_path::vec")]` for `Vec<PathBuf>` fields.
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| Encoded(path)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        let paths = Vec::<Decoded>::deserialize(deserializer)?;
+        Ok(paths.into_iter().map(|decoded| decoded.0).collect())
+    }
+}
+
+/// `#[serde(with = "wire_path::option")]` for `Option<PathBuf>` fields.
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        path: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        path.as_deref().map(Encoded).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        let path = Option::<Decoded>::deserialize(deserializer)?;
+        Ok(path.map(|decoded| decoded.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbatim_prefixes_are_stripped() {
+        assert_eq!(
+            without_verbatim_prefix(Path::new(r"\\?\C:\Users\dev\project")),
+            Path::new(r"C:\Users\dev\project")
+        );
+        assert_eq!(
+            without_verbatim_prefix(Path::new(r"\\?\UNC\server\share\project")),
+            Path::new(r"\\server\share\project")
+        );
+        assert_eq!(
+            without_verbatim_prefix(Path::new("/home/dev/project")),
+            Path::new("/home/dev/project")
+        );
+
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(without_verbatim_prefix(Path::new(&long)), Path::new(&long));
+    }
+
+    #[test]
+    fn test_paths_are_sent_with_slashes() -> anyhow::Result<()> {
+        let path = PathBuf::from_iter(["src", "core", "mod.rs"]);
+        assert_eq!(to_wire(&path).to_str(), Some("src/core/mod.rs"));
+        assert_eq!(from_wire(to_wire(&path).into_owned()), path);
+
+        // Encoded exactly like a `PathBuf`, which older peers expect.
+        #[derive(Serialize, Deserialize)]
+        struct Message(
+            #[serde(with = "super")] PathBuf,
+            #[serde(with = "vec")] Vec<PathBuf>,
+        );
+        let encoded = bincode::serialize(&Message(path.clone(), vec![path.clone()]))?;
+        let wire = PathBuf::from("src/core/mod.rs");
+        assert_eq!(encoded, bincode::serialize(&(&wire, vec![&wire]))?);
+        let Message(decoded, decoded_paths) = bincode::deserialize(&encoded)?;
+        assert_eq!(decoded, path);
+        assert_eq!(decoded_paths, [path]);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_names_are_sent_as_bytes() -> anyhow::Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Message(#[serde(with = "super")] PathBuf);
+        let latin1 = Message(Path::new(OsStr::from_bytes(b"caf\xe9.txt")).to_owned());
+
+        let encoded = bincode::serialize(&latin1)?;
+        assert_eq!(encoded, bincode::serialize(&b"caf\xe9.txt"[..])?);
+        assert_eq!(bincode::deserialize::<Message>(&encoded)?, latin1);
+
+        let json = serde_json::to_string(&latin1)?;
+        assert_eq!(json, "[99,97,102,233,46,116,120,116]");
+        assert_eq!(serde_json::from_str::<Message>(&json)?, latin1);
+        let utf8 = Message("café.txt".into());
+        assert_eq!(serde_json::to_string(&utf8)?, "\"café.txt\"");
+        assert_eq!(serde_json::from_str::<Message>("\"café.txt\"")?, utf8);
+
+        Ok(())
+    }
+}
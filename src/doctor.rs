@@ -0,0 +1,463 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::bail;
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, process::Command, time::Instant};
+use tokio_tungstenite::client_async;
+use tungstenite::{
+    client::IntoClientRequest,
+    http::{header::AUTHORIZATION, HeaderValue},
+    Message,
+};
+
+use crate::core::{
+    message::{Handshake, ReceiverMessage, PROTOCOL_HEADER, PROTOCOL_VERSION},
+    timeout::with_timeout,
+};
+
+/// Round trips above this make watch mode feel sluggish.
+const SLOW_ROUND_TRIP: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+/// The outcome of one check, with what to do about it when it did not pass.
+#[derive(Debug)]
+pub struct Check {
+    pub status: Status,
+    pub summary: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(summary: impl Display) -> Self {
+        Self {
+            status: Status::Ok,
+            summary: summary.to_string(),
+            fix: None,
+        }
+    }
+
+    fn warning(summary: impl Display, fix: impl Display) -> Self {
+        Self {
+            status: Status::Warning,
+            summary: summary.to_string(),
+            fix: Some(fix.to_string()),
+        }
+    }
+
+    fn failed(summary: impl Display, fix: impl Display) -> Self {
+        Self {
+            status: Status::Failed,
+            summary: summary.to_string(),
+            fix: Some(fix.to_string()),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "WARNING",
+            Status::Failed => "FAILED",
+        };
+        write!(f, "{:<8}{}", label, self.summary)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n        fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// What `doctor` looks at: the directory to sync from and, optionally, a listener.
+#[derive(Debug)]
+pub struct Options {
+    pub from: PathBuf,
+    pub to: Option<String>,
+    pub key: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Runs every check, printing each as it completes. Returns whether none failed.
+pub async fn run(options: &Options) -> bool {
+    let mut checks = vec![];
+    let mut report = |check: Check| {
+        println!("{}", check);
+        checks.push(check);
+    };
+
+    let watchman = watchman_version().await;
+    report(match &watchman {
+        Ok(version) => Check::ok(format!("watchman {} is installed", version)),
+        Err(err) => Check::warning(
+            format!(
+                "watchman is not available, --watch will not work: {:#}",
+                err
+            ),
+            "install watchman, see https://facebook.github.io/watchman/docs/install",
+        ),
+    });
+
+    let dir_check = check_dir(&options.from);
+    let dir_ok = dir_check.status == Status::Ok;
+    report(dir_check);
+    if dir_ok {
+        if watchman.is_ok() {
+            report(check_watchable(&options.from).await);
+        }
+        if let Some(check) = check_inotify_limit(&options.from) {
+            report(check);
+        }
+        for check in check_filesystem(&options.from) {
+            report(check);
+        }
+    }
+
+    if let Some(to) = &options.to {
+        for check in check_listener(to, options.key.as_deref(), options.timeout).await {
+            report(check);
+        }
+    }
+
+    checks.iter().all(|check| check.status != Status::Failed)
+}
+
+async fn watchman_version() -> anyhow::Result<String> {
+    let output = Command::new("watchman").arg("--version").output().await?;
+    if !output.status.success() {
+        bail!("watchman --version failed with {}", output.status)
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn check_dir(dir: &Path) -> Check {
+    let shown = dir.display();
+    match fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => match fs::read_dir(dir) {
+            Ok(_) => Check::ok(format!("{} is a readable directory", shown)),
+            Err(err) => Check::failed(
+                format!("{} cannot be listed: {}", shown, err),
+                "give the user running white-caiman read and execute permission on it",
+            ),
+        },
+        Ok(_) => Check::ok(format!(
+            "{} is a file, it is synced and watched on its own",
+            shown
+        )),
+        Err(err) => Check::failed(
+            format!("{} cannot be read: {}", shown, err),
+            "pass an existing directory with --from",
+        ),
+    }
+}
+
+/// Asks watchman to watch the directory, as `sync --watch` does.
+async fn check_watchable(dir: &Path) -> Check {
+    let shown = dir.display();
+    let output = Command::new("watchman")
+        .arg("watch-project")
+        .arg(dir)
+        .output()
+        .await;
+    let reply = output
+        .map_err(anyhow::Error::from)
+        .and_then(|output| Ok(serde_json::from_slice::<serde_json::Value>(&output.stdout)?));
+    match reply {
+        Ok(reply) => match reply["error"].as_str() {
+            None => Check::ok(format!("watchman can watch {}", shown)),
+            Some(error) => Check::failed(
+                format!("watchman cannot watch {}: {}", shown, error),
+                "check watchman's log (watchman get-log) and its root restrictions in /etc/watchman.json",
+            ),
+        },
+        Err(err) => Check::failed(
+            format!("watchman did not answer about {}: {}", shown, err),
+            "restart it with `watchman shutdown-server` and try again",
+        ),
+    }
+}
+
+/// On Linux, watchman needs an inotify watch per directory.
+#[cfg(target_os = "linux")]
+fn check_inotify_limit(dir: &Path) -> Option<Check> {
+    let limit: usize = fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let dirs = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_dir())
+        .count();
+
+    Some(match dirs * 2 > limit {
+        false => Check::ok(format!(
+            "{} directories to watch, within the inotify limit of {}",
+            dirs, limit
+        )),
+        true => Check::warning(
+            format!(
+                "{} directories to watch, close to or over the inotify limit of {}",
+                dirs, limit
+            ),
+            format!(
+                "raise it with `sudo sysctl fs.inotify.max_user_watches={}`",
+                (dirs * 4).max(524288)
+            ),
+        ),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_inotify_limit(_dir: &Path) -> Option<Check> {
+    None
+}
+
+/// Case sensitivity and symlink support of the directory's filesystem, found by creating probe
+/// files in it.
+fn check_filesystem(dir: &Path) -> Vec<Check> {
+    if !dir.is_dir() {
+        return vec![];
+    }
+
+    let probe = dir.join(format!(".caiman-doctor-{}", std::process::id()));
+    if let Err(err) = fs::write(&probe, "") {
+        return vec![Check::warning(
+            format!(
+                "could not probe the filesystem of {}: {}",
+                dir.display(),
+                err
+            ),
+            "run doctor as a user that can write to the directory to check its filesystem",
+        )];
+    }
+
+    let mut checks = vec![];
+    let upper = dir.join(format!(".CAIMAN-DOCTOR-{}", std::process::id()));
+    checks.push(match upper.exists() {
+        false => Check::ok("the filesystem is case-sensitive"),
+        true => Check::warning(
+            "the filesystem is case-insensitive, paths differing only in case collide",
+            "avoid such paths, or sync to and from case-insensitive filesystems only",
+        ),
+    });
+
+    let link = dir.join(format!(".caiman-doctor-{}-link", std::process::id()));
+    checks.push(match symlink(&probe, &link) {
+        Ok(()) => Check::ok("the filesystem supports symlinks"),
+        Err(err) => Check::warning(
+            format!("the filesystem does not support symlinks: {}", err),
+            "keep symlinks out of directories synced to or from this filesystem",
+        ),
+    });
+
+    let _ = fs::remove_file(&link);
+    let _ = fs::remove_file(&probe);
+    checks
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Connects to the listener and asks for its directory state, as `verify` does, timing each
+/// step. A plain TCP probe would end a listener waiting for a sender, a verification does not.
+async fn check_listener(to: &str, key: Option<&str>, timeout: Duration) -> Vec<Check> {
+    let mut checks = vec![];
+    let mut request = match to.into_client_request() {
+        Ok(request) => request,
+        Err(err) => {
+            checks.push(Check::failed(
+                format!("{} is not a listener address: {}", to, err),
+                "pass it as ws://<host>:<port>",
+            ));
+            return checks;
+        }
+    };
+    match request.uri().scheme_str() {
+        Some("ws") => (),
+        Some("wss") => {
+            checks.push(Check::failed(
+                format!("{} uses TLS, which doctor does not check", to),
+                "check it with `white-caiman verify`, passing --ca and --client-cert as to sync",
+            ));
+            return checks;
+        }
+        _ => {
+            checks.push(Check::failed(
+                format!("{} is not a websocket address", to),
+                "pass it as ws://<host>:<port>",
+            ));
+            return checks;
+        }
+    }
+    request
+        .headers_mut()
+        .insert(PROTOCOL_HEADER, HeaderValue::from(PROTOCOL_VERSION));
+    if let Some(key) = key {
+        match format!("Bearer {}", key).parse() {
+            Ok(bearer) => {
+                request.headers_mut().insert(AUTHORIZATION, bearer);
+            }
+            Err(err) => {
+                checks.push(Check::failed(
+                    format!("the key cannot be sent: {}", err),
+                    "use a key made of printable ASCII characters",
+                ));
+                return checks;
+            }
+        }
+    }
+
+    let host = request.uri().host().unwrap_or_default().to_owned();
+    let port = request.uri().port_u16().unwrap_or(80);
+    let started = Instant::now();
+    let connect = TcpStream::connect((host.as_str(), port));
+    let stream = match with_timeout(timeout, "connecting to the listener", connect).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            checks.push(Check::failed(
+                format!("could not connect to {}: {}", to, err),
+                "check that the listener is running, on this port, and that no firewall is in the way",
+            ));
+            return checks;
+        }
+        Err(err) => {
+            checks.push(Check::failed(
+                format!("could not connect to {}: {}", to, err),
+                "check the host name, and that no firewall silently drops the connection",
+            ));
+            return checks;
+        }
+    };
+    let round_trip = started.elapsed();
+    checks.push(match round_trip > SLOW_ROUND_TRIP {
+        false => Check::ok(format!(
+            "connected to {} in {} (one round trip)",
+            to,
+            millis(round_trip)
+        )),
+        true => Check::warning(
+            format!(
+                "connected to {} in {}, round trips are slow",
+                to,
+                millis(round_trip)
+            ),
+            "raise --debounce to send fewer, larger batches, and --timeout if sessions time out",
+        ),
+    });
+
+    let started = Instant::now();
+    let res = with_timeout(timeout, "exchanging directory states", async {
+        let (mut socket, _) = client_async(request, stream).await?;
+        let handshake = bincode::serialize(&Handshake::Verify {
+            dests: vec![PathBuf::new()],
+        })?;
+        socket.send(Message::binary(handshake)).await?;
+        let reply = match socket.next().await {
+            Some(Ok(Message::Binary(reply))) => reply,
+            Some(Ok(Message::Close(Some(frame)))) => {
+                bail!("the listener refused the session: {}", frame.reason)
+            }
+            Some(Ok(_)) => bail!("unexpected reply"),
+            Some(Err(err)) => return Err(err.into()),
+            None => bail!("the listener closed the connection"),
+        };
+        let ReceiverMessage::Tree(tree) = bincode::deserialize(&reply)? else {
+            bail!("unexpected reply, expected the listener's directory state")
+        };
+        let _ = socket.close(None).await;
+        anyhow::Ok(tree.len())
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|res| res);
+
+    checks.push(match res {
+        Ok(entries) => Check::ok(format!(
+            "the listener answered in {} with {} entries",
+            millis(started.elapsed()),
+            entries
+        )),
+        Err(err) if key.is_none() => Check::failed(
+            format!("the listener did not answer: {:#}", err),
+            "pass --key if the listener was started with --key or --tenants",
+        ),
+        Err(err) => Check::failed(
+            format!("the listener did not answer: {:#}", err),
+            "check that the key matches the listener's or one of its tenants'",
+        ),
+    });
+
+    checks
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::file_tree::FileTree;
+    use tempfile::TempDir;
+    use tokio::{net::TcpListener, test};
+
+    #[test]
+    async fn test_checks_listener_and_filesystem() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("README.md"), "hello")?;
+        let checks = check_filesystem(dir.path());
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().all(|check| check.status != Status::Failed));
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let to = format!("ws://{}", listener.local_addr()?);
+        let tree = FileTree::new(dir.path()).await?;
+        let entries = tree.len();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut socket = tokio_tungstenite::accept_async(stream).await?;
+            let Some(Ok(Message::Binary(handshake))) = socket.next().await else {
+                bail!("no handshake")
+            };
+            let Handshake::Verify { .. } = bincode::deserialize(&handshake)? else {
+                bail!("not a verification")
+            };
+            let reply = bincode::serialize(&ReceiverMessage::Tree(tree))?;
+            socket.send(Message::binary(reply)).await?;
+            anyhow::Ok(())
+        });
+
+        let checks = check_listener(&to, None, Duration::from_secs(5)).await;
+        assert_eq!(checks.len(), 2, "{:?}", checks);
+        assert_eq!(checks[1].status, Status::Ok, "{}", checks[1]);
+        assert!(checks[1]
+            .summary
+            .ends_with(&format!("with {} entries", entries)));
+
+        let checks = check_listener("wss://localhost:1", None, Duration::from_secs(5)).await;
+        assert_eq!(checks[0].status, Status::Failed);
+
+        Ok(())
+    }
+}
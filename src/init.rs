@@ -0,0 +1,318 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::core::utils::quoted;
+
+/// Settings read from the current directory before parsing the command line, as `CAIMAN_*`
+/// variables. `init` writes it.
+pub const ENV_FILE: &str = "caiman.env";
+
+const DEFAULT_TO: &str = "ws://localhost:8080";
+
+/// Files whose presence tells the kind of project a directory holds, and what such projects
+/// generate that is not worth syncing.
+const PROJECT_KINDS: &[(&str, &[&str], &[&str])] = &[
+    ("Rust", &["Cargo.toml"], &["target"]),
+    ("Node.js", &["package.json"], &["node_modules"]),
+    (
+        "Python",
+        &["pyproject.toml", "setup.py", "requirements.txt"],
+        &["__pycache__", ".venv", ".pytest_cache", "*.pyc"],
+    ),
+    ("Maven", &["pom.xml"], &["target"]),
+    (
+        "Gradle",
+        &["build.gradle", "build.gradle.kts"],
+        &["build", ".gradle"],
+    ),
+    ("Git", &[".git"], &[".git"]),
+];
+
+/// What `init` asks for, written to the `ENV_FILE`.
+#[derive(Debug)]
+pub struct Settings {
+    pub from: String,
+    pub to: String,
+    pub excludes: Vec<String>,
+    pub key: Option<String>,
+}
+
+impl Settings {
+    pub fn to_env(&self) -> String {
+        let mut env = String::from("# Written by `white-caiman init`, flags and variables set in the environment take precedence.\n");
+        env.push_str(&format!("CAIMAN_FROM={}\n", self.from));
+        env.push_str(&format!("CAIMAN_TO={}\n", self.to));
+        if !self.excludes.is_empty() {
+            env.push_str(&format!("CAIMAN_EXCLUDE={}\n", self.excludes.join(",")));
+        }
+        if let Some(key) = &self.key {
+            env.push_str(&format!("CAIMAN_KEY={}\n", key));
+        }
+
+        env
+    }
+
+    /// The port of `to`, for the listener.
+    fn port(&self) -> &str {
+        self.to
+            .rsplit_once(':')
+            .map(|(_, port)| port.trim_end_matches('/'))
+            .filter(|port| !port.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit()))
+            .unwrap_or("8080")
+    }
+}
+
+/// The kinds of project found in `dir`, with the patterns they suggest excluding.
+pub fn detect(dir: &Path) -> Vec<(&'static str, &'static [&'static str])> {
+    PROJECT_KINDS
+        .iter()
+        .filter(|(_, markers, _)| markers.iter().any(|marker| dir.join(marker).exists()))
+        .map(|&(kind, _, excludes)| (kind, excludes))
+        .collect()
+}
+
+/// Asks for the settings on `output`, reading the answers from `input`. Empty answers pick the
+/// default shown in brackets.
+pub fn ask(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    env_file: &Path,
+) -> anyhow::Result<Settings> {
+    let from = prompt(input, output, "Directory to sync", ".")?;
+    let to = prompt(input, output, "Listener address", DEFAULT_TO)?;
+
+    let mut suggested: Vec<String> = vec![];
+    let kinds = detect(Path::new(&from));
+    match kinds.is_empty() {
+        true => writeln!(output, "No known kind of project detected in {}", from)?,
+        false => {
+            let names: Vec<_> = kinds.iter().map(|(kind, _)| *kind).collect();
+            writeln!(output, "Detected a {} project", names.join(" and "))?;
+        }
+    }
+    for &pattern in kinds.iter().flat_map(|(_, excludes)| excludes.iter()) {
+        if !suggested.iter().any(|suggested| suggested == pattern) {
+            suggested.push(pattern.to_owned());
+        }
+    }
+    // The file holds the key, which has no business on the listener.
+    let env_dir = env_file.parent().filter(|dir| !dir.as_os_str().is_empty());
+    if same_dir(env_dir.unwrap_or(Path::new(".")), Path::new(&from)) {
+        let name = env_file.file_name().and_then(|name| name.to_str());
+        suggested.extend(name.map(String::from));
+    }
+    let suggested = suggested.join(",");
+    let excludes = prompt(
+        input,
+        output,
+        "Names to exclude, comma-separated (- for none)",
+        &suggested,
+    )?;
+    let excludes = match excludes.as_str() {
+        "-" => vec![],
+        excludes => excludes
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+
+    let generate = prompt(input, output, "Generate a key for the listener? (y/n)", "y")?;
+    let key = match generate.to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(hex::encode(rand::random::<[u8; 32]>())),
+        _ => None,
+    };
+
+    Ok(Settings {
+        from,
+        to,
+        excludes,
+        key,
+    })
+}
+
+fn prompt(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: &str,
+) -> anyhow::Result<String> {
+    match default.is_empty() {
+        true => write!(output, "{}: ", question)?,
+        false => write!(output, "{} [{}]: ", question, default)?,
+    }
+    output.flush()?;
+
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        bail!("no answer to \"{}\"", question)
+    }
+
+    Ok(match answer.trim() {
+        "" => default.to_owned(),
+        answer => answer.to_owned(),
+    })
+}
+
+fn same_dir(dir1: &Path, dir2: &Path) -> bool {
+    match (dir1.canonicalize(), dir2.canonicalize()) {
+        (Ok(dir1), Ok(dir2)) => dir1 == dir2,
+        _ => dir1 == dir2,
+    }
+}
+
+/// Runs the wizard on the terminal and writes the settings to `env_file`.
+pub fn run(env_file: &Path, force: bool) -> anyhow::Result<()> {
+    if env_file.exists() && !force {
+        bail!(
+            "{} already exists, pass --force to overwrite it",
+            quoted(env_file)
+        )
+    }
+
+    let mut stdout = std::io::stdout();
+    let settings = ask(&mut std::io::stdin().lock(), &mut stdout, env_file)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // It may hold the key.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(env_file)
+        .and_then(|mut file| file.write_all(settings.to_env().as_bytes()))
+        .with_context(|| format!("writing {}", quoted(env_file)))?;
+
+    println!("\nSettings written to {}", quoted(env_file));
+    println!("Start the listener on the receiving machine with:");
+    let key = match &settings.key {
+        Some(key) => format!("CAIMAN_KEY={} ", key),
+        None => String::new(),
+    };
+    println!(
+        "  {}white-caiman listen --port {} --output-dir <OUTPUT_DIR>",
+        key,
+        settings.port()
+    );
+    match env_file == Path::new(ENV_FILE) {
+        true => println!("Then run `white-caiman sync` from this directory, with --watch to keep syncing changes"),
+        false => println!("Then move it to the directory you sync from as {} and run `white-caiman sync` there, with --watch to keep syncing changes", ENV_FILE),
+    }
+
+    Ok(())
+}
+
+/// Sets the variables of `env_file`, if it exists, that are not set in the environment already.
+/// Must run before any other thread is started.
+pub fn load_env_file(env_file: &Path) {
+    let Ok(contents) = std::fs::read_to_string(env_file) else {
+        return;
+    };
+
+    for entry in parse_env(&contents) {
+        match entry {
+            Ok((name, value)) => {
+                if std::env::var_os(&name).is_none() {
+                    std::env::set_var(name, value);
+                }
+            }
+            Err(line) => eprintln!(
+                "WARNING: ignoring line {} of {}, expected NAME=value",
+                line,
+                quoted(env_file)
+            ),
+        }
+    }
+}
+
+/// The `NAME=value` lines of an env file, skipping blank lines and `#` comments. Values may be
+/// double-quoted. Malformed lines are reported by number.
+fn parse_env(contents: &str) -> Vec<Result<(String, String), usize>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            let (name, value) = line.split_once('=').ok_or(i + 1)?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(i + 1);
+            }
+
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            Ok((name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_wizard_suggests_excludes_and_writes_env() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("Cargo.toml"), "[package]")?;
+        fs::create_dir(dir.path().join(".git"))?;
+
+        let from = dir.path().to_string_lossy().into_owned();
+        let env_file = dir.path().join(ENV_FILE);
+        let mut input = format!("{}\nws://192.168.1.20:9000\n\n\n", from).into_bytes();
+        let mut output = vec![];
+        let settings = ask(&mut input.as_slice(), &mut output, &env_file)?;
+
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("Detected a Rust and Git project"));
+        assert_eq!(settings.excludes, ["target", ".git", ENV_FILE]);
+        assert_eq!(settings.key.as_ref().map(String::len), Some(64));
+        assert_eq!(settings.port(), "9000");
+
+        let env = settings.to_env();
+        let parsed: Vec<_> = parse_env(&env)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parsed[0], ("CAIMAN_FROM".to_owned(), from));
+        assert_eq!(
+            parsed[2],
+            (
+                "CAIMAN_EXCLUDE".to_owned(),
+                format!("target,.git,{}", ENV_FILE)
+            )
+        );
+        assert_eq!(parsed[3].0, "CAIMAN_KEY");
+
+        input = b"\n\n-\nn\n".to_vec();
+        let settings = ask(&mut input.as_slice(), &mut vec![], &env_file)?;
+        assert_eq!(settings.to, DEFAULT_TO);
+        assert!(settings.excludes.is_empty());
+        assert!(settings.key.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_env() {
+        let parsed = parse_env("# comment\n\nCAIMAN_TO = \"ws://host:1\"\nnot a variable\n");
+        assert_eq!(
+            parsed,
+            [
+                Ok(("CAIMAN_TO".to_owned(), "ws://host:1".to_owned())),
+                Err(4)
+            ]
+        );
+    }
+}
@@ -0,0 +1,12 @@
+pub mod bench;
+pub mod conformance;
+pub mod core;
+pub mod doctor;
+pub mod init;
+pub mod manifest;
+pub mod mirror;
+pub mod receiver;
+pub mod relay;
+pub mod sender;
+pub mod snapshot;
+pub mod storage;
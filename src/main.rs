@@ -1,12 +1,18 @@
+use std::path::Path;
+
 use clap::Parser;
+use white_caiman::init;
 
 mod cli;
-mod core;
-mod receiver;
-mod sender;
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Before the runtime starts its threads, as it changes the environment.
+    init::load_env_file(Path::new(init::ENV_FILE));
     let cli = cli::Cli::parse();
+    run(cli);
+}
+
+#[tokio::main]
+async fn run(cli: cli::Cli) {
     cli.run().await;
 }
@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+
+use crate::core::{
+    file_tree::{FileTree, FileTreeNodeType, ScanOptions},
+    utils::{quoted, sha256_file},
+};
+
+/// The hash of the files a manifest lists: SHA-256 by default, as `sha256sum` writes, or the
+/// SHA-1 the sender and listener compare, as `sha1sum` writes, which needs no second hashing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algo {
+    #[default]
+    Sha256,
+    Sha1,
+}
+
+impl FromStr for Algo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Algo::Sha256),
+            "sha1" => Ok(Algo::Sha1),
+            _ => Err(format!("unknown hash '{}', expected sha256 or sha1", s)),
+        }
+    }
+}
+
+impl Algo {
+    /// The algorithm of a hash, by its length.
+    fn of(hash: &[u8]) -> Option<Self> {
+        match hash.len() {
+            32 => Some(Algo::Sha256),
+            20 => Some(Algo::Sha1),
+            _ => None,
+        }
+    }
+
+    /// Scans `dir`, only hashing its files while scanning for SHA-1.
+    async fn scan(self, dir: &Path, options: ScanOptions) -> anyhow::Result<FileTree> {
+        FileTree::new_with(
+            dir,
+            ScanOptions {
+                size_only: self == Algo::Sha256,
+                quick_check: false,
+                ..options
+            },
+        )
+        .await
+    }
+
+    /// The hash of a node of a tree from `scan`, `None` for what is not a file.
+    async fn hash(
+        self,
+        dir: &Path,
+        path: &Path,
+        typ: &FileTreeNodeType,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let hash = match (self, typ) {
+            (
+                Algo::Sha1,
+                FileTreeNodeType::File {
+                    sha1: Some(sha1), ..
+                },
+            ) => sha1.to_vec(),
+            (Algo::Sha256, FileTreeNodeType::File { .. }) => sha256_file(&dir.join(path))
+                .await
+                .with_context(|| format!("hashing {}", quoted(path)))?
+                .to_vec(),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(hash))
+    }
+}
+
+/// A `sha256sum`-style manifest of every file below `dir`, or `sha1sum`-style with `Algo::Sha1`:
+/// one `<hex>  <path>` line per file, with paths relative to `dir` and separated by `/`, so that
+/// `sha256sum -c` can check it too.
+pub async fn generate(dir: &Path, algo: Algo, options: ScanOptions) -> anyhow::Result<String> {
+    let tree = algo.scan(dir, options).await?;
+
+    let mut manifest = String::new();
+    for node in tree.iter() {
+        let Some(hash) = algo.hash(dir, &node.path, &node.typ).await? else {
+            continue;
+        };
+
+        manifest.push_str(&hex::encode(hash));
+        manifest.push_str("  ");
+        manifest.push_str(&manifest_path(&node.path)?);
+        manifest.push('\n');
+    }
+
+    Ok(manifest)
+}
+
+fn manifest_path(path: &Path) -> anyhow::Result<String> {
+    let Some(path) = path.to_str() else {
+        bail!("{} is not valid UTF-8", quoted(path));
+    };
+    if path.contains('\n') {
+        bail!("{} contains a line break", quoted(Path::new(path)));
+    }
+
+    Ok(path.replace(std::path::MAIN_SEPARATOR, "/"))
+}
+
+/// The entries of a manifest, by path.
+pub type Entries = BTreeMap<PathBuf, Vec<u8>>;
+
+/// Reads the lines written by `generate`, also accepting the binary mode marker of `sha256sum`
+/// and `sha1sum`. Returns the algorithm of the hashes, the same on every line.
+pub fn parse(manifest: &str) -> anyhow::Result<(Algo, Entries)> {
+    let mut algo = None;
+    let mut entries = BTreeMap::new();
+    for (number, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let parsed = line.split_once(' ').and_then(|(hash, path)| {
+            let hash = hex::decode(hash).ok()?;
+            let path = path.strip_prefix([' ', '*'])?;
+            Some((PathBuf::from(path), Algo::of(&hash)?, hash))
+        });
+        let Some((path, line_algo, hash)) = parsed else {
+            bail!(
+                "line {} is not a SHA-256 or SHA-1 and a path: {}",
+                number + 1,
+                line
+            );
+        };
+        if *algo.get_or_insert(line_algo) != line_algo {
+            bail!("line {} mixes SHA-256 and SHA-1 hashes", number + 1);
+        }
+        entries.insert(path, hash);
+    }
+
+    Ok((algo.unwrap_or_default(), entries))
+}
+
+/// How a directory compares with a manifest.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub checked: usize,
+    pub missing: Vec<PathBuf>,
+    pub mismatched: Vec<PathBuf>,
+    /// Files of the directory the manifest does not list, which are not a failure.
+    pub unlisted: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    pub fn failed(&self) -> usize {
+        self.missing.len() + self.mismatched.len()
+    }
+}
+
+/// Hashes the files of `dir` and compares them with the manifest at `manifest_path`, with the
+/// algorithm of its hashes.
+pub async fn check(
+    dir: &Path,
+    manifest_path: &Path,
+    options: ScanOptions,
+) -> anyhow::Result<CheckReport> {
+    let manifest = std::fs::read_to_string(manifest_path)
+        .with_context(|| format!("reading {}", quoted(manifest_path)))?;
+    let (algo, mut expected) =
+        parse(&manifest).with_context(|| format!("in {}", quoted(manifest_path)))?;
+
+    let tree = algo.scan(dir, options).await?;
+
+    let mut report = CheckReport::default();
+    for node in tree.iter() {
+        let Some(hash) = algo.hash(dir, &node.path, &node.typ).await? else {
+            continue;
+        };
+
+        let path = PathBuf::from(manifest_path_lossy(&node.path));
+        match expected.remove(&path) {
+            Some(expected) if expected == hash => report.checked += 1,
+            Some(_) => {
+                report.checked += 1;
+                report.mismatched.push(path);
+            }
+            None => report.unlisted.push(path),
+        }
+    }
+
+    report.checked += expected.len();
+    report.missing = expected.into_keys().collect();
+
+    Ok(report)
+}
+
+/// Paths that cannot be in a manifest are compared as is, so they show as unlisted.
+fn manifest_path_lossy(path: &Path) -> String {
+    manifest_path(path).unwrap_or_else(|_| path.to_string_lossy().into_owned())
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for path in &self.missing {
+            writeln!(f, "FAILED  {}: missing", path.display())?;
+        }
+        for path in &self.mismatched {
+            writeln!(f, "FAILED  {}: checksum mismatch", path.display())?;
+        }
+        if !self.unlisted.is_empty() {
+            writeln!(f, "Not in the manifest:")?;
+            for path in &self.unlisted {
+                writeln!(f, "  - {}", path.display())?;
+            }
+        }
+
+        match self.failed() {
+            0 => write!(f, "All {} files match the manifest", self.checked),
+            failed => write!(f, "{} of {} files failed", failed, self.checked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_check_against_generated_manifest() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("src"))?;
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}")?;
+        fs::write(dir.path().join("README.md"), "readme")?;
+
+        let manifest = generate(dir.path(), Algo::Sha256, ScanOptions::default()).await?;
+        assert_eq!(
+            manifest,
+            format!(
+                "{}  README.md\n{}  src/main.rs\n",
+                sha256_hex("readme"),
+                sha256_hex("fn main() {}")
+            )
+        );
+        let manifest_dir = TempDir::new()?;
+        let manifest_path = manifest_dir.path().join("SHA256SUMS");
+        fs::write(&manifest_path, manifest)?;
+
+        let report = check(dir.path(), &manifest_path, ScanOptions::default()).await?;
+        assert_eq!((report.checked, report.failed()), (2, 0));
+
+        // Manifests of SHA-1 hashes, as sha1sum writes, are checked with SHA-1.
+        let sha1_manifest = generate(dir.path(), Algo::Sha1, ScanOptions::default()).await?;
+        assert!(sha1_manifest.starts_with(&format!("{}  README.md\n", sha1_hex("readme"))));
+        let sha1_manifest_path = manifest_dir.path().join("SHA1SUMS");
+        fs::write(&sha1_manifest_path, sha1_manifest)?;
+        let report = check(dir.path(), &sha1_manifest_path, ScanOptions::default()).await?;
+        assert_eq!((report.checked, report.failed()), (2, 0));
+
+        fs::write(dir.path().join("README.md"), "edited")?;
+        fs::remove_file(dir.path().join("src/main.rs"))?;
+        fs::write(dir.path().join("extra.txt"), "extra")?;
+        let report = check(dir.path(), &manifest_path, ScanOptions::default()).await?;
+        assert_eq!(report.mismatched, [PathBuf::from("README.md")]);
+        assert_eq!(report.missing, [PathBuf::from("src/main.rs")]);
+        assert_eq!(report.unlisted, [PathBuf::from("extra.txt")]);
+
+        Ok(())
+    }
+
+    fn sha256_hex(contents: &str) -> String {
+        hex::encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            contents.as_bytes(),
+        ))
+    }
+
+    fn sha1_hex(contents: &str) -> String {
+        use sha1::{Digest, Sha1};
+        hex::encode(Sha1::digest(contents))
+    }
+}
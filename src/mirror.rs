@@ -0,0 +1,197 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    core::{roots::Roots, transport::loopback, utils::quoted},
+    receiver::{Receiver, ReceiverOptions},
+    sender::{Sender, SenderOptions},
+};
+
+/// Syncs `roots` into a local directory, running the sender and the receiver in this process
+/// and connecting them in memory rather than through a socket. Otherwise sessions go through the
+/// whole pipeline, watch mode and hooks included.
+pub struct Mirror {
+    roots: Roots,
+    out_dir: PathBuf,
+    sender: SenderOptions,
+    receiver: ReceiverOptions,
+}
+
+impl Mirror {
+    pub fn new(
+        roots: Roots,
+        out_dir: impl Into<PathBuf>,
+        sender: SenderOptions,
+        receiver: ReceiverOptions,
+    ) -> Self {
+        Self {
+            roots,
+            out_dir: out_dir.into(),
+            sender,
+            receiver,
+        }
+    }
+
+    pub async fn start(self, watch: bool) -> anyhow::Result<()> {
+        check_disjoint(&self.roots, &self.out_dir)?;
+        tokio::fs::create_dir_all(&self.out_dir)
+            .await
+            .with_context(|| format!("creating {}", quoted(&self.out_dir)))?;
+
+        let (loopback, listener) = loopback();
+        let receiver = Receiver::new(0, self.out_dir, self.receiver);
+        let sender = Sender::with_loopback(self.roots, loopback, self.sender);
+        // The receiver stops once the sender, and with it the loopback, is dropped.
+        let send = async move { sender.start(watch).await };
+        let (sent, received) = tokio::join!(send, receiver.start_loopback(listener));
+
+        sent.and(received)
+    }
+}
+
+/// Mirroring a directory into itself, or the other way around, would never settle in watch mode.
+fn check_disjoint(roots: &Roots, out_dir: &Path) -> anyhow::Result<()> {
+    let resolved_out_dir = resolve(out_dir).context("resolving the output directory")?;
+    for root in roots.iter() {
+        let Some(path) = resolve(&root.path) else {
+            continue;
+        };
+
+        if path.starts_with(&resolved_out_dir) || resolved_out_dir.starts_with(&path) {
+            bail!(
+                "cannot mirror {} into {}, one contains the other",
+                quoted(&root.path),
+                quoted(out_dir)
+            )
+        }
+    }
+
+    Ok(())
+}
+
+/// The canonical form of `path`, which may not exist yet.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    match path.canonicalize() {
+        Ok(path) => Some(path),
+        Err(_) => Some(resolve(path.parent()?)?.join(path.file_name()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{file_tree::FileTree, file_tree_diff::TreeDiff, message::DEFAULT_MAX_MESSAGE_SIZE},
+        receiver::{
+            metrics::Metrics,
+            middleware::{ConvertEol, LineEnding, MiddlewareChain},
+            ApplyOptions,
+        },
+    };
+    use std::{
+        fs,
+        net::Ipv4Addr,
+        sync::{atomic::Ordering, Arc},
+    };
+    use tempfile::TempDir;
+    use tokio::test;
+
+    fn receiver_options() -> ReceiverOptions {
+        ReceiverOptions {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            access: Default::default(),
+            path: None,
+            tls: None,
+            identities: None,
+            keepalive: SenderOptions::default().keepalive,
+            reconnect: false,
+            timeout: SenderOptions::default().timeout,
+            jobs: 8,
+            scan: Default::default(),
+            apply: Default::default(),
+            metrics: Default::default(),
+            stage_dir: None,
+            staged_ttl: None,
+            key: None,
+            control: None,
+            status_api: None,
+            hooks: Default::default(),
+            json_summary: false,
+            audit_log: None,
+            webhooks: Default::default(),
+            sync_state: None,
+            backups: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            memory: None,
+            applied: None,
+        }
+    }
+
+    #[test]
+    async fn test_mirror_syncs_local_directories() -> anyhow::Result<()> {
+        let from = TempDir::new()?;
+        let to = TempDir::new()?;
+        fs::create_dir_all(from.path().join("src/nested"))?;
+        fs::write(from.path().join("src/nested/lib.rs"), "pub fn lib() {}")?;
+        fs::write(from.path().join("README.md"), "# mirrored")?;
+        fs::write(to.path().join("stale.txt"), "left over")?;
+
+        let mirror = Mirror::new(
+            Roots::single(from.path()),
+            to.path(),
+            SenderOptions::default(),
+            receiver_options(),
+        );
+        mirror.start(false).await?;
+
+        let mirrored = FileTree::new(to.path()).await?;
+        let source = FileTree::new(from.path()).await?;
+        let diff = TreeDiff::from(&mirrored, &source);
+        assert!(diff.is_empty(), "{}", diff);
+
+        let nested = Mirror::new(
+            Roots::single(from.path()),
+            from.path().join("copy"),
+            SenderOptions::default(),
+            receiver_options(),
+        );
+        assert!(nested.start(false).await.is_err());
+        assert!(!from.path().join("copy").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_converted_files_are_not_sent_again() -> anyhow::Result<()> {
+        let from = TempDir::new()?;
+        let to = TempDir::new()?;
+        let out_dir = to.path().join("out");
+        fs::write(from.path().join("notes.txt"), "one\ntwo\n")?;
+
+        let metrics = Arc::new(Metrics::default());
+        for _ in 0..2 {
+            let options = ReceiverOptions {
+                apply: Arc::new(ApplyOptions {
+                    middleware: MiddlewareChain::new().with(ConvertEol::from(LineEnding::Crlf)),
+                    ..Default::default()
+                }),
+                metrics: metrics.clone(),
+                ..receiver_options()
+            };
+            let mirror = Mirror::new(
+                Roots::single(from.path()),
+                &out_dir,
+                SenderOptions::default(),
+                options,
+            );
+            mirror.start(false).await?;
+        }
+
+        assert_eq!(fs::read(out_dir.join("notes.txt"))?, b"one\r\ntwo\r\n");
+        assert_eq!(metrics.changes.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+}
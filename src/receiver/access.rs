@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The window connection rates are measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Source addresses tracked for rate limiting before those without a recent connection are
+/// forgotten.
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+/// A network, like `10.0.0.0/8` or `fd00::/8`. A bare address is a network of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in '{}'", s))?;
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 senders connecting to a dual-stack socket show up as IPv4-mapped IPv6 addresses.
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => matches_prefix(
+                network.to_bits().into(),
+                addr.to_bits().into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                matches_prefix(network.to_bits(), addr.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn matches_prefix(network: u128, addr: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift == bits || network >> shift == addr >> shift
+}
+
+/// Which senders may connect to a listener, and how often.
+#[derive(Debug, Clone, Default)]
+pub struct AccessOptions {
+    /// Only accept connections from these networks, or from anywhere when empty.
+    pub allow: Vec<Cidr>,
+    /// Connections accepted from each source address per minute.
+    pub max_rate: Option<u32>,
+    /// Connections open at once, including those being turned away because the listener is busy.
+    pub max_connections: Option<usize>,
+}
+
+/// Why a connection was closed before the websocket handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    NotAllowed,
+    RateLimited,
+    TooManyConnections,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::NotAllowed => write!(f, "address not allowed"),
+            Refusal::RateLimited => write!(f, "too many connections per minute"),
+            Refusal::TooManyConnections => write!(f, "too many open connections"),
+        }
+    }
+}
+
+/// Applies `AccessOptions` to incoming connections.
+#[derive(Debug, Default)]
+pub struct Admission {
+    options: AccessOptions,
+    /// Start of the current window and connections since, by source address.
+    recent: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    open: Arc<AtomicUsize>,
+}
+
+/// An admitted connection, counted as open until dropped.
+#[derive(Debug)]
+pub struct Admitted(Arc<AtomicUsize>);
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Admission {
+    pub fn new(options: AccessOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Lets a connection from `addr` in, or tells why not. Every connection from an allowed
+    /// address counts towards its rate, admitted or not.
+    pub fn admit(&self, addr: IpAddr) -> Result<Admitted, Refusal> {
+        let allow = &self.options.allow;
+        if !allow.is_empty() && !allow.iter().any(|network| network.contains(addr)) {
+            return Err(Refusal::NotAllowed);
+        }
+
+        if let Some(max_rate) = self.options.max_rate {
+            let now = Instant::now();
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() >= MAX_TRACKED_ADDRESSES {
+                recent.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+            }
+            let (start, count) = recent.entry(addr.to_canonical()).or_insert((now, 0));
+            if now.duration_since(*start) >= RATE_WINDOW {
+                (*start, *count) = (now, 0);
+            }
+            if *count >= max_rate {
+                return Err(Refusal::RateLimited);
+            }
+            *count += 1;
+        }
+
+        let open = self.open.fetch_add(1, Ordering::Relaxed);
+        let admitted = Admitted(self.open.clone());
+        if self
+            .options
+            .max_connections
+            .is_some_and(|max_connections| open >= max_connections)
+        {
+            return Err(Refusal::TooManyConnections);
+        }
+
+        Ok(admitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr = |s: &str| s.parse::<Cidr>().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(cidr("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(cidr("fd00::/8").contains(ip("fd12::1")));
+        assert!(!cidr("fd00::/8").contains(ip("10.0.0.1")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_admission_limits() {
+        let admission = Admission::new(AccessOptions {
+            allow: vec!["127.0.0.0/8".parse().unwrap()],
+            max_rate: Some(3),
+            max_connections: Some(2),
+        });
+        let local = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(
+            admission.admit("10.0.0.1".parse().unwrap()).unwrap_err(),
+            Refusal::NotAllowed
+        );
+        let first = admission.admit(local).unwrap();
+        let _second = admission.admit(local).unwrap();
+        assert_eq!(
+            admission.admit(local).unwrap_err(),
+            Refusal::TooManyConnections
+        );
+        drop(first);
+        assert_eq!(admission.admit(local).unwrap_err(), Refusal::RateLimited);
+        // Other addresses have their own rate.
+        assert!(admission.admit("127.0.0.2".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,1392 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use anyhow::bail;
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, watch, Semaphore},
+};
+use walkdir::WalkDir;
+
+use super::{
+    audit_log::{AuditLog, AuditRecord},
+    blobs::Blobs,
+    converted::{Conversion, Converted},
+    middleware::MiddlewareChain,
+    preallocate,
+    quota::{check_free_space, disk_usage, Quota},
+    reorder::ReorderBuffer,
+    tombstones::Tombstones,
+    trash::Trash,
+    versions::Versions,
+    wal::{remove_unpacked, WriteAheadLog},
+};
+use crate::core::{
+    activity::Activity,
+    budget::{MemoryBudget, Reservation},
+    compression::{archive_entries, decompress_dir, unpack_files},
+    file_tree::{is_same_file, linked_inode, ScanOptions},
+    message::{Chunk, FileChangeMessage, PackedFiles, Rejection, SyncMessage, MIN_DEDUP_SIZE},
+    policy,
+    specials::{create_fifo, special_kind, SpecialKind},
+    utils::{clone_file, hash_file, quoted},
+};
+
+/// Payloads from this size on are checked against the free disk space before being written.
+const FREE_SPACE_CHECKED_SIZE: u64 = 1024 * 1024;
+
+/// Files sent in chunks are written here, next to their final path, until the last one arrives.
+const PARTIAL_SUFFIX: &str = ".caiman-partial";
+
+/// A change refused with a `Rejection`, by its path.
+type Rejected = (PathBuf, Rejection);
+
+struct InFlight {
+    id: u64,
+    paths: Vec<PathBuf>,
+    // Never written to: the sender half is dropped when the apply task finishes.
+    done: watch::Receiver<()>,
+}
+
+/// Settings for how changes are written into the output directory.
+#[derive(Debug, Default)]
+pub struct ApplyOptions {
+    pub middleware: MiddlewareChain,
+    /// Keep local files that are newer than the incoming version, like `rsync --update`.
+    pub update_only: bool,
+    /// Only create entries missing from the output directory, never modify or delete existing
+    /// ones.
+    pub ignore_existing: bool,
+    /// Refuse changes that would grow the output directory past this.
+    pub quota: Option<Quota>,
+    /// Allocate edited files to their final size before writing them, and check that the
+    /// initial sync fits on disk before requesting anything.
+    pub preallocate: bool,
+    /// Hold deletions back as tombstones instead of applying them right away.
+    pub tombstones: Option<Tombstones>,
+    /// Keep previous versions of the files that are overwritten or deleted.
+    pub versions: Option<Versions>,
+    /// Move deleted entries to the trash instead of removing them.
+    pub trash: Option<Trash>,
+    /// Recreate the FIFOs senders send as `FifoCreated` changes.
+    pub specials: bool,
+    /// The files written during the session, for `FileFromHash` changes to be copied from.
+    pub blobs: Blobs,
+    /// The files the middleware rewrote, for scans to be compared as if written as sent.
+    pub converted: Converted,
+    /// Record the changes being applied, to recover from a crash on startup.
+    pub wal: Option<WriteAheadLog>,
+}
+
+/// Applies incoming changes concurrently, with at most `jobs` running at once. Changes are first
+/// held back until the changes they depend on have arrived, then each one waits for them and for
+/// every earlier change whose paths overlap with its own, so per-path order is preserved.
+pub struct ApplyPipeline {
+    out_dir: PathBuf,
+    permits: Arc<Semaphore>,
+    options: Arc<ApplyOptions>,
+    reorder: ReorderBuffer,
+    in_flight: Vec<InFlight>,
+    failed: Arc<AtomicU64>,
+    activity: Option<Arc<Mutex<Activity>>>,
+    /// The log of applied changes, and the peer they came from.
+    audit: Option<(Arc<AuditLog>, String)>,
+    /// Bounds the payloads of the changes received and not applied yet.
+    budget: Option<MemoryBudget>,
+    /// The budget reserved by changes not spawned yet, by id.
+    reservations: HashMap<u64, Reservation>,
+    /// Changes refused before anything was written, to tell the sender about.
+    rejected: (
+        mpsc::UnboundedSender<Rejected>,
+        mpsc::UnboundedReceiver<Rejected>,
+    ),
+}
+
+impl ApplyPipeline {
+    pub fn new(out_dir: &Path, jobs: usize, options: Arc<ApplyOptions>) -> Self {
+        Self {
+            out_dir: out_dir.to_owned(),
+            permits: Arc::new(Semaphore::new(jobs.max(1))),
+            options,
+            reorder: ReorderBuffer::default(),
+            in_flight: vec![],
+            failed: Default::default(),
+            activity: None,
+            audit: None,
+            budget: None,
+            reservations: HashMap::new(),
+            rejected: mpsc::unbounded_channel(),
+        }
+    }
+
+    /// Records the changes applied, and those that could not be, in `activity` too.
+    pub fn reporting_to(mut self, activity: Arc<Mutex<Activity>>) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Logs the changes applied successfully to `log`, as sent by `peer`.
+    pub fn auditing(mut self, log: Option<Arc<AuditLog>>, peer: String) -> Self {
+        self.audit = log.map(|log| (log, peer));
+        self
+    }
+
+    /// Holds the payloads of the changes received and not applied yet within `budget`, see
+    /// `reserve`.
+    pub fn within(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Reserves the budget for the payload of `message` until it is applied, waiting for room
+    /// before it is submitted. While changes are held back, waiting could keep their
+    /// dependencies from ever being received, so they only reserve what is left.
+    pub async fn reserve(&mut self, message: &SyncMessage) {
+        let Some(budget) = &self.budget else {
+            return;
+        };
+
+        let size = message.change.payload_len();
+        let reservation = match self.held_back() {
+            0 => Some(budget.reserve(size).await),
+            _ => budget.try_reserve(size),
+        };
+        if let Some(reservation) = reservation {
+            self.reservations.insert(message.id, reservation);
+        }
+    }
+
+    /// Logs and records a change applied outside of the pipeline, like the deletions of a diff.
+    pub fn audit(&self, change: &FileChangeMessage) {
+        if let Some(activity) = &self.activity {
+            activity.lock().unwrap().changed(change);
+        }
+        if let Some((log, peer)) = &self.audit {
+            append(log, AuditRecord::of(peer, change), self.activity.as_ref());
+        }
+    }
+
+    pub fn submit(&mut self, message: SyncMessage) {
+        for message in self.reorder.push(message) {
+            self.spawn(message);
+        }
+    }
+
+    /// Records that the message with this id was dropped by the sender.
+    pub fn skip(&mut self, id: u64) {
+        for message in self.reorder.skip(id) {
+            self.spawn(message);
+        }
+    }
+
+    /// Number of received changes still waiting for one of their dependencies.
+    pub fn held_back(&self) -> usize {
+        self.reorder.pending()
+    }
+
+    /// Number of released changes not applied yet.
+    pub fn running(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|task| task.done.has_changed().is_ok())
+            .count()
+    }
+
+    /// Waits for the next change refused with a `Rejection`.
+    pub async fn rejected(&mut self) -> Rejected {
+        // The pipeline keeps a sender, so the channel never closes.
+        self.rejected.1.recv().await.expect("an open channel")
+    }
+
+    /// Number of changes that could not be applied so far.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn spawn(&mut self, message: SyncMessage) {
+        self.in_flight
+            .retain(|task| task.done.has_changed().is_ok());
+
+        let paths: Vec<_> = message
+            .change
+            .paths()
+            .into_iter()
+            .map(Path::to_owned)
+            .collect();
+        let dependencies: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|task| message.depends_on.contains(&task.id) || overlaps(&task.paths, &paths))
+            .map(|task| task.done.clone())
+            .collect();
+
+        let message_id = message.id;
+        let reservation = self.reservations.remove(&message_id);
+        let (done_tx, done_rx) = watch::channel(());
+        let out_dir = self.out_dir.clone();
+        let permits = self.permits.clone();
+        let options = self.options.clone();
+        let failed = self.failed.clone();
+        let activity = self.activity.clone();
+        let audit = self.audit.clone();
+        let rejected = self.rejected.0.clone();
+        tokio::spawn(async move {
+            let _done = done_tx;
+            let _reservation = reservation;
+            for mut dependency in dependencies {
+                let _ = dependency.changed().await;
+            }
+
+            let _permit = permits.acquire_owned().await;
+            let record = audit
+                .as_ref()
+                .map(|(_, peer)| AuditRecord::of(peer, &message.change));
+            let path = message.change.path().to_owned();
+            let label = message.change.label();
+            match apply_change(&out_dir, message.change, &options).await {
+                Ok(()) => {
+                    if let Some(activity) = &activity {
+                        activity.lock().unwrap().record_change(label, path);
+                    }
+                    if let (Some((log, _)), Some(record)) = (&audit, record) {
+                        append(log, record, activity.as_ref());
+                    }
+                }
+                Err(err) => {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    eprintln!(
+                        "An error occurred while handling message {}: {}",
+                        message.id, err
+                    );
+                    if let Some(activity) = activity {
+                        let error = format!("applying message {}: {:#}", message.id, err);
+                        activity.lock().unwrap().error(error);
+                    }
+                    if let Some(rejection) = err.downcast_ref::<Rejection>() {
+                        let _ = rejected.send((path, rejection.clone()));
+                    }
+                }
+            }
+        });
+
+        self.in_flight.push(InFlight {
+            id: message_id,
+            paths,
+            done: done_rx,
+        });
+    }
+
+    /// Waits for every released change to be applied.
+    pub async fn drain(&mut self) {
+        for mut task in self.in_flight.drain(..) {
+            let _ = task.done.changed().await;
+        }
+    }
+}
+
+/// Audit log failures are reported, but do not fail the change, which is already applied.
+fn append(log: &AuditLog, record: AuditRecord, activity: Option<&Arc<Mutex<Activity>>>) {
+    if let Err(err) = log.append(record) {
+        eprintln!("{:#}", err);
+        if let Some(activity) = activity {
+            activity.lock().unwrap().error(format!("{:#}", err));
+        }
+    }
+}
+
+fn overlaps(paths1: &[PathBuf], paths2: &[PathBuf]) -> bool {
+    paths1.iter().any(|path1| {
+        paths2
+            .iter()
+            .any(|path2| path1.starts_with(path2) || path2.starts_with(path1))
+    })
+}
+
+pub async fn apply_change(
+    out_dir: &Path,
+    message: FileChangeMessage,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let middleware = &options.middleware;
+    let (message, checksum) = match policy::decode(message)? {
+        FileChangeMessage::Checksummed(change, sha1) => {
+            check_received(&change, sha1)?;
+            (*change, Some(sha1))
+        }
+        message => (message, None),
+    };
+    if let FileChangeMessage::FileBatch(dir, packed) = message {
+        return apply_batch(out_dir, &dir, packed, options).await;
+    }
+    if options.ignore_existing && touches_existing(out_dir, &message).await {
+        println!("Keeping {}, it already exists", quoted(message.path()));
+        return Ok(());
+    }
+
+    if let Some(tombstones) = &options.tombstones {
+        match &message {
+            FileChangeMessage::FileDeleted(path) => return tombstones.record(path, false),
+            FileChangeMessage::DirectoryDeleted(path) => return tombstones.record(path, true),
+            FileChangeMessage::DirectoryContentsEdited(_) => (),
+            message => {
+                let target = match message {
+                    FileChangeMessage::Rename(old_path, new_path) => {
+                        tombstones.revive(out_dir, old_path)?;
+                        new_path
+                    }
+                    message => message.path(),
+                };
+                // What replaces an entry whose deletion was held back deletes it for good.
+                if let Some(tombstone) = tombstones.revive(out_dir, target)? {
+                    remove_entry(out_dir, target, tombstone.dir, options).await?;
+                }
+            }
+        }
+    }
+
+    let _entry = match &options.wal {
+        Some(wal) => wal.begin(out_dir, &message).await?,
+        None => None,
+    };
+    match message {
+        FileChangeMessage::FileCreated(path) => {
+            keep_version(out_dir, &path, options)?;
+            let file_path = out_dir.join(&path);
+            remove_special(&file_path).await?;
+            tokio::fs::File::create(file_path).await?;
+            options.converted.forget(&path);
+        }
+        FileChangeMessage::FifoCreated(path) => create_fifo_at(out_dir, &path, options).await?,
+        FileChangeMessage::FileDeleted(path) => {
+            remove_entry(out_dir, &path, false, options).await?;
+            options.converted.forget(&path);
+        }
+        FileChangeMessage::Rename(old_path, new_path) => {
+            let from = out_dir.join(&old_path);
+            let to = out_dir.join(new_path.as_path());
+            // Replayed, the entry was renamed already.
+            if !exists(&from).await && exists(&to).await {
+                return Ok(());
+            }
+            keep_version(out_dir, &new_path, options)?;
+            resize(options, freed(&to, options), 0)?;
+            tokio::fs::rename(from, to).await?;
+            options.converted.forget(&new_path);
+            options.converted.rename(&old_path, &new_path);
+        }
+        FileChangeMessage::EmptyDirectoryCreated(path) => {
+            let dir_path = out_dir.join(path);
+            create_dir(&dir_path).await?;
+        }
+        FileChangeMessage::DirectoryCreated(path, compressed) => {
+            let dir_path = out_dir.join(&path);
+            // The compressed size is a lower bound of the unpacked one.
+            check_payload_space(out_dir, compressed.len() as u64)?;
+            // Replayed, the archive is unpacked again over what it unpacked before. Only its
+            // entries are rolled back then, not what the directory held before.
+            let existing = match dir_path.is_dir() {
+                true => Some(archive_entries(&compressed).await?),
+                false => None,
+            };
+            // The files the archive overwrites are kept as versions first, as edited files are.
+            let overwritten = existing.iter().flatten().filter(|entry| {
+                entry
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            });
+            for entry in overwritten {
+                keep_version(out_dir, &path.join(entry), options)?;
+            }
+            let before = usage(&dir_path, options);
+            create_dir(&dir_path).await?;
+            decompress_dir(dir_path.as_path(), compressed.as_ref()).await?;
+            transform_unpacked(out_dir, &dir_path, options).await?;
+            // Archives only reveal their size once unpacked.
+            if let Err(err) = resize(options, before, usage(&dir_path, options)) {
+                match existing {
+                    Some(entries) => {
+                        remove_unpacked(&dir_path, entries)?;
+                        resize(options, before, usage(&dir_path, options))?;
+                    }
+                    None => tokio::fs::remove_dir_all(dir_path).await?,
+                }
+                return Err(err);
+            }
+        }
+        FileChangeMessage::DirectoryDeleted(path) => {
+            remove_entry(out_dir, &path, true, options).await?;
+            options.converted.forget(&path);
+        }
+        FileChangeMessage::FileEdited(path, contents, mtime) => {
+            if options.update_only && is_newer(&out_dir.join(&path), mtime).await {
+                println!("Keeping {}, the local copy is newer", quoted(&path));
+                return Ok(());
+            }
+
+            let (contents, conversion) = match middleware.applies_to(&path) {
+                true => {
+                    let converted = middleware.transform(&path, contents.clone());
+                    let conversion = Conversion::of(&contents, &converted, mtime);
+                    (converted, conversion)
+                }
+                false => (contents, None),
+            };
+
+            // Only contents written as sent can be copied for `FileFromHash` changes.
+            let sha1 = (!middleware.applies_to(&path)).then(|| Sha1::digest(&contents).into());
+            let size = contents.len() as u64;
+            keep_version(out_dir, &path, options)?;
+            let file_path = out_dir.join(&path);
+            remove_special(&file_path).await?;
+            resize(options, usage(&file_path, options), size)?;
+            if let Err(err) = check_payload_space(out_dir, size) {
+                resize(options, size, usage(&file_path, options))?;
+                return Err(err);
+            }
+            // Checksummed contents are read back, and with a write-ahead log all contents are
+            // written, through a partial file replacing the file once complete.
+            let written = checksum.map(|_| sha1.unwrap_or_else(|| Sha1::digest(&contents).into()));
+            let write_path = match written.is_some() || options.wal.is_some() {
+                true => partial_path(&file_path),
+                false => file_path.clone(),
+            };
+            match options.preallocate {
+                true => preallocate::write(&write_path, &contents).await?,
+                false => tokio::fs::write(&write_path, contents).await?,
+            }
+            if let Some(written) = written {
+                if let Err(err) = check_written(&write_path, 0, size, written).await {
+                    resize(options, size, usage(&file_path, options))?;
+                    return Err(err);
+                }
+            }
+            set_mtime(&write_path, mtime).await?;
+            if write_path != file_path {
+                tokio::fs::rename(&write_path, &file_path).await?;
+            }
+            if let Some(sha1) = sha1 {
+                options.blobs.record(&path, size, sha1);
+            }
+            options.converted.record(&path, conversion);
+        }
+        FileChangeMessage::FileFromHash(path, sha1, mtime) => {
+            copy_blob(out_dir, &path, sha1, mtime, options).await?
+        }
+        FileChangeMessage::HardlinkCreated(target, path) => {
+            link_file(out_dir, &target, &path, options).await?
+        }
+        FileChangeMessage::FileChunk(path, chunk) => {
+            write_chunk(out_dir, &path, chunk, checksum, options).await?
+        }
+        FileChangeMessage::GzippedFileEdited(..)
+        | FileChangeMessage::GzippedDirectoryCreated(..) => {
+            unreachable!("gzipped payloads are decoded before being applied")
+        }
+        FileChangeMessage::FileBatch(..) => unreachable!("batches are applied file by file"),
+        FileChangeMessage::Checksummed(..) => unreachable!("checksums are checked before applying"),
+        // Resolved by the session, which re-exchanges the directory's subtree.
+        FileChangeMessage::DirectoryContentsEdited(_) => (),
+    }
+
+    Ok(())
+}
+
+/// Applies the files of a batch one by one, as if each had been sent on its own. Every file is
+/// attempted, the first failure is returned.
+async fn apply_batch(
+    out_dir: &Path,
+    dir: &Path,
+    packed: PackedFiles,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let mut result = Ok(());
+    for file in unpack_files(&packed.archive).await? {
+        let change = FileChangeMessage::FileEdited(dir.join(file.path), file.contents, file.mtime);
+        let applied = Box::pin(apply_change(out_dir, change, options)).await;
+        if result.is_ok() {
+            result = applied;
+        }
+    }
+
+    result
+}
+
+/// Writes a chunk of the file at `path` into a partial file next to it, which replaces the file
+/// once complete. The first chunk is checked like a whole edited file would be, later ones are
+/// dropped if it was refused. The holes left between the chunks of sparse files stay holes.
+/// Chunks with a `checksum` are read back once written, and drop the partial file if they differ.
+async fn write_chunk(
+    out_dir: &Path,
+    path: &Path,
+    chunk: Chunk,
+    checksum: Option<[u8; 20]>,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let file_path = out_dir.join(path);
+    let partial_path = partial_path(&file_path);
+
+    let mut partial = match chunk.offset {
+        0 => {
+            if options.update_only && is_newer(&file_path, chunk.mtime).await {
+                println!("Keeping {}, the local copy is newer", quoted(path));
+                return Ok(());
+            }
+
+            // Replayed, the first chunk starts over the partial file it made room for.
+            let restarted = match exists(&partial_path).await {
+                true => chunk.size,
+                false => 0,
+            };
+            resize(options, usage(&file_path, options) + restarted, chunk.size)?;
+            if let Err(err) = check_payload_space(out_dir, chunk.size) {
+                resize(options, chunk.size, usage(&file_path, options))?;
+                return Err(err);
+            }
+            let partial = tokio::fs::File::create(&partial_path).await?;
+            if options.preallocate {
+                preallocate::allocate(&partial, &partial_path, chunk.size).await?;
+            }
+            partial
+        }
+        _ => match tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&partial_path)
+            .await
+        {
+            Ok(partial) => partial,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        },
+    };
+
+    partial.seek(std::io::SeekFrom::Start(chunk.offset)).await?;
+    partial.write_all(&chunk.data).await?;
+    partial.flush().await?;
+    if let Some(sha1) = checksum {
+        let len = chunk.data.len() as u64;
+        if let Err(err) = check_written(&partial_path, chunk.offset, len, sha1).await {
+            resize(options, chunk.size, usage(&file_path, options))?;
+            return Err(err);
+        }
+    }
+    if !chunk.is_last() {
+        return Ok(());
+    }
+    // Sparse files may end with a hole, which no chunk wrote.
+    partial.set_len(chunk.size).await?;
+    drop(partial);
+
+    let (sha1, conversion) = match options.middleware.applies_to(path) {
+        true => (
+            None,
+            transform_file(path, &partial_path, chunk.mtime, options).await?,
+        ),
+        false if chunk.size >= MIN_DEDUP_SIZE => (Some(hash_file(&partial_path).await?), None),
+        false => (None, None),
+    };
+    set_mtime(&partial_path, chunk.mtime).await?;
+    keep_version(out_dir, path, options)?;
+    tokio::fs::rename(partial_path, file_path).await?;
+    if let Some(sha1) = sha1 {
+        options.blobs.record(path, chunk.size, sha1);
+    }
+    options.converted.record(path, conversion);
+
+    Ok(())
+}
+
+/// Writes the file at `path` as a copy of a file already written with the contents with `sha1`.
+/// The copy goes through a partial file like chunks do, so that the file is replaced at once.
+async fn copy_blob(
+    out_dir: &Path,
+    path: &Path,
+    sha1: [u8; 20],
+    mtime: SystemTime,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let file_path = out_dir.join(path);
+    if options.update_only && is_newer(&file_path, mtime).await {
+        println!("Keeping {}, the local copy is newer", quoted(path));
+        return Ok(());
+    }
+
+    let source = options.blobs.find(out_dir, &sha1).await?;
+    let size = tokio::fs::metadata(&source).await?.len();
+    resize(options, usage(&file_path, options), size)?;
+    if let Err(err) = check_payload_space(out_dir, size) {
+        resize(options, size, usage(&file_path, options))?;
+        return Err(err);
+    }
+
+    let partial_path = partial_path(&file_path);
+    let _ = tokio::fs::remove_file(&partial_path).await;
+    clone_file(&source, &partial_path)?;
+    let transformed = options.middleware.applies_to(path);
+    let conversion = match transformed {
+        true => transform_file(path, &partial_path, mtime, options).await?,
+        false => None,
+    };
+    set_mtime(&partial_path, mtime).await?;
+    keep_version(out_dir, path, options)?;
+    tokio::fs::rename(partial_path, file_path).await?;
+    if !transformed {
+        options.blobs.record(path, size, sha1);
+    }
+    options.converted.record(path, conversion);
+
+    Ok(())
+}
+
+/// Fails with `Rejection::ChecksumMismatch` unless the contents or the data `change` carries have
+/// `sha1`, as they did when sent.
+fn check_received(change: &FileChangeMessage, sha1: [u8; 20]) -> anyhow::Result<()> {
+    let received: [u8; 20] = match change {
+        FileChangeMessage::FileEdited(_, contents, _) => Sha1::digest(contents).into(),
+        FileChangeMessage::FileChunk(_, chunk) => Sha1::digest(&chunk.data).into(),
+        _ => return Ok(()),
+    };
+    match received == sha1 {
+        true => Ok(()),
+        false => Err(Rejection::ChecksumMismatch.into()),
+    }
+}
+
+/// Reads back the `len` bytes written at `offset` of the partial file at `path`, removing the file
+/// and failing with `Rejection::ChecksumMismatch` unless they have `sha1`.
+async fn check_written(path: &Path, offset: u64, len: u64, sha1: [u8; 20]) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut written = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut written).await?;
+    if <[u8; 20]>::from(Sha1::digest(&written)) == sha1 {
+        return Ok(());
+    }
+
+    tokio::fs::remove_file(path).await?;
+    Err(Rejection::ChecksumMismatch.into())
+}
+
+pub(super) fn partial_path(file_path: &Path) -> PathBuf {
+    let mut partial_path = file_path.to_owned().into_os_string();
+    partial_path.push(PARTIAL_SUFFIX);
+    PathBuf::from(partial_path)
+}
+
+/// Leaves the partial files of files being received out of `scan` too, for scans sending the
+/// output directory on, which would send them half-written.
+pub fn excluding_partials(scan: ScanOptions) -> ScanOptions {
+    scan.excluding(&format!("*{}", PARTIAL_SUFFIX))
+}
+
+/// Deletes the file or directory at `path`, or moves it to the trash. An entry that is gone
+/// already, deleted by an earlier delivery of the same change, is left that way.
+pub(super) async fn remove_entry(
+    out_dir: &Path,
+    path: &Path,
+    dir: bool,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    if !exists(&out_dir.join(path)).await {
+        return Ok(());
+    }
+    if options.versions.is_some() {
+        for entry in WalkDir::new(out_dir.join(path)).into_iter() {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                keep_version(out_dir, entry.path().strip_prefix(out_dir)?, options)?;
+            }
+        }
+    }
+
+    let size = usage(&out_dir.join(path), options);
+    if let Some(trash) = options.trash {
+        // The local trash is still part of the output directory.
+        return match trash.put(out_dir, path)? {
+            Trash::System => resize(options, size, 0),
+            Trash::Local => Ok(()),
+        };
+    }
+
+    let path = out_dir.join(path);
+    match dir {
+        true => tokio::fs::remove_dir_all(path).await?,
+        false => tokio::fs::remove_file(path).await?,
+    }
+    resize(options, size, 0)
+}
+
+/// Keeps the current contents of the file at `path`, if there is one, as one of its versions.
+/// Versions count towards the quota.
+fn keep_version(out_dir: &Path, path: &Path, options: &ApplyOptions) -> anyhow::Result<()> {
+    let Some(versions) = &options.versions else {
+        return Ok(());
+    };
+    let file_path = out_dir.join(path);
+    if !file_path.is_file() {
+        return Ok(());
+    }
+
+    resize(options, 0, usage(&file_path, options))?;
+    let pruned = versions.keep(out_dir, path)?;
+    resize(options, pruned, 0)
+}
+
+/// Checks that large payloads fit on disk, small ones are not worth measuring the free space for.
+fn check_payload_space(out_dir: &Path, size: u64) -> anyhow::Result<()> {
+    match size >= FREE_SPACE_CHECKED_SIZE {
+        true => check_free_space(out_dir, size),
+        false => Ok(()),
+    }
+}
+
+/// Disk usage of `path`, only measured when there is a quota to account it to.
+fn usage(path: &Path, options: &ApplyOptions) -> u64 {
+    match options.quota {
+        Some(_) => disk_usage(path),
+        None => 0,
+    }
+}
+
+/// What replacing the entry at `path` frees: nothing for a file whose contents other hard links
+/// keep, as when a replay renames or links over one of the links it created before.
+fn freed(path: &Path, options: &ApplyOptions) -> u64 {
+    match std::fs::metadata(path) {
+        Ok(metadata) if linked_inode(&metadata).is_some() => 0,
+        _ => usage(path, options),
+    }
+}
+
+fn resize(options: &ApplyOptions, from: u64, to: u64) -> anyhow::Result<()> {
+    match &options.quota {
+        Some(quota) => quota.resize(from, to),
+        None => Ok(()),
+    }
+}
+
+async fn touches_existing(out_dir: &Path, message: &FileChangeMessage) -> bool {
+    let target = match message {
+        FileChangeMessage::FileDeleted(_) | FileChangeMessage::DirectoryDeleted(_) => return true,
+        FileChangeMessage::DirectoryContentsEdited(_) => return false,
+        FileChangeMessage::Rename(_, new_path) => new_path,
+        message => message.path(),
+    };
+
+    tokio::fs::try_exists(out_dir.join(target))
+        .await
+        .unwrap_or(true)
+}
+
+/// Replaces the file at `path` with a hard link to the file at `target`, written earlier in the
+/// session, failing with `Rejection::MissingContents` if it is not there anymore.
+async fn link_file(
+    out_dir: &Path,
+    target: &Path,
+    path: &Path,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    let target_path = out_dir.join(target);
+    if !tokio::fs::metadata(&target_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_file())
+    {
+        return Err(Rejection::MissingContents.into());
+    }
+
+    let file_path = out_dir.join(path);
+    if is_same_file(&target_path, &file_path) {
+        return Ok(());
+    }
+    keep_version(out_dir, path, options)?;
+    resize(options, freed(&file_path, options), 0)?;
+    match tokio::fs::remove_file(&file_path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => (),
+    }
+    tokio::fs::hard_link(target_path, file_path).await?;
+
+    Ok(())
+}
+
+/// Replaces the entry at `path` with a FIFO, unless it is one already. Refused unless the
+/// receiver recreates FIFOs, as they block whatever reads them until something writes to them.
+async fn create_fifo_at(out_dir: &Path, path: &Path, options: &ApplyOptions) -> anyhow::Result<()> {
+    if !options.specials {
+        bail!(
+            "{} is a FIFO, which are only recreated with --specials",
+            quoted(path)
+        );
+    }
+
+    let fifo_path = out_dir.join(path);
+    match tokio::fs::symlink_metadata(&fifo_path).await {
+        Ok(metadata) if special_kind(metadata.file_type()) == Some(SpecialKind::Fifo) => {
+            return Ok(())
+        }
+        Ok(metadata) if metadata.is_dir() => {
+            bail!("{} is a directory, not replaced by a FIFO", quoted(path))
+        }
+        Ok(_) => {
+            keep_version(out_dir, path, options)?;
+            resize(options, usage(&fifo_path, options), 0)?;
+            tokio::fs::remove_file(&fifo_path).await?;
+        }
+        Err(_) => (),
+    }
+    create_fifo(&fifo_path)?;
+
+    Ok(())
+}
+
+/// Whether there is an entry at `path`, a dangling symlink included.
+async fn exists(path: &Path) -> bool {
+    tokio::fs::symlink_metadata(path).await.is_ok()
+}
+
+/// Creates the directory at `path`, unless there is one already.
+async fn create_dir(path: &Path) -> anyhow::Result<()> {
+    match tokio::fs::create_dir(path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+/// Removes the special file at `path` that a regular file replaces, which writing to would block
+/// or fail instead.
+async fn remove_special(path: &Path) -> anyhow::Result<()> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if special_kind(metadata.file_type()).is_some() => {
+            Ok(tokio::fs::remove_file(path).await?)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Gives a written file the sender's mtime, so that quick checks of later syncs find it unchanged.
+async fn set_mtime(path: &Path, mtime: SystemTime) -> anyhow::Result<()> {
+    let file = tokio::fs::File::options().write(true).open(path).await?;
+    file.into_std().await.set_modified(mtime)?;
+    Ok(())
+}
+
+async fn is_newer(path: &Path, than: SystemTime) -> bool {
+    let modified = tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified());
+
+    modified.is_ok_and(|modified| modified > than)
+}
+
+/// Runs the middleware over the file written at `write_path` for `path`, which was sent with
+/// `mtime`, returning how it was converted.
+async fn transform_file(
+    path: &Path,
+    write_path: &Path,
+    mtime: SystemTime,
+    options: &ApplyOptions,
+) -> anyhow::Result<Option<Conversion>> {
+    let contents = Bytes::from(tokio::fs::read(write_path).await?);
+    let converted = options.middleware.transform(path, contents.clone());
+    let conversion = Conversion::of(&contents, &converted, mtime);
+    tokio::fs::write(write_path, converted).await?;
+
+    Ok(conversion)
+}
+
+/// Runs the middleware over the files of a freshly unpacked directory archive, keeping their
+/// mtimes.
+async fn transform_unpacked(
+    out_dir: &Path,
+    dir_path: &Path,
+    options: &ApplyOptions,
+) -> anyhow::Result<()> {
+    for entry in WalkDir::new(dir_path).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().strip_prefix(out_dir)?;
+        if !options.middleware.applies_to(path) {
+            options.converted.record(path, None);
+            continue;
+        }
+
+        let mtime = entry.metadata()?.modified()?;
+        let conversion = transform_file(path, entry.path(), mtime, options).await?;
+        set_mtime(entry.path(), mtime).await?;
+        options.converted.record(path, conversion);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::{fs, time::Duration};
+    use tempfile::TempDir;
+    use tokio::test;
+
+    fn message(id: u64, change: FileChangeMessage) -> SyncMessage {
+        SyncMessage {
+            id,
+            depends_on: vec![],
+            change,
+        }
+    }
+
+    #[test]
+    async fn test_overlapping_changes_keep_their_order() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let mut pipeline = ApplyPipeline::new(out_dir.path(), 4, Default::default());
+
+        let large = Bytes::from(vec![b'A'; 4 * 1024 * 1024]);
+        pipeline.submit(message(
+            0,
+            FileChangeMessage::EmptyDirectoryCreated("dir".into()),
+        ));
+        pipeline.submit(message(
+            1,
+            FileChangeMessage::FileEdited("dir/large.txt".into(), large.clone(), SystemTime::now()),
+        ));
+        pipeline.submit(message(
+            2,
+            FileChangeMessage::Rename("dir".into(), "renamed".into()),
+        ));
+        pipeline.submit(message(
+            3,
+            FileChangeMessage::FileEdited(
+                "other.txt".into(),
+                Bytes::from("other"),
+                SystemTime::now(),
+            ),
+        ));
+        pipeline.drain().await;
+
+        assert!(!out_dir.path().join("dir").exists());
+        assert_eq!(fs::read(out_dir.path().join("renamed/large.txt"))?, large);
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("other.txt"))?,
+            "other"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_held_back_changes_do_not_exhaust_the_budget() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let budget = MemoryBudget::new(8 * 1024);
+        let mut pipeline =
+            ApplyPipeline::new(out_dir.path(), 2, Default::default()).within(Some(budget.clone()));
+        let edit = |path: &str| {
+            let contents = Bytes::from(vec![b'A'; 8 * 1024]);
+            FileChangeMessage::FileEdited(path.into(), contents, SystemTime::now())
+        };
+
+        // The first change to arrive takes the whole budget while waiting for the one it
+        // depends on, which must not wait for it in turn.
+        let messages = [
+            SyncMessage {
+                depends_on: vec![0],
+                ..message(1, edit("a.txt"))
+            },
+            message(2, edit("b.txt")),
+            message(0, edit("a.txt")),
+        ];
+        let submitted = tokio::time::timeout(Duration::from_secs(5), async {
+            for message in messages {
+                pipeline.reserve(&message).await;
+                pipeline.submit(message);
+            }
+            pipeline.drain().await;
+        });
+        submitted.await?;
+
+        assert!(out_dir.path().join("a.txt").exists());
+        assert!(out_dir.path().join("b.txt").exists());
+        assert_eq!(budget.available(), budget.limit());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_update_only_keeps_newer_local_files() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("hotfix.txt"), "hotfix")?;
+        fs::write(out_dir.path().join("stale.txt"), "stale")?;
+
+        let options = ApplyOptions {
+            update_only: true,
+            ..Default::default()
+        };
+        let edit = |path: &str, mtime| {
+            FileChangeMessage::FileEdited(path.into(), Bytes::from("incoming"), mtime)
+        };
+
+        let hour = std::time::Duration::from_secs(3600);
+        apply_change(
+            out_dir.path(),
+            edit("hotfix.txt", SystemTime::now() - hour),
+            &options,
+        )
+        .await?;
+        apply_change(
+            out_dir.path(),
+            edit("stale.txt", SystemTime::now() + hour),
+            &options,
+        )
+        .await?;
+
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("hotfix.txt"))?,
+            "hotfix"
+        );
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("stale.txt"))?,
+            "incoming"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_ignore_existing_only_creates_missing_entries() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("local.txt"), "local")?;
+
+        let options = ApplyOptions {
+            ignore_existing: true,
+            ..Default::default()
+        };
+        let edit = |path: &str| {
+            FileChangeMessage::FileEdited(path.into(), Bytes::from("incoming"), SystemTime::now())
+        };
+
+        apply_change(out_dir.path(), edit("local.txt"), &options).await?;
+        apply_change(out_dir.path(), edit("missing.txt"), &options).await?;
+        let delete = FileChangeMessage::FileDeleted("local.txt".into());
+        apply_change(out_dir.path(), delete, &options).await?;
+
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("local.txt"))?,
+            "local"
+        );
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("missing.txt"))?,
+            "incoming"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_quota_refuses_edits_past_its_limit() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("old.txt"), "0123456789")?;
+
+        let quota = Quota::new(bytesize::ByteSize::b(16));
+        quota.reset(out_dir.path());
+        let options = ApplyOptions {
+            quota: Some(quota),
+            ..Default::default()
+        };
+        let edit = |path: &str, contents: &'static str| {
+            FileChangeMessage::FileEdited(path.into(), Bytes::from(contents), SystemTime::now())
+        };
+
+        assert!(
+            apply_change(out_dir.path(), edit("new.txt", "0123456789"), &options)
+                .await
+                .is_err()
+        );
+        assert!(!out_dir.path().join("new.txt").exists());
+
+        let delete = FileChangeMessage::FileDeleted("old.txt".into());
+        apply_change(out_dir.path(), delete, &options).await?;
+        apply_change(out_dir.path(), edit("new.txt", "0123456789"), &options).await?;
+        assert_eq!(options.quota.unwrap().used(), bytesize::ByteSize::b(10));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_archives_past_the_quota_keep_what_was_there() -> anyhow::Result<()> {
+        let src = TempDir::new()?;
+        fs::write(src.path().join("large.txt"), "0123456789".repeat(4))?;
+        let archive = crate::core::compression::compress_dir(src.path()).await?;
+        let out_dir = TempDir::new()?;
+        fs::create_dir(out_dir.path().join("docs"))?;
+        fs::write(out_dir.path().join("docs/mine.txt"), "mine")?;
+
+        let quota = Quota::new(bytesize::ByteSize::b(16));
+        quota.reset(out_dir.path());
+        let options = ApplyOptions {
+            quota: Some(quota),
+            ..Default::default()
+        };
+        let unpack = FileChangeMessage::DirectoryCreated("docs".into(), archive);
+        assert!(apply_change(out_dir.path(), unpack, &options)
+            .await
+            .is_err());
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("docs/mine.txt"))?,
+            "mine"
+        );
+        assert!(!out_dir.path().join("docs/large.txt").exists());
+        assert_eq!(options.quota.unwrap().used(), bytesize::ByteSize::b(4));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_archives_keep_versions_of_the_files_they_overwrite() -> anyhow::Result<()> {
+        let src = TempDir::new()?;
+        fs::write(src.path().join("notes.txt"), "theirs")?;
+        let archive = crate::core::compression::compress_dir(src.path()).await?;
+        let out_dir = TempDir::new()?;
+        fs::create_dir(out_dir.path().join("docs"))?;
+        fs::write(out_dir.path().join("docs/notes.txt"), "mine")?;
+
+        let options = ApplyOptions {
+            versions: Some(Versions::new(2)),
+            ..Default::default()
+        };
+        let unpack = FileChangeMessage::DirectoryCreated("docs".into(), archive);
+        apply_change(out_dir.path(), unpack, &options).await?;
+        let path = Path::new("docs/notes.txt");
+        assert_eq!(fs::read_to_string(out_dir.path().join(path))?, "theirs");
+        let kept = crate::receiver::versions::list(out_dir.path(), path)?;
+        assert_eq!(kept.len(), 1);
+        crate::receiver::versions::restore(out_dir.path(), path, &kept[0].0)?;
+        assert_eq!(fs::read_to_string(out_dir.path().join(path))?, "mine");
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_pipeline_reports_rejected_changes() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let options = ApplyOptions {
+            quota: Some(Quota::new(bytesize::ByteSize::b(4))),
+            ..Default::default()
+        };
+        let mut pipeline = ApplyPipeline::new(out_dir.path(), 2, Arc::new(options));
+        pipeline.submit(message(
+            0,
+            FileChangeMessage::FileEdited(
+                "large.txt".into(),
+                Bytes::from("0123456789"),
+                SystemTime::now(),
+            ),
+        ));
+        pipeline.drain().await;
+
+        let (path, rejection) = pipeline.rejected().await;
+        assert_eq!(path, Path::new("large.txt"));
+        assert_eq!(
+            rejection,
+            Rejection::QuotaExceeded {
+                limit: 4,
+                used: 0,
+                needed: 10
+            }
+        );
+        assert_eq!(pipeline.failed(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_chunks_replace_the_file_once_complete() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("video.mp4"), "old")?;
+        let chunk = |path: &str, offset: u64, data: &'static str| {
+            let chunk = Chunk {
+                offset,
+                size: 10,
+                data: Bytes::from(data),
+                mtime: SystemTime::now(),
+            };
+            FileChangeMessage::FileChunk(path.into(), chunk)
+        };
+
+        let options = ApplyOptions::default();
+        apply_change(out_dir.path(), chunk("video.mp4", 0, "0123"), &options).await?;
+        apply_change(out_dir.path(), chunk("video.mp4", 4, "4567"), &options).await?;
+        assert_eq!(fs::read_to_string(out_dir.path().join("video.mp4"))?, "old");
+        apply_change(out_dir.path(), chunk("video.mp4", 8, "89"), &options).await?;
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("video.mp4"))?,
+            "0123456789"
+        );
+        assert!(!out_dir.path().join("video.mp4.caiman-partial").exists());
+
+        // Chunks of sparse files leave holes out, the last one setting the file's size.
+        apply_change(out_dir.path(), chunk("disk.img", 0, "01"), &options).await?;
+        apply_change(out_dir.path(), chunk("disk.img", 10, ""), &options).await?;
+        assert_eq!(
+            fs::read(out_dir.path().join("disk.img"))?,
+            b"01\0\0\0\0\0\0\0\0"
+        );
+
+        // Once the first chunk is refused, the others are dropped quietly.
+        let options = ApplyOptions {
+            quota: Some(Quota::new(bytesize::ByteSize::b(4))),
+            ..Default::default()
+        };
+        assert!(
+            apply_change(out_dir.path(), chunk("new.mp4", 0, "0123"), &options)
+                .await
+                .is_err()
+        );
+        apply_change(out_dir.path(), chunk("new.mp4", 4, "4567"), &options).await?;
+        apply_change(out_dir.path(), chunk("new.mp4", 8, "89"), &options).await?;
+        assert!(!out_dir.path().join("new.mp4").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_checksum_mismatches_are_rejected() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("notes.txt"), "old")?;
+        let options = ApplyOptions::default();
+        let edit = |contents: &'static str| {
+            FileChangeMessage::FileEdited(
+                "notes.txt".into(),
+                Bytes::from(contents),
+                SystemTime::now(),
+            )
+        };
+
+        let FileChangeMessage::Checksummed(_, sha1) = edit("new").checksummed() else {
+            panic!("edits are checksummed");
+        };
+        let corrupted = FileChangeMessage::Checksummed(Box::new(edit("nex")), sha1);
+        let err = apply_change(out_dir.path(), corrupted, &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Rejection::ChecksumMismatch));
+        assert_eq!(fs::read_to_string(out_dir.path().join("notes.txt"))?, "old");
+
+        apply_change(out_dir.path(), edit("new").checksummed(), &options).await?;
+        assert_eq!(fs::read_to_string(out_dir.path().join("notes.txt"))?, "new");
+        assert!(!out_dir.path().join("notes.txt.caiman-partial").exists());
+
+        let chunk = |offset: u64, data: &'static str| {
+            let chunk = Chunk {
+                offset,
+                size: 6,
+                data: Bytes::from(data),
+                mtime: SystemTime::now(),
+            };
+            FileChangeMessage::FileChunk("video.mp4".into(), chunk).checksummed()
+        };
+        apply_change(out_dir.path(), chunk(0, "012"), &options).await?;
+        apply_change(out_dir.path(), chunk(3, "345"), &options).await?;
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("video.mp4"))?,
+            "012345"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_replayed_changes_leave_the_same_directory() -> anyhow::Result<()> {
+        let src = TempDir::new()?;
+        fs::write(src.path().join("readme.md"), "docs")?;
+        let archive = crate::core::compression::compress_dir(src.path()).await?;
+        let mtime = SystemTime::now();
+        let chunk = |offset: u64, data: &'static str| {
+            let chunk = Chunk {
+                offset,
+                size: 4,
+                data: Bytes::from(data),
+                mtime,
+            };
+            FileChangeMessage::FileChunk("video.mp4".into(), chunk)
+        };
+        let changes = || {
+            vec![
+                FileChangeMessage::EmptyDirectoryCreated("empty".into()),
+                FileChangeMessage::DirectoryCreated("docs".into(), archive.clone()),
+                FileChangeMessage::FileCreated("created.txt".into()),
+                FileChangeMessage::FileEdited("edited.txt".into(), Bytes::from("edited"), mtime),
+                FileChangeMessage::Rename("edited.txt".into(), "renamed.txt".into()),
+                FileChangeMessage::HardlinkCreated("renamed.txt".into(), "linked.txt".into()),
+                FileChangeMessage::FileDeleted("old.txt".into()),
+                FileChangeMessage::DirectoryDeleted("old".into()),
+                chunk(0, "01"),
+                chunk(2, "23"),
+            ]
+        };
+
+        // Each change delivered once, each one delivered twice in a row, then all of them
+        // delivered again, as after a reconnection.
+        let mut applied = vec![];
+        for delivery in 0..3 {
+            let out_dir = TempDir::new()?;
+            fs::write(out_dir.path().join("old.txt"), "old")?;
+            fs::create_dir(out_dir.path().join("old"))?;
+            fs::write(out_dir.path().join("old/file.txt"), "old")?;
+            let quota = Quota::new(bytesize::ByteSize::kib(1));
+            quota.reset(out_dir.path());
+            let options = ApplyOptions {
+                quota: Some(quota),
+                ..Default::default()
+            };
+            for (change, replay) in changes().into_iter().zip(changes()) {
+                apply_change(out_dir.path(), change, &options).await?;
+                if delivery == 1 {
+                    apply_change(out_dir.path(), replay, &options).await?;
+                }
+            }
+            if delivery == 2 {
+                for change in changes() {
+                    apply_change(out_dir.path(), change, &options).await?;
+                }
+            }
+
+            let read = |path: &str| fs::read_to_string(out_dir.path().join(path)).ok();
+            let paths = ["docs/readme.md", "created.txt", "edited.txt", "renamed.txt"];
+            let contents: Vec<_> = paths
+                .into_iter()
+                .chain(["linked.txt", "video.mp4"])
+                .map(read)
+                .collect();
+            assert!(out_dir.path().join("empty").is_dir());
+            assert!(!out_dir.path().join("old").exists());
+            assert!(!out_dir.path().join("video.mp4.caiman-partial").exists());
+            applied.push((contents, options.quota.unwrap().used()));
+            out_dir.close()?;
+        }
+        assert_eq!(applied[0], applied[1]);
+        assert_eq!(applied[0], applied[2]);
+        assert_eq!(applied[0].0[3].as_deref(), Some("edited"));
+        assert_eq!(applied[0].0[5].as_deref(), Some("0123"));
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_overlaps() {
+        let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+
+        assert!(overlaps(&paths(&["a"]), &paths(&["a/b"])));
+        assert!(overlaps(&paths(&["a/b"]), &paths(&["a"])));
+        assert!(overlaps(&paths(&["x", "a"]), &paths(&["a"])));
+        assert!(!overlaps(&paths(&["a"]), &paths(&["ab"])));
+        assert!(!overlaps(&paths(&["a/b"]), &paths(&["a/c"])));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::core::{message::FileChangeMessage, policy, utils::quoted, wire_path};
+
+/// Append-only log of the changes a receiver applied, one JSON record per line, so that
+/// operators can tell what was pushed when and by whom.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the change was applied, as an RFC 3339 timestamp.
+    pub at: String,
+    /// The sender's address.
+    pub peer: String,
+    /// The `FileChangeMessage` variant.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(with = "wire_path")]
+    pub path: PathBuf,
+    /// Where renames moved `path` to.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "wire_path::option"
+    )]
+    pub new_path: Option<PathBuf>,
+    /// Size of the file, or of the directory archive, uncompressed.
+    pub bytes: Option<u64>,
+    /// SHA-1 of the same contents, in hex.
+    pub sha1: Option<String>,
+}
+
+impl AuditRecord {
+    /// Describes `change` before it is applied, which consumes it. The timestamp is set once
+    /// it is logged.
+    pub fn of(peer: &str, change: &FileChangeMessage) -> Self {
+        if let FileChangeMessage::Checksummed(change, _) = change {
+            return Self::of(peer, change);
+        }
+        let (kind, new_path, contents) = match change {
+            FileChangeMessage::FileCreated(_) => ("FileCreated", None, Some(digest(&[]))),
+            FileChangeMessage::FileDeleted(_) => ("FileDeleted", None, None),
+            FileChangeMessage::FileEdited(_, contents, _) => {
+                ("FileEdited", None, Some(digest(contents)))
+            }
+            FileChangeMessage::GzippedFileEdited(_, compressed, _) => {
+                let contents = policy::decompress(compressed).ok();
+                ("GzippedFileEdited", None, contents.as_deref().map(digest))
+            }
+            FileChangeMessage::EmptyDirectoryCreated(_) => ("EmptyDirectoryCreated", None, None),
+            FileChangeMessage::DirectoryCreated(_, contents) => {
+                ("DirectoryCreated", None, Some(digest(contents)))
+            }
+            FileChangeMessage::GzippedDirectoryCreated(_, compressed) => {
+                let contents = policy::decompress(compressed).ok();
+                (
+                    "GzippedDirectoryCreated",
+                    None,
+                    contents.as_deref().map(digest),
+                )
+            }
+            FileChangeMessage::DirectoryDeleted(_) => ("DirectoryDeleted", None, None),
+            FileChangeMessage::Rename(_, new_path) => ("Rename", Some(new_path.clone()), None),
+            FileChangeMessage::DirectoryContentsEdited(_) => {
+                ("DirectoryContentsEdited", None, None)
+            }
+            FileChangeMessage::FileChunk(_, chunk) => {
+                ("FileChunk", None, Some(digest(&chunk.data)))
+            }
+            FileChangeMessage::FileFromHash(..) => ("FileFromHash", None, None),
+            FileChangeMessage::HardlinkCreated(target, _) => {
+                ("HardlinkCreated", Some(target.clone()), None)
+            }
+            FileChangeMessage::FifoCreated(_) => ("FifoCreated", None, None),
+            FileChangeMessage::FileBatch(_, packed) if packed.gzip => {
+                let archive = policy::decompress(&packed.archive).ok();
+                ("FileBatch", None, archive.as_deref().map(digest))
+            }
+            FileChangeMessage::FileBatch(_, packed) => {
+                ("FileBatch", None, Some(digest(&packed.archive)))
+            }
+            FileChangeMessage::Checksummed(..) => unreachable!("described as the inner change"),
+        };
+
+        let (bytes, sha1) = match change {
+            FileChangeMessage::FileFromHash(_, sha1, _) => (None, Some(hex::encode(sha1))),
+            _ => contents.unzip(),
+        };
+        Self {
+            at: String::new(),
+            peer: peer.to_owned(),
+            kind: kind.to_owned(),
+            path: change.path().to_owned(),
+            new_path,
+            bytes,
+            sha1,
+        }
+    }
+}
+
+fn digest(contents: &[u8]) -> (u64, String) {
+    (contents.len() as u64, hex::encode(Sha1::digest(contents)))
+}
+
+impl AuditLog {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening the audit log {}", quoted(path)))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `record`, stamped with the current time, in a single write so that the lines of
+    /// concurrent changes never interleave.
+    pub fn append(&self, mut record: AuditRecord) -> anyhow::Result<()> {
+        record.at = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .with_context(|| format!("writing to the audit log {}", quoted(&self.path)))
+    }
+
+    /// The last `limit` records of changes to `path`, renames from or to it included, oldest
+    /// first. Lines that cannot be parsed are skipped.
+    pub fn history(&self, path: &Path, limit: usize) -> anyhow::Result<Vec<AuditRecord>> {
+        let contents = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("reading the audit log {}", quoted(&self.path)))?;
+        let mut records: Vec<AuditRecord> = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .filter(|record: &AuditRecord| {
+                record.path == path || record.new_path.as_deref() == Some(path)
+            })
+            .collect();
+        records.drain(..records.len().saturating_sub(limit));
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::policy::PolicyTable;
+    use bytes::Bytes;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_records_are_appended() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("audit.ndjson");
+        let edited = PolicyTable::default().encode(FileChangeMessage::FileEdited(
+            PathBuf::from("src/main.rs"),
+            Bytes::from("fn main() {}\n".repeat(10)),
+            SystemTime::now(),
+        ))?;
+
+        let log = AuditLog::open(&path)?;
+        log.append(AuditRecord::of("127.0.0.1:50000", &edited))?;
+        drop(log);
+        let log = AuditLog::open(&path)?;
+        let renamed =
+            FileChangeMessage::Rename(PathBuf::from("src/main.rs"), PathBuf::from("main.rs"));
+        log.append(AuditRecord::of("127.0.0.1:50001", &renamed))?;
+
+        let contents = std::fs::read_to_string(&path)?;
+        let records = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<AuditRecord>, _>>()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kind, "GzippedFileEdited");
+        assert_eq!(records[0].bytes, Some(130));
+        assert_eq!(
+            records[0].sha1.as_deref(),
+            Some(hex::encode(Sha1::digest("fn main() {}\n".repeat(10))).as_str())
+        );
+        assert_eq!(records[1].peer, "127.0.0.1:50001");
+        assert_eq!(records[1].new_path, Some(PathBuf::from("main.rs")));
+        assert_eq!(records[1].sha1, None);
+
+        let history = log.history(Path::new("main.rs"), 10)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].kind, "Rename");
+        assert_eq!(log.history(Path::new("src/main.rs"), 1)?[0].kind, "Rename");
+        assert!(log.history(Path::new("src"), 10)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request},
+    http::{header::AUTHORIZATION, StatusCode},
+};
+
+use crate::core::message::{PROTOCOL_HEADER, PROTOCOL_VERSION};
+
+/// The key a sender presents as `Authorization: Bearer <key>`.
+pub fn bearer_key(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Compares keys in constant time, so that response times do not tell how much of a key matched.
+pub fn keys_match(expected: &str, key: &str) -> bool {
+    expected.len() == key.len()
+        && expected
+            .bytes()
+            .zip(key.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn unauthorized() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("unknown or missing key".into()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+}
+
+pub fn untrusted() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("unknown or missing identity".into()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+}
+
+pub fn not_found() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("no sync endpoint at this path".into()));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+pub fn wrong_protocol() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(format!(
+        "protocol version {} required",
+        PROTOCOL_VERSION
+    )));
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    response
+}
+
+/// Checks that the sender speaks the receiver's `PROTOCOL_VERSION`, failing with the version it
+/// presents otherwise, if any.
+pub fn speaks_protocol(request: &Request) -> Result<(), Option<String>> {
+    let version = request
+        .headers()
+        .get(PROTOCOL_HEADER)
+        .map(|version| String::from_utf8_lossy(version.as_bytes()).into_owned());
+    match version {
+        Some(version) if version == PROTOCOL_VERSION.to_string() => Ok(()),
+        version => Err(version),
+    }
+}
+
+/// Whether the handshake is for `path`, leading and trailing slashes aside, or for any path
+/// without one.
+pub fn serves_path(path: Option<&str>, request: &Request) -> bool {
+    path.is_none_or(|path| request.uri().path().trim_matches('/') == path.trim_matches('/'))
+}
+
+/// Whether the sender presents `key`, when there is one.
+pub fn presents_key(key: Option<&str>, request: &Request) -> bool {
+    key.is_none_or(|key| bearer_key(request).is_some_and(|presented| keys_match(key, presented)))
+}
+
+/// Why a sender's websocket handshake was refused.
+#[derive(Debug)]
+pub enum Refusal {
+    /// Nothing is served at this URL path.
+    WrongPath(String),
+    /// The sender speaks another protocol version, or none when it predates them.
+    WrongProtocol(Option<String>),
+    WrongKey,
+    /// The sender's identity could not be verified, or is not trusted.
+    Untrusted(String),
+}
+
+impl Refusal {
+    /// The response the handshake is refused with.
+    pub fn response(&self) -> ErrorResponse {
+        match self {
+            Refusal::WrongPath(_) => not_found(),
+            Refusal::WrongProtocol(_) => wrong_protocol(),
+            Refusal::WrongKey => unauthorized(),
+            Refusal::Untrusted(_) => untrusted(),
+        }
+    }
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::WrongPath(path) => write!(f, "nothing served at {}", path),
+            Refusal::WrongProtocol(Some(version)) => write!(
+                f,
+                "speaks protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            Refusal::WrongProtocol(None) => write!(
+                f,
+                "speaks no protocol version, expected {}",
+                PROTOCOL_VERSION
+            ),
+            Refusal::WrongKey => write!(f, "wrong or missing key"),
+            Refusal::Untrusted(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_keys_match() {
+        assert!(keys_match("secret", "secret"));
+        assert!(!keys_match("secret", "secreT"));
+        assert!(!keys_match("secret", "secre"));
+        assert!(!keys_match("", "secret"));
+    }
+
+    #[test]
+    async fn test_serves_path() {
+        let request = |uri: &str| Request::get(uri).body(()).unwrap();
+
+        assert!(serves_path(None, &request("ws://host/anything")));
+        assert!(serves_path(Some("/caiman"), &request("ws://host/caiman")));
+        assert!(serves_path(Some("caiman/"), &request("ws://host/caiman")));
+        assert!(serves_path(Some("/"), &request("ws://host")));
+        assert!(!serves_path(Some("/caiman"), &request("ws://host/")));
+        assert!(!serves_path(
+            Some("/caiman"),
+            &request("ws://host/caiman/sub")
+        ));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use tokio::task::JoinHandle;
+use walkdir::WalkDir;
+
+use super::versions::CAIMAN_DIR;
+use crate::core::utils::{clone_file, quoted};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// How many backups are kept: the latest one of each of the last `hourly` hours, `daily` days
+/// and `weekly` weeks that have one. The latest backup is always kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+/// Periodic backups of the output directory, each in its own directory of `dir` named after the
+/// time it was taken. Files unchanged since the previous backup are hard links to it, so a backup
+/// only takes the space of what changed, like rsnapshot.
+#[derive(Debug, Clone)]
+pub struct BackupOptions {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    pub retention: Retention,
+}
+
+/// Backs up `out_dir` into a new directory of `backup_dir`, returning it.
+pub fn take(out_dir: &Path, backup_dir: &Path) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(backup_dir)
+        .with_context(|| format!("creating {}", quoted(backup_dir)))?;
+    let previous = list(backup_dir)?.into_iter().next().map(|(_, path)| path);
+
+    let name = timestamp(SystemTime::now());
+    let backup = backup_dir.join(&name);
+    if backup.exists() {
+        return Ok(backup);
+    }
+
+    // Backups only get their name once complete, so interrupted ones are never mistaken for one.
+    let partial = backup_dir.join(format!(".{}.partial", name));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    let walk = WalkDir::new(out_dir)
+        .into_iter()
+        .filter_entry(|entry| entry.path() != out_dir.join(CAIMAN_DIR));
+    for entry in walk {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(out_dir)?;
+        let target = partial.join(path);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir(&target)?;
+        } else if entry.file_type().is_file() {
+            let previous = previous.as_ref().map(|previous| previous.join(path));
+            link_or_copy(entry.path(), previous.as_deref(), &target)
+                .with_context(|| format!("backing up {}", quoted(path)))?;
+        }
+    }
+    std::fs::rename(&partial, &backup)?;
+
+    Ok(backup)
+}
+
+/// Hard links `target` to the previous backup's copy of `source` when it did not change since,
+/// as far as its size and mtime tell, and copies `source` otherwise.
+fn link_or_copy(source: &Path, previous: Option<&Path>, target: &Path) -> anyhow::Result<()> {
+    let metadata = source.metadata()?;
+    let modified = metadata.modified()?;
+    let unchanged = previous
+        .and_then(|previous| previous.metadata().ok())
+        .filter(|previous| previous.len() == metadata.len())
+        .and_then(|previous| previous.modified().ok())
+        .is_some_and(|previous_modified| previous_modified == modified);
+    if let (true, Some(previous)) = (unchanged, previous) {
+        std::fs::hard_link(previous, target)?;
+        return Ok(());
+    }
+
+    clone_file(source, target)?;
+    // The next backup tells unchanged files by their mtime.
+    File::options()
+        .write(true)
+        .open(target)?
+        .set_modified(modified)?;
+
+    Ok(())
+}
+
+/// Deletes the backups of `backup_dir` that `retention` does not keep, returning how many.
+pub fn prune(backup_dir: &Path, retention: Retention) -> anyhow::Result<usize> {
+    let backups = list(backup_dir)?;
+    let times: Vec<_> = backups.iter().map(|&(time, _)| time).collect();
+    let mut pruned = 0;
+    for ((_, path), keep) in backups.iter().zip(retained(&times, retention)) {
+        if !keep {
+            std::fs::remove_dir_all(path)?;
+            pruned += 1;
+        }
+    }
+
+    Ok(pruned)
+}
+
+/// Which of `times`, sorted from the latest, `retention` keeps.
+fn retained(times: &[SystemTime], retention: Retention) -> Vec<bool> {
+    let mut keep = vec![false; times.len()];
+    if let Some(latest) = keep.first_mut() {
+        *latest = true;
+    }
+
+    // The epoch was a Thursday, weeks start on Mondays.
+    keep_latest_per(times, retention.hourly, |secs| secs / HOUR, &mut keep);
+    keep_latest_per(times, retention.daily, |secs| secs / DAY, &mut keep);
+    keep_latest_per(
+        times,
+        retention.weekly,
+        |secs| (secs / DAY + 3) / 7,
+        &mut keep,
+    );
+
+    keep
+}
+
+/// Keeps the latest of `times` in each of the last `count` periods that have one, `period`
+/// telling the period of a time from its seconds since the epoch.
+fn keep_latest_per(times: &[SystemTime], count: usize, period: fn(u64) -> u64, keep: &mut [bool]) {
+    let mut last_period = None;
+    let mut kept = 0;
+    for (i, time) in times.iter().enumerate() {
+        if kept == count {
+            break;
+        }
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if last_period != Some(period(secs)) {
+            last_period = Some(period(secs));
+            keep[i] = true;
+            kept += 1;
+        }
+    }
+}
+
+/// The backups of `backup_dir`, from the latest.
+fn list(backup_dir: &Path) -> anyhow::Result<Vec<(SystemTime, PathBuf)>> {
+    if !backup_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(backup_dir)? {
+        let entry = entry?;
+        let time = entry.file_name().to_str().and_then(parse_timestamp);
+        if let (Some(time), true) = (time, entry.file_type()?.is_dir()) {
+            backups.push((time, entry.path()));
+        }
+    }
+    backups.sort_by(|backup1, backup2| backup2.cmp(backup1));
+
+    Ok(backups)
+}
+
+/// Sortable and valid in file names on every platform, e.g. `2024-05-02T091244Z`.
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(':', "")
+}
+
+fn parse_timestamp(name: &str) -> Option<SystemTime> {
+    let rfc3339 = format!(
+        "{}:{}:{}",
+        name.get(..13)?,
+        name.get(13..15)?,
+        name.get(15..)?
+    );
+    humantime::parse_rfc3339(&rfc3339).ok()
+}
+
+/// Backs up the output directory every `interval` and prunes old backups, for as long as it is
+/// kept.
+pub struct BackupTask(JoinHandle<()>);
+
+impl BackupTask {
+    pub fn spawn(out_dir: PathBuf, options: BackupOptions) -> anyhow::Result<Self> {
+        if options.dir.starts_with(&out_dir) {
+            bail!(
+                "the backup directory {} must be outside of the output directory",
+                quoted(&options.dir)
+            );
+        }
+
+        // Restarting the listener does not back up again before the interval is over.
+        let since_latest = list(&options.dir)?
+            .first()
+            .and_then(|(time, _)| time.elapsed().ok())
+            .unwrap_or(options.interval);
+        let start = tokio::time::Instant::now() + options.interval.saturating_sub(since_latest);
+
+        Ok(Self(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(start, options.interval);
+            loop {
+                ticker.tick().await;
+                let (out_dir, options) = (out_dir.clone(), options.clone());
+                let res = tokio::task::spawn_blocking(move || {
+                    let backup = take(&out_dir, &options.dir)?;
+                    prune(&options.dir, options.retention)?;
+                    anyhow::Ok(backup)
+                })
+                .await;
+                match res {
+                    Ok(Ok(backup)) => {
+                        println!("Backed up the output directory to {}", quoted(&backup))
+                    }
+                    Ok(Err(err)) => eprintln!("An error occurred while backing up: {:#}", err),
+                    Err(err) => eprintln!("An error occurred while backing up: {}", err),
+                }
+            }
+        })))
+    }
+}
+
+impl Drop for BackupTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backups_share_unchanged_files() -> anyhow::Result<()> {
+        let (out_dir, backup_dir) = (TempDir::new()?, TempDir::new()?);
+        fs::create_dir(out_dir.path().join("docs"))?;
+        fs::write(out_dir.path().join("docs/guide.md"), "guide")?;
+        fs::write(out_dir.path().join("notes.txt"), "v1")?;
+        fs::create_dir_all(out_dir.path().join(CAIMAN_DIR).join("versions"))?;
+
+        let first = take(out_dir.path(), backup_dir.path())?;
+        // Backups are named after the second they were taken in.
+        let first = {
+            let renamed = backup_dir
+                .path()
+                .join(timestamp(SystemTime::now() - Duration::from_secs(60)));
+            fs::rename(&first, &renamed)?;
+            renamed
+        };
+        fs::write(out_dir.path().join("notes.txt"), "v2")?;
+        let second = take(out_dir.path(), backup_dir.path())?;
+
+        assert_eq!(fs::read_to_string(first.join("notes.txt"))?, "v1");
+        assert_eq!(fs::read_to_string(second.join("notes.txt"))?, "v2");
+        assert!(!second.join(CAIMAN_DIR).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: PathBuf| fs::metadata(path).map(|metadata| metadata.ino());
+            assert_eq!(
+                inode(first.join("docs/guide.md"))?,
+                inode(second.join("docs/guide.md"))?
+            );
+            assert_ne!(
+                inode(first.join("notes.txt"))?,
+                inode(second.join("notes.txt"))?
+            );
+        }
+
+        assert_eq!(list(backup_dir.path())?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_keeps_latest_of_each_period() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000 * DAY + 12 * HOUR + 30 * 60);
+        let ago = |secs: u64| now - Duration::from_secs(secs);
+        let times = [
+            ago(0),
+            ago(10 * 60),
+            ago(HOUR),
+            ago(2 * HOUR),
+            ago(DAY),
+            ago(2 * DAY),
+            ago(9 * DAY),
+        ];
+        let retention = Retention {
+            hourly: 2,
+            daily: 2,
+            weekly: 2,
+        };
+
+        assert_eq!(
+            retained(&times, retention),
+            [true, false, true, false, true, false, true]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::core::{
+    message::{Rejection, MIN_DEDUP_SIZE},
+    utils::hash_file,
+};
+
+/// Files written whole during the session, by the SHA-1 of their contents, for the sender's
+/// `FileFromHash` changes to be copied from. Entries are checked before being copied from, since
+/// the files may have changed since.
+#[derive(Debug, Default)]
+pub struct Blobs(Mutex<HashMap<[u8; 20], PathBuf>>);
+
+impl Blobs {
+    /// Records that the file at `path` of the output directory holds contents of `size` bytes
+    /// with `sha1`, as sent. Files too small to be deduplicated are not worth remembering.
+    pub fn record(&self, path: &Path, size: u64, sha1: [u8; 20]) {
+        if size >= MIN_DEDUP_SIZE {
+            self.0.lock().unwrap().insert(sha1, path.to_owned());
+        }
+    }
+
+    /// Forgets every file, when a new session starts.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    /// The full path of a file of `out_dir` holding the contents with `sha1`, failing with
+    /// `Rejection::MissingContents` if none does anymore.
+    pub async fn find(&self, out_dir: &Path, sha1: &[u8; 20]) -> anyhow::Result<PathBuf> {
+        let path = self.0.lock().unwrap().get(sha1).cloned();
+        if let Some(path) = path {
+            let path = out_dir.join(path);
+            if hash_file(&path).await.is_ok_and(|hash| hash == *sha1) {
+                return Ok(path);
+            }
+            self.0.lock().unwrap().remove(sha1);
+        }
+
+        Err(Rejection::MissingContents.into())
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::staging::sibling;
+use crate::core::{
+    file_tree::{unix_secs, FileTree, FileTreeNodeType},
+    utils::quoted,
+    wire_path,
+};
+
+/// A file the middleware rewrote: its size and hash as written, and as the sender sent it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conversion {
+    size: u64,
+    sha1: [u8; 20],
+    /// Whole seconds since the epoch, as quick checked trees record mtimes.
+    mtime: u64,
+    sent_size: u64,
+    sent_sha1: [u8; 20],
+}
+
+impl Conversion {
+    /// The conversion of `sent` into `written`, `None` when the middleware left it as it was.
+    pub fn of(sent: &[u8], written: &[u8], mtime: SystemTime) -> Option<Self> {
+        if sent == written {
+            return None;
+        }
+
+        Some(Self {
+            size: written.len() as u64,
+            sha1: Sha1::digest(written).into(),
+            mtime: unix_secs(mtime),
+            sent_size: sent.len() as u64,
+            sent_sha1: Sha1::digest(sent).into(),
+        })
+    }
+
+    /// The type of a file as sent, if `typ` is still the file as written.
+    fn as_sent(&self, typ: &FileTreeNodeType) -> Option<FileTreeNodeType> {
+        let sent = match *typ {
+            FileTreeNodeType::File {
+                size,
+                sha1: Some(sha1),
+            } if size == self.size && sha1 == self.sha1 => FileTreeNodeType::File {
+                size: self.sent_size,
+                sha1: Some(self.sent_sha1),
+            },
+            FileTreeNodeType::File { size, sha1: None } if size == self.size => {
+                FileTreeNodeType::File {
+                    size: self.sent_size,
+                    sha1: None,
+                }
+            }
+            FileTreeNodeType::TimedFile { size, mtime }
+                if size == self.size && mtime == self.mtime =>
+            {
+                FileTreeNodeType::TimedFile {
+                    size: self.sent_size,
+                    mtime,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(sent)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(with = "wire_path")]
+    path: PathBuf,
+    #[serde(flatten)]
+    conversion: Conversion,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Where the conversions are persisted, once loaded.
+    journal: Option<PathBuf>,
+    files: HashMap<PathBuf, Conversion>,
+    /// Whether `files` changed since they were last saved.
+    dirty: bool,
+}
+
+/// The files of the output directory that the middleware rewrote, by path, with what the sender
+/// sent for them. Scans of the output directory are compared with the sender's as if those files
+/// had been written as sent, or every converted file would be sent again on every sync. The
+/// conversions are kept in a JSON file next to the output directory, so they outlive restarts.
+#[derive(Debug, Default)]
+pub struct Converted(Mutex<State>);
+
+impl Converted {
+    /// Reads the conversions recorded for `out_dir` by earlier runs.
+    pub fn load(&self, out_dir: &Path) -> anyhow::Result<()> {
+        let journal = sibling(out_dir, "converted")?;
+        let records: Vec<Record> = match std::fs::read(&journal) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("reading {}", quoted(&journal)))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(&journal))),
+        };
+
+        let mut state = self.0.lock().unwrap();
+        *state = State {
+            journal: Some(journal),
+            files: records
+                .into_iter()
+                .map(|record| (record.path, record.conversion))
+                .collect(),
+            dirty: false,
+        };
+
+        Ok(())
+    }
+
+    /// Records how the file at `path` was written, forgetting it when it was written as sent.
+    pub fn record(&self, path: &Path, conversion: Option<Conversion>) {
+        let mut state = self.0.lock().unwrap();
+        let changed = match conversion {
+            Some(conversion) => {
+                state.files.insert(path.to_owned(), conversion.clone()) != Some(conversion)
+            }
+            None => state.files.remove(path).is_some(),
+        };
+        state.dirty |= changed;
+    }
+
+    /// Forgets the file at `path` and the files below it, once deleted.
+    pub fn forget(&self, path: &Path) {
+        let mut state = self.0.lock().unwrap();
+        let before = state.files.len();
+        state.files.retain(|file, _| !file.starts_with(path));
+        state.dirty |= state.files.len() != before;
+    }
+
+    /// Moves the conversions of the entry at `from` and of the entries below it to `to`.
+    pub fn rename(&self, from: &Path, to: &Path) {
+        let mut state = self.0.lock().unwrap();
+        let moved: Vec<_> = state
+            .files
+            .keys()
+            .filter(|file| file.starts_with(from))
+            .cloned()
+            .collect();
+        for file in moved {
+            let conversion = state.files.remove(&file).unwrap();
+            let renamed = to.join(file.strip_prefix(from).unwrap());
+            state.files.insert(renamed, conversion);
+            state.dirty = true;
+        }
+    }
+
+    /// `tree`, a scan of the output directory, with the converted files as the sender sent them.
+    /// Files changed since they were written are left as scanned.
+    pub fn as_sent(&self, tree: FileTree) -> FileTree {
+        let state = self.0.lock().unwrap();
+        if state.files.is_empty() {
+            return tree;
+        }
+
+        tree.mapped(|node| state.files.get(&node.path)?.as_sent(&node.typ))
+    }
+
+    /// Persists the conversions recorded since they were last saved.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        let Some(journal) = state.journal.as_ref().filter(|_| state.dirty) else {
+            return Ok(());
+        };
+
+        let records: Vec<_> = state
+            .files
+            .iter()
+            .map(|(path, conversion)| Record {
+                path: path.clone(),
+                conversion: conversion.clone(),
+            })
+            .collect();
+        let mut temp_path = journal.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(&records)?)?;
+        std::fs::rename(&temp_path, journal)
+            .with_context(|| format!("replacing {}", quoted(journal)))?;
+        state.dirty = false;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+};
+
+use tokio::task::JoinHandle;
+
+use crate::core::utils::shell_command;
+
+/// Changed paths listed in `CAIMAN_CHANGED_PATHS`, beyond which the list is cut short to stay
+/// within the environment size limits. `CAIMAN_CHANGED_COUNT` always has the full count.
+const MAX_LISTED_PATHS: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    /// The initial sync was applied.
+    Sync,
+    /// A later batch of changes was applied.
+    Change,
+}
+
+/// Shell commands run once changes are applied, e.g. to restart a dev server. Commands run in the
+/// background, one after the other, so that slow hooks do not stall the session.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    on_sync: Option<String>,
+    on_change: Option<String>,
+    last: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Hooks {
+    pub fn new(on_sync: Option<String>, on_change: Option<String>) -> Self {
+        Self {
+            on_sync,
+            on_change,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// The same commands, run independently of this set's.
+    pub fn fresh(&self) -> Self {
+        Self::new(self.on_sync.clone(), self.on_change.clone())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.on_sync.is_none() && self.on_change.is_none()
+    }
+
+    pub fn run(&self, event: HookEvent, out_dir: &Path, changed: Vec<PathBuf>) {
+        let command = match event {
+            HookEvent::Sync => &self.on_sync,
+            HookEvent::Change => &self.on_change,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+
+        let mut shell = shell_command(&command);
+        let listed: Vec<_> = changed
+            .iter()
+            .take(MAX_LISTED_PATHS)
+            .map(|path| path.to_string_lossy())
+            .collect();
+        shell
+            .current_dir(out_dir)
+            .env("CAIMAN_EVENT", event.name())
+            .env("CAIMAN_OUTPUT_DIR", out_dir)
+            .env("CAIMAN_CHANGED_COUNT", changed.len().to_string())
+            .env("CAIMAN_CHANGED_PATHS", listed.join("\n"))
+            .stdin(Stdio::null());
+
+        let mut last = self.last.lock().unwrap();
+        let previous = last.take();
+        *last = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            match shell.status().await {
+                Ok(status) if status.success() => (),
+                Ok(status) => eprintln!("Hook {:?} failed with {}", command, status),
+                Err(err) => eprintln!("Could not run hook {:?}: {}", command, err),
+            }
+        }));
+    }
+
+    /// Waits for every hook started so far to finish.
+    pub async fn wait(&self) {
+        let last = self.last.lock().unwrap().take();
+        if let Some(last) = last {
+            let _ = last.await;
+        }
+    }
+}
+
+impl HookEvent {
+    fn name(self) -> &'static str {
+        match self {
+            HookEvent::Sync => "sync",
+            HookEvent::Change => "change",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[cfg(unix)]
+    #[test]
+    async fn test_hooks_run_in_order_with_changed_paths() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let hooks = Hooks::new(
+            Some("echo \"$CAIMAN_EVENT $CAIMAN_CHANGED_COUNT\" >> hooks.log".into()),
+            Some("sleep 0.1; echo \"$CAIMAN_EVENT $CAIMAN_CHANGED_PATHS\" >> hooks.log".into()),
+        );
+
+        hooks.run(
+            HookEvent::Sync,
+            out_dir.path(),
+            vec!["a".into(), "b".into()],
+        );
+        hooks.run(HookEvent::Change, out_dir.path(), vec!["c".into()]);
+        hooks.run(HookEvent::Sync, out_dir.path(), vec![]);
+        hooks.wait().await;
+
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("hooks.log"))?,
+            "sync 2\nchange c\nsync 0\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+
+/// Counters kept across the sessions served by a receiver.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    pub sessions: AtomicU64,
+    pub rejected: AtomicU64,
+    pub changes: AtomicU64,
+    pub failed: AtomicU64,
+    pub bytes_received: AtomicU64,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} sessions ({} rejected), {} changes ({} failed), {} received",
+            get(&self.sessions),
+            get(&self.rejected),
+            get(&self.changes),
+            get(&self.failed),
+            ByteSize::b(get(&self.bytes_received))
+        )
+    }
+}
@@ -0,0 +1,200 @@
+use std::{fmt::Debug, path::Path, str::FromStr};
+
+use bytes::Bytes;
+use globset::{Glob, GlobMatcher};
+
+use crate::core::policy::is_binary;
+
+/// A stage transforming file contents right before they are written into the output directory.
+/// Paths are relative to the output directory.
+pub trait Middleware: Send + Sync {
+    fn applies_to(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn transform(&self, path: &Path, contents: Bytes) -> Bytes;
+}
+
+/// Runs middleware stages in the order they were added, skipping stages that do not apply to
+/// the file being written.
+#[derive(Default)]
+pub struct MiddlewareChain {
+    stages: Vec<Box<dyn Middleware>>,
+}
+
+impl Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MiddlewareChain")
+            .field("stages", &self.stages.len())
+            .finish()
+    }
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, stage: impl Middleware + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn applies_to(&self, path: &Path) -> bool {
+        self.stages.iter().any(|stage| stage.applies_to(path))
+    }
+
+    pub fn transform(&self, path: &Path, contents: Bytes) -> Bytes {
+        self.stages
+            .iter()
+            .filter(|stage| stage.applies_to(path))
+            .fold(contents, |contents, stage| stage.transform(path, contents))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(LineEnding::native()),
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            _ => Err(format!(
+                "unknown line ending '{}', expected native, lf or crlf",
+                s
+            )),
+        }
+    }
+}
+
+impl LineEnding {
+    /// The line ending of the platform the receiver runs on.
+    pub fn native() -> Self {
+        match cfg!(windows) {
+            true => LineEnding::Crlf,
+            false => LineEnding::Lf,
+        }
+    }
+
+    pub fn convert(self, contents: &[u8]) -> Vec<u8> {
+        let mut converted = Vec::with_capacity(contents.len());
+        for (i, &byte) in contents.iter().enumerate() {
+            match (self, byte) {
+                (LineEnding::Lf, b'\r') if contents.get(i + 1) == Some(&b'\n') => (),
+                (LineEnding::Crlf, b'\n') if i == 0 || contents[i - 1] != b'\r' => {
+                    converted.extend_from_slice(b"\r\n")
+                }
+                _ => converted.push(byte),
+            }
+        }
+
+        converted
+    }
+}
+
+/// Converts line endings of the text files matching `glob`, or of every text file when no glob is
+/// given. Binary files are left untouched. Parsed from `<native|lf|crlf>` or
+/// `<glob>=<native|lf|crlf>`.
+#[derive(Debug, Clone)]
+pub struct ConvertEol {
+    glob: Option<GlobMatcher>,
+    line_ending: LineEnding,
+}
+
+impl From<LineEnding> for ConvertEol {
+    fn from(line_ending: LineEnding) -> Self {
+        Self {
+            glob: None,
+            line_ending,
+        }
+    }
+}
+
+impl FromStr for ConvertEol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (glob, line_ending) = match s.rsplit_once('=') {
+            Some((glob, line_ending)) => {
+                let glob = Glob::new(glob).map_err(|err| err.to_string())?;
+                (Some(glob.compile_matcher()), line_ending)
+            }
+            None => (None, s),
+        };
+
+        Ok(Self {
+            glob,
+            line_ending: line_ending.parse()?,
+        })
+    }
+}
+
+impl Middleware for ConvertEol {
+    fn applies_to(&self, path: &Path) -> bool {
+        self.glob.as_ref().is_none_or(|glob| glob.is_match(path))
+    }
+
+    fn transform(&self, _path: &Path, contents: Bytes) -> Bytes {
+        if is_binary(&contents) {
+            return contents;
+        }
+
+        Bytes::from(self.line_ending.convert(&contents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_ending_conversion() {
+        let mixed = b"one\r\ntwo\nthree\rfour\n";
+
+        assert_eq!(LineEnding::Lf.convert(mixed), b"one\ntwo\nthree\rfour\n");
+        assert_eq!(
+            LineEnding::Crlf.convert(mixed),
+            b"one\r\ntwo\r\nthree\rfour\r\n"
+        );
+        assert_eq!(LineEnding::Crlf.convert(b"\n"), b"\r\n");
+    }
+
+    #[test]
+    fn test_convert_eol_parsing() {
+        let all: ConvertEol = "crlf".parse().unwrap();
+        assert!(all.applies_to(Path::new("any/file.txt")));
+
+        let scripts: ConvertEol = "*.sh=lf".parse().unwrap();
+        assert!(scripts.applies_to(Path::new("bin/run.sh")));
+        assert!(!scripts.applies_to(Path::new("README.md")));
+
+        assert!("*.sh=cr".parse::<ConvertEol>().is_err());
+    }
+
+    #[test]
+    fn test_binary_files_are_not_converted() {
+        let convert: ConvertEol = "native".parse().unwrap();
+        assert_eq!(convert.line_ending, LineEnding::native());
+
+        let binary = Bytes::from_static(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        assert_eq!(
+            convert.transform(Path::new("image.png"), binary.clone()),
+            binary
+        );
+
+        let crlf: ConvertEol = LineEnding::Crlf.into();
+        let text = Bytes::from_static(b"one\ntwo\n");
+        assert_eq!(crlf.transform(Path::new("a.txt"), text), "one\r\ntwo\r\n");
+    }
+}
@@ -1,128 +1,1734 @@
+pub mod access;
+mod apply;
+pub mod audit_log;
+mod auth;
+pub mod backups;
+mod blobs;
+mod converted;
+pub mod hooks;
+pub mod metrics;
+pub mod middleware;
+mod preallocate;
+pub mod quota;
+mod reorder;
+#[cfg(feature = "axum")]
+pub mod service;
+pub mod staging;
+pub mod status_api;
+mod sync_state;
+pub mod tenants;
+pub mod tombstones;
+pub mod trash;
+pub mod versions;
+pub mod wal;
+
 use anyhow::{bail, Context};
-use futures::{SinkExt, StreamExt};
-use std::path::Path;
+use bytesize::ByteSize;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
+
+use access::{AccessOptions, Admission, Admitted};
+pub(crate) use apply::apply_change;
+pub use apply::{excluding_partials, ApplyOptions};
+use apply::{remove_entry, ApplyPipeline};
+use audit_log::AuditLog;
+use auth::{presents_key, serves_path, speaks_protocol, Refusal};
+use backups::{BackupOptions, BackupTask};
+use hooks::{HookEvent, Hooks};
+use metrics::Metrics;
+use staging::{ExpiryTask, Journal, JournalEntry};
+use status_api::{ApiState, StatusApi, TreeHash};
+use tombstones::PurgeTask;
+pub use tombstones::Tombstones;
+pub use trash::Trash;
+pub use versions::Versions;
+pub use wal::WriteAheadLog;
 
 use crate::core::{
-    compression::decompress_dir, file_tree::FileTree, file_tree_diff::TreeDiff,
-    message::FileChangeMessage,
+    activity::Activity,
+    budget::MemoryBudget,
+    control::{ControlAddr, ControlEvent, ControlServer, Controls},
+    file_tree::{divergent_subtrees, root_checksum, FileTree, ScanOptions, SubtreeChecksum},
+    file_tree_diff::{PagedDiff, TreeDiff},
+    identity::{Identities, ListenerExchange},
+    keepalive::{DeadConnection, Keepalive, KeepaliveConfig},
+    merge::{self, MergeReport},
+    message::{
+        FileChangeMessage, Handshake, ReceiverMessage, RequestMessage, SenderMessage, SyncMessage,
+        TreePage, CHECKSUMS_HEADER, DEDUP_HEADER, FILE_BATCH_HEADER, GZIP_ARCHIVES_HEADER,
+        HARDLINKS_HEADER, MAX_MESSAGE_SIZE_HEADER, OUT_DIR_HEADER, PROTOCOL_HEADER,
+        PROTOCOL_VERSION, QUICK_CHECK_HEADER, SPARSE_FILES_HEADER, SPECIALS_HEADER,
+        SUBTREE_HASHES_HEADER, TREE_PAGES_HEADER,
+    },
+    overlap::DirIdentity,
+    roots::Roots,
+    summary::Transfer,
+    timeout::{with_timeout, TimedOut},
+    tls::ServerTls,
+    transport::{BoxedTransport, LoopbackListener},
+    utils::quoted,
+    webhook::{WebhookEvent, Webhooks},
 };
 
+/// What sessions run over: websocket connections the receiver accepted, or upgraded by a server
+/// it is embedded in, see `service`.
+trait Socket:
+    Stream<Item = Result<tungstenite::Message, tungstenite::Error>>
+    + Sink<tungstenite::Message, Error = tungstenite::Error>
+    + Unpin
+    + Send
+{
+}
+
+impl<T> Socket for T where
+    T: Stream<Item = Result<tungstenite::Message, tungstenite::Error>>
+        + Sink<tungstenite::Message, Error = tungstenite::Error>
+        + Unpin
+        + Send
+{
+}
+
+type WsStream = Box<dyn Socket>;
+type WsSink = SplitSink<WsStream, tungstenite::Message>;
+type WsSource = SplitStream<WsStream>;
+
+/// How long listeners pause after failing to accept a connection, e.g. out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Senders must get through the TLS and websocket handshakes and authenticate within this, unless
+/// the session timeout is shorter, so that clients too slow to present a key do not hold the
+/// session for long.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct ReceiverOptions {
+    /// Address to listen on, along with the port.
+    pub bind: IpAddr,
+    /// Which senders may connect, and how often.
+    pub access: AccessOptions,
+    /// Only serve websocket handshakes for this URL path, e.g. `/caiman` behind a reverse proxy.
+    pub path: Option<String>,
+    /// Serve senders over TLS, and require their client certificates if it has a client CA.
+    pub tls: Option<ServerTls>,
+    /// Only serve senders authenticating with a trusted identity, authenticating to them too.
+    pub identities: Option<Arc<Identities>>,
+    pub keepalive: KeepaliveConfig,
+    pub reconnect: bool,
+    pub timeout: Duration,
+    pub jobs: usize,
+    pub scan: ScanOptions,
+    pub apply: Arc<ApplyOptions>,
+    pub metrics: Arc<Metrics>,
+    /// Audit mode: journal each session's changes in this directory instead of applying them,
+    /// until an operator approves the session.
+    pub stage_dir: Option<PathBuf>,
+    /// Discard staged sessions left alone for this long.
+    pub staged_ttl: Option<Duration>,
+    /// Only serve senders presenting this key.
+    pub key: Option<String>,
+    /// Serve `control::Command`s on this address.
+    pub control: Option<ControlAddr>,
+    /// Serve the read-only HTTP API of `status_api` on this address.
+    pub status_api: Option<SocketAddr>,
+    pub hooks: Hooks,
+    /// Print the initial sync's summary as JSON.
+    pub json_summary: bool,
+    /// Log every applied change here.
+    pub audit_log: Option<Arc<AuditLog>>,
+    pub webhooks: Webhooks,
+    /// Keep the sender's tree as of the last completed sync in this file, and diff the next
+    /// initial syncs against it too, so that the receiver's own changes are told apart from the
+    /// sender's.
+    pub sync_state: Option<PathBuf>,
+    /// Back up the output directory periodically.
+    pub backups: Option<BackupOptions>,
+    /// Close sessions sending a message larger than this, once reassembled from its fragments.
+    /// Senders learn it during the handshake and split larger payloads.
+    pub max_message_size: u64,
+    /// Bounds the payloads received and not applied yet, across sessions.
+    pub memory: Option<MemoryBudget>,
+    /// Report what each batch changed here once applied, e.g. for a `Relay` to send it on.
+    pub applied: Option<mpsc::UnboundedSender<Vec<Applied>>>,
+}
+
+/// The initial sync, until its batch ends.
+struct InitialSync {
+    transfer: Transfer,
+    /// The sender's tree, recorded as the sync state once the sync completed.
+    tree: FileTree,
+}
+
+/// How a session ended: verification sessions leave the receiver listening for the next sender.
+enum SessionEnd {
+    Synced,
+    Verified,
+    /// The sender did not present the expected key, a valid client certificate or a trusted
+    /// identity.
+    Unauthorized,
+}
+
+/// What a sender's websocket handshake settled, once it was accepted.
+struct Handshaken {
+    /// Whether the sender's tree has `TimedFile` nodes, see `run_session`.
+    quick_check: bool,
+    /// The identity exchange the sender must complete, when only trusted senders are served.
+    exchange: Option<ListenerExchange>,
+}
+
+/// What a change did to the output directory, as far as the paths it touched go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Applied {
+    /// A file was written, linked or deleted.
+    File(PathBuf),
+    /// A directory was created whole or deleted, or files were written into it.
+    Directory(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+impl Applied {
+    /// What `change` does, unless it leaves the output directory as it was or only makes a FIFO.
+    fn of(change: &FileChangeMessage) -> Option<Self> {
+        let applied = match change {
+            FileChangeMessage::FileCreated(path)
+            | FileChangeMessage::FileDeleted(path)
+            | FileChangeMessage::FileEdited(path, ..)
+            | FileChangeMessage::GzippedFileEdited(path, ..)
+            | FileChangeMessage::FileChunk(path, _)
+            | FileChangeMessage::FileFromHash(path, ..)
+            | FileChangeMessage::HardlinkCreated(_, path) => Applied::File(path.clone()),
+            FileChangeMessage::EmptyDirectoryCreated(path)
+            | FileChangeMessage::DirectoryCreated(path, _)
+            | FileChangeMessage::GzippedDirectoryCreated(path, _)
+            | FileChangeMessage::DirectoryDeleted(path)
+            | FileChangeMessage::FileBatch(path, _) => Applied::Directory(path.clone()),
+            FileChangeMessage::Rename(old_path, new_path) => {
+                Applied::Renamed(old_path.clone(), new_path.clone())
+            }
+            FileChangeMessage::Checksummed(change, _) => return Applied::of(change),
+            FileChangeMessage::DirectoryContentsEdited(_) | FileChangeMessage::FifoCreated(_) => {
+                return None
+            }
+        };
+        Some(applied)
+    }
+}
+
+/// Where a session's changes go: applied to the output directory, or journaled until approved in
+/// audit mode.
+enum ChangeSink {
+    Apply {
+        pipeline: ApplyPipeline,
+        /// Paths touched since the last batch ended, for hooks.
+        changed: Vec<PathBuf>,
+        /// What the changes since the last batch ended did, for `ReceiverOptions::applied`.
+        applied: Vec<Applied>,
+    },
+    Stage(Journal),
+}
+
+impl ChangeSink {
+    async fn submit(&mut self, message: SyncMessage) -> anyhow::Result<()> {
+        match self {
+            ChangeSink::Apply {
+                pipeline,
+                changed,
+                applied,
+            } => {
+                changed.extend(message.change.paths().into_iter().map(Path::to_owned));
+                applied.extend(Applied::of(&message.change));
+                pipeline.reserve(&message).await;
+                pipeline.submit(message)
+            }
+            ChangeSink::Stage(journal) => journal.append(&JournalEntry::Sync(message)).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn skip(&mut self, id: u64) -> anyhow::Result<()> {
+        match self {
+            ChangeSink::Apply { pipeline, .. } => pipeline.skip(id),
+            ChangeSink::Stage(journal) => journal.append(&JournalEntry::Skipped(id)).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the changes received so far to be applied or recorded.
+    async fn drain(&mut self) -> anyhow::Result<()> {
+        match self {
+            ChangeSink::Apply { pipeline, .. } => pipeline.drain().await,
+            ChangeSink::Stage(journal) => journal.flush().await?,
+        }
+
+        Ok(())
+    }
+
+    /// Applies or records the renames and deletions of a diff, returning the entries to request.
+    async fn apply_diff(
+        &mut self,
+        diff: &TreeDiff<'_>,
+        out_dir: &Path,
+        options: &ApplyOptions,
+    ) -> anyhow::Result<Vec<RequestMessage>> {
+        match self {
+            ChangeSink::Apply {
+                pipeline,
+                changed,
+                applied,
+            } => {
+                let renamed = diff.renames();
+                let deleted = diff.deletions();
+                changed.extend(
+                    (renamed.iter().chain(&deleted))
+                        .flat_map(|change| change.paths())
+                        .map(Path::to_owned),
+                );
+                applied.extend(renamed.iter().chain(&deleted).filter_map(Applied::of));
+                let requests = match &options.tombstones {
+                    Some(tombstones) => {
+                        for change in &deleted {
+                            let dir = matches!(change, FileChangeMessage::DirectoryDeleted(_));
+                            tombstones.record(change.path(), dir)?;
+                        }
+                        let mut requests = diff.rename_dirs(out_dir).await;
+                        requests.extend(diff.requests());
+                        requests
+                    }
+                    // Deleted files are kept as versions or moved to the trash first.
+                    None if options.versions.is_some() || options.trash.is_some() => {
+                        for change in &deleted {
+                            let dir = matches!(change, FileChangeMessage::DirectoryDeleted(_));
+                            if let Err(err) =
+                                remove_entry(out_dir, change.path(), dir, options).await
+                            {
+                                eprintln!(
+                                    "An error occurred while deleting {}: {}",
+                                    quoted(change.path()),
+                                    err
+                                );
+                            }
+                        }
+                        let mut requests = diff.rename_dirs(out_dir).await;
+                        requests.extend(diff.requests());
+                        requests
+                    }
+                    None => diff.apply(out_dir).await,
+                };
+                for change in renamed.iter().chain(&deleted) {
+                    pipeline.audit(change);
+                }
+                Ok(requests)
+            }
+            ChangeSink::Stage(journal) => {
+                journal.record_diff(diff).await?;
+                Ok(diff.requests())
+            }
+        }
+    }
+
+    fn is_staged(&self) -> bool {
+        matches!(self, ChangeSink::Stage(_))
+    }
+
+    /// Waits for the next change refused before being applied, which never happens when changes
+    /// are only staged.
+    async fn rejected(&mut self) -> ReceiverMessage {
+        match self {
+            ChangeSink::Apply { pipeline, .. } => {
+                let (path, rejection) = pipeline.rejected().await;
+                ReceiverMessage::ChangeRejected(path, rejection)
+            }
+            ChangeSink::Stage(_) => std::future::pending().await,
+        }
+    }
+
+    /// Changes received but not applied yet, none when changes are only staged.
+    fn pending(&self) -> usize {
+        match self {
+            ChangeSink::Apply { pipeline, .. } => pipeline.held_back() + pipeline.running(),
+            ChangeSink::Stage(_) => 0,
+        }
+    }
+
+    /// The paths applied since the last call and what was done to them, none when changes are
+    /// only staged.
+    fn take_changed(&mut self) -> Option<(Vec<PathBuf>, Vec<Applied>)> {
+        match self {
+            ChangeSink::Apply {
+                changed, applied, ..
+            } => Some((std::mem::take(changed), std::mem::take(applied))),
+            ChangeSink::Stage(_) => None,
+        }
+    }
+
+    async fn finish(self, metrics: &Metrics) -> anyhow::Result<()> {
+        match self {
+            ChangeSink::Apply { mut pipeline, .. } => {
+                pipeline.drain().await;
+                metrics
+                    .failed
+                    .fetch_add(pipeline.failed(), Ordering::Relaxed);
+                if pipeline.held_back() > 0 {
+                    eprintln!(
+                        "Discarding {} changes whose dependencies never arrived",
+                        pipeline.held_back()
+                    );
+                }
+            }
+            ChangeSink::Stage(journal) => {
+                println!("Session {} staged, waiting for approval", journal.id());
+                journal.finish().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Receiver<P: AsRef<Path>> {
     port: u32,
     out_dir: P,
+    options: ReceiverOptions,
+    admission: Admission,
+    controls: Arc<Controls>,
+    activity: Arc<Mutex<Activity>>,
+    /// Served by the status API, invalidated once changes are applied.
+    tree_hash: Arc<TreeHash>,
+    /// Held by the session in progress, when senders are served through `service`.
+    #[cfg_attr(not(feature = "axum"), allow(dead_code))]
+    busy: tokio::sync::Mutex<()>,
 }
 
 impl<P: AsRef<Path>> Receiver<P> {
-    pub fn new(port: u32, out_dir: P) -> Self {
-        Self { port, out_dir }
+    pub fn new(port: u32, out_dir: P, options: ReceiverOptions) -> Self {
+        Self {
+            port,
+            out_dir,
+            admission: Admission::new(options.access.clone()),
+            options,
+            controls: Default::default(),
+            activity: Default::default(),
+            tree_hash: Default::default(),
+            busy: Default::default(),
+        }
     }
 
     pub async fn start(&self) -> anyhow::Result<()> {
-        let tree = FileTree::new(&self.out_dir).await?;
-        let addr = format!("127.0.0.1:{}", self.port);
-        let listener = TcpListener::bind(&addr).await?;
-        println!("WebSocket server listening on {}", addr.as_str());
+        self.recover().await?;
+        let _expiry = self.spawn_expiry();
+        let _purge = self.spawn_purge()?;
+        let _backups = self.spawn_backups()?;
+        let _control = self.spawn_control().await?;
+        let _status_api = self.spawn_status_api().await?;
+        let mut tree = self.scan_out_dir().await?;
+        let addr = SocketAddr::new(self.options.bind, self.port.try_into()?);
+        let listener = TcpListener::bind(addr).await?;
+        println!("WebSocket server listening on {}", addr);
 
-        tokio::select! {
-            res = listener.accept() => {
-                let (stream, _) = res.unwrap();
-                self.sync_dir(&tree, stream).await?
+        loop {
+            tokio::select! {
+                (stream, addr) = accept(&listener) => {
+                    let Some(_admitted) = self.admit(addr) else {
+                        continue;
+                    };
+                    match self.serve_session(&listener, &tree, stream, addr).await {
+                        Err(err) if self.options.reconnect && is_connection_error(&err) => {
+                            eprintln!("{}\nWaiting for the sender to reconnect", err);
+                            tree = self.scan_out_dir().await?;
+                            continue;
+                        }
+                        Ok(SessionEnd::Verified | SessionEnd::Unauthorized) => continue,
+                        res => res?,
+                    };
+                }
+
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Shutting down gracefully");
+                }
+
+                _ = self.controls.shutdown_requested() => {
+                    println!("Shutting down gracefully");
+                }
+            };
+
+            break Ok(());
+        }
+    }
+
+    /// Describes the listener, for its control endpoint and status API.
+    fn status(&self) -> impl Fn() -> serde_json::Value + Send + Sync + 'static {
+        let out_dir = self.out_dir.as_ref().to_path_buf();
+        let metrics = self.options.metrics.clone();
+        let activity = self.activity.clone();
+        let apply = self.options.apply.clone();
+        move || {
+            let mut status = serde_json::json!({
+                "role": "receiver",
+                "output_dir": out_dir,
+                "metrics": metrics,
+                "activity": *activity.lock().unwrap(),
+            });
+            if let Some(tombstones) = &apply.tombstones {
+                status["pending_deletions"] = serde_json::json!(tombstones.pending());
             }
+            status
+        }
+    }
+
+    async fn spawn_control(&self) -> anyhow::Result<Option<ControlServer>> {
+        let Some(addr) = self.options.control.clone() else {
+            return Ok(None);
+        };
+
+        Ok(Some(
+            ControlServer::spawn(addr, self.controls.clone(), self.status()).await?,
+        ))
+    }
 
-            _ = tokio::signal::ctrl_c() => {
-                println!("Shutting down gracefully");
+    async fn spawn_status_api(&self) -> anyhow::Result<Option<StatusApi>> {
+        let Some(addr) = self.options.status_api else {
+            return Ok(None);
+        };
+
+        let status = self.status();
+        let controls = self.controls.clone();
+        let (out_dir, scan) = (self.out_dir.as_ref().to_path_buf(), self.options.scan);
+        let apply = self.options.apply.clone();
+        let state = ApiState {
+            status: Box::new(move || controls.describe(status())),
+            activity: self.activity.clone(),
+            tree: Box::new(move || {
+                let (out_dir, apply) = (out_dir.clone(), apply.clone());
+                Box::pin(async move {
+                    let tree = FileTree::new_with(out_dir, scan).await?;
+                    Ok(apply.converted.as_sent(tree))
+                })
+            }),
+            tree_hash: self.tree_hash.clone(),
+            audit_log: self.options.audit_log.clone(),
+        };
+        Ok(Some(StatusApi::spawn(addr, state).await?))
+    }
+
+    /// Serves a sender running in the same process, until it disconnects for good.
+    pub async fn start_loopback(&self, mut listener: LoopbackListener) -> anyhow::Result<()> {
+        self.recover().await?;
+        let _expiry = self.spawn_expiry();
+        let _purge = self.spawn_purge()?;
+        let _backups = self.spawn_backups()?;
+        while let Some(stream) = listener.accept().await {
+            let tree = self.scan_out_dir().await?;
+            self.sync_dir(&tree, stream, "loopback").await?;
+        }
+
+        Ok(())
+    }
+
+    /// Recovers from the changes a crash of an earlier run left unfinished, and loads the files
+    /// it converted, before the output directory is scanned.
+    async fn recover(&self) -> anyhow::Result<()> {
+        if !self.options.apply.middleware.is_empty() {
+            self.options.apply.converted.load(self.out_dir.as_ref())?;
+        }
+        if let Some(wal) = &self.options.apply.wal {
+            for recovered in wal
+                .recover(self.out_dir.as_ref(), &self.options.apply)
+                .await?
+            {
+                println!("Recovering from an interrupted run: {}", recovered);
             }
+        }
+
+        Ok(())
+    }
+
+    fn spawn_expiry(&self) -> Option<ExpiryTask> {
+        let stage_dir = self.options.stage_dir.clone()?;
+        let ttl = self.options.staged_ttl?;
+        Some(ExpiryTask::spawn(stage_dir, ttl))
+    }
+
+    /// Loads the deletions held back by earlier runs, and applies them as they become due.
+    fn spawn_purge(&self) -> anyhow::Result<Option<PurgeTask>> {
+        if let Some(tombstones) = &self.options.apply.tombstones {
+            tombstones.load(self.out_dir.as_ref())?;
+        }
+
+        Ok(PurgeTask::spawn(
+            self.out_dir.as_ref().to_owned(),
+            self.options.apply.clone(),
+            self.controls.clone(),
+        ))
+    }
+
+    fn spawn_backups(&self) -> anyhow::Result<Option<BackupTask>> {
+        let Some(options) = self.options.backups.clone() else {
+            return Ok(None);
+        };
+
+        BackupTask::spawn(self.out_dir.as_ref().to_owned(), options).map(Some)
+    }
+
+    /// Applies the access options to a connection from `addr`, which is closed when refused.
+    fn admit(&self, addr: SocketAddr) -> Option<Admitted> {
+        match self.admission.admit(addr.ip()) {
+            Ok(admitted) => Some(admitted),
+            Err(refusal) => {
+                eprintln!("Refusing connection from {}: {}", addr, refusal);
+                self.options
+                    .metrics
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Runs a sync session to completion, turning away any other sender that connects meanwhile.
+    async fn serve_session(
+        &self,
+        listener: &TcpListener,
+        tree: &FileTree,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> anyhow::Result<SessionEnd> {
+        let session = async {
+            let Some(stream) = self.secure(stream, addr).await else {
+                return Ok(SessionEnd::Unauthorized);
+            };
+            self.sync_dir(tree, stream, addr).await
+        };
+        tokio::pin!(session);
+
+        loop {
+            tokio::select! {
+                res = &mut session => break res,
+                (stream, addr) = accept(listener) => {
+                    let Some(admitted) = self.admit(addr) else {
+                        continue;
+                    };
+                    eprintln!("Rejecting sender at {}, a sync session is already in progress", addr);
+                    self.options.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    let (tls, timeout) = (self.options.tls.clone(), self.options.timeout);
+                    tokio::spawn(async move {
+                        let _admitted = admitted;
+                        reject_busy(stream, tls, timeout).await
+                    });
+                }
+            }
+        }
+    }
+
+    /// Runs the TLS handshake if the receiver serves TLS. Senders failing it, e.g. for lack of a
+    /// valid client certificate, are turned away.
+    async fn secure(&self, stream: TcpStream, addr: SocketAddr) -> Option<BoxedTransport> {
+        let Some(tls) = &self.options.tls else {
+            return Some(Box::new(stream));
+        };
+
+        let timeout = handshake_timeout(self.options.timeout);
+        let handshake = with_timeout(timeout, "TLS handshake", tls.accept(stream))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|res| res);
+        match handshake {
+            Ok(stream) => Some(stream),
+            Err(err) => {
+                self.reject(addr, format!("{:#}", err));
+                None
+            }
+        }
+    }
+
+    /// Completes the identity exchange, reading the sender's signature from its first message.
+    async fn authenticate(
+        &self,
+        socket: &mut WsStream,
+        exchange: ListenerExchange,
+        addr: impl Display,
+    ) -> anyhow::Result<()> {
+        let Some(identities) = &self.options.identities else {
+            return Ok(());
+        };
+
+        let message = with_timeout(
+            handshake_timeout(self.options.timeout),
+            "authenticating the sender",
+            socket.next(),
+        )
+        .await?
+        .context("the sender disconnected before authenticating")??;
+        let tungstenite::Message::Binary(signature) = message else {
+            bail!("the sender did not sign the challenge")
         };
+        // Senders connect from another port each time, senders trusted on first use are named
+        // after their host.
+        let addr = addr.to_string();
+        let host = addr
+            .rsplit_once(':')
+            .map_or(addr.as_str(), |(host, _)| host);
+        let name = exchange.verify(&identities.peers, &signature, host)?;
+        println!("Sender at {} authenticated as {}", addr, name);
 
         Ok(())
     }
 
-    async fn sync_dir(&self, tree: &FileTree, stream: TcpStream) -> anyhow::Result<()> {
-        let socket = tokio_tungstenite::accept_async(stream).await?;
-        let (mut write, mut read) = socket.split();
+    /// Logs why the sender at `addr` is turned away, counting it as rejected.
+    fn reject(&self, addr: impl Display, reason: impl Display) {
+        eprintln!("Rejecting sender at {}: {}", addr, reason);
+        self.options
+            .metrics
+            .rejected
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Answers a sender's websocket handshake, telling it in `response` what the receiver supports.
+    fn answer_handshake(
+        &self,
+        request: &Request,
+        response: &mut Response,
+    ) -> Result<Handshaken, Refusal> {
+        if !serves_path(self.options.path.as_deref(), request) {
+            return Err(Refusal::WrongPath(request.uri().path().to_owned()));
+        }
+        if let Err(version) = speaks_protocol(request) {
+            return Err(Refusal::WrongProtocol(version));
+        }
+        advertise_limits(response, self.options.max_message_size);
+        advertise_dedup(response, &self.options.apply);
+        advertise_specials(response, &self.options.apply);
+        let out_dir = DirIdentity::of(self.out_dir.as_ref());
+        advertise_out_dir(response, out_dir.as_ref());
+        if !presents_key(self.options.key.as_deref(), request) {
+            return Err(Refusal::WrongKey);
+        }
+        let exchange = match &self.options.identities {
+            Some(identities) => Some(
+                identities
+                    .answer(request.headers(), response.headers_mut())
+                    .map_err(Refusal::Untrusted)?,
+            ),
+            None => None,
+        };
 
-        let initial_message = read
-            .next()
+        Ok(Handshaken {
+            quick_check: request.headers().contains_key(QUICK_CHECK_HEADER),
+            exchange,
+        })
+    }
+
+    async fn sync_dir(
+        &self,
+        tree: &FileTree,
+        stream: BoxedTransport,
+        addr: impl Display,
+    ) -> anyhow::Result<SessionEnd> {
+        let max_message_size = self.options.max_message_size;
+        let mut answer = None;
+        // The callback's signature is imposed by tungstenite.
+        #[allow(clippy::result_large_err)]
+        let handshake = |request: &Request, mut response: Response| {
+            let handshaken = self.answer_handshake(request, &mut response);
+            let refused = handshaken.as_ref().err().map(Refusal::response);
+            answer = Some(handshaken);
+            match refused {
+                Some(refused) => Err(refused),
+                None => Ok(response),
+            }
+        };
+        let accepted = with_timeout(
+            handshake_timeout(self.options.timeout),
+            "websocket handshake",
+            tokio_tungstenite::accept_hdr_async_with_config(
+                stream,
+                handshake,
+                Some(websocket_config(max_message_size)),
+            ),
+        )
+        .await;
+        if let Some(Err(refusal)) = answer {
+            self.reject(&addr, refusal);
+            return Ok(SessionEnd::Unauthorized);
+        }
+        // Senders failing the handshake, e.g. too slow to get through it, are turned away like
+        // refused ones rather than ending the listener.
+        let accepted = accepted
+            .map_err(anyhow::Error::from)
+            .and_then(|res| Ok(res?));
+        let socket = match accepted {
+            Ok(socket) => socket,
+            Err(err) => {
+                self.reject(&addr, format!("{:#}", err));
+                return Ok(SessionEnd::Unauthorized);
+            }
+        };
+        let Some(Ok(handshaken)) = answer else {
+            unreachable!("accepted handshakes are answered")
+        };
+
+        self.serve_socket(Box::new(socket), handshaken, Some(tree), addr)
             .await
-            .context("Unexpected end of stream, sender did not send initial directoy state")??;
+    }
+
+    /// Runs a session over a socket the sender's handshake was accepted on, once the sender
+    /// authenticates if it must. `prescanned` is passed on to `run_session`.
+    async fn serve_socket(
+        &self,
+        mut socket: WsStream,
+        handshaken: Handshaken,
+        prescanned: Option<&FileTree>,
+        addr: impl Display,
+    ) -> anyhow::Result<SessionEnd> {
+        if let Some(exchange) = handshaken.exchange {
+            if let Err(err) = self.authenticate(&mut socket, exchange, &addr).await {
+                self.reject(&addr, format!("{:#}", err));
+                let _ = socket.close().await;
+                return Ok(SessionEnd::Unauthorized);
+            }
+        }
+
+        self.activity.lock().unwrap().connected(&addr);
+        let res = self
+            .run_session(socket, prescanned, handshaken.quick_check)
+            .await;
+        let error = {
+            let mut activity = self.activity.lock().unwrap();
+            activity.disconnected();
+            let error = res.as_ref().err().map(|err| format!("{:#}", err));
+            if let Some(error) = &error {
+                activity.error(error);
+            }
+            error
+        };
+
+        if let Some(error) = error {
+            self.post_webhook(WebhookEvent::Error(error)).await;
+        }
+        // Verification sessions are not syncs.
+        if !matches!(res, Ok(SessionEnd::Verified)) {
+            self.post_webhook(WebhookEvent::Disconnected).await;
+        }
+
+        res
+    }
+
+    async fn post_webhook(&self, event: WebhookEvent) {
+        let peer = self.activity.lock().unwrap().peer.clone();
+        self.options.webhooks.post(event, "receiver", peer).await;
+    }
+
+    /// Serves a sender over an accepted connection. `prescanned` is the output directory's tree
+    /// if it was scanned while waiting for the sender. `quick_check` is set when the sender's
+    /// tree has `TimedFile` nodes, the local tree being quick checked too to compare them.
+    async fn run_session(
+        &self,
+        socket: WsStream,
+        prescanned: Option<&FileTree>,
+        quick_check: bool,
+    ) -> anyhow::Result<SessionEnd> {
+        let metrics = &self.options.metrics;
+        metrics.sessions.fetch_add(1, Ordering::Relaxed);
+        // Senders only deduplicate files against the ones they sent during the same session.
+        self.options.apply.blobs.clear();
+        let mut transfer = Transfer::start();
+        let (mut write, mut read) = socket.split();
+
+        let initial_message = with_timeout(
+            self.options.timeout,
+            "waiting for the initial directory state",
+            read.next(),
+        )
+        .await?
+        .context("Unexpected end of stream, sender did not send initial directoy state")??;
 
         let initial_message = match initial_message {
             tungstenite::Message::Binary(bin) => bin,
             _ => bail!("Incorrect initial message format, expected binary message"),
         };
 
-        let remote_tree: FileTree = bincode::deserialize(&initial_message)?;
-        if !remote_tree.is_valid() {
+        // Paged trees are received once the local tree is scanned, see `diff_tree_pages`.
+        let (roots, remote_tree) = match bincode::deserialize(&initial_message)? {
+            Handshake::Sync { dests, tree } => {
+                (Roots::under(self.out_dir.as_ref(), dests)?, Some(tree))
+            }
+            Handshake::PagedSync { dests } => (Roots::under(self.out_dir.as_ref(), dests)?, None),
+            Handshake::Verify { dests } => {
+                println!("Sending directory state for verification");
+                let roots = Roots::under(self.out_dir.as_ref(), dests)?;
+                let encoded = bincode::serialize(&ReceiverMessage::Tree(
+                    self.scan_roots(&roots, self.options.scan).await?,
+                ))?;
+                let reply = write.send(tungstenite::Message::binary(encoded));
+                with_timeout(self.options.timeout, "sending the directory state", reply).await??;
+                return Ok(SessionEnd::Verified);
+            }
+        };
+        if remote_tree
+            .as_ref()
+            .is_some_and(|tree| !is_valid_tree(tree, &roots))
+        {
             bail!("Invalid file tree received, aborting")
         }
+        self.post_webhook(WebhookEvent::SyncStarted).await;
 
-        let diff = TreeDiff::from(tree, &remote_tree);
-        let requested_files = diff.apply(self.out_dir.as_ref()).await;
-        println!("Initial sync completed\n{}", &diff);
+        // The tree scanned while waiting only covers a sender syncing into the whole directory,
+        // and is hashed.
+        let rescanned;
+        let tree = match prescanned {
+            Some(tree) if roots.dests() == [PathBuf::new()] && !quick_check => tree,
+            _ => {
+                roots.create_parents().await?;
+                let scan = ScanOptions {
+                    quick_check,
+                    ..self.options.scan
+                };
+                rescanned = self.scan_roots(&roots, scan).await?;
+                &rescanned
+            }
+        };
 
-        let encoded = bincode::serialize(&requested_files)?;
-        write.send(tungstenite::Message::binary(encoded)).await?;
+        let mut sink = match &self.options.stage_dir {
+            Some(stage_dir) => {
+                let journal = Journal::create(stage_dir, self.out_dir.as_ref()).await?;
+                println!(
+                    "Staging session {}, review it with `white-caiman staged show --stage-dir {} --session {}`",
+                    journal.id(),
+                    stage_dir.display(),
+                    journal.id()
+                );
+                ChangeSink::Stage(journal)
+            }
+            None => ChangeSink::Apply {
+                pipeline: ApplyPipeline::new(
+                    self.out_dir.as_ref(),
+                    self.options.jobs,
+                    self.options.apply.clone(),
+                )
+                .reporting_to(self.activity.clone())
+                .auditing(self.options.audit_log.clone(), self.peer())
+                .within(self.options.memory.clone()),
+                changed: vec![],
+                applied: vec![],
+            },
+        };
+
+        let ancestor = match &self.options.sync_state {
+            Some(path) => sync_state::load(path)?,
+            None => None,
+        };
+        // Merging with the sync state, saving it and preallocating need the whole tree.
+        let remote_tree = match remote_tree {
+            None if self.options.sync_state.is_some() || self.options.apply.preallocate => {
+                Some(self.receive_tree_pages(&mut read, &roots).await?)
+            }
+            remote_tree => remote_tree,
+        };
+        let (requested_files, remote_tree) = match remote_tree {
+            Some(remote_tree) => {
+                let (diff, merge) = self.initial_diff(tree, &remote_tree, ancestor.as_ref());
+                transfer.diffed(&diff);
+                if let Some(merge) = &merge {
+                    if !sink.is_staged() {
+                        sync_state::keep_conflicting(self.out_dir.as_ref(), merge)?;
+                    }
+                }
+                let requested_files = sink
+                    .apply_diff(&diff, self.out_dir.as_ref(), &self.options.apply)
+                    .await?;
+                if self.options.apply.preallocate && !sink.is_staged() {
+                    let out_dir = self.out_dir.as_ref();
+                    let incoming =
+                        preallocate::incoming_size(out_dir, &remote_tree, &requested_files);
+                    if let Err(err) = preallocate::check_space(out_dir, incoming) {
+                        let close = write.send(tungstenite::Message::Close(Some(CloseFrame {
+                            code: CloseCode::Error,
+                            reason: err.to_string().into(),
+                        })));
+                        with_timeout(self.options.timeout, "closing the connection", close)
+                            .await??;
+                        return Err(err);
+                    }
+                }
+                self.measure_usage();
+                match sink.is_staged() {
+                    true => println!("Initial sync staged\n{}", &diff),
+                    false => println!("Initial sync completed\n{}", &diff),
+                }
+                if let Some(merge) = merge.filter(|merge| !merge.is_empty()) {
+                    println!("Compared with the last synced state:{}", merge);
+                }
+                (requested_files, remote_tree)
+            }
+            None => {
+                let requested_files = self
+                    .diff_tree_pages(&mut read, &roots, tree, &mut sink, &mut transfer)
+                    .await?;
+                self.measure_usage();
+                match sink.is_staged() {
+                    true => println!("Initial sync staged"),
+                    false => println!("Initial sync completed"),
+                }
+                (requested_files, FileTree::default())
+            }
+        };
+
+        let encoded = bincode::serialize(&ReceiverMessage::Requests(requested_files))?;
+        with_timeout(
+            self.options.timeout,
+            "sending the files request",
+            write.send(tungstenite::Message::binary(encoded)),
+        )
+        .await??;
+
+        let mut keepalive = Keepalive::new(self.options.keepalive);
+        let res = self
+            .receive_changes(
+                &mut write,
+                &mut read,
+                &roots,
+                &mut sink,
+                &mut keepalive,
+                InitialSync {
+                    transfer,
+                    tree: remote_tree,
+                },
+            )
+            .await;
+        sink.finish(metrics).await?;
+        self.save_conversions();
+        self.tree_hash.invalidate();
+        self.options.hooks.wait().await;
+
+        res.map(|_| SessionEnd::Synced)
+    }
 
-        while let Some(message) = read.next().await {
+    async fn receive_changes(
+        &self,
+        write: &mut WsSink,
+        read: &mut WsSource,
+        roots: &Roots,
+        sink: &mut ChangeSink,
+        keepalive: &mut Keepalive,
+        initial: InitialSync,
+    ) -> anyhow::Result<()> {
+        let mut controls = self.controls.attach();
+        // Messages received while paused, handled in order once resumed.
+        let mut held = VecDeque::new();
+        let mut fragments = vec![];
+        // Counts the initial sync, until its batch ends.
+        let mut initial = Some(initial);
+        loop {
+            self.activity.lock().unwrap().files_pending = held.len() + sink.pending();
+            let message = tokio::select! {
+                message = read.next() => message,
+                res = keepalive.tick() => {
+                    res?;
+                    let ping = write.send(tungstenite::Message::Ping(Vec::new()));
+                    with_timeout(self.options.timeout, "sending a ping", ping).await??;
+                    continue;
+                }
+
+                rejected = sink.rejected() => {
+                    self.reply(write, Some(rejected)).await?;
+                    continue;
+                }
+
+                event = controls.next() => match event {
+                    ControlEvent::Paused => {
+                        println!("Paused, holding changes back");
+                        continue;
+                    }
+                    ControlEvent::Resumed => {
+                        println!("Resumed, applying {} held messages", held.len());
+                        while let Some(message) = held.pop_front() {
+                            let reply = self.handle_message(roots, sink, message, &mut initial).await?;
+                            self.reply(write, reply).await?;
+                        }
+                        continue;
+                    }
+                    ControlEvent::Resync if sink.is_staged() => {
+                        println!("Resync requested, ignored in audit mode");
+                        continue;
+                    }
+                    ControlEvent::Resync => {
+                        println!("Resync requested");
+                        sink.drain().await?;
+                        self.reply(write, Some(ReceiverMessage::ChecksumsRequested)).await?;
+                        continue;
+                    }
+                    ControlEvent::Shutdown => {
+                        println!("Shutting down gracefully");
+                        if !held.is_empty() {
+                            eprintln!("Dropping {} messages held while paused", held.len());
+                        }
+                        let close = write.send(tungstenite::Message::Close(Some(CloseFrame {
+                            code: CloseCode::Away,
+                            reason: "receiver is shutting down".into(),
+                        })));
+                        with_timeout(self.options.timeout, "closing the connection", close)
+                            .await??;
+                        break;
+                    }
+                },
+            };
+
+            let Some(message) = message else {
+                break;
+            };
+
+            if let Err(tungstenite::Error::Capacity(_)) = message {
+                return self.refuse_oversized(write).await;
+            }
             if message.is_err() {
                 continue;
             }
 
-            let message: FileChangeMessage = match message.as_ref().unwrap() {
-                tungstenite::Message::Binary(bin) => bincode::deserialize(bin).unwrap(),
+            keepalive.seen();
+
+            let message: SenderMessage = match message.as_ref().unwrap() {
+                tungstenite::Message::Binary(bin) => {
+                    let metrics = &self.options.metrics;
+                    let received = bin.len() as u64;
+                    metrics
+                        .bytes_received
+                        .fetch_add(received, Ordering::Relaxed);
+                    self.activity.lock().unwrap().bytes_transferred += received;
+                    bincode::deserialize(bin)
+                        .context("Malformed message received, the sender may be incompatible")?
+                }
                 tungstenite::Message::Close(_) => {
                     println!("Stream closed, exiting");
                     break;
                 }
+                tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) => continue,
                 _ => {
                     eprintln!("Received non-binary message, ignoring");
                     continue;
                 }
             };
 
-            if let Err(err) = self.handle_message(message).await {
-                eprintln!("An error occurred while handling message: {}", err);
+            // Large messages arrive in fragments, possibly with control messages in between.
+            let message = match message {
+                SenderMessage::Fragment { data, last } => {
+                    if (fragments.len() + data.len()) as u64 > self.options.max_message_size {
+                        return self.refuse_oversized(write).await;
+                    }
+                    fragments.extend_from_slice(&data);
+                    if !last {
+                        continue;
+                    }
+
+                    bincode::deserialize(&std::mem::take(&mut fragments))?
+                }
+                message => message,
             };
+
+            if controls.is_paused() {
+                held.push_back(message);
+                continue;
+            }
+
+            let reply = self
+                .handle_message(roots, sink, message, &mut initial)
+                .await?;
+            self.reply(write, reply).await?;
         }
 
         Ok(())
     }
 
-    async fn handle_message(&self, message: FileChangeMessage) -> anyhow::Result<()> {
-        match message {
-            FileChangeMessage::FileCreated(path) => {
-                let file_path = self.out_dir.as_ref().join(path);
-                tokio::fs::File::create(file_path).await?;
+    /// Ends the session over a message larger than `max_message_size`, which senders honoring
+    /// the handshake never send.
+    async fn refuse_oversized(&self, write: &mut WsSink) -> anyhow::Result<()> {
+        let reason = format!(
+            "message larger than the {} limit",
+            ByteSize::b(self.options.max_message_size)
+        );
+        let close = write.send(tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::Size,
+            reason: reason.clone().into(),
+        })));
+        with_timeout(self.options.timeout, "closing the connection", close).await??;
+
+        bail!("Refusing a {}", reason)
+    }
+
+    async fn reply(
+        &self,
+        write: &mut WsSink,
+        reply: Option<ReceiverMessage>,
+    ) -> anyhow::Result<()> {
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+
+        let encoded = bincode::serialize(&reply)?;
+        let reply = write.send(tungstenite::Message::binary(encoded));
+        with_timeout(self.options.timeout, "sending a reply", reply).await??;
+
+        Ok(())
+    }
+
+    async fn handle_message(
+        &self,
+        roots: &Roots,
+        sink: &mut ChangeSink,
+        message: SenderMessage,
+        initial: &mut Option<InitialSync>,
+    ) -> anyhow::Result<Option<ReceiverMessage>> {
+        let reply = match message {
+            SenderMessage::Sync(message) => {
+                let paths = message.change.paths();
+                if let Some(path) = paths.into_iter().find(|path| !roots.contains(path)) {
+                    bail!(
+                        "Refusing a change to {}, outside of the synced directories",
+                        quoted(path)
+                    )
+                }
+
+                // Watchman does not say what changed inside an edited directory, so its
+                // subtree is re-exchanged and diffed once earlier changes are applied.
+                let reply = match &message.change {
+                    FileChangeMessage::DirectoryContentsEdited(path) => {
+                        Some(ReceiverMessage::SubtreesRequested(vec![path.clone()]))
+                    }
+                    _ => None,
+                };
+
+                self.options.metrics.changes.fetch_add(1, Ordering::Relaxed);
+                if let Some(initial) = initial {
+                    initial.transfer.count(&message.change);
+                }
+                sink.submit(message).await?;
+                reply
             }
-            FileChangeMessage::FileDeleted(path) => {
-                let file_path = self.out_dir.as_ref().join(path);
-                tokio::fs::remove_file(file_path).await?;
+            SenderMessage::Skipped(id) => {
+                sink.skip(id).await?;
+                None
             }
-            FileChangeMessage::Rename(old_path, new_path) => {
-                let from = self.out_dir.as_ref().join(old_path);
-                let to = self.out_dir.as_ref().join(new_path);
-                tokio::fs::rename(from, to).await?;
+            // Staged changes are not in the output directory yet, so it cannot match.
+            SenderMessage::RootChecksum(_) | SenderMessage::Checksums(_) if sink.is_staged() => {
+                None
+            }
+            SenderMessage::RootChecksum(checksum) => {
+                sink.drain().await?;
+                self.compare_root_checksum(roots, &checksum).await?
+            }
+            SenderMessage::Checksums(checksums) => {
+                sink.drain().await?;
+                self.compare_checksums(roots, &checksums).await?
             }
-            FileChangeMessage::EmptyDirectoryCreated(path) => {
-                let dir_path = self.out_dir.as_ref().join(path);
-                tokio::fs::create_dir(dir_path).await?;
+            SenderMessage::Subtree(path, remote_subtree) => {
+                sink.drain().await?;
+                self.resync_subtree(roots, sink, &path, &remote_subtree)
+                    .await?
             }
-            FileChangeMessage::DirectoryCreated(path, compressed) => {
-                let dir_path = self.out_dir.as_ref().join(path);
-                tokio::fs::create_dir(dir_path.as_path()).await?;
-                decompress_dir(dir_path.as_path(), compressed.as_ref()).await?;
+            SenderMessage::FullTree(remote_tree) => {
+                sink.drain().await?;
+                self.resync_tree(roots, sink, &remote_tree).await?
+            }
+            SenderMessage::BatchEnd => {
+                let initial = initial.take();
+                let event = match initial {
+                    Some(_) => HookEvent::Sync,
+                    None => HookEvent::Change,
+                };
+                self.run_hooks(sink, event).await?;
+                self.activity.lock().unwrap().synced();
+                self.save_conversions();
+                self.rehash_tree(sink).await?;
+                if let Some(initial) = initial {
+                    sink.drain().await?;
+                    let summary = initial.transfer.finish();
+                    summary.print(self.options.json_summary);
+                    if !sink.is_staged() {
+                        self.save_sync_state(roots, initial.tree);
+                    }
+                    self.post_webhook(WebhookEvent::SyncCompleted(summary))
+                        .await;
+                }
+                None
             }
-            FileChangeMessage::DirectoryDeleted(path) => {
-                let dir_path = self.out_dir.as_ref().join(path);
-                tokio::fs::remove_dir_all(dir_path).await?;
+            SenderMessage::Fragment { .. } => bail!("Nested message fragment received"),
+        };
+
+        Ok(reply)
+    }
+
+    /// Scans the whole output directory, see `scan_roots`.
+    async fn scan_out_dir(&self) -> anyhow::Result<FileTree> {
+        let tree = FileTree::new_with(&self.out_dir, self.options.scan).await?;
+        Ok(self.options.apply.converted.as_sent(tree))
+    }
+
+    /// Scans the output directory below `roots`, with the files the middleware converted as the
+    /// sender sent them, for them to be compared with the sender's.
+    async fn scan_roots(&self, roots: &Roots, scan: ScanOptions) -> anyhow::Result<FileTree> {
+        let tree = roots.tree(scan).await?;
+        Ok(self.options.apply.converted.as_sent(tree))
+    }
+
+    fn peer(&self) -> String {
+        let peer = self.activity.lock().unwrap().peer.clone();
+        peer.unwrap_or_default()
+    }
+
+    fn diff<'tree>(&self, local: &'tree FileTree, remote: &'tree FileTree) -> TreeDiff<'tree> {
+        self.narrowed(TreeDiff::from(local, remote))
+    }
+
+    /// Leaves existing entries alone with `ignore_existing`.
+    fn narrowed<'tree>(&self, diff: TreeDiff<'tree>) -> TreeDiff<'tree> {
+        match self.options.apply.ignore_existing {
+            true => diff.without_existing(),
+            false => diff,
+        }
+    }
+
+    /// Diffs the sender's tree against `local` page by page as it arrives, applying or recording
+    /// the deletions of each page right away, and returns the entries to request.
+    async fn diff_tree_pages(
+        &self,
+        read: &mut WsSource,
+        roots: &Roots,
+        local: &FileTree,
+        sink: &mut ChangeSink,
+        transfer: &mut Transfer,
+    ) -> anyhow::Result<Vec<RequestMessage>> {
+        let mut paged = PagedDiff::new(local);
+        let mut previous = None;
+        let mut requests = vec![];
+        loop {
+            let page = self.read_tree_page(read, roots, &mut previous).await?;
+            let diff = self.narrowed(paged.page(&page.nodes, page.last));
+            transfer.diffed(&diff);
+            requests.extend(
+                sink.apply_diff(&diff, self.out_dir.as_ref(), &self.options.apply)
+                    .await?,
+            );
+            if !diff.is_empty() {
+                println!("Diffed {} entries{}", page.nodes.len(), &diff);
+            }
+            if page.last {
+                return Ok(requests);
+            }
+        }
+    }
+
+    /// Receives the whole tree of a `Handshake::PagedSync`.
+    async fn receive_tree_pages(
+        &self,
+        read: &mut WsSource,
+        roots: &Roots,
+    ) -> anyhow::Result<FileTree> {
+        let mut previous = None;
+        let mut nodes = vec![];
+        loop {
+            let page = self.read_tree_page(read, roots, &mut previous).await?;
+            nodes.extend(page.nodes);
+            if page.last {
+                return Ok(FileTree::from_iter(nodes));
+            }
+        }
+    }
+
+    /// Reads the next `TreePage`, checking it follows the `previous` path.
+    async fn read_tree_page(
+        &self,
+        read: &mut WsSource,
+        roots: &Roots,
+        previous: &mut Option<PathBuf>,
+    ) -> anyhow::Result<TreePage> {
+        let message = with_timeout(
+            self.options.timeout,
+            "waiting for the directory state",
+            read.next(),
+        )
+        .await?
+        .context("Unexpected end of stream, sender did not send its whole directory state")??;
+        let tungstenite::Message::Binary(bin) = message else {
+            bail!("Incorrect directory state format, expected binary message")
+        };
+
+        let page: TreePage = bincode::deserialize(&bin)?;
+        let sorted = previous
+            .iter()
+            .chain(page.nodes.iter().map(|node| &node.path))
+            .is_sorted();
+        if !sorted || !page.nodes.iter().all(|node| roots.contains(&node.path)) {
+            bail!("Invalid file tree received, aborting")
+        }
+        if let Some(node) = page.nodes.last() {
+            *previous = Some(node.path.clone());
+        }
+
+        Ok(page)
+    }
+
+    /// Diffs three ways when the sync state is known.
+    fn initial_diff<'tree>(
+        &self,
+        local: &'tree FileTree,
+        remote: &'tree FileTree,
+        ancestor: Option<&FileTree>,
+    ) -> (TreeDiff<'tree>, Option<MergeReport<'tree>>) {
+        let Some(ancestor) = ancestor else {
+            return (self.diff(local, remote), None);
+        };
+
+        let (diff, merge) = merge::three_way(local, remote, ancestor);
+        (self.narrowed(diff), Some(merge))
+    }
+
+    fn save_sync_state(&self, roots: &Roots, tree: FileTree) {
+        let Some(path) = &self.options.sync_state else {
+            return;
+        };
+
+        let out_dir = self.out_dir.as_ref();
+        if let Err(err) = sync_state::save(path, out_dir, roots, tree, self.options.scan.size_only)
+        {
+            eprintln!("WARNING: could not save the sync state: {:#}", err);
+        }
+    }
+
+    /// Has the status API hash the tree again once the changes received so far are applied.
+    async fn rehash_tree(&self, sink: &mut ChangeSink) -> anyhow::Result<()> {
+        if self.options.status_api.is_some() && !sink.is_staged() {
+            sink.drain().await?;
+            self.tree_hash.invalidate();
+        }
+
+        Ok(())
+    }
+
+    fn save_conversions(&self) {
+        if let Err(err) = self.options.apply.converted.save() {
+            eprintln!("WARNING: could not save the converted files: {:#}", err);
+        }
+    }
+
+    async fn compare_root_checksum(
+        &self,
+        roots: &Roots,
+        remote_checksum: &[u8; 20],
+    ) -> anyhow::Result<Option<ReceiverMessage>> {
+        let local_checksums = self
+            .scan_roots(roots, self.options.scan)
+            .await?
+            .top_level_checksums();
+        if root_checksum(&local_checksums) == *remote_checksum {
+            return Ok(None);
+        }
+
+        println!("Tree checksum mismatch, comparing entries");
+        Ok(Some(ReceiverMessage::ChecksumsRequested))
+    }
+
+    async fn compare_checksums(
+        &self,
+        roots: &Roots,
+        remote_checksums: &[SubtreeChecksum],
+    ) -> anyhow::Result<Option<ReceiverMessage>> {
+        let local_checksums = self
+            .scan_roots(roots, self.options.scan)
+            .await?
+            .top_level_checksums();
+        let divergent = divergent_subtrees(&local_checksums, remote_checksums);
+        if divergent.is_empty() {
+            return Ok(None);
+        }
+
+        println!("Checksum mismatch, rescanning {} entries", divergent.len());
+        Ok(Some(ReceiverMessage::SubtreesRequested(divergent)))
+    }
+
+    async fn resync_subtree(
+        &self,
+        roots: &Roots,
+        sink: &mut ChangeSink,
+        path: &Path,
+        remote_subtree: &FileTree,
+    ) -> anyhow::Result<Option<ReceiverMessage>> {
+        if !is_valid_tree(remote_subtree, roots) {
+            bail!("Invalid file tree received for {}, aborting", quoted(path))
+        }
+
+        let local_subtree = roots.subtree(path, self.options.scan).await?;
+        let local_subtree = self.options.apply.converted.as_sent(local_subtree);
+        let diff = self.diff(&local_subtree, remote_subtree);
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        let requested_files = sink
+            .apply_diff(&diff, self.out_dir.as_ref(), &self.options.apply)
+            .await?;
+        self.measure_usage();
+        println!("Resynced {}\n{}", quoted(path), &diff);
+
+        Ok(Some(ReceiverMessage::Requests(requested_files)))
+    }
+
+    async fn resync_tree(
+        &self,
+        roots: &Roots,
+        sink: &mut ChangeSink,
+        remote_tree: &FileTree,
+    ) -> anyhow::Result<Option<ReceiverMessage>> {
+        if !is_valid_tree(remote_tree, roots) {
+            bail!("Invalid file tree received for a full resync, aborting")
+        }
+
+        let local_tree = self.scan_roots(roots, self.options.scan).await?;
+        let diff = self.diff(&local_tree, remote_tree);
+        println!("Full resync\n{}", &diff);
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        let requested_files = sink
+            .apply_diff(&diff, self.out_dir.as_ref(), &self.options.apply)
+            .await?;
+        self.measure_usage();
+        Ok(Some(ReceiverMessage::Requests(requested_files)))
+    }
+
+    /// Runs the hook for a batch once it is applied, and reports what it did. Batches that
+    /// changed nothing only count for the initial sync.
+    async fn run_hooks(&self, sink: &mut ChangeSink, event: HookEvent) -> anyhow::Result<()> {
+        let Some((changed, applied)) = sink.take_changed() else {
+            return Ok(());
+        };
+        if self.options.hooks.is_empty() && self.options.applied.is_none() {
+            return Ok(());
+        }
+
+        sink.drain().await?;
+        if let Some(report) = &self.options.applied {
+            // Nobody may be listening any more once the relay stopped.
+            if !applied.is_empty() {
+                let _ = report.send(applied);
             }
-            FileChangeMessage::FileEdited(path, contents) => {
-                let file_path = self.out_dir.as_ref().join(path);
-                tokio::fs::write(file_path, contents).await?;
+        }
+        let worth_running = matches!(event, HookEvent::Sync) || !changed.is_empty();
+        if !self.options.hooks.is_empty() && worth_running {
+            self.options
+                .hooks
+                .run(event, self.out_dir.as_ref(), changed);
+        }
+
+        Ok(())
+    }
+
+    /// Deletions from diffs bypass the quota's accounting, so measure again after applying them.
+    fn measure_usage(&self) {
+        if let Some(quota) = &self.options.apply.quota {
+            quota.reset(self.out_dir.as_ref());
+        }
+    }
+}
+
+/// Lets tungstenite refuse single frames and messages over `max_message_size`, like reassembled
+/// fragments are.
+fn websocket_config(max_message_size: u64) -> WebSocketConfig {
+    let max_message_size = usize::try_from(max_message_size).unwrap_or(usize::MAX);
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..Default::default()
+    }
+}
+
+/// Tells the sender the protocol version and how large its messages may be, in the handshake's
+/// response, and that small files may come packed together and archives gzipped.
+fn advertise_limits(response: &mut Response, max_message_size: u64) {
+    let headers = response.headers_mut();
+    headers.insert(PROTOCOL_HEADER, HeaderValue::from(PROTOCOL_VERSION));
+    headers.insert(MAX_MESSAGE_SIZE_HEADER, HeaderValue::from(max_message_size));
+    headers.insert(FILE_BATCH_HEADER, HeaderValue::from_static("1"));
+    headers.insert(GZIP_ARCHIVES_HEADER, HeaderValue::from_static("1"));
+    headers.insert(TREE_PAGES_HEADER, HeaderValue::from_static("1"));
+    headers.insert(SUBTREE_HASHES_HEADER, HeaderValue::from_static("1"));
+    headers.insert(QUICK_CHECK_HEADER, HeaderValue::from_static("1"));
+    headers.insert(SPARSE_FILES_HEADER, HeaderValue::from_static("1"));
+    headers.insert(CHECKSUMS_HEADER, HeaderValue::from_static("1"));
+}
+
+/// Tells the sender it may send `FileFromHash` and `HardlinkCreated` changes, unless files are
+/// not always written as sent and could not be copied or linked to.
+fn advertise_dedup(response: &mut Response, apply: &ApplyOptions) {
+    if !apply.update_only && !apply.ignore_existing && apply.middleware.is_empty() {
+        let headers = response.headers_mut();
+        headers.insert(DEDUP_HEADER, HeaderValue::from_static("1"));
+        headers.insert(HARDLINKS_HEADER, HeaderValue::from_static("1"));
+    }
+}
+
+/// Tells the sender it may send `FifoCreated` changes, when FIFOs are recreated.
+fn advertise_specials(response: &mut Response, apply: &ApplyOptions) {
+    if apply.specials {
+        let headers = response.headers_mut();
+        headers.insert(SPECIALS_HEADER, HeaderValue::from_static("1"));
+    }
+}
+
+/// Tells the sender where files are written, for it to refuse syncing into its own source.
+fn advertise_out_dir(response: &mut Response, out_dir: Option<&DirIdentity>) {
+    if let Some(value) = out_dir.and_then(|dir| HeaderValue::from_str(&dir.to_header()).ok()) {
+        response.headers_mut().insert(OUT_DIR_HEADER, value);
+    }
+}
+
+/// Trees must be sorted, and stay within the directories the sender announced.
+fn is_valid_tree(tree: &FileTree, roots: &Roots) -> bool {
+    tree.is_valid() && tree.iter().all(|node| roots.contains(&node.path))
+}
+
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.is::<DeadConnection>() || err.is::<TimedOut>()
+}
+
+fn handshake_timeout(timeout: Duration) -> Duration {
+    timeout.min(HANDSHAKE_TIMEOUT)
+}
+
+/// Accepts the next connection. Failing to accept one is usually transient, e.g. running out of
+/// file descriptors, so it is logged and retried after a pause rather than ending the listener.
+async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                eprintln!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
             }
-            FileChangeMessage::DirectoryContentsEdited(_) => (),
         }
+    }
+}
+
+async fn reject_busy(
+    stream: TcpStream,
+    tls: Option<ServerTls>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let handshake = handshake_timeout(timeout);
+    let stream = match tls {
+        Some(tls) => with_timeout(handshake, "TLS handshake", tls.accept(stream)).await??,
+        None => Box::new(stream),
+    };
+    let accept = tokio_tungstenite::accept_async(stream);
+    let socket = with_timeout(handshake, "websocket handshake", accept).await??;
+    close_busy(Box::new(socket)).await
+}
+
+async fn close_busy(mut socket: WsStream) -> anyhow::Result<()> {
+    socket
+        .send(tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::Again,
+            reason: "receiver is busy with another sync session targeting the same directory"
+                .into(),
+        })))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{message::DEFAULT_MAX_MESSAGE_SIZE, transport::loopback},
+        sender::SenderOptions,
+    };
+    use std::net::Ipv4Addr;
+    use tempfile::TempDir;
+    use tokio::test;
+    use tokio_tungstenite::client_async;
+    use tungstenite::client::IntoClientRequest;
+
+    fn receiver_options() -> ReceiverOptions {
+        ReceiverOptions {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            access: Default::default(),
+            path: None,
+            tls: None,
+            identities: None,
+            keepalive: SenderOptions::default().keepalive,
+            reconnect: false,
+            timeout: SenderOptions::default().timeout,
+            jobs: 8,
+            scan: Default::default(),
+            apply: Default::default(),
+            metrics: Default::default(),
+            stage_dir: None,
+            staged_ttl: None,
+            key: None,
+            control: None,
+            status_api: None,
+            hooks: Default::default(),
+            json_summary: false,
+            audit_log: None,
+            webhooks: Default::default(),
+            sync_state: None,
+            backups: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            memory: None,
+            applied: None,
+        }
+    }
+
+    #[test]
+    async fn test_incompatible_senders_end_their_session() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let (loopback, listener) = loopback();
+        let receiver = Receiver::new(0, out_dir.path(), receiver_options());
+        let send = async move {
+            // Senders predating protocol versions are refused during the handshake.
+            let request = "ws://loopback".into_client_request()?;
+            assert!(client_async(request, loopback.connect()?).await.is_err());
+
+            let mut request = "ws://loopback".into_client_request()?;
+            request
+                .headers_mut()
+                .insert(PROTOCOL_HEADER, HeaderValue::from(PROTOCOL_VERSION));
+            let (mut socket, response) = client_async(request, loopback.connect()?).await?;
+            assert_eq!(
+                response.headers()[PROTOCOL_HEADER],
+                PROTOCOL_VERSION.to_string()
+            );
+            let handshake = bincode::serialize(&Handshake::Sync {
+                dests: vec![PathBuf::new()],
+                tree: FileTree::default(),
+            })?;
+            socket.send(tungstenite::Message::binary(handshake)).await?;
+            socket.send(tungstenite::Message::binary([0xff; 8])).await?;
+            // Read until the receiver drops the connection.
+            while let Some(Ok(_)) = socket.next().await {}
+            anyhow::Ok(())
+        };
+
+        let (sent, received) = tokio::join!(send, receiver.start_loopback(listener));
+        sent?;
+        let err = received.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Malformed message"),
+            "{:#}",
+            err
+        );
+        assert_eq!(receiver.options.metrics.rejected.load(Ordering::Relaxed), 1);
 
         Ok(())
     }
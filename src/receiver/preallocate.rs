@@ -0,0 +1,119 @@
+use std::{io::ErrorKind, path::Path};
+
+use anyhow::{bail, Context};
+use bytesize::ByteSize;
+use fs4::tokio::AsyncFileExt;
+use tokio::io::AsyncWriteExt;
+
+use super::quota::disk_usage;
+use crate::core::{
+    file_tree::{FileTree, FileTreeNodeType},
+    message::RequestMessage,
+    utils::quoted,
+};
+
+/// How much the output directory grows once the requested entries arrive, replacing what is
+/// there now.
+pub fn incoming_size(out_dir: &Path, remote_tree: &FileTree, requests: &[RequestMessage]) -> u64 {
+    requests
+        .iter()
+        .map(|request| {
+            let path = match request {
+                RequestMessage::File(path) | RequestMessage::Dir(path) => path,
+            };
+            // The tree is sorted, so an entry's subtree directly follows it.
+            let start = remote_tree.partition_point(|node| node.path < *path);
+            let incoming: u64 = remote_tree[start..]
+                .iter()
+                .take_while(|node| node.path.starts_with(path))
+                .map(|node| match node.typ {
+                    FileTreeNodeType::File { size, .. }
+                    | FileTreeNodeType::TimedFile { size, .. } => size,
+                    FileTreeNodeType::Dir
+                    | FileTreeNodeType::HashedDir { .. }
+                    | FileTreeNodeType::Fifo => 0,
+                })
+                .sum();
+
+            incoming.saturating_sub(disk_usage(&out_dir.join(path)))
+        })
+        .sum()
+}
+
+/// Fails when the filesystem of `out_dir` cannot hold `incoming` more bytes, before the sender
+/// starts sending them.
+pub fn check_space(out_dir: &Path, incoming: u64) -> anyhow::Result<()> {
+    let available = fs4::available_space(out_dir)
+        .with_context(|| format!("measuring the free space of {}", quoted(out_dir)))?;
+    if incoming > available {
+        bail!(
+            "not enough space in {}: {} to receive, {} available",
+            quoted(out_dir),
+            ByteSize::b(incoming),
+            ByteSize::b(available)
+        )
+    }
+
+    Ok(())
+}
+
+/// Writes `contents` into a file allocated to its final size first, so that it is laid out
+/// contiguously and a full disk fails before anything is written. Filesystems that cannot
+/// preallocate get a plain write.
+pub async fn write(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    allocate(&file, path, contents.len() as u64).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Allocates `size` bytes to the freshly created `file` at `path`, if its filesystem can.
+pub async fn allocate(file: &tokio::fs::File, path: &Path, size: u64) -> anyhow::Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    match file.allocate(size).await {
+        Err(err) if err.kind() != ErrorKind::Unsupported => {
+            Err(err).with_context(|| format!("preallocating {}", quoted(path)))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::file_tree_diff::TreeDiff;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_incoming_size_counts_growth() -> anyhow::Result<()> {
+        let (local, remote) = (TempDir::new()?, TempDir::new()?);
+        fs::create_dir_all(remote.path().join("assets/icons"))?;
+        fs::write(remote.path().join("assets/icons/logo.svg"), "0123456789")?;
+        fs::write(remote.path().join("assets/style.css"), "01234")?;
+        fs::write(remote.path().join("README.md"), "0123456789")?;
+        fs::write(local.path().join("README.md"), "0123")?;
+
+        let local_tree = FileTree::new(local.path()).await?;
+        let remote_tree = FileTree::new(remote.path()).await?;
+        let requests = TreeDiff::from(&local_tree, &remote_tree).requests();
+        let incoming = incoming_size(local.path(), &remote_tree, &requests);
+        assert_eq!(incoming, 10 + 5 + (10 - 4));
+        check_space(local.path(), incoming)?;
+        assert!(check_space(local.path(), u64::MAX).is_err());
+
+        let readme = local.path().join("README.md");
+        write(&readme, b"01").await?;
+        assert_eq!(fs::read_to_string(&readme)?, "01");
+        write(&readme, b"").await?;
+        assert_eq!(fs::read(&readme)?.len(), 0);
+
+        Ok(())
+    }
+}
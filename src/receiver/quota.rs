@@ -0,0 +1,119 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use bytesize::ByteSize;
+use walkdir::WalkDir;
+
+use crate::core::{message::Rejection, utils::quoted};
+
+/// Caps how much disk space the output directory may take. Usage is measured at the start of
+/// each session and after every resync, then tracked as changes are applied.
+#[derive(Debug)]
+pub struct Quota {
+    limit: ByteSize,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(limit: ByteSize) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn used(&self) -> ByteSize {
+        ByteSize::b(self.used.load(Ordering::Relaxed))
+    }
+
+    pub fn limit(&self) -> ByteSize {
+        self.limit
+    }
+
+    /// Measures the usage of `dir` from scratch.
+    pub fn reset(&self, dir: &Path) {
+        self.used.store(disk_usage(dir), Ordering::Relaxed);
+    }
+
+    /// Accounts for an entry going from `from` to `to` bytes, refusing growth past the limit.
+    /// Shrinking is always allowed, so that an over-quota directory can be cleaned up.
+    pub fn resize(&self, from: u64, to: u64) -> anyhow::Result<()> {
+        let resized = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let resized = used.saturating_sub(from).saturating_add(to);
+                match to > from && resized > self.limit.as_u64() {
+                    true => None,
+                    false => Some(resized),
+                }
+            });
+
+        if let Err(used) = resized {
+            return Err(Rejection::QuotaExceeded {
+                limit: self.limit.as_u64(),
+                used,
+                needed: to - from,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Fails with `Rejection::DiskFull` when the filesystem of `path` has less than `needed` bytes
+/// free, so that a full disk refuses a change instead of failing halfway through writing it.
+pub fn check_free_space(path: &Path, needed: u64) -> anyhow::Result<()> {
+    let available = fs4::available_space(path)
+        .with_context(|| format!("measuring the free space of {}", quoted(path)))?;
+    if needed > available {
+        return Err(Rejection::DiskFull { available, needed }.into());
+    }
+
+    Ok(())
+}
+
+/// Total size of the files below `path`, or of `path` itself if it is a file. Missing paths take
+/// no space.
+pub fn disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_quota_refuses_growth_past_its_limit() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::create_dir(dir.path().join("nested"))?;
+        fs::write(dir.path().join("nested/a.txt"), "0123456789")?;
+        fs::write(dir.path().join("b.txt"), "01234")?;
+
+        let quota = Quota::new(ByteSize::b(20));
+        quota.reset(dir.path());
+        assert_eq!(quota.used(), ByteSize::b(15));
+
+        quota.resize(5, 10)?;
+        assert!(quota.resize(0, 1).is_err());
+        assert_eq!(quota.used(), ByteSize::b(20));
+
+        quota.resize(10, 0)?;
+        assert_eq!(quota.used(), ByteSize::b(10));
+        assert!(quota.resize(0, 11).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::BTreeSet;
+
+use crate::core::message::SyncMessage;
+
+/// Holds back messages until every message they causally depend on has been released, so
+/// changes to the same paths are applied in the order they were made on the sender, whatever
+/// order they arrive in.
+#[derive(Default)]
+pub struct ReorderBuffer {
+    // Every id below the watermark has been released, `released` holds the ones above it.
+    watermark: u64,
+    released: BTreeSet<u64>,
+    pending: Vec<SyncMessage>,
+}
+
+impl ReorderBuffer {
+    /// Buffers `message` and returns the messages that became ready to apply, in causal order.
+    pub fn push(&mut self, message: SyncMessage) -> Vec<SyncMessage> {
+        self.pending.push(message);
+        self.release_ready()
+    }
+
+    /// Marks `id` as never coming and returns the messages that were only waiting on it.
+    pub fn skip(&mut self, id: u64) -> Vec<SyncMessage> {
+        self.mark_released(id);
+        self.release_ready()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn is_released(&self, id: u64) -> bool {
+        id < self.watermark || self.released.contains(&id)
+    }
+
+    fn mark_released(&mut self, id: u64) {
+        self.released.insert(id);
+        while self.released.remove(&self.watermark) {
+            self.watermark += 1;
+        }
+    }
+
+    fn release_ready(&mut self) -> Vec<SyncMessage> {
+        let mut ready = vec![];
+        loop {
+            let position = self.pending.iter().position(|message| {
+                message
+                    .depends_on
+                    .iter()
+                    .all(|&dependency| self.is_released(dependency))
+            });
+
+            let Some(position) = position else {
+                break;
+            };
+
+            let message = self.pending.swap_remove(position);
+            self.mark_released(message.id);
+            ready.push(message);
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::message::FileChangeMessage;
+
+    fn message(id: u64, depends_on: Vec<u64>) -> SyncMessage {
+        SyncMessage {
+            id,
+            depends_on,
+            change: FileChangeMessage::FileCreated(format!("{}.txt", id).into()),
+        }
+    }
+
+    fn ids(messages: Vec<SyncMessage>) -> Vec<u64> {
+        messages.into_iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn test_out_of_order_messages_are_held_back() {
+        let mut buffer = ReorderBuffer::default();
+
+        assert_eq!(ids(buffer.push(message(2, vec![1]))), Vec::<u64>::new());
+        assert_eq!(ids(buffer.push(message(3, vec![]))), vec![3]);
+        assert_eq!(ids(buffer.push(message(4, vec![0, 2]))), Vec::<u64>::new());
+        assert_eq!(ids(buffer.push(message(1, vec![]))), vec![1, 2]);
+        assert_eq!(buffer.pending(), 1);
+        assert_eq!(ids(buffer.skip(0)), vec![4]);
+        assert_eq!(buffer.pending(), 0);
+    }
+}
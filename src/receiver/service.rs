@@ -0,0 +1,231 @@
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+
+use axum::{
+    extract::{
+        ws::{self, WebSocket},
+        ConnectInfo, State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode, Uri},
+    response::IntoResponse,
+};
+use futures::{future, SinkExt, StreamExt};
+use tungstenite::{
+    handshake::server::{Request, Response},
+    protocol::CloseFrame,
+};
+
+use super::{close_busy, Receiver, Socket, WsStream};
+
+/// Serves a sender upgrading its connection through axum, for receivers mounted in an existing
+/// server, e.g. with `Router::new().route("/sync", get(service::sync)).with_state(receiver)`.
+///
+/// The server takes care of TLS and of its own middleware, the receiver's options for handshakes
+/// applying on top: the path, key and identities senders must present, and the access options
+/// when the server is run with `into_make_service_with_connect_info::<SocketAddr>`. Senders are
+/// served one at a time, others being turned away as busy. Only sessions are served, the tasks
+/// `Receiver::start` runs alongside them, e.g. the control endpoint, are not.
+pub async fn sync<P>(
+    State(receiver): State<Arc<Receiver<P>>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    uri: Uri,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> axum::response::Response
+where
+    P: AsRef<Path> + Send + Sync + 'static,
+{
+    let addr = connect_info.map(|ConnectInfo(addr)| addr);
+    let admitted = match addr {
+        Some(addr) => match receiver.admit(addr) {
+            Some(admitted) => Some(admitted),
+            None => return StatusCode::FORBIDDEN.into_response(),
+        },
+        None => None,
+    };
+    let peer = addr.map_or_else(|| "an unknown address".to_owned(), |addr| addr.to_string());
+
+    let mut request = Request::new(());
+    *request.uri_mut() = uri;
+    *request.headers_mut() = headers;
+    let mut response = Response::new(());
+    let handshaken = match receiver.answer_handshake(&request, &mut response) {
+        Ok(handshaken) => handshaken,
+        Err(refusal) => {
+            let (parts, body) = refusal.response().into_parts();
+            receiver.reject(&peer, refusal);
+            return (parts.status, body.unwrap_or_default()).into_response();
+        }
+    };
+
+    let max_message_size = usize::try_from(receiver.options.max_message_size).unwrap_or(usize::MAX);
+    let mut upgraded = upgrade
+        .max_message_size(max_message_size)
+        .max_frame_size(max_message_size)
+        .on_upgrade(move |socket| async move {
+            let _admitted = admitted;
+            let socket: WsStream = Box::new(adapt(socket));
+            let Ok(_session) = receiver.busy.try_lock() else {
+                eprintln!(
+                    "Rejecting sender at {}, a sync session is already in progress",
+                    peer
+                );
+                receiver
+                    .options
+                    .metrics
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                let _ = close_busy(socket).await;
+                return;
+            };
+
+            if let Err(err) = receiver.serve_socket(socket, handshaken, None, &peer).await {
+                eprintln!("Session with the sender at {} failed: {:#}", peer, err);
+            }
+        });
+    upgraded
+        .headers_mut()
+        .extend(std::mem::take(response.headers_mut()));
+    upgraded
+}
+
+/// Axum's websocket as the socket sessions run over, converting messages both ways.
+// The error type is imposed by tungstenite.
+#[allow(clippy::result_large_err)]
+fn adapt(socket: WebSocket) -> impl Socket {
+    socket
+        .sink_map_err(from_axum_error)
+        .with(|message| future::ready(Ok::<_, tungstenite::Error>(to_axum(message))))
+        .map(|message| message.map(from_axum).map_err(from_axum_error))
+}
+
+fn from_axum(message: ws::Message) -> tungstenite::Message {
+    match message {
+        ws::Message::Text(text) => tungstenite::Message::Text(text),
+        ws::Message::Binary(data) => tungstenite::Message::Binary(data),
+        ws::Message::Ping(data) => tungstenite::Message::Ping(data),
+        ws::Message::Pong(data) => tungstenite::Message::Pong(data),
+        ws::Message::Close(frame) => tungstenite::Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+    }
+}
+
+fn to_axum(message: tungstenite::Message) -> ws::Message {
+    match message {
+        tungstenite::Message::Text(text) => ws::Message::Text(text),
+        tungstenite::Message::Binary(data) => ws::Message::Binary(data),
+        tungstenite::Message::Ping(data) => ws::Message::Ping(data),
+        tungstenite::Message::Pong(data) => ws::Message::Pong(data),
+        tungstenite::Message::Close(frame) => {
+            ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+                code: frame.code.into(),
+                reason: frame.reason,
+            }))
+        }
+        // Raw frames are only ever sent, never by sessions.
+        tungstenite::Message::Frame(frame) => ws::Message::Binary(frame.into_data()),
+    }
+}
+
+/// Axum wraps tungstenite's errors, which are unwrapped for sessions to tell them apart, e.g.
+/// messages over the size limit.
+fn from_axum_error(err: axum::Error) -> tungstenite::Error {
+    match err.into_inner().downcast::<tungstenite::Error>() {
+        Ok(err) => *err,
+        Err(err) => tungstenite::Error::Io(std::io::Error::other(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            file_tree::FileTree, file_tree_diff::TreeDiff, message::DEFAULT_MAX_MESSAGE_SIZE,
+            roots::Roots,
+        },
+        receiver::ReceiverOptions,
+        sender::{Sender, SenderOptions},
+    };
+    use axum::{routing::get, Router};
+    use std::{fs, net::Ipv4Addr};
+    use tempfile::TempDir;
+    use tokio::{net::TcpListener, test};
+
+    fn receiver_options() -> ReceiverOptions {
+        ReceiverOptions {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            access: Default::default(),
+            path: None,
+            tls: None,
+            identities: None,
+            keepalive: SenderOptions::default().keepalive,
+            reconnect: false,
+            timeout: SenderOptions::default().timeout,
+            jobs: 8,
+            scan: Default::default(),
+            apply: Default::default(),
+            metrics: Default::default(),
+            stage_dir: None,
+            staged_ttl: None,
+            key: Some("secret".to_owned()),
+            control: None,
+            status_api: None,
+            hooks: Default::default(),
+            json_summary: false,
+            audit_log: None,
+            webhooks: Default::default(),
+            sync_state: None,
+            backups: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            memory: None,
+            applied: None,
+        }
+    }
+
+    #[test]
+    async fn test_senders_are_served_through_axum() -> anyhow::Result<()> {
+        let from = TempDir::new()?;
+        let to = TempDir::new()?;
+        fs::create_dir_all(from.path().join("src"))?;
+        fs::write(from.path().join("src/lib.rs"), "pub fn lib() {}")?;
+        fs::write(from.path().join("README.md"), "# embedded")?;
+
+        let receiver = Arc::new(Receiver::new(0, to.path().to_owned(), receiver_options()));
+        let app = Router::new()
+            .route("/sync", get(sync))
+            .with_state(receiver.clone());
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("ws://{}/sync", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await
+        });
+
+        let unauthorized = Sender::new(Roots::single(from.path()), &url, SenderOptions::default());
+        assert!(unauthorized.start(false).await.is_err());
+        assert_eq!(receiver.options.metrics.rejected.load(Ordering::Relaxed), 1);
+
+        let options = SenderOptions {
+            key: Some("secret".to_owned()),
+            ..Default::default()
+        };
+        Sender::new(Roots::single(from.path()), &url, options)
+            .start(false)
+            .await?;
+        // Changes may still be applied once the sender is done.
+        drop(receiver.busy.lock().await);
+        let synced = FileTree::new(to.path()).await?;
+        let source = FileTree::new(from.path()).await?;
+        let diff = TreeDiff::from(&synced, &source);
+        assert!(diff.is_empty(), "{}", diff);
+
+        server.abort();
+        Ok(())
+    }
+}
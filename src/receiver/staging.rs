@@ -0,0 +1,636 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use bytesize::ByteSize;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    task::JoinHandle,
+};
+use walkdir::WalkDir;
+
+use super::apply::{apply_change, ApplyOptions, ApplyPipeline};
+use crate::core::{
+    file_tree_diff::TreeDiff,
+    message::{FileChangeMessage, SyncMessage},
+    utils::{clone_file, quoted},
+};
+
+const INFO_FILE: &str = "session";
+const JOURNAL_FILE: &str = "journal";
+
+/// How often expired sessions are looked for, at most.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Directories of the sessions this process is staging, which never expire.
+static LIVE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// What an audit-mode receiver records instead of applying changes, in the order it would have
+/// applied them.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    /// A deletion found by diffing the trees. Everything recorded before it must be applied
+    /// first, as the receiver drains its pipeline before applying a diff.
+    Change(FileChangeMessage),
+    Sync(SyncMessage),
+    Skipped(u64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Absolute path of the directory the changes are meant for.
+    pub out_dir: PathBuf,
+    pub started: SystemTime,
+    /// Set once the sender disconnected, after which nothing is added to the journal.
+    pub finished: bool,
+    /// The rendered diffs the session's deletions and requests came from.
+    pub diffs: Vec<String>,
+}
+
+/// A session being staged: `<stage dir>/<id>/` holds its info and journal until it is approved.
+pub struct Journal {
+    dir: PathBuf,
+    info: SessionInfo,
+    writer: BufWriter<File>,
+}
+
+impl Journal {
+    pub async fn create(stage_dir: &Path, out_dir: &Path) -> anyhow::Result<Self> {
+        let started = SystemTime::now();
+        // Tenants may stage sessions in the same stage directory within the same millisecond.
+        let millis = started.duration_since(UNIX_EPOCH)?.as_millis();
+        let id = format!("{}-{:04x}", millis, rand::random::<u16>());
+        let dir = stage_dir.join(&id);
+        tokio::fs::create_dir_all(stage_dir)
+            .await
+            .with_context(|| format!("creating {}", quoted(stage_dir)))?;
+        tokio::fs::create_dir(&dir)
+            .await
+            .with_context(|| format!("creating {}", quoted(&dir)))?;
+
+        let info = SessionInfo {
+            id,
+            out_dir: std::path::absolute(out_dir)?,
+            started,
+            finished: false,
+            diffs: vec![],
+        };
+        write_info(&dir, &info).await?;
+        let writer = BufWriter::new(File::create(dir.join(JOURNAL_FILE)).await?);
+        LIVE.lock().unwrap().insert(dir.clone());
+
+        Ok(Self { dir, info, writer })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.info.id
+    }
+
+    /// Entries are length-prefixed, so that the journal can be read back one entry at a time.
+    pub async fn append(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let encoded = bincode::serialize(entry)?;
+        self.writer.write_u64(encoded.len() as u64).await?;
+        self.writer.write_all(&encoded).await?;
+
+        Ok(())
+    }
+
+    /// Records the renames and deletions of a diff, and the diff itself for review.
+    pub async fn record_diff(&mut self, diff: &TreeDiff<'_>) -> anyhow::Result<()> {
+        for change in diff.renames().into_iter().chain(diff.deletions()) {
+            self.append(&JournalEntry::Change(change)).await?;
+        }
+
+        self.info.diffs.push(diff.to_string());
+        write_info(&self.dir, &self.info).await
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Marks the session as ready for approval.
+    pub async fn finish(mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        self.info.finished = true;
+        write_info(&self.dir, &self.info).await
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        LIVE.lock().unwrap().remove(&self.dir);
+    }
+}
+
+async fn write_info(dir: &Path, info: &SessionInfo) -> anyhow::Result<()> {
+    tokio::fs::write(dir.join(INFO_FILE), bincode::serialize(info)?).await?;
+    Ok(())
+}
+
+pub async fn read_info(stage_dir: &Path, id: &str) -> anyhow::Result<SessionInfo> {
+    let path = stage_dir.join(id).join(INFO_FILE);
+    let encoded = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("no staged session {} in {}", id, quoted(stage_dir)))?;
+
+    Ok(bincode::deserialize(&encoded)?)
+}
+
+pub async fn read_journal(stage_dir: &Path, id: &str) -> anyhow::Result<Vec<JournalEntry>> {
+    let file = File::open(stage_dir.join(id).join(JOURNAL_FILE)).await?;
+    let mut reader = BufReader::new(file);
+    let mut entries = vec![];
+    loop {
+        let len = match reader.read_u64().await {
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut encoded = vec![0; len as usize];
+        reader.read_exact(&mut encoded).await?;
+        entries.push(bincode::deserialize(&encoded)?);
+    }
+
+    Ok(entries)
+}
+
+/// Every staged session, oldest first.
+pub async fn list(stage_dir: &Path) -> anyhow::Result<Vec<SessionInfo>> {
+    let mut sessions = vec![];
+    let mut entries = match tokio::fs::read_dir(stage_dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(sessions),
+        Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(stage_dir))),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let id = entry.file_name().to_string_lossy().into_owned();
+        if let Ok(info) = read_info(stage_dir, &id).await {
+            sessions.push(info);
+        }
+    }
+    sessions.sort_by_key(|session| session.started);
+
+    Ok(sessions)
+}
+
+/// Applies a finished staged session, then discards it. The session is applied to a copy of the
+/// output directory, which only replaces the directory once every change succeeded, so that a
+/// session is applied either whole or not at all.
+pub async fn approve(
+    stage_dir: &Path,
+    id: &str,
+    jobs: usize,
+    options: Arc<ApplyOptions>,
+) -> anyhow::Result<()> {
+    let info = read_info(stage_dir, id).await?;
+    if !info.finished {
+        bail!("session {} is still in progress", id)
+    }
+
+    let out_dir = info.out_dir.as_path();
+    let work_dir = sibling(out_dir, &format!("approving-{}", id))?;
+    let journal = read_journal(stage_dir, id).await?;
+    if !options.middleware.is_empty() {
+        options.converted.load(out_dir)?;
+    }
+    copy_dir(out_dir, &work_dir)
+        .with_context(|| format!("copying {} to apply the session", quoted(out_dir)))?;
+
+    let failed = replay(&work_dir, journal, jobs, options.clone()).await;
+    if failed > 0 {
+        tokio::fs::remove_dir_all(&work_dir).await?;
+        bail!("{} changes failed, nothing was applied", failed)
+    }
+
+    let previous_dir = sibling(out_dir, &format!("previous-{}", id))?;
+    replace_dir(out_dir, &work_dir, &previous_dir)?;
+    options.converted.save()?;
+
+    discard(stage_dir, id).await
+}
+
+/// Replaces `out_dir` with `work_dir`. On Linux the two are exchanged at once. Elsewhere, or on
+/// filesystems that cannot exchange them, `out_dir` is moved to `previous_dir` first and moved
+/// back if `work_dir` cannot take its place, so that it is never left missing.
+fn replace_dir(out_dir: &Path, work_dir: &Path, previous_dir: &Path) -> anyhow::Result<()> {
+    if exchange(out_dir, work_dir).is_ok() {
+        std::fs::remove_dir_all(work_dir)?;
+        return Ok(());
+    }
+
+    std::fs::rename(out_dir, previous_dir)
+        .with_context(|| format!("moving {} aside", quoted(out_dir)))?;
+    if let Err(err) = std::fs::rename(work_dir, out_dir) {
+        std::fs::rename(previous_dir, out_dir)
+            .with_context(|| format!("restoring {}", quoted(out_dir)))?;
+        return Err(err).with_context(|| format!("replacing {}", quoted(out_dir)));
+    }
+    std::fs::remove_dir_all(previous_dir)?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn exchange(path1: &Path, path2: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path1 = std::ffi::CString::new(path1.as_os_str().as_bytes())?;
+    let path2 = std::ffi::CString::new(path2.as_os_str().as_bytes())?;
+    let exchanged = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            path1.as_ptr(),
+            libc::AT_FDCWD,
+            path2.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    match exchanged {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_path1: &Path, _path2: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Discards a finished staged session without applying it.
+pub async fn reject(stage_dir: &Path, id: &str) -> anyhow::Result<()> {
+    let info = read_info(stage_dir, id).await?;
+    if !info.finished {
+        bail!("session {} is still in progress", id)
+    }
+
+    discard(stage_dir, id).await
+}
+
+async fn discard(stage_dir: &Path, id: &str) -> anyhow::Result<()> {
+    tokio::fs::remove_dir_all(stage_dir.join(id)).await?;
+    Ok(())
+}
+
+/// Discards the sessions nobody approved or rejected within `ttl` of their last write, including
+/// sessions left in progress by a receiver that went away. Returns the expired sessions' ids.
+pub async fn expire(stage_dir: &Path, ttl: Duration) -> anyhow::Result<Vec<String>> {
+    let mut expired = vec![];
+    for session in list(stage_dir).await? {
+        let dir = stage_dir.join(&session.id);
+        if LIVE.lock().unwrap().contains(&dir) {
+            continue;
+        }
+
+        let idle = last_write(&dir)
+            .and_then(|written| written.elapsed().ok())
+            .unwrap_or_default();
+        if idle < ttl {
+            continue;
+        }
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        println!(
+            "Expired staged session {} for {}, untouched for {}",
+            session.id,
+            session.out_dir.display(),
+            humantime::format_duration(Duration::from_secs(idle.as_secs()))
+        );
+        expired.push(session.id);
+    }
+
+    Ok(expired)
+}
+
+fn last_write(dir: &Path) -> Option<SystemTime> {
+    [INFO_FILE, JOURNAL_FILE]
+        .iter()
+        .filter_map(|file| fs::metadata(dir.join(file)).ok()?.modified().ok())
+        .max()
+}
+
+/// Expires staged sessions in the background for as long as it is kept.
+pub struct ExpiryTask(JoinHandle<()>);
+
+impl ExpiryTask {
+    pub fn spawn(stage_dir: PathBuf, ttl: Duration) -> Self {
+        Self(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(ttl.min(EXPIRY_INTERVAL));
+            loop {
+                ticker.tick().await;
+                if let Err(err) = expire(&stage_dir, ttl).await {
+                    eprintln!("An error occurred while expiring staged sessions: {}", err);
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for ExpiryTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Applies the journal in order, returning how many changes could not be applied.
+async fn replay(
+    out_dir: &Path,
+    journal: Vec<JournalEntry>,
+    jobs: usize,
+    options: Arc<ApplyOptions>,
+) -> u64 {
+    let mut pipeline = ApplyPipeline::new(out_dir, jobs, options.clone());
+    let mut failed = 0;
+    for entry in journal {
+        match entry {
+            JournalEntry::Change(change) => {
+                pipeline.drain().await;
+                if let Err(err) = apply_change(out_dir, change, &options).await {
+                    eprintln!("An error occurred while applying a deletion: {}", err);
+                    failed += 1;
+                }
+            }
+            JournalEntry::Sync(message) => pipeline.submit(message),
+            JournalEntry::Skipped(id) => pipeline.skip(id),
+        }
+    }
+    pipeline.drain().await;
+
+    failed + pipeline.failed()
+}
+
+/// A hidden path next to `dir`, on the same filesystem so that it can be renamed into place.
+pub(super) fn sibling(dir: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .with_context(|| format!("{} has no name", quoted(dir)))?;
+
+    Ok(dir.with_file_name(format!(".{}.{}", name.to_string_lossy(), suffix)))
+}
+
+/// Copies a directory, keeping modification times so that `--update-only` still compares against
+/// the local versions.
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if file_type.is_file() {
+            clone_file(entry.path(), &target)?;
+            let modified = entry.metadata()?.modified()?;
+            fs::File::options()
+                .write(true)
+                .open(&target)?
+                .set_modified(modified)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> anyhow::Result<()> {
+    bail!("cannot copy symbolic link {}", quoted(from))
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let change = match self {
+            JournalEntry::Change(change) => change,
+            JournalEntry::Sync(message) => &message.change,
+            JournalEntry::Skipped(id) => return write!(f, "skipped message {}", id),
+        };
+
+        describe(change, f)
+    }
+}
+
+fn describe(change: &FileChangeMessage, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match change {
+        FileChangeMessage::FileCreated(path) => write!(f, "create {}", quoted(path)),
+        FileChangeMessage::FifoCreated(path) => write!(f, "create FIFO {}", quoted(path)),
+        FileChangeMessage::FileDeleted(path) => write!(f, "delete {}", quoted(path)),
+        FileChangeMessage::FileEdited(path, contents, _)
+        | FileChangeMessage::GzippedFileEdited(path, contents, _) => write!(
+            f,
+            "write {} ({} on the wire)",
+            quoted(path),
+            ByteSize::b(contents.len() as u64)
+        ),
+        FileChangeMessage::EmptyDirectoryCreated(path) => {
+            write!(f, "create directory {}", quoted(path))
+        }
+        FileChangeMessage::FileFromHash(path, sha1, _) => write!(
+            f,
+            "write {} from identical contents {}",
+            quoted(path),
+            hex::encode(sha1)
+        ),
+        FileChangeMessage::FileBatch(path, packed) => write!(
+            f,
+            "write {} files below {} ({} on the wire)",
+            packed.files,
+            quoted(path),
+            ByteSize::b(packed.archive.len() as u64)
+        ),
+        FileChangeMessage::FileChunk(path, chunk) => write!(
+            f,
+            "write {} of {} at {} ({} on the wire)",
+            quoted(path),
+            ByteSize::b(chunk.size),
+            chunk.offset,
+            ByteSize::b(chunk.data.len() as u64)
+        ),
+        FileChangeMessage::DirectoryCreated(path, archive)
+        | FileChangeMessage::GzippedDirectoryCreated(path, archive) => write!(
+            f,
+            "create directory {} ({} archive)",
+            quoted(path),
+            ByteSize::b(archive.len() as u64)
+        ),
+        FileChangeMessage::DirectoryDeleted(path) => {
+            write!(f, "delete directory {}", quoted(path))
+        }
+        FileChangeMessage::HardlinkCreated(target, path) => {
+            write!(f, "link {} to {}", quoted(path), quoted(target))
+        }
+        FileChangeMessage::Rename(from, to) => {
+            write!(f, "rename {} to {}", quoted(from), quoted(to))
+        }
+        FileChangeMessage::DirectoryContentsEdited(path) => {
+            write!(f, "rescan directory {}", quoted(path))
+        }
+        FileChangeMessage::Checksummed(change, sha1) => {
+            describe(change, f)?;
+            write!(f, ", checksummed {}", hex::encode(sha1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_staged_changes_are_applied_on_approval() -> anyhow::Result<()> {
+        let (stage_dir, out_dir) = (TempDir::new()?, TempDir::new()?);
+        fs::write(out_dir.path().join("old.txt"), "old")?;
+
+        let mut journal = Journal::create(stage_dir.path(), out_dir.path()).await?;
+        let id = journal.id().to_owned();
+        journal
+            .append(&JournalEntry::Change(FileChangeMessage::FileDeleted(
+                "old.txt".into(),
+            )))
+            .await?;
+        journal
+            .append(&JournalEntry::Sync(SyncMessage {
+                id: 0,
+                depends_on: vec![],
+                change: FileChangeMessage::FileEdited(
+                    "new.txt".into(),
+                    Bytes::from("new"),
+                    SystemTime::now(),
+                ),
+            }))
+            .await?;
+
+        let options = Arc::new(ApplyOptions::default());
+        assert!(approve(stage_dir.path(), &id, 1, options.clone())
+            .await
+            .is_err());
+        assert!(out_dir.path().join("old.txt").exists());
+
+        journal.finish().await?;
+        assert_eq!(read_journal(stage_dir.path(), &id).await?.len(), 2);
+        assert_eq!(list(stage_dir.path()).await?.len(), 1);
+        approve(stage_dir.path(), &id, 1, options).await?;
+        assert!(!out_dir.path().join("old.txt").exists());
+        assert_eq!(fs::read_to_string(out_dir.path().join("new.txt"))?, "new");
+        assert!(!stage_dir.path().join(&id).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_failed_approvals_leave_the_directory_untouched() -> anyhow::Result<()> {
+        let (stage_dir, out_dir) = (TempDir::new()?, TempDir::new()?);
+        let out_dir = out_dir.path().join("out");
+        fs::create_dir(&out_dir)?;
+        fs::write(out_dir.join("kept.txt"), "kept")?;
+
+        let mut journal = Journal::create(stage_dir.path(), &out_dir).await?;
+        let id = journal.id().to_owned();
+        let changes = [
+            FileChangeMessage::FileDeleted("kept.txt".into()),
+            FileChangeMessage::Rename("missing.txt".into(), "renamed.txt".into()),
+        ];
+        for change in changes {
+            journal.append(&JournalEntry::Change(change)).await?;
+        }
+        journal.finish().await?;
+
+        let options = Arc::new(ApplyOptions::default());
+        assert!(approve(stage_dir.path(), &id, 1, options).await.is_err());
+        assert_eq!(fs::read_to_string(out_dir.join("kept.txt"))?, "kept");
+        assert_eq!(fs::read_dir(out_dir.parent().unwrap())?.count(), 1);
+
+        reject(stage_dir.path(), &id).await?;
+        assert!(list(stage_dir.path()).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_directories_are_restored_when_they_cannot_be_replaced() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&out_dir)?;
+        fs::write(out_dir.join("kept.txt"), "kept")?;
+
+        let missing = dir.path().join("missing");
+        assert!(replace_dir(&out_dir, &missing, &dir.path().join("previous")).is_err());
+        assert_eq!(fs::read_to_string(out_dir.join("kept.txt"))?, "kept");
+        assert!(!dir.path().join("previous").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_sessions_staged_at_once_keep_their_journals() -> anyhow::Result<()> {
+        let (stage_dir, out_dir) = (TempDir::new()?, TempDir::new()?);
+        let mut journals = vec![];
+        for _ in 0..10 {
+            journals.push(Journal::create(stage_dir.path(), out_dir.path()).await?);
+        }
+        for journal in &mut journals {
+            journal
+                .append(&JournalEntry::Change(FileChangeMessage::FileDeleted(
+                    "old.txt".into(),
+                )))
+                .await?;
+        }
+        let ids: Vec<_> = journals
+            .iter()
+            .map(|journal| journal.id().to_owned())
+            .collect();
+        for journal in journals {
+            journal.finish().await?;
+        }
+
+        assert_eq!(list(stage_dir.path()).await?.len(), ids.len());
+        for id in &ids {
+            assert_eq!(read_journal(stage_dir.path(), id).await?.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_only_idle_sessions_expire() -> anyhow::Result<()> {
+        let (stage_dir, out_dir) = (TempDir::new()?, TempDir::new()?);
+        let live = Journal::create(stage_dir.path(), out_dir.path()).await?;
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let abandoned = Journal::create(stage_dir.path(), out_dir.path()).await?;
+        let abandoned_id = abandoned.id().to_owned();
+        drop(abandoned);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(expire(stage_dir.path(), Duration::from_secs(60))
+            .await?
+            .is_empty());
+        assert_eq!(
+            expire(stage_dir.path(), Duration::from_millis(10)).await?,
+            vec![abandoned_id]
+        );
+        assert_eq!(list(stage_dir.path()).await?[0].id, live.id());
+
+        Ok(())
+    }
+}
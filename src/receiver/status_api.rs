@@ -0,0 +1,428 @@
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+#[cfg(not(feature = "dashboard"))]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpStream},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{watch, Notify},
+    task::JoinHandle,
+};
+
+use super::audit_log::AuditLog;
+use crate::core::{
+    activity::Activity,
+    file_tree::{root_checksum, FileTree},
+};
+
+#[cfg(feature = "dashboard")]
+mod dashboard;
+
+/// Requests taking longer than this to arrive are dropped. Answering them is not bounded, except
+/// with the dashboard: axum reads requests itself, so they are answered within this long instead.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Changes to a path reported by `/history`, the latest ones.
+const HISTORY_LIMIT: usize = 100;
+
+/// What the status API reports on.
+pub struct ApiState {
+    /// The listener's status, as reported by its control endpoint.
+    pub status: Box<dyn Fn() -> serde_json::Value + Send + Sync>,
+    pub activity: Arc<Mutex<Activity>>,
+    /// Scans the output directory, as compared with the sender's tree.
+    pub tree: Box<dyn Fn() -> BoxFuture<'static, anyhow::Result<FileTree>> + Send + Sync>,
+    pub tree_hash: Arc<TreeHash>,
+    /// Where `/history` reads from, rather than the recent changes, if the listener keeps one.
+    pub audit_log: Option<Arc<AuditLog>>,
+}
+
+/// The hash of the output directory's tree, hashed again in the background once told that changes
+/// were applied rather than on every request, for requests not to make the listener rehash the
+/// whole directory over and over.
+pub struct TreeHash {
+    hash: watch::Sender<Option<Result<[u8; 20], String>>>,
+    stale: Notify,
+}
+
+impl Default for TreeHash {
+    fn default() -> Self {
+        Self {
+            hash: watch::channel(None).0,
+            stale: Notify::new(),
+        }
+    }
+}
+
+impl TreeHash {
+    /// Has the tree hashed again, once the hashing under way if any is done.
+    pub fn invalidate(&self) {
+        self.stale.notify_one();
+    }
+
+    /// The latest hash, waiting for the first one.
+    async fn get(&self) -> Result<[u8; 20], String> {
+        let mut hash = self.hash.subscribe();
+        let hash = hash.wait_for(Option::is_some).await;
+        hash.map_or_else(|err| Err(err.to_string()), |hash| hash.clone().unwrap())
+    }
+}
+
+/// Hashes the tree, then again every time it is invalidated.
+async fn rehash(state: Arc<ApiState>) {
+    loop {
+        let hash = (state.tree)()
+            .await
+            .map(|tree| root_checksum(&tree.top_level_checksums()))
+            .map_err(|err| format!("{:#}", err));
+        state.tree_hash.hash.send_replace(Some(hash));
+        state.tree_hash.stale.notified().await;
+    }
+}
+
+/// Serves a read-only HTTP API on the listener's state until dropped, for load balancers and
+/// deploy scripts to check that the output directory is fresh before relying on it:
+///
+/// - `GET /healthz`: `200 OK` while the listener runs.
+/// - `GET /status`: the listener's status, as `ctl status` shows it.
+/// - `GET /last-sync`: when the last batch of changes was applied, `503 Service Unavailable`
+///   unless one was and none is pending, or if it is older than the `max_age` query parameter,
+///   e.g. `?max_age=10m`.
+/// - `GET /tree-hash`: the hash of the output directory's tree, the sender's one once synced. It
+///   is computed when the API starts and once changes are applied, not on every request.
+/// - `GET /history?path=...`: the latest changes applied to a path, from the audit log if kept.
+///
+/// Built with the `dashboard` feature, a web dashboard showing the same is served on `/` too.
+pub struct StatusApi {
+    task: JoinHandle<()>,
+    addr: SocketAddr,
+}
+
+impl StatusApi {
+    pub async fn spawn(addr: SocketAddr, state: ApiState) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding the status API to {}", addr))?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(state);
+        let task = tokio::spawn(async move {
+            tokio::join!(serve_all(listener, state.clone()), rehash(state));
+        });
+        println!("Status API listening on http://{}", addr);
+
+        Ok(Self { task, addr })
+    }
+
+    /// The address requests are served on, with the port picked by the system if it was 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for StatusApi {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Response {
+    /// The status code, e.g. 404.
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        let mut body = serde_json::to_vec_pretty(&body).unwrap_or_default();
+        body.push(b'\n');
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body).into_bytes(),
+        }
+    }
+}
+
+#[cfg(feature = "dashboard")]
+async fn serve_all(listener: TcpListener, state: Arc<ApiState>) {
+    dashboard::serve_all(listener, state).await
+}
+
+#[cfg(not(feature = "dashboard"))]
+async fn serve_all(listener: TcpListener, state: Arc<ApiState>) {
+    loop {
+        let (stream, _) = super::accept(&listener).await;
+        let state = state.clone();
+        tokio::spawn(async move {
+            let _ = serve(stream, &state).await;
+        });
+    }
+}
+
+/// Answers a single request, closing the connection afterwards.
+#[cfg(not(feature = "dashboard"))]
+async fn serve(stream: TcpStream, state: &ApiState) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request(read)).await??;
+
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let response = match (method, target) {
+        ("GET" | "HEAD", Some(target)) => route(target, state).await,
+        (_, Some(_)) => Response::text(405, "only GET requests are served"),
+        _ => Response::text(400, "invalid request line"),
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+    if method != "HEAD" {
+        head.extend(response.body);
+    }
+    write.write_all(&head).await?;
+    write.shutdown().await?;
+    Ok(())
+}
+
+/// The reason phrase of the status codes `route` answers with.
+#[cfg(not(feature = "dashboard"))]
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads the request line of a request. The headers are not needed, but are read for clients
+/// waiting to send them all.
+#[cfg(not(feature = "dashboard"))]
+async fn read_request(read: OwnedReadHalf) -> anyhow::Result<String> {
+    let mut lines = BufReader::new(read).lines();
+    let request_line = lines.next_line().await?.unwrap_or_default();
+    while let Some(header) = lines.next_line().await? {
+        if header.is_empty() {
+            break;
+        }
+    }
+
+    Ok(request_line)
+}
+
+async fn route(target: &str, state: &ApiState) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/healthz" => Response::text(200, "ok"),
+        "/status" => Response::json(200, (state.status)()),
+        "/last-sync" => {
+            let max_age = query_param(query, "max_age")
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose();
+            match max_age {
+                Ok(max_age) => last_sync(&state.activity.lock().unwrap(), max_age),
+                Err(err) => Response::text(400, &format!("invalid max_age: {}", err)),
+            }
+        }
+        "/tree-hash" => match state.tree_hash.get().await {
+            Ok(tree_hash) => Response::json(
+                200,
+                serde_json::json!({ "tree_hash": hex::encode(tree_hash) }),
+            ),
+            Err(err) => Response::json(500, serde_json::json!({ "error": err })),
+        },
+        "/history" => match query_param(query, "path") {
+            Some(path) => history(state, Path::new(&path)).await,
+            None => Response::text(400, "missing path"),
+        },
+        _ => Response::text(404, "not found"),
+    }
+}
+
+/// The value of the `name` parameter in `query`, percent-decoded.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))?;
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The latest changes to `path`, oldest first.
+async fn history(state: &ApiState, path: &Path) -> Response {
+    let Some(log) = state.audit_log.clone() else {
+        let activity = state.activity.lock().unwrap();
+        let changes: Vec<_> = activity
+            .recent_changes
+            .iter()
+            .filter(|change| change.path == path)
+            .collect();
+        return Response::json(
+            200,
+            serde_json::json!({ "source": "recent_changes", "changes": changes }),
+        );
+    };
+
+    let path = path.to_owned();
+    match tokio::task::spawn_blocking(move || log.history(&path, HISTORY_LIMIT)).await {
+        Ok(Ok(records)) => Response::json(
+            200,
+            serde_json::json!({ "source": "audit_log", "changes": records }),
+        ),
+        Ok(Err(err)) => Response::json(500, serde_json::json!({ "error": format!("{:#}", err) })),
+        Err(err) => Response::json(500, serde_json::json!({ "error": err.to_string() })),
+    }
+}
+
+/// Whether the output directory is fresh: a batch of changes was applied, within `max_age` if
+/// given, and no change is pending.
+fn last_sync(activity: &Activity, max_age: Option<Duration>) -> Response {
+    let age = activity
+        .last_sync
+        .as_deref()
+        .and_then(|last_sync| humantime::parse_rfc3339(last_sync).ok())
+        .map(|last_sync| {
+            SystemTime::now()
+                .duration_since(last_sync)
+                .unwrap_or_default()
+        });
+    let fresh = match age {
+        Some(age) => activity.files_pending == 0 && max_age.is_none_or(|max_age| age <= max_age),
+        None => false,
+    };
+
+    let body = serde_json::json!({
+        "last_sync": activity.last_sync,
+        "seconds_ago": age.map(|age| age.as_secs()),
+        "connected": activity.connected,
+        "files_pending": activity.files_pending,
+        "fresh": fresh,
+    });
+    match fresh {
+        true => Response::json(200, body),
+        false => Response::json(503, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_status_api_reports_freshness() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("a.txt"), "a")?;
+        let activity = Arc::new(Mutex::new(Activity::default()));
+        let tree_hash = Arc::new(TreeHash::default());
+        let tree_dir = out_dir.path().to_owned();
+        let state = ApiState {
+            status: Box::new(|| serde_json::json!({ "role": "receiver" })),
+            activity: activity.clone(),
+            tree: Box::new(move || Box::pin(FileTree::new(tree_dir.clone()))),
+            tree_hash: tree_hash.clone(),
+            audit_log: None,
+        };
+        let api = StatusApi::spawn("127.0.0.1:0".parse()?, state).await?;
+        let get = |path: &str| {
+            let url = format!("http://{}{}", api.addr(), path);
+            async move {
+                let response = reqwest::get(url).await?;
+                anyhow::Ok((response.status().as_u16(), response.text().await?))
+            }
+        };
+        let json = |body: &str| serde_json::from_str::<serde_json::Value>(body);
+
+        assert_eq!(get("/healthz").await?, (200, "ok\n".to_owned()));
+        assert_eq!(get("/missing").await?.0, 404);
+        let (code, status) = get("/status").await?;
+        assert_eq!(code, 200);
+        assert_eq!(json(&status)?["role"], "receiver");
+
+        // Never synced.
+        assert_eq!(get("/last-sync").await?.0, 503);
+        activity.lock().unwrap().synced();
+        let (code, last_sync) = get("/last-sync?max_age=1h").await?;
+        assert_eq!(code, 200);
+        assert_eq!(json(&last_sync)?["fresh"], true);
+        assert_eq!(get("/last-sync?max_age=soon").await?.0, 400);
+        activity.lock().unwrap().files_pending = 3;
+        assert_eq!(get("/last-sync").await?.0, 503);
+
+        let expected = || async {
+            let tree = FileTree::new(out_dir.path()).await?;
+            anyhow::Ok(hex::encode(root_checksum(&tree.top_level_checksums())))
+        };
+        let (code, hashed) = get("/tree-hash").await?;
+        assert_eq!(code, 200);
+        assert_eq!(json(&hashed)?["tree_hash"], expected().await?);
+        // Only hashed again once invalidated.
+        fs::write(out_dir.path().join("b.txt"), "b")?;
+        assert_eq!(get("/tree-hash").await?.1, hashed);
+        tree_hash.invalidate();
+        let rehashed = loop {
+            let (_, rehashed) = get("/tree-hash").await?;
+            if rehashed != hashed {
+                break rehashed;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(json(&rehashed)?["tree_hash"], expected().await?);
+
+        activity
+            .lock()
+            .unwrap()
+            .record_change("edited", "docs/read me.md".into());
+        activity
+            .lock()
+            .unwrap()
+            .record_change("edited", "a.txt".into());
+        let (code, history) = get("/history?path=docs%2Fread+me.md").await?;
+        assert_eq!(code, 200);
+        let changes = &json(&history)?["changes"];
+        assert_eq!(changes.as_array().map(Vec::len), Some(1));
+        assert_eq!(changes[0]["change"], "edited");
+        assert_eq!(get("/history").await?.0, 400);
+
+        Ok(())
+    }
+}
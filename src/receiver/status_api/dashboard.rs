@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use tokio::net::TcpListener;
+
+use super::{route, ApiState, REQUEST_TIMEOUT};
+
+const INDEX: &str = include_str!("dashboard/index.html");
+const SCRIPT: &str = include_str!("dashboard/dashboard.js");
+const STYLE: &str = include_str!("dashboard/dashboard.css");
+
+/// Serves the dashboard's page and assets, which poll the status API, and the API itself.
+pub async fn serve_all(listener: TcpListener, state: Arc<ApiState>) {
+    let app = Router::new()
+        .route("/", get(|| async { Html(INDEX) }))
+        .route(
+            "/dashboard.js",
+            get(|| async { asset("text/javascript", SCRIPT) }),
+        )
+        .route("/dashboard.css", get(|| async { asset("text/css", STYLE) }))
+        .fallback(api)
+        .layer(middleware::from_fn(timeout))
+        .with_state(state);
+    if let Err(err) = axum::serve(listener, app).await {
+        eprintln!("The status API stopped: {}", err);
+    }
+}
+
+/// Answers `408 Request Timeout` to the requests not answered within `REQUEST_TIMEOUT`.
+async fn timeout(request: Request, next: Next) -> Response {
+    match tokio::time::timeout(REQUEST_TIMEOUT, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => (StatusCode::REQUEST_TIMEOUT, "request timed out\n").into_response(),
+    }
+}
+
+fn asset(content_type: &'static str, body: &'static str) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, content_type)], body)
+}
+
+async fn api(State(state): State<Arc<ApiState>>, method: Method, uri: Uri) -> impl IntoResponse {
+    let response = match method {
+        Method::GET | Method::HEAD => {
+            let target = uri
+                .path_and_query()
+                .map_or(uri.path(), |target| target.as_str());
+            route(target, &state).await
+        }
+        _ => super::Response::text(405, "only GET requests are served"),
+    };
+
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let headers = [
+        (header::CONTENT_TYPE, response.content_type),
+        (header::CACHE_CONTROL, "no-store"),
+    ];
+    (status, headers, response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::file_tree::FileTree, receiver::status_api::StatusApi};
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_dashboard_is_served_with_the_api() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let tree_dir = out_dir.path().to_owned();
+        let state = ApiState {
+            status: Box::new(|| serde_json::json!({ "role": "receiver" })),
+            activity: Arc::new(Mutex::new(Default::default())),
+            tree: Box::new(move || Box::pin(FileTree::new(tree_dir.clone()))),
+            tree_hash: Default::default(),
+            audit_log: None,
+        };
+        let api = StatusApi::spawn("127.0.0.1:0".parse()?, state).await?;
+        let url = |path: &str| format!("http://{}{}", api.addr(), path);
+
+        let index = reqwest::get(url("/")).await?;
+        assert_eq!(index.status(), 200);
+        assert!(index.text().await?.contains("/dashboard.js"));
+        let script = reqwest::get(url("/dashboard.js")).await?;
+        assert_eq!(script.headers()["content-type"], "text/javascript");
+        assert_eq!(reqwest::get(url("/healthz")).await?.text().await?, "ok\n");
+        assert_eq!(reqwest::get(url("/missing")).await?.status(), 404);
+        let posted = reqwest::Client::new().post(url("/status")).send().await?;
+        assert_eq!(posted.status(), 405);
+
+        Ok(())
+    }
+}
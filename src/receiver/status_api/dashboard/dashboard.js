@@ -0,0 +1,154 @@
+// Polls the status API and renders what it reports. The page is served by the status API itself,
+// so every request is relative to it.
+
+const POLL_INTERVAL_MS = 2000;
+
+let historyPath = null;
+
+function element(tag, text, className) {
+  const el = document.createElement(tag);
+  if (text !== undefined && text !== null) {
+    el.textContent = text;
+  }
+  if (className) {
+    el.className = className;
+  }
+  return el;
+}
+
+function fill(list, entries) {
+  list.replaceChildren(
+    ...entries.flatMap(([term, value, className]) => [
+      element("dt", term),
+      element("dd", value, className),
+    ]),
+  );
+}
+
+function bytes(count) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (count >= 1024 && unit < units.length - 1) {
+    count /= 1024;
+    unit += 1;
+  }
+  return `${count.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+function row(cells, onPathClick) {
+  const tr = element("tr");
+  cells.forEach(([text, className]) => {
+    const td = element("td", text, className);
+    if (className === "path") {
+      td.addEventListener("click", () => onPathClick(text));
+    }
+    tr.append(td);
+  });
+  return tr;
+}
+
+function emptyRow(columns, text) {
+  const td = element("td", text, "empty");
+  td.colSpan = columns;
+  const tr = element("tr");
+  tr.append(td);
+  return tr;
+}
+
+function render(status) {
+  const activity = status.activity;
+  document.getElementById("output-dir").textContent = status.output_dir;
+  document.getElementById("updated").textContent =
+    `Updated ${new Date().toLocaleTimeString()}`;
+
+  const connection = activity.connected
+    ? ["connected", "ok"]
+    : ["not connected", "bad"];
+  fill(document.getElementById("sender"), [
+    ["Status", connection[0], connection[1]],
+    [activity.connected ? "Peer" : "Last peer", activity.peer ?? "none"],
+    ["Pending", activity.files_pending],
+    ["Last sync", activity.last_sync ?? "never"],
+    ["Paused", status.paused ? "yes" : "no"],
+    ["Active sessions", status.active_sessions],
+  ]);
+
+  const metrics = status.metrics;
+  const totals = [
+    ["Sessions", `${metrics.sessions} (${metrics.rejected} rejected)`],
+    ["Changes", `${metrics.changes} (${metrics.failed} failed)`],
+    ["Received", bytes(metrics.bytes_received)],
+  ];
+  if (status.pending_deletions !== undefined) {
+    totals.push(["Pending deletions", status.pending_deletions]);
+  }
+  fill(document.getElementById("metrics"), totals);
+
+  const changes = [...activity.recent_changes].reverse();
+  document.getElementById("changes").replaceChildren(
+    ...(changes.length
+      ? changes.map((change) =>
+          row([[change.at], [change.change], [change.path, "path"]], showHistory),
+        )
+      : [emptyRow(3, "No changes applied yet.")]),
+  );
+
+  const errors = [...activity.recent_errors].reverse();
+  document.getElementById("errors").replaceChildren(
+    ...(errors.length
+      ? errors.map((error) => element("li", `${error.at}  ${error.error}`, "bad"))
+      : [element("li", "None.", "empty")]),
+  );
+}
+
+function describe(change) {
+  if (change.new_path) {
+    return `to ${change.new_path}`;
+  }
+  const details = [];
+  if (change.peer) {
+    details.push(`from ${change.peer}`);
+  }
+  if (change.bytes !== undefined && change.bytes !== null) {
+    details.push(bytes(change.bytes));
+  }
+  if (change.sha1) {
+    details.push(`sha1 ${change.sha1.slice(0, 12)}`);
+  }
+  return details.join(", ");
+}
+
+async function showHistory(path) {
+  historyPath = path;
+  const response = await fetch(`/history?path=${encodeURIComponent(path)}`);
+  const history = await response.json();
+  if (historyPath !== path) {
+    return;
+  }
+
+  document.getElementById("history-path").textContent = path;
+  document.getElementById("history-section").hidden = false;
+  const rows = response.ok
+    ? [...history.changes].reverse().map((change) =>
+        row([[change.at], [change.type ?? change.change], [describe(change)]]),
+      )
+    : [emptyRow(3, history.error)];
+  document.getElementById("history").replaceChildren(
+    ...(rows.length ? rows : [emptyRow(3, "No changes recorded.")]),
+  );
+}
+
+async function poll() {
+  try {
+    const response = await fetch("/status");
+    render(await response.json());
+    if (historyPath !== null) {
+      await showHistory(historyPath);
+    }
+  } catch (err) {
+    document.getElementById("updated").textContent = `Unreachable: ${err}`;
+  }
+  setTimeout(poll, POLL_INTERVAL_MS);
+}
+
+poll();
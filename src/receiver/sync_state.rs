@@ -0,0 +1,72 @@
+use std::{path::Path, time::SystemTime};
+
+use anyhow::Context;
+
+use crate::{
+    core::{
+        file_tree::FileTree,
+        merge::{Conflict, MergeReport, CONFLICT_SUFFIX},
+        roots::Roots,
+        utils::{clone_file, quoted},
+    },
+    snapshot::Snapshot,
+};
+
+/// Reads the sender's tree as of the last completed sync, none before the first one.
+pub fn load(path: &Path) -> anyhow::Result<Option<FileTree>> {
+    if !path.try_exists()? {
+        return Ok(None);
+    }
+
+    let snapshot = Snapshot::read(path).context("reading the sync state")?;
+    Ok(Some(snapshot.tree))
+}
+
+/// Records `tree`, the sender's tree of a completed sync into `roots`. Entries outside of `roots`
+/// come from other senders' syncs and stay as they were. The state is a snapshot, so that
+/// `diff-snapshots` can show it.
+pub fn save(
+    path: &Path,
+    out_dir: &Path,
+    roots: &Roots,
+    tree: FileTree,
+    size_only: bool,
+) -> anyhow::Result<()> {
+    let previous = load(path)?.map(|previous| {
+        previous.filtered(|path| !path.as_os_str().is_empty() && !roots.contains(path))
+    });
+    let snapshot = Snapshot {
+        dir: out_dir.to_owned(),
+        taken_at: SystemTime::now(),
+        size_only,
+        tree: FileTree::merged(previous.into_iter().chain([tree])),
+    };
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    snapshot.write(Path::new(&temp_path))?;
+    std::fs::rename(&temp_path, path).with_context(|| format!("replacing {}", quoted(path)))?;
+
+    Ok(())
+}
+
+/// Copies the receiver's version of the files edited on both sides next to them, before the
+/// sender's version replaces them.
+pub fn keep_conflicting(out_dir: &Path, report: &MergeReport) -> anyhow::Result<()> {
+    for (path, conflict) in report.conflicts.iter() {
+        if *conflict != Conflict::BothEdited {
+            continue;
+        }
+
+        let from = out_dir.join(path);
+        let mut to = from.as_os_str().to_owned();
+        to.push(CONFLICT_SUFFIX);
+        let to = Path::new(&to);
+        if to.exists() {
+            std::fs::remove_file(to)?;
+        }
+        clone_file(&from, to).with_context(|| format!("keeping a copy of {}", quoted(path)))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bytesize::ByteSize;
+use serde::{Deserialize, Deserializer};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tungstenite::handshake::server::{Request, Response};
+
+use super::{
+    accept,
+    access::Admission,
+    advertise_dedup, advertise_limits, advertise_out_dir, advertise_specials,
+    auth::{
+        bearer_key, keys_match, not_found, serves_path, speaks_protocol, unauthorized,
+        wrong_protocol, Refusal,
+    },
+    backups::BackupOptions,
+    close_busy, handshake_timeout,
+    middleware::{ConvertEol, LineEnding, MiddlewareChain},
+    quota::Quota,
+    tombstones::Tombstones,
+    trash::Trash,
+    versions::{self, Versions},
+    wal::WriteAheadLog,
+    websocket_config, ApplyOptions, Receiver, ReceiverOptions,
+};
+use crate::core::{
+    file_tree::ScanOptions, message::QUICK_CHECK_HEADER, overlap::DirIdentity,
+    timeout::with_timeout, tls::ServerTls, utils::quoted,
+};
+
+/// One tenant of a shared listener: senders presenting its key sync into its own directory,
+/// under its own quota and policies.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub key: String,
+    pub root: PathBuf,
+    #[serde(default)]
+    pub quota: Option<ByteSize>,
+    #[serde(default)]
+    pub update_only: bool,
+    #[serde(default)]
+    pub ignore_existing: bool,
+    #[serde(default)]
+    pub preallocate: bool,
+    #[serde(default, deserialize_with = "parse_eol")]
+    pub eol: Option<LineEnding>,
+    #[serde(default, deserialize_with = "parse_duration")]
+    pub delete_after: Option<Duration>,
+    #[serde(default)]
+    pub keep_versions: Option<usize>,
+    #[serde(default, deserialize_with = "parse_trash")]
+    pub use_trash: Option<Trash>,
+    #[serde(default)]
+    pub specials: bool,
+    #[serde(default)]
+    pub journal: bool,
+}
+
+impl TenantConfig {
+    /// `scan`, leaving out the directories the tenant's own files are kept in.
+    fn scan_options(&self, mut scan: ScanOptions) -> ScanOptions {
+        if self.keep_versions.is_some() || self.journal {
+            scan = versions::excluding_versions(scan);
+        }
+        if let Some(trash) = self.use_trash {
+            scan = trash.excluded_from(scan);
+        }
+        ScanOptions {
+            fifos: self.specials,
+            ..scan
+        }
+    }
+
+    fn apply_options(&self) -> ApplyOptions {
+        ApplyOptions {
+            middleware: self
+                .eol
+                .map(ConvertEol::from)
+                .into_iter()
+                .fold(MiddlewareChain::new(), MiddlewareChain::with),
+            update_only: self.update_only,
+            ignore_existing: self.ignore_existing,
+            quota: self.quota.map(Quota::new),
+            preallocate: self.preallocate,
+            tombstones: self.delete_after.map(Tombstones::new),
+            versions: self.keep_versions.map(Versions::new),
+            trash: self.use_trash,
+            specials: self.specials,
+            blobs: Default::default(),
+            converted: Default::default(),
+            wal: self.journal.then(WriteAheadLog::default),
+        }
+    }
+}
+
+fn parse_eol<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LineEnding>, D::Error> {
+    let eol = String::deserialize(deserializer)?;
+    eol.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+fn parse_trash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Trash>, D::Error> {
+    let trash = String::deserialize(deserializer)?;
+    trash.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+fn parse_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// The `[tenants.<name>]` sections of a listener's configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantsConfig {
+    pub tenants: BTreeMap<String, TenantConfig>,
+}
+
+impl TenantsConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", quoted(path)))?;
+        let config: Self =
+            toml::from_str(&contents).with_context(|| format!("parsing {}", quoted(path)))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Keys must tell tenants apart, and no tenant may reach into another one's directory.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.tenants.is_empty() {
+            bail!("no tenants configured")
+        }
+
+        let tenants: Vec<_> = self.tenants.iter().collect();
+        for (i, (name, tenant)) in tenants.iter().enumerate() {
+            if tenant.key.is_empty() {
+                bail!("tenant {} has an empty key", name)
+            }
+
+            for (other_name, other) in tenants[..i].iter() {
+                if other.key == tenant.key {
+                    bail!("tenants {} and {} have the same key", other_name, name)
+                }
+
+                if tenant.root.starts_with(&other.root) || other.root.starts_with(&tenant.root) {
+                    bail!(
+                        "the directories of tenants {} and {} overlap, as {} and {}",
+                        other_name,
+                        name,
+                        quoted(&other.root),
+                        quoted(&tenant.root)
+                    )
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Tenant {
+    name: String,
+    key: String,
+    receiver: Receiver<PathBuf>,
+    busy: Mutex<()>,
+}
+
+/// Serves several tenants from one listener, each tenant having its own receiver. Tenants sync
+/// concurrently, but each one runs a single session at a time.
+pub struct Gateway {
+    bind: IpAddr,
+    port: u32,
+    admission: Admission,
+    tls: Option<ServerTls>,
+    path: Option<Arc<str>>,
+    timeout: Duration,
+    max_message_size: u64,
+    tenants: Arc<Vec<Tenant>>,
+}
+
+impl Gateway {
+    /// Every tenant shares `options`, except for how changes are applied, which comes from its
+    /// configuration, and for staged sessions, which go to a subdirectory named after it.
+    /// Sessions end independently of each other, so `reconnect` does not apply.
+    pub fn new(port: u32, config: TenantsConfig, options: &ReceiverOptions) -> Self {
+        let tenants = config
+            .tenants
+            .into_iter()
+            .map(|(name, tenant)| {
+                let options = ReceiverOptions {
+                    bind: options.bind,
+                    // Connections are admitted by the gateway.
+                    access: Default::default(),
+                    // So are the TLS handshake and the path.
+                    path: None,
+                    tls: None,
+                    identities: None,
+                    keepalive: options.keepalive,
+                    reconnect: false,
+                    timeout: options.timeout,
+                    jobs: options.jobs,
+                    scan: tenant.scan_options(options.scan),
+                    apply: Arc::new(tenant.apply_options()),
+                    metrics: Default::default(),
+                    stage_dir: options.stage_dir.as_ref().map(|dir| dir.join(&name)),
+                    staged_ttl: options.staged_ttl,
+                    key: None,
+                    control: None,
+                    status_api: None,
+                    hooks: options.hooks.fresh(),
+                    json_summary: options.json_summary,
+                    audit_log: options.audit_log.clone(),
+                    webhooks: options.webhooks.clone(),
+                    sync_state: options.sync_state.as_ref().map(|path| {
+                        let mut path = path.as_os_str().to_owned();
+                        path.push(".");
+                        path.push(&name);
+                        PathBuf::from(path)
+                    }),
+                    backups: options.backups.as_ref().map(|backups| BackupOptions {
+                        dir: backups.dir.join(&name),
+                        ..backups.clone()
+                    }),
+                    max_message_size: options.max_message_size,
+                    memory: options.memory.clone(),
+                    // Tenants are not relayed.
+                    applied: None,
+                };
+
+                Tenant {
+                    name,
+                    key: tenant.key,
+                    receiver: Receiver::new(port, tenant.root, options),
+                    busy: Mutex::new(()),
+                }
+            })
+            .collect();
+
+        Self {
+            bind: options.bind,
+            port,
+            admission: Admission::new(options.access.clone()),
+            tls: options.tls.clone(),
+            path: options.path.as_deref().map(Arc::from),
+            timeout: options.timeout,
+            max_message_size: options.max_message_size,
+            tenants: Arc::new(tenants),
+        }
+    }
+
+    pub async fn start(&self) -> anyhow::Result<()> {
+        let _expiry: Vec<_> = self
+            .tenants
+            .iter()
+            .filter_map(|tenant| tenant.receiver.spawn_expiry())
+            .collect();
+        for tenant in self.tenants.iter() {
+            let root = &tenant.receiver.out_dir;
+            tokio::fs::create_dir_all(root)
+                .await
+                .with_context(|| format!("creating {}", quoted(root)))?;
+            tenant.receiver.recover().await?;
+        }
+        let _purge = self
+            .tenants
+            .iter()
+            .map(|tenant| tenant.receiver.spawn_purge())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let _backups = self
+            .tenants
+            .iter()
+            .map(|tenant| tenant.receiver.spawn_backups())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let addr = SocketAddr::new(self.bind, self.port.try_into()?);
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "WebSocket server listening on {} for {} tenants",
+            addr,
+            self.tenants.len()
+        );
+
+        loop {
+            tokio::select! {
+                (stream, addr) = accept(&listener) => {
+                    let admitted = match self.admission.admit(addr.ip()) {
+                        Ok(admitted) => admitted,
+                        Err(refusal) => {
+                            eprintln!("Refusing connection from {}: {}", addr, refusal);
+                            continue;
+                        }
+                    };
+                    let (tenants, tls, path) = (self.tenants.clone(), self.tls.clone(), self.path.clone());
+                    let (timeout, max_message_size) = (self.timeout, self.max_message_size);
+                    tokio::spawn(async move {
+                        let _admitted = admitted;
+                        let res = serve(&tenants, stream, addr, tls.as_ref(), path.as_deref(), timeout, max_message_size).await;
+                        if let Err(err) = res {
+                            eprintln!("Rejecting sender at {}: {:#}", addr, err);
+                        }
+                    });
+                }
+
+                _ = tokio::signal::ctrl_c() => {
+                    println!("Shutting down gracefully");
+                    for tenant in self.tenants.iter() {
+                        println!("[{}] {}", tenant.name, tenant.receiver.options.metrics);
+                    }
+
+                    break Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Authenticates the sender during the websocket handshake, after the TLS one if the gateway
+/// serves TLS, then runs its tenant's session.
+async fn serve(
+    tenants: &[Tenant],
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls: Option<&ServerTls>,
+    path: Option<&str>,
+    timeout: Duration,
+    max_message_size: u64,
+) -> anyhow::Result<()> {
+    let handshake = handshake_timeout(timeout);
+    let stream = match tls {
+        Some(tls) => with_timeout(handshake, "TLS handshake", tls.accept(stream)).await??,
+        None => Box::new(stream),
+    };
+    let mut tenant = None;
+    let mut wrong_path = None;
+    let mut other_protocol = None;
+    let mut quick_check = false;
+    // The callback's signature is imposed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let authenticate = |request: &Request, mut response: Response| {
+        if !serves_path(path, request) {
+            wrong_path = Some(request.uri().path().to_owned());
+            return Err(not_found());
+        }
+        if let Err(version) = speaks_protocol(request) {
+            other_protocol = Some(Refusal::WrongProtocol(version));
+            return Err(wrong_protocol());
+        }
+        quick_check = request.headers().contains_key(QUICK_CHECK_HEADER);
+        advertise_limits(&mut response, max_message_size);
+        tenant = bearer_key(request)
+            .and_then(|key| tenants.iter().find(|tenant| keys_match(&tenant.key, key)));
+        match tenant {
+            Some(tenant) => {
+                advertise_dedup(&mut response, &tenant.receiver.options.apply);
+                advertise_specials(&mut response, &tenant.receiver.options.apply);
+                let out_dir = DirIdentity::of(&tenant.receiver.out_dir);
+                advertise_out_dir(&mut response, out_dir.as_ref());
+                Ok(response)
+            }
+            None => Err(unauthorized()),
+        }
+    };
+
+    let accepted = with_timeout(
+        handshake,
+        "websocket handshake",
+        tokio_tungstenite::accept_hdr_async_with_config(
+            stream,
+            authenticate,
+            Some(websocket_config(max_message_size)),
+        ),
+    )
+    .await?;
+    if let Some(path) = wrong_path {
+        bail!("nothing served at {}", path)
+    }
+    if let Some(refusal) = other_protocol {
+        bail!("{}", refusal)
+    }
+    let Some(tenant) = tenant else {
+        bail!("unknown or missing key")
+    };
+    let socket = accepted?;
+
+    let metrics = &tenant.receiver.options.metrics;
+    let Ok(_session) = tenant.busy.try_lock() else {
+        eprintln!(
+            "[{}] Rejecting sender at {}, a sync session is already in progress",
+            tenant.name, addr
+        );
+        metrics.rejected.fetch_add(1, Ordering::Relaxed);
+        return close_busy(Box::new(socket)).await;
+    };
+
+    println!("[{}] Session started by {}", tenant.name, addr);
+    let res = tenant
+        .receiver
+        .run_session(Box::new(socket), None, quick_check)
+        .await;
+    let usage = match &tenant.receiver.options.apply.quota {
+        Some(quota) => format!(", {} of {} used", quota.used(), quota.limit()),
+        None => String::new(),
+    };
+    match res {
+        Ok(_) => println!("[{}] Session ended: {}{}", tenant.name, metrics, usage),
+        Err(err) => eprintln!(
+            "[{}] Session failed: {}\n{}{}",
+            tenant.name, err, metrics, usage
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[test]
+    async fn test_tenants_config() -> anyhow::Result<()> {
+        let config: TenantsConfig = toml::from_str(
+            r#"
+            [tenants.web]
+            key = "web-key"
+            root = "/srv/sync/web"
+            quota = "10 MiB"
+            update_only = true
+
+            [tenants.docs]
+            key = "docs-key"
+            root = "/srv/sync/docs"
+            eol = "lf"
+            "#,
+        )?;
+        config.validate()?;
+
+        let web = &config.tenants["web"];
+        assert_eq!(web.quota, Some(ByteSize::mib(10)));
+        assert!(web.update_only && !web.ignore_existing);
+        assert_eq!(config.tenants["docs"].eol, Some(LineEnding::Lf));
+
+        let shared_key = r#"
+            [tenants.a]
+            key = "key"
+            root = "/srv/a"
+            [tenants.b]
+            key = "key"
+            root = "/srv/b"
+        "#;
+        assert!(toml::from_str::<TenantsConfig>(shared_key)?
+            .validate()
+            .is_err());
+
+        let nested_roots = r#"
+            [tenants.a]
+            key = "a"
+            root = "/srv/a"
+            [tenants.b]
+            key = "b"
+            root = "/srv/a/b"
+        "#;
+        assert!(toml::from_str::<TenantsConfig>(nested_roots)?
+            .validate()
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use super::{
+    apply::{remove_entry, ApplyOptions},
+    staging::sibling,
+};
+use crate::core::{control::Controls, utils::quoted, wire_path};
+
+/// How often due tombstones are looked for, at most.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A deletion held back until its grace period is over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    #[serde(with = "wire_path")]
+    pub path: PathBuf,
+    pub dir: bool,
+    /// RFC 3339 timestamp of when the sender deleted it.
+    pub deleted_at: String,
+}
+
+impl Tombstone {
+    fn is_due(&self, grace: Duration) -> bool {
+        humantime::parse_rfc3339(&self.deleted_at).map_or(true, |deleted_at| {
+            deleted_at.elapsed().is_ok_and(|age| age >= grace)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Where the tombstones are persisted, once loaded.
+    journal: Option<PathBuf>,
+    tombstones: Vec<Tombstone>,
+}
+
+/// Deletions recorded instead of applied, and applied once they are `grace` old or on request,
+/// for receivers backing up a directory rather than mirroring it. The tombstones are kept in a
+/// JSON file next to the output directory, so they outlive restarts.
+#[derive(Debug)]
+pub struct Tombstones {
+    grace: Duration,
+    state: Mutex<State>,
+}
+
+impl Tombstones {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            state: Default::default(),
+        }
+    }
+
+    /// Reads the tombstones recorded for `out_dir` by earlier runs.
+    pub fn load(&self, out_dir: &Path) -> anyhow::Result<()> {
+        let journal = sibling(out_dir, "tombstones")?;
+        let tombstones = match std::fs::read(&journal) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("reading {}", quoted(&journal)))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(&journal))),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        *state = State {
+            journal: Some(journal),
+            tombstones,
+        };
+
+        Ok(())
+    }
+
+    pub fn pending(&self) -> Vec<Tombstone> {
+        self.state.lock().unwrap().tombstones.clone()
+    }
+
+    /// Holds back the deletion of `path`. Deleting it again keeps the earliest time.
+    pub fn record(&self, path: &Path, dir: bool) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .tombstones
+            .iter()
+            .any(|tombstone| tombstone.path == path)
+        {
+            return Ok(());
+        }
+
+        println!(
+            "Deleting {} after {}",
+            quoted(path),
+            humantime::format_duration(self.grace)
+        );
+        state.tombstones.push(Tombstone {
+            path: path.to_owned(),
+            dir,
+            deleted_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        });
+        save(&state)
+    }
+
+    /// Forgets the tombstones `path` brings back to life: the one at `path` itself, which is
+    /// returned, and those of the entries below it. The tombstones of its parent directories in
+    /// `out_dir` are replaced by tombstones of their other entries, so that only `path` is spared.
+    pub fn revive(&self, out_dir: &Path, path: &Path) -> anyhow::Result<Option<Tombstone>> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.tombstones.len();
+        let mut revived = None;
+        let mut parents = vec![];
+        state.tombstones.retain(|tombstone| {
+            if tombstone.path == path {
+                revived = Some(tombstone.clone());
+                return false;
+            }
+            if path.starts_with(&tombstone.path) {
+                parents.push(tombstone.clone());
+                return false;
+            }
+            !tombstone.path.starts_with(path)
+        });
+        let changed = state.tombstones.len() != before;
+        for parent in parents {
+            for tombstone in siblings(out_dir, &parent, path) {
+                if !state
+                    .tombstones
+                    .iter()
+                    .any(|other| other.path == tombstone.path)
+                {
+                    state.tombstones.push(tombstone);
+                }
+            }
+        }
+
+        if changed {
+            save(&state)?;
+        }
+        Ok(revived)
+    }
+
+    /// Removes the tombstones whose grace period is over, or all of them with `all`.
+    fn take_due(&self, all: bool) -> anyhow::Result<Vec<Tombstone>> {
+        let mut state = self.state.lock().unwrap();
+        let (due, pending) = std::mem::take(&mut state.tombstones)
+            .into_iter()
+            .partition(|tombstone| all || tombstone.is_due(self.grace));
+        state.tombstones = pending;
+
+        save(&state)?;
+        Ok(due)
+    }
+}
+
+/// Tombstones for the entries below the directory of `parent` that are not on the way to `path`,
+/// deleted when `parent` was.
+fn siblings(out_dir: &Path, parent: &Tombstone, path: &Path) -> Vec<Tombstone> {
+    let mut tombstones = vec![];
+    let mut dir = parent.path.clone();
+    for component in path.strip_prefix(&parent.path).unwrap_or(path).components() {
+        let Ok(entries) = std::fs::read_dir(out_dir.join(&dir)) else {
+            break;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            if entry.file_name() != component.as_os_str() {
+                tombstones.push(Tombstone {
+                    path: dir.join(entry.file_name()),
+                    dir: entry.file_type().is_ok_and(|typ| typ.is_dir()),
+                    deleted_at: parent.deleted_at.clone(),
+                });
+            }
+        }
+        dir.push(component);
+    }
+
+    tombstones
+}
+
+fn save(state: &State) -> anyhow::Result<()> {
+    let Some(journal) = &state.journal else {
+        return Ok(());
+    };
+
+    let mut temp_path = journal.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(&state.tombstones)?)?;
+    std::fs::rename(&temp_path, journal)
+        .with_context(|| format!("replacing {}", quoted(journal)))?;
+
+    Ok(())
+}
+
+/// Applies the deletions that are due, or all of them when `all`. Returns how many were
+/// applied.
+pub async fn purge(out_dir: &Path, options: &ApplyOptions, all: bool) -> anyhow::Result<usize> {
+    let Some(tombstones) = &options.tombstones else {
+        return Ok(0);
+    };
+
+    let due = tombstones.take_due(all)?;
+    for tombstone in due.iter() {
+        match remove_entry(out_dir, &tombstone.path, tombstone.dir, options).await {
+            Ok(()) => println!("Deleted {}", quoted(&tombstone.path)),
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound) => {}
+            Err(err) => eprintln!(
+                "An error occurred while deleting {}: {}",
+                quoted(&tombstone.path),
+                err
+            ),
+        }
+    }
+
+    Ok(due.len())
+}
+
+/// Applies due deletions periodically, and all of them when `ctl apply-deletes` asks to, until
+/// dropped.
+pub struct PurgeTask(JoinHandle<()>);
+
+impl PurgeTask {
+    pub fn spawn(
+        out_dir: PathBuf,
+        options: Arc<ApplyOptions>,
+        controls: Arc<Controls>,
+    ) -> Option<Self> {
+        let grace = options.tombstones.as_ref()?.grace;
+        let mut requests = controls.apply_deletes_requests();
+        Some(Self(tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(grace.clamp(Duration::from_secs(1), PURGE_INTERVAL));
+            loop {
+                let all = tokio::select! {
+                    _ = ticker.tick() => false,
+                    Ok(()) = requests.changed() => true,
+                };
+                match purge(&out_dir, &options, all).await {
+                    Ok(applied) if all => println!("Applied {} held back deletions", applied),
+                    Ok(_) => (),
+                    Err(err) => eprintln!("An error occurred while applying deletions: {}", err),
+                }
+            }
+        })))
+    }
+}
+
+impl Drop for PurgeTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_deletions_wait_for_grace_period() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let out_dir = dir.path().join("out");
+        fs::create_dir_all(out_dir.join("logs"))?;
+        fs::write(out_dir.join("logs/app.log"), "log")?;
+        fs::write(out_dir.join("notes.txt"), "notes")?;
+        fs::write(out_dir.join("todo.txt"), "todo")?;
+
+        let options = ApplyOptions {
+            tombstones: Some(Tombstones::new(Duration::from_secs(3600))),
+            ..Default::default()
+        };
+        let tombstones = options.tombstones.as_ref().unwrap();
+        tombstones.load(&out_dir)?;
+        tombstones.record(Path::new("logs"), true)?;
+        tombstones.record(Path::new("notes.txt"), false)?;
+        tombstones.record(Path::new("todo.txt"), false)?;
+        assert_eq!(purge(&out_dir, &options, false).await?, 0);
+        assert!(out_dir.join("notes.txt").exists());
+
+        // Synced again before its grace period ended.
+        assert!(tombstones
+            .revive(&out_dir, Path::new("todo.txt"))?
+            .is_some());
+
+        // Restarting keeps the tombstones.
+        let restarted = Tombstones::new(Duration::from_secs(3600));
+        restarted.load(&out_dir)?;
+        assert_eq!(restarted.pending().len(), 2);
+
+        assert_eq!(purge(&out_dir, &options, true).await?, 2);
+        assert!(!out_dir.join("logs").exists());
+        assert!(!out_dir.join("notes.txt").exists());
+        assert!(out_dir.join("todo.txt").exists());
+        assert!(tombstones.pending().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_reviving_an_entry_keeps_the_deletion_of_its_siblings() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let out_dir = dir.path().join("out");
+        fs::create_dir_all(out_dir.join("old/sub"))?;
+        fs::write(out_dir.join("old/a.txt"), "a")?;
+        fs::write(out_dir.join("old/sub/b.txt"), "b")?;
+
+        let options = ApplyOptions {
+            tombstones: Some(Tombstones::new(Duration::from_secs(3600))),
+            ..Default::default()
+        };
+        let tombstones = options.tombstones.as_ref().unwrap();
+        tombstones.load(&out_dir)?;
+        tombstones.record(Path::new("old"), true)?;
+
+        // Created in the deleted directory before its grace period ended.
+        assert!(tombstones
+            .revive(&out_dir, Path::new("old/sub/new.txt"))?
+            .is_none());
+        let mut pending: Vec<_> = tombstones
+            .pending()
+            .into_iter()
+            .map(|tombstone| (tombstone.path, tombstone.dir))
+            .collect();
+        pending.sort();
+        assert_eq!(
+            pending,
+            [
+                (PathBuf::from("old/a.txt"), false),
+                (PathBuf::from("old/sub/b.txt"), false)
+            ]
+        );
+
+        assert_eq!(purge(&out_dir, &options, true).await?, 2);
+        assert!(!out_dir.join("old/a.txt").exists());
+        assert!(!out_dir.join("old/sub/b.txt").exists());
+        assert!(out_dir.join("old/sub").exists());
+
+        Ok(())
+    }
+}
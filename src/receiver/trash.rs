@@ -0,0 +1,123 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use super::versions::timestamp;
+use crate::core::{file_tree::ScanOptions, utils::quoted};
+
+/// Where deleted entries are moved with `Trash::Local`, relative to the output directory.
+pub const TRASH_DIR: &str = ".caiman-trash";
+
+/// Where deleted entries go instead of being removed for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trash {
+    /// The platform's trash, e.g. `~/.local/share/Trash` on Linux or the Recycle Bin on Windows.
+    System,
+    /// `TRASH_DIR` inside the output directory.
+    Local,
+}
+
+impl FromStr for Trash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Trash::System),
+            "local" => Ok(Trash::Local),
+            _ => Err(format!("unknown trash '{}', expected system or local", s)),
+        }
+    }
+}
+
+impl Trash {
+    /// Moves the entry at `path` of `out_dir` to the trash. Entries the platform's trash refuses,
+    /// e.g. on a filesystem without one, go to the local trash instead. Returns the trash they
+    /// went to.
+    pub fn put(self, out_dir: &Path, path: &Path) -> anyhow::Result<Trash> {
+        out_dir.join(path).symlink_metadata()?;
+        if self == Trash::System {
+            match system_trash(&out_dir.join(path)) {
+                Ok(()) => return Ok(Trash::System),
+                Err(err) => eprintln!(
+                    "WARNING: could not move {} to the trash, moving it to {} instead: {:#}",
+                    quoted(path),
+                    TRASH_DIR,
+                    err
+                ),
+            }
+        }
+
+        let target = local_target(out_dir, path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(out_dir.join(path), &target)
+            .with_context(|| format!("moving {} to {}", quoted(path), TRASH_DIR))?;
+
+        Ok(Trash::Local)
+    }
+
+    /// Leaves the local trash out of `scan` too, so that trashed entries are never synced or
+    /// deleted.
+    pub fn excluded_from(self, scan: ScanOptions) -> ScanOptions {
+        scan.excluding(TRASH_DIR)
+    }
+}
+
+#[cfg(feature = "trash")]
+fn system_trash(path: &Path) -> anyhow::Result<()> {
+    trash::delete(path)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "trash"))]
+fn system_trash(_path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("this build has no trash support")
+}
+
+/// `path` inside the local trash, followed by the time it is trashed at if an earlier entry
+/// took its place.
+fn local_target(out_dir: &Path, path: &Path) -> PathBuf {
+    let target = out_dir.join(TRASH_DIR).join(path);
+    if !target.exists() {
+        return target;
+    }
+
+    let mut target = target.into_os_string();
+    target.push(".");
+    target.push(timestamp(SystemTime::now()));
+    PathBuf::from(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_local_trash_keeps_every_deletion() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::create_dir(out_dir.path().join("docs"))?;
+        fs::write(out_dir.path().join("docs/notes.txt"), "v1")?;
+        Trash::Local.put(out_dir.path(), Path::new("docs/notes.txt"))?;
+
+        fs::write(out_dir.path().join("docs/notes.txt"), "v2")?;
+        Trash::Local.put(out_dir.path(), Path::new("docs"))?;
+
+        let trash = out_dir.path().join(TRASH_DIR);
+        assert_eq!(fs::read_to_string(trash.join("docs/notes.txt"))?, "v1");
+        let trashed_dir = fs::read_dir(&trash)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .find(|path| path.as_ref().is_ok_and(|path| !path.ends_with("docs")))
+            .expect("the second deletion")?;
+        assert_eq!(fs::read_to_string(trashed_dir.join("notes.txt"))?, "v2");
+        assert!(!out_dir.path().join("docs").exists());
+
+        Ok(())
+    }
+}
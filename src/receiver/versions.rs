@@ -0,0 +1,177 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+
+use crate::core::{
+    file_tree::ScanOptions,
+    utils::{clone_file, quoted},
+};
+
+/// Directory of the output directory the listener keeps its own files in, left out of its scans.
+pub const CAIMAN_DIR: &str = ".caiman";
+
+/// The previous versions of `<path>` are kept in `<VERSIONS_DIR>/<path>/<timestamp>`, relative to
+/// the output directory.
+const VERSIONS_DIR: &str = "versions";
+
+/// Previous versions of the files the sender overwrites or deletes, the last `keep` of each.
+#[derive(Debug)]
+pub struct Versions {
+    keep: usize,
+}
+
+impl Versions {
+    pub fn new(keep: usize) -> Self {
+        Self { keep }
+    }
+
+    /// Copies the file at `path` into its versions before it is replaced, then drops its oldest
+    /// versions past `keep`. Returns the size of the versions dropped.
+    pub fn keep(&self, out_dir: &Path, path: &Path) -> anyhow::Result<u64> {
+        let dir = save(out_dir, path)?;
+        let versions = list_dir(&dir)?;
+        let mut pruned = 0;
+        for version in versions.iter().rev().skip(self.keep) {
+            let version = dir.join(version);
+            pruned += version.metadata()?.len();
+            std::fs::remove_file(version)?;
+        }
+
+        Ok(pruned)
+    }
+}
+
+/// Leaves the `CAIMAN_DIR` out of `scan` too, so that versions and the write-ahead log are never
+/// synced or deleted.
+pub fn excluding_versions(scan: ScanOptions) -> ScanOptions {
+    scan.excluding(CAIMAN_DIR)
+}
+
+/// Copies the file at `path` into a new version, returning the directory of its versions.
+fn save(out_dir: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let dir = versions_dir(out_dir, path);
+    std::fs::create_dir_all(&dir)?;
+
+    // Edits within the same millisecond keep the first version.
+    let version = dir.join(timestamp(SystemTime::now()));
+    if !version.exists() {
+        clone_file(&out_dir.join(path), &version)
+            .with_context(|| format!("keeping a version of {}", quoted(path)))?;
+    }
+
+    Ok(dir)
+}
+
+fn versions_dir(out_dir: &Path, path: &Path) -> PathBuf {
+    out_dir.join(CAIMAN_DIR).join(VERSIONS_DIR).join(path)
+}
+
+/// Sortable and valid in file names on every platform, e.g. `2024-05-02T091244.310Z`.
+pub(super) fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time)
+        .to_string()
+        .replace(':', "")
+}
+
+/// The versions kept in `dir`, oldest first. Subdirectories hold the versions of the files below
+/// a path that was once a file and is now a directory.
+fn list_dir(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut versions = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            versions.extend(entry.file_name().into_string().ok());
+        }
+    }
+    versions.sort();
+
+    Ok(versions)
+}
+
+/// The versions kept of the file at `path`, oldest first, with their sizes.
+pub fn list(out_dir: &Path, path: &Path) -> anyhow::Result<Vec<(String, u64)>> {
+    let path = relative(path)?;
+    let dir = versions_dir(out_dir, path);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    list_dir(&dir)?
+        .into_iter()
+        .map(|version| {
+            let size = dir.join(&version).metadata()?.len();
+            Ok((version, size))
+        })
+        .collect()
+}
+
+/// Brings back `version` of the file at `path`. Its current contents are kept as a version
+/// first, so that restoring can be undone.
+pub fn restore(out_dir: &Path, path: &Path, version: &str) -> anyhow::Result<()> {
+    let path = relative(path)?;
+    let source = versions_dir(out_dir, path).join(version);
+    if Path::new(version).file_name() != Some(version.as_ref()) || !source.is_file() {
+        bail!("{} has no version {}", quoted(path), version);
+    }
+
+    let target = out_dir.join(path);
+    if target.is_file() {
+        save(out_dir, path)?;
+        std::fs::remove_file(&target)?;
+    } else if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    clone_file(&source, &target).with_context(|| format!("restoring {}", quoted(path)))?;
+
+    Ok(())
+}
+
+fn relative(path: &Path) -> anyhow::Result<&Path> {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    if path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!("{} must be relative to the output directory", quoted(path));
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keeps_and_restores_versions() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        let path = Path::new("docs/notes.txt");
+        fs::create_dir(out_dir.path().join("docs"))?;
+
+        let versions = Versions::new(2);
+        for contents in ["v1", "v2", "v3"] {
+            fs::write(out_dir.path().join(path), contents)?;
+            versions.keep(out_dir.path(), path)?;
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        fs::write(out_dir.path().join(path), "v4")?;
+
+        let kept = list(out_dir.path(), path)?;
+        assert_eq!(kept.len(), 2);
+
+        restore(out_dir.path(), path, &kept[0].0)?;
+        assert_eq!(fs::read_to_string(out_dir.path().join(path))?, "v2");
+        // The restored-over contents became a version too.
+        assert_eq!(list(out_dir.path(), path)?.len(), 3);
+
+        assert!(restore(out_dir.path(), path, "missing").is_err());
+        assert!(list(out_dir.path(), Path::new("../escape")).is_err());
+
+        Ok(())
+    }
+}
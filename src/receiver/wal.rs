@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    apply::{partial_path, remove_entry, ApplyOptions},
+    versions::CAIMAN_DIR,
+};
+use crate::core::{
+    compression::archive_entries,
+    message::FileChangeMessage,
+    records::{push_record, read_records},
+    utils::quoted,
+    wire_path,
+};
+
+/// The log is kept in `<CAIMAN_DIR>/<WAL_FILE>`, relative to the output directory.
+const WAL_FILE: &str = "journal";
+
+/// What a change may leave behind in the output directory when the listener dies applying it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Pending {
+    /// A file written into the partial file next to it, which replaces it once complete.
+    Write(#[serde(with = "wire_path")] PathBuf),
+    /// A directory unpacked from an archive where there was none, removed whole if unfinished.
+    Unpack(#[serde(with = "wire_path")] PathBuf),
+    /// A file or a directory being deleted.
+    Delete(#[serde(with = "wire_path")] PathBuf, bool),
+    /// An archive unpacked into a directory that was there before, with the paths of its entries
+    /// relative to it: only those are removed if unfinished.
+    UnpackInto(
+        #[serde(with = "wire_path")] PathBuf,
+        #[serde(with = "wire_path::vec")] Vec<PathBuf>,
+    ),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    /// A change about to be applied, numbered in the order changes began.
+    Begun(u64, Pending),
+    Ended(u64),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The log, once recovered from.
+    file: Option<File>,
+    out_dir: PathBuf,
+    next: u64,
+    /// The changes begun and not ended yet.
+    open: BTreeMap<u64, Pending>,
+    /// The files whose partial file outlived the change writing it, until a later chunk
+    /// completes it.
+    partials: BTreeSet<PathBuf>,
+}
+
+/// A write-ahead log of the changes being applied, recording what each may leave behind before it
+/// starts writing and that it ended once done. On startup, what the changes a crash interrupted
+/// left behind is rolled back, or finished for deletions, so that every file is either as it was
+/// or as it was sent, and the sender's next sync sends the rest. The log is emptied whenever no
+/// change is being applied, so it stays small, but for the partial files of files sent in chunks.
+#[derive(Debug, Default)]
+pub struct WriteAheadLog {
+    state: Mutex<State>,
+}
+
+/// How a change interrupted by a crash was recovered from.
+#[derive(Debug)]
+pub enum Recovered {
+    /// The partial file of an unfinished write was removed, the file is as it was.
+    Write(PathBuf),
+    /// A directory unpacked partway was removed.
+    Unpack(PathBuf),
+    /// What was unpacked partway into a directory that was there before was removed.
+    UnpackInto(PathBuf),
+    /// An unfinished deletion was finished.
+    Delete(PathBuf),
+}
+
+impl Display for Recovered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recovered::Write(path) => write!(f, "dropped the unfinished write of {}", quoted(path)),
+            Recovered::Unpack(path) => {
+                write!(f, "removed {}, which was unpacked partway", quoted(path))
+            }
+            Recovered::UnpackInto(path) => {
+                write!(f, "removed what was unpacked partway into {}", quoted(path))
+            }
+            Recovered::Delete(path) => write!(f, "finished deleting {}", quoted(path)),
+        }
+    }
+}
+
+/// A change recorded as begun, recorded as ended when dropped, whether it was applied or not.
+pub struct Entry<'log> {
+    log: &'log WriteAheadLog,
+    seq: u64,
+}
+
+impl WriteAheadLog {
+    /// Recovers from the changes left unfinished in `out_dir` by an earlier run, then starts a
+    /// new log. Deletions held back as tombstones are left to them.
+    pub async fn recover(
+        &self,
+        out_dir: &Path,
+        options: &ApplyOptions,
+    ) -> anyhow::Result<Vec<Recovered>> {
+        let dir = out_dir.join(CAIMAN_DIR);
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", quoted(&dir)))?;
+        let path = dir.join(WAL_FILE);
+        let records = match std::fs::read(&path) {
+            Ok(contents) => read_records(&contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(&path))),
+        };
+
+        let mut begun = vec![];
+        let mut ended = BTreeSet::new();
+        for record in records {
+            match record {
+                Record::Begun(seq, pending) => begun.push((seq, pending)),
+                Record::Ended(seq) => {
+                    ended.insert(seq);
+                }
+            }
+        }
+
+        let mut recovered = vec![];
+        for (seq, pending) in begun {
+            match pending {
+                // Chunks leave their partial file behind until the last one, even once ended.
+                Pending::Write(path) => {
+                    let partial_path = partial_path(&out_dir.join(&path));
+                    if partial_path.exists() {
+                        std::fs::remove_file(&partial_path)?;
+                        recovered.push(Recovered::Write(path));
+                    }
+                }
+                _ if ended.contains(&seq) => (),
+                Pending::Unpack(path) => {
+                    let dir_path = out_dir.join(&path);
+                    if dir_path.exists() {
+                        std::fs::remove_dir_all(&dir_path)?;
+                        recovered.push(Recovered::Unpack(path));
+                    }
+                }
+                Pending::UnpackInto(path, entries) => {
+                    remove_unpacked(&out_dir.join(&path), entries)?;
+                    recovered.push(Recovered::UnpackInto(path));
+                }
+                Pending::Delete(path, dir) => {
+                    let exists = out_dir.join(&path).symlink_metadata().is_ok();
+                    if exists && options.tombstones.is_none() {
+                        remove_entry(out_dir, &path, dir, options).await?;
+                        recovered.push(Recovered::Delete(path));
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", quoted(&path)))?;
+        file.set_len(0)?;
+        *self.state.lock().unwrap() = State {
+            file: Some(file),
+            out_dir: out_dir.to_owned(),
+            ..Default::default()
+        };
+
+        Ok(recovered)
+    }
+
+    /// Records that `change` is about to be applied to `out_dir`, if it could leave something
+    /// behind. Nothing is recorded before the log is recovered from.
+    pub async fn begin(
+        &self,
+        out_dir: &Path,
+        change: &FileChangeMessage,
+    ) -> anyhow::Result<Option<Entry<'_>>> {
+        let Some(pending) = pending(out_dir, change).await? else {
+            return Ok(None);
+        };
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next;
+        let Some(file) = &mut state.file else {
+            return Ok(None);
+        };
+
+        append(file, &Record::Begun(seq, pending.clone()))?;
+        state.next += 1;
+        state.open.insert(seq, pending);
+        Ok(Some(Entry { log: self, seq }))
+    }
+
+    fn end(&self, seq: u64) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            file: Some(file),
+            out_dir,
+            next,
+            open,
+            partials,
+        } = &mut *state
+        else {
+            return Ok(());
+        };
+
+        if let Some(Pending::Write(path)) = open.remove(&seq) {
+            partials.insert(path);
+        }
+        if !open.is_empty() {
+            return append(file, &Record::Ended(seq));
+        }
+
+        // Emptied, but for the partial files still waiting for their next chunk.
+        file.set_len(0)?;
+        partials.retain(|path| partial_path(&out_dir.join(path)).exists());
+        for path in partials.iter() {
+            append(file, &Record::Begun(*next, Pending::Write(path.clone())))?;
+            *next += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Entry<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.log.end(self.seq) {
+            eprintln!("WARNING: could not write to the journal: {:#}", err);
+        }
+    }
+}
+
+async fn pending(out_dir: &Path, change: &FileChangeMessage) -> anyhow::Result<Option<Pending>> {
+    let pending = match change {
+        FileChangeMessage::FileEdited(path, ..)
+        | FileChangeMessage::GzippedFileEdited(path, ..)
+        | FileChangeMessage::FileChunk(path, _)
+        | FileChangeMessage::FileFromHash(path, ..) => Pending::Write(path.clone()),
+        // Gzipped archives are decoded before being applied.
+        FileChangeMessage::DirectoryCreated(path, archive) => match out_dir.join(path).is_dir() {
+            true => Pending::UnpackInto(path.clone(), archive_entries(archive).await?),
+            false => Pending::Unpack(path.clone()),
+        },
+        FileChangeMessage::GzippedDirectoryCreated(path, _) => Pending::Unpack(path.clone()),
+        FileChangeMessage::FileDeleted(path) => Pending::Delete(path.clone(), false),
+        FileChangeMessage::DirectoryDeleted(path) => Pending::Delete(path.clone(), true),
+        FileChangeMessage::Checksummed(change, _) => {
+            return Box::pin(pending(out_dir, change)).await
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(pending))
+}
+
+/// Removes the `entries` of an archive unpacked partway into `dir_path`, deepest first. Their
+/// directories are only removed once emptied, so that what was there before the archive stays.
+pub(super) fn remove_unpacked(dir_path: &Path, entries: Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries: BTreeSet<_> = entries
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        })
+        .collect();
+    for entry in entries.iter().rev() {
+        let path = dir_path.join(entry);
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                let _ = std::fs::remove_dir(&path);
+            }
+            Ok(_) => std::fs::remove_file(&path)?,
+            Err(_) => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn append(file: &mut File, record: &Record) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    push_record(&mut buf, record)?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{compression::compress_dir, message::Chunk};
+    use bytes::Bytes;
+    use std::{fs, time::SystemTime};
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_crashes_are_rolled_back_or_finished() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("notes.txt"), "old")?;
+        fs::create_dir(out_dir.path().join("old"))?;
+        fs::write(out_dir.path().join("old/file.txt"), "old")?;
+        let options = ApplyOptions::default();
+        let wal = WriteAheadLog::default();
+        assert!(wal.recover(out_dir.path(), &options).await?.is_empty());
+
+        // The partial file of a file sent in chunks outlives the log being emptied.
+        let chunk = Chunk {
+            offset: 0,
+            size: 4,
+            data: Bytes::from("01"),
+            mtime: SystemTime::now(),
+        };
+        let entry = wal
+            .begin(
+                out_dir.path(),
+                &FileChangeMessage::FileChunk("video.mp4".into(), chunk),
+            )
+            .await?;
+        fs::write(out_dir.path().join("video.mp4.caiman-partial"), "01")?;
+        drop(entry);
+
+        // What the changes being applied when the listener died left behind.
+        let edit = FileChangeMessage::FileEdited(
+            "notes.txt".into(),
+            Bytes::from("new"),
+            SystemTime::now(),
+        );
+        std::mem::forget(wal.begin(out_dir.path(), &edit).await?);
+        fs::write(out_dir.path().join("notes.txt.caiman-partial"), "ne")?;
+        let unpack = FileChangeMessage::DirectoryCreated("new".into(), Bytes::new());
+        std::mem::forget(wal.begin(out_dir.path(), &unpack).await?);
+        fs::create_dir(out_dir.path().join("new"))?;
+        let delete = FileChangeMessage::DirectoryDeleted("old".into());
+        std::mem::forget(wal.begin(out_dir.path(), &delete).await?);
+        fs::remove_file(out_dir.path().join("old/file.txt"))?;
+        // Changes that ended are not recovered from.
+        let done = FileChangeMessage::DirectoryCreated("done".into(), Bytes::new());
+        let entry = wal.begin(out_dir.path(), &done).await?;
+        fs::create_dir(out_dir.path().join("done"))?;
+        drop(entry);
+
+        let recovered = WriteAheadLog::default()
+            .recover(out_dir.path(), &options)
+            .await?;
+        assert_eq!(recovered.len(), 4);
+        assert!(!out_dir.path().join("video.mp4.caiman-partial").exists());
+        assert_eq!(fs::read_to_string(out_dir.path().join("notes.txt"))?, "old");
+        assert!(!out_dir.path().join("notes.txt.caiman-partial").exists());
+        assert!(!out_dir.path().join("new").exists());
+        assert!(!out_dir.path().join("old").exists());
+        assert!(out_dir.path().join("done").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    async fn test_unpacking_into_a_directory_only_rolls_back_its_entries() -> anyhow::Result<()> {
+        let source_dir = TempDir::new()?;
+        fs::create_dir(source_dir.path().join("sub"))?;
+        fs::write(source_dir.path().join("sub/theirs.txt"), "theirs")?;
+        fs::write(source_dir.path().join("theirs.txt"), "theirs")?;
+        let archive = compress_dir(source_dir.path()).await?;
+
+        let out_dir = TempDir::new()?;
+        fs::create_dir_all(out_dir.path().join("docs/mine"))?;
+        fs::write(out_dir.path().join("docs/mine.txt"), "mine")?;
+        let options = ApplyOptions::default();
+        let wal = WriteAheadLog::default();
+        wal.recover(out_dir.path(), &options).await?;
+
+        let unpack = FileChangeMessage::DirectoryCreated("docs".into(), archive);
+        std::mem::forget(wal.begin(out_dir.path(), &unpack).await?);
+        fs::create_dir(out_dir.path().join("docs/sub"))?;
+        fs::write(out_dir.path().join("docs/sub/theirs.txt"), "the")?;
+
+        let recovered = WriteAheadLog::default()
+            .recover(out_dir.path(), &options)
+            .await?;
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(
+            fs::read_to_string(out_dir.path().join("docs/mine.txt"))?,
+            "mine"
+        );
+        assert!(out_dir.path().join("docs/mine").is_dir());
+        assert!(!out_dir.path().join("docs/sub").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+#![allow(deprecated)]
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+use watchman_client::prelude::*;
+
+use crate::{
+    core::{file_change::FileChange, roots::Roots, utils::quoted},
+    receiver::{excluding_partials, Applied, Receiver, ReceiverOptions},
+    sender::{Fanout, Sender, SenderOptions},
+};
+
+/// A listener sending whatever it applies on to a downstream listener, so that changes spread
+/// through a tree of listeners rather than the sender connecting to each one. Downstream, the
+/// output directory is synced like any other: initial syncs catch the listener up, then each batch
+/// the relay applies is sent on as if it was watched. Changes made in the output directory by
+/// other means are only sent on by the next initial sync.
+pub struct Relay {
+    port: u32,
+    out_dir: PathBuf,
+    to: String,
+    receiver: ReceiverOptions,
+    sender: SenderOptions,
+}
+
+impl Relay {
+    pub fn new(
+        port: u32,
+        out_dir: impl Into<PathBuf>,
+        to: impl Into<String>,
+        receiver: ReceiverOptions,
+        sender: SenderOptions,
+    ) -> Self {
+        Self {
+            port,
+            out_dir: out_dir.into(),
+            to: to.into(),
+            receiver,
+            sender,
+        }
+    }
+
+    /// Listens, and sends on meanwhile, until the listener stops. The output directory is
+    /// scanned like the listener scans it, leaving out its own files and those being received.
+    pub async fn start(mut self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.out_dir)
+            .await
+            .with_context(|| format!("creating {}", quoted(&self.out_dir)))?;
+
+        let (report, mut applied) = mpsc::unbounded_channel();
+        self.receiver.applied = Some(report);
+        self.sender.scan = excluding_partials(self.receiver.scan);
+        let roots = Roots::single(&self.out_dir);
+        let fanout = Arc::new(Fanout::fed());
+        let receiver = Receiver::new(self.port, &self.out_dir, self.receiver);
+        let sender = Sender::new(roots.clone(), &self.to, self.sender).sharing(fanout.clone());
+        let forward = async {
+            let mut next_ino = 0;
+            while let Some(applied) = applied.recv().await {
+                fanout.report(changes(&roots, applied, &mut next_ino));
+            }
+        };
+
+        tokio::select! {
+            res = receiver.start() => res,
+            res = sender.start(true) => {
+                res.with_context(|| format!("sending on to {}", self.to))
+            }
+            // The receiver reports until it stops.
+            _ = forward => Ok(()),
+        }
+    }
+}
+
+/// What a batch applied, as the watcher would report it: the paths in the state they are in now,
+/// directories being sent whole since files may have been written into them. Every change gets
+/// its own inode number, but for both ends of a rename, so that renames are sent as such.
+fn changes(roots: &Roots, applied: Vec<Applied>, next_ino: &mut u64) -> Vec<FileChange> {
+    let mut changes = vec![];
+    for applied in applied {
+        *next_ino += 1;
+        match applied {
+            Applied::File(path) => changes.push(change(roots, path, false, *next_ino)),
+            Applied::Directory(path) => changes.push(change(roots, path, true, *next_ino)),
+            Applied::Renamed(from, to) => {
+                let to = change(roots, to, false, *next_ino);
+                let is_dir = matches!(*to.typ, FileType::Directory);
+                changes.push(file_change(from, false, is_dir, *next_ino));
+                changes.push(to);
+            }
+        }
+    }
+    changes
+}
+
+/// A change of the path as it is now, or of what it was if it is gone.
+fn change(roots: &Roots, path: PathBuf, was_dir: bool, ino: u64) -> FileChange {
+    let metadata = roots
+        .resolve(&path)
+        .and_then(|resolved| std::fs::symlink_metadata(resolved).ok());
+    let is_dir = metadata
+        .as_ref()
+        .map_or(was_dir, |metadata| metadata.is_dir());
+    file_change(path, metadata.is_some(), is_dir, ino)
+}
+
+fn file_change(path: PathBuf, exists: bool, is_dir: bool, ino: u64) -> FileChange {
+    FileChange {
+        name: NameField::new(path),
+        exists: ExistsField::new(exists),
+        is_new: NewField::new(exists && is_dir),
+        ctime: CTimeField::new(0),
+        mtime: MTimeField::new(0),
+        typ: FileTypeField::new(match is_dir {
+            true => FileType::Directory,
+            false => FileType::Regular,
+        }),
+        ino: InodeNumberField::new(ino),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        file_change::SortedFileChanges, message::FileChangeMessage, transfer::TransferJob,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_applied_changes_are_sent_on_as_watched() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::write(out_dir.path().join("edited.txt"), "edited")?;
+        fs::create_dir_all(out_dir.path().join("batch/nested"))?;
+        fs::create_dir(out_dir.path().join("moved"))?;
+        let roots = Roots::single(out_dir.path());
+
+        let applied = vec![
+            Applied::File("edited.txt".into()),
+            Applied::File("deleted.txt".into()),
+            Applied::Directory("batch".into()),
+            Applied::Directory("removed".into()),
+            Applied::Renamed("docs".into(), "moved".into()),
+        ];
+        let mut sorted = SortedFileChanges::from(changes(&roots, applied, &mut 0));
+        let mut jobs = vec![];
+        while let Some(job) = sorted.next_job() {
+            jobs.push(match job {
+                TransferJob::Ready(FileChangeMessage::Rename(from, to)) => {
+                    format!("rename {} {}", from.display(), to.display())
+                }
+                TransferJob::Ready(FileChangeMessage::FileDeleted(path)) => {
+                    format!("delete file {}", path.display())
+                }
+                TransferJob::Ready(FileChangeMessage::DirectoryDeleted(path)) => {
+                    format!("delete dir {}", path.display())
+                }
+                TransferJob::File(path) => format!("send file {}", path.display()),
+                TransferJob::Directory(path) => format!("send dir {}", path.display()),
+                job => panic!("unexpected job {:?}", job),
+            });
+        }
+        jobs.sort();
+        assert_eq!(
+            jobs,
+            [
+                "delete dir removed",
+                "delete file deleted.txt",
+                "rename docs moved",
+                "send dir batch",
+                "send file edited.txt",
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::sent_files::{Known, SentFiles};
+use crate::core::{
+    file_tree::{FileTree, FileTreeNodeType, ScanOptions},
+    message::MIN_DEDUP_SIZE,
+    roots::Roots,
+    transfer::TransferJob,
+};
+
+/// Turns the jobs of files identical to one already sent during the session into
+/// `TransferJob::FromHash`, for the receiver to copy. Hashes come from the trees sent to the
+/// receiver, so only the files it requests in reply are deduplicated. Directories holding such
+/// files are sent entry by entry instead of as an archive.
+#[derive(Debug)]
+pub struct Dedup {
+    files: SentFiles<Hashes>,
+}
+
+/// Hashes of the large files of the trees sent since the last batch of requests.
+#[derive(Debug, Default)]
+struct Hashes {
+    known: BTreeMap<PathBuf, [u8; 20]>,
+    /// How many of the `known` files have each hash.
+    copies: HashMap<[u8; 20], usize>,
+}
+
+impl Dedup {
+    pub fn new(roots: Arc<Roots>, scan: ScanOptions) -> Self {
+        Self {
+            files: SentFiles::new(roots, scan, Hashes::default()),
+        }
+    }
+
+    /// Remembers the hashes of the files of `tree`, which the receiver's requests will be about.
+    pub fn learn(&mut self, tree: &FileTree) {
+        for node in tree.iter() {
+            if let FileTreeNodeType::File {
+                size,
+                sha1: Some(sha1),
+            } = node.typ
+            {
+                if size >= MIN_DEDUP_SIZE {
+                    self.files.known.know(node.path.clone(), sha1);
+                }
+            }
+        }
+    }
+
+    /// Forgets the hashes learnt, once the requests they were for are scheduled: files changing
+    /// afterwards would not match them anymore.
+    pub fn forget_known(&mut self) {
+        self.files.known = Hashes::default();
+    }
+
+    /// Forgets the files sent, when the receiver could not copy one of them.
+    pub fn forget_sent(&mut self) {
+        self.files.forget_sent();
+    }
+
+    /// See `SentFiles::apply`.
+    pub fn dedupe(&mut self, job: TransferJob) -> Vec<TransferJob> {
+        self.files
+            .apply(job, &|path, sha1, source| TransferJob::FromHash {
+                path,
+                sha1,
+                source: source.to_owned(),
+            })
+    }
+}
+
+impl Hashes {
+    fn know(&mut self, path: PathBuf, sha1: [u8; 20]) {
+        self.take(&path);
+        self.known.insert(path, sha1);
+        *self.copies.entry(sha1).or_default() += 1;
+    }
+}
+
+impl Known for Hashes {
+    type Key = [u8; 20];
+
+    fn take(&mut self, path: &Path) -> Option<[u8; 20]> {
+        let sha1 = self.known.remove(path)?;
+        if let Some(copies) = self.copies.get_mut(&sha1) {
+            *copies -= 1;
+        }
+        Some(sha1)
+    }
+
+    /// Whether a file below `dir` has the contents of another one, sent or yet to be.
+    fn matches_within(&self, dir: &Path, sent: &HashMap<[u8; 20], PathBuf>) -> bool {
+        self.known
+            .range(dir.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(dir))
+            .any(|(_, sha1)| sent.contains_key(sha1) || self.copies[sha1] > 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::message::FileChangeMessage;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_dedupes_identical_files() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        let large = vec![7; MIN_DEDUP_SIZE as usize];
+        fs::create_dir_all(root.path().join("vendor/a"))?;
+        fs::create_dir_all(root.path().join("vendor/b"))?;
+        fs::write(root.path().join("vendor/a/lib.so"), &large)?;
+        fs::write(root.path().join("vendor/b/lib.so"), &large)?;
+        fs::write(root.path().join("build.so"), &large)?;
+        fs::write(root.path().join("small.txt"), "small")?;
+        fs::write(root.path().join("small-copy.txt"), "small")?;
+
+        let roots = Arc::new(Roots::single(root.path().to_owned()));
+        let mut dedup = Dedup::new(roots, ScanOptions::default());
+        dedup.learn(&FileTree::new(root.path()).await?);
+        let file = |path: &str| TransferJob::File(path.into());
+        let is_copy_of = |job: &TransferJob, of: &str| match job {
+            TransferJob::FromHash { source, .. } => source == Path::new(of),
+            _ => false,
+        };
+
+        assert!(matches!(
+            dedup.dedupe(file("vendor/a/lib.so"))[..],
+            [TransferJob::File(_)]
+        ));
+        let copy = dedup.dedupe(file("vendor/b/lib.so")).remove(0);
+        assert!(is_copy_of(&copy, "vendor/a/lib.so"));
+        assert_eq!(
+            copy.paths(),
+            [Path::new("vendor/b/lib.so"), Path::new("vendor/a/lib.so")]
+        );
+        assert!(matches!(
+            dedup.dedupe(file("small.txt"))[..],
+            [TransferJob::File(_)]
+        ));
+        assert!(matches!(
+            dedup.dedupe(file("small-copy.txt"))[..],
+            [TransferJob::File(_)]
+        ));
+
+        // Once the file sent is gone, the next copy is sent in full and copied from instead.
+        dedup.dedupe(TransferJob::Ready(FileChangeMessage::DirectoryDeleted(
+            "vendor/a".into(),
+        )));
+        assert!(matches!(
+            dedup.dedupe(file("build.so"))[..],
+            [TransferJob::File(_)]
+        ));
+
+        // Directories holding copies are unpacked so that they can be copied.
+        dedup.learn(&FileTree::new(root.path()).await?);
+        let jobs = dedup.dedupe(TransferJob::Directory("vendor".into()));
+        assert_eq!(jobs.len(), 5);
+        assert!(is_copy_of(&jobs[2], "build.so"));
+        assert!(is_copy_of(&jobs[4], "build.so"));
+
+        Ok(())
+    }
+}
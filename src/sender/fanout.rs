@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use tokio::time::Instant;
+
+use super::{
+    watcher::{SharedWatcher, Watcher},
+    Sender, SenderOptions,
+};
+use crate::core::{
+    file_change::FileChange,
+    file_tree::{FileTree, ScanOptions},
+    roots::Roots,
+};
+
+/// What senders syncing the same roots to several listeners share: a single watcher, and the
+/// scans of the sessions starting together, so that the roots are scanned, hashed and watched
+/// once rather than once per listener. Each sender keeps its own connection otherwise, reconnects
+/// on its own, and sends its listener whatever it is missing, however far behind the others.
+pub struct Fanout {
+    watcher: Option<SharedWatcher>,
+    /// The last scan, and when it started.
+    last_scan: tokio::sync::Mutex<Option<(Instant, FileTree)>>,
+}
+
+/// A session's share of a `Fanout`: the watcher it follows, and since when.
+pub struct Following {
+    since: Instant,
+    watcher: Option<Watcher>,
+}
+
+impl Following {
+    /// The watcher reporting what changed since the session started following the `Fanout`.
+    pub fn into_watcher(self) -> Option<Watcher> {
+        self.watcher
+    }
+}
+
+impl Fanout {
+    /// Starts watching the roots for the senders sharing it, in watch mode.
+    pub async fn new(roots: &Roots, options: &SenderOptions, watch: bool) -> anyhow::Result<Self> {
+        let watcher = match watch {
+            true => Some(
+                SharedWatcher::new(roots, options.scan, options.watcher, &options.watchman).await?,
+            ),
+            false => None,
+        };
+
+        Ok(Self {
+            watcher,
+            last_scan: Default::default(),
+        })
+    }
+
+    /// Shares a watcher reporting the changes fed to it with `report`, instead of watching the
+    /// roots, e.g. those a relay applied to them.
+    pub fn fed() -> Self {
+        Self {
+            watcher: Some(SharedWatcher::fed()),
+            last_scan: Default::default(),
+        }
+    }
+
+    /// Reports `changes` to the senders following a fed `Fanout`, as if they were watched.
+    pub fn report(&self, changes: Vec<FileChange>) {
+        if let Some(watcher) = &self.watcher {
+            watcher.report(changes);
+        }
+    }
+
+    /// Starts following the watcher, for a session about to scan the roots.
+    pub fn follow(&self) -> Following {
+        Following {
+            since: Instant::now(),
+            watcher: self.watcher.as_ref().map(SharedWatcher::follow),
+        }
+    }
+
+    /// A tree of the roots scanned after `following` started, so that the watcher reports every
+    /// change the tree misses: the last one if it is recent enough, a new one otherwise. Every
+    /// sender scans with the same options.
+    pub async fn tree(
+        &self,
+        roots: &Roots,
+        scan: ScanOptions,
+        following: &Following,
+    ) -> anyhow::Result<FileTree> {
+        let mut last_scan = self.last_scan.lock().await;
+        if let Some((started, tree)) = &*last_scan {
+            if *started >= following.since {
+                return Ok(tree.clone());
+            }
+        }
+
+        let started = Instant::now();
+        let tree = roots.tree(scan).await?;
+        *last_scan = Some((started, tree.clone()));
+        Ok(tree)
+    }
+}
+
+/// A sender's share of a `Fanout`. Its first session follows the `Fanout` from when the sender
+/// joined it, so that senders joining together before starting share their first scan.
+pub struct FanoutShare {
+    pub fanout: Arc<Fanout>,
+    first: Mutex<Option<Following>>,
+}
+
+impl FanoutShare {
+    pub fn new(fanout: Arc<Fanout>) -> Self {
+        let first = fanout.follow();
+        Self {
+            fanout,
+            first: Mutex::new(Some(first)),
+        }
+    }
+
+    pub fn follow(&self) -> Following {
+        match self.first.lock().unwrap().take() {
+            Some(first) => first,
+            None => self.fanout.follow(),
+        }
+    }
+}
+
+/// Syncs the roots to every listener, each with its own options, sharing scans and the watcher.
+/// A listener failing does not stop the others: the sync fails once they all stopped.
+pub async fn sync_all(
+    roots: Roots,
+    targets: &[(String, SenderOptions)],
+    watch: bool,
+) -> anyhow::Result<()> {
+    let Some((_, options)) = targets.first() else {
+        return Ok(());
+    };
+    let fanout = Arc::new(Fanout::new(&roots, options, watch).await?);
+    let senders: Vec<_> = targets
+        .iter()
+        .map(|(to, options)| {
+            let sender = Sender::new(roots.clone(), to, options.clone()).sharing(fanout.clone());
+            (to, sender)
+        })
+        .collect();
+
+    let results = futures::future::join_all(senders.iter().map(|(to, sender)| async move {
+        let res = sender.start(watch).await;
+        if let Err(err) = &res {
+            println!("Syncing to {} failed:\n{}", to, err);
+        }
+        res
+    }))
+    .await;
+
+    let failed = results.iter().filter(|res| res.is_err()).count();
+    if failed > 0 {
+        bail!(
+            "syncing to {} of {} listeners failed",
+            failed,
+            targets.len()
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_sessions_starting_together_share_scans() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("a.txt"), "a")?;
+        let roots = Roots::single(root.path());
+        let fanout = Fanout::new(&roots, &SenderOptions::default(), false).await?;
+        let scan = ScanOptions::default();
+        let has =
+            |tree: &FileTree, path: &str| tree.iter().any(|node| node.path == Path::new(path));
+
+        let (first, second) = (fanout.follow(), fanout.follow());
+        assert!(has(&fanout.tree(&roots, scan, &first).await?, "a.txt"));
+        fs::write(root.path().join("b.txt"), "b")?;
+        // Scanned after the second session started following, so the watcher reports b.txt.
+        assert!(!has(&fanout.tree(&roots, scan, &second).await?, "b.txt"));
+        // A session starting later needs a scan of its own.
+        let third = fanout.follow();
+        assert!(has(&fanout.tree(&roots, scan, &third).await?, "b.txt"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::sent_files::{Known, SentFiles};
+use crate::core::{
+    file_tree::{FileTree, ScanOptions},
+    roots::Roots,
+    transfer::TransferJob,
+};
+
+/// Turns the jobs of files hard linked to one already sent during the session into
+/// `TransferJob::Hardlink`, for the receiver to link instead of writing a copy. Links come from
+/// the trees sent to the receiver, so only the files it requests in reply are linked. Directories
+/// holding linked files are sent entry by entry instead of as an archive.
+#[derive(Debug)]
+pub struct Hardlinks {
+    files: SentFiles<Links>,
+}
+
+/// The linked files of the trees sent since the last batch of requests, with the first path of
+/// the file they are a link to.
+#[derive(Debug, Default)]
+struct Links(BTreeMap<PathBuf, PathBuf>);
+
+impl Hardlinks {
+    pub fn new(roots: Arc<Roots>, scan: ScanOptions) -> Self {
+        Self {
+            files: SentFiles::new(roots, scan, Links::default()),
+        }
+    }
+
+    /// Remembers the hard links of `tree`, which the receiver's requests will be about.
+    pub fn learn(&mut self, tree: &FileTree) {
+        let Links(known) = &mut self.files.known;
+        for (link, target) in tree.links() {
+            known.insert(target.to_owned(), target.to_owned());
+            known.insert(link.to_owned(), target.to_owned());
+        }
+    }
+
+    /// Forgets the links learnt, once the requests they were for are scheduled: files linked or
+    /// unlinked afterwards would not match them anymore.
+    pub fn forget_known(&mut self) {
+        self.files.known = Links::default();
+    }
+
+    /// Forgets the files sent, when the receiver could not link to one of them.
+    pub fn forget_sent(&mut self) {
+        self.files.forget_sent();
+    }
+
+    /// See `SentFiles::apply`.
+    pub fn link(&mut self, job: TransferJob) -> Vec<TransferJob> {
+        self.files
+            .apply(job, &|path, _, target| TransferJob::Hardlink {
+                path,
+                target: target.to_owned(),
+            })
+    }
+}
+
+impl Known for Links {
+    /// The first path of the file linked to.
+    type Key = PathBuf;
+
+    fn take(&mut self, path: &Path) -> Option<PathBuf> {
+        self.0.remove(path)
+    }
+
+    /// Whether a file below `dir` is linked to another one, sent or yet to be.
+    fn matches_within(&self, dir: &Path, _: &HashMap<PathBuf, PathBuf>) -> bool {
+        self.0
+            .range(dir.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(dir))
+            .next()
+            .is_some()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::core::message::FileChangeMessage;
+    use std::fs;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_links_files_to_the_first_one_sent() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join("store"))?;
+        fs::create_dir_all(root.path().join("snapshot"))?;
+        fs::write(root.path().join("store/lib.so"), "lib")?;
+        fs::hard_link(
+            root.path().join("store/lib.so"),
+            root.path().join("snapshot/lib.so"),
+        )?;
+        fs::hard_link(root.path().join("store/lib.so"), root.path().join("lib.so"))?;
+        fs::write(root.path().join("snapshot/other.so"), "lib")?;
+
+        let roots = Arc::new(Roots::single(root.path().to_owned()));
+        let mut links = Hardlinks::new(roots, ScanOptions::default());
+        let tree = FileTree::new(root.path()).await?;
+        assert_eq!(
+            tree.link_target(Path::new("store/lib.so")),
+            Some(Path::new("lib.so"))
+        );
+        links.learn(&tree);
+        let file = |path: &str| TransferJob::File(path.into());
+        let is_link_to = |job: &TransferJob, to: &str| match job {
+            TransferJob::Hardlink { target, .. } => target == Path::new(to),
+            _ => false,
+        };
+
+        assert!(matches!(
+            links.link(file("snapshot/lib.so"))[..],
+            [TransferJob::File(_)]
+        ));
+        let link = links.link(file("store/lib.so")).remove(0);
+        assert!(is_link_to(&link, "snapshot/lib.so"));
+        assert_eq!(
+            link.paths(),
+            [Path::new("store/lib.so"), Path::new("snapshot/lib.so")]
+        );
+        assert!(matches!(
+            links.link(file("snapshot/other.so"))[..],
+            [TransferJob::File(_)]
+        ));
+
+        // Once the file sent is gone, the next link is sent in full and linked to instead.
+        links.link(TransferJob::Ready(FileChangeMessage::DirectoryDeleted(
+            "snapshot".into(),
+        )));
+        assert!(matches!(
+            links.link(file("lib.so"))[..],
+            [TransferJob::File(_)]
+        ));
+
+        // Directories holding links are unpacked so that they can be linked.
+        links.learn(&FileTree::new(root.path()).await?);
+        let jobs = links.link(TransferJob::Directory("snapshot".into()));
+        assert_eq!(jobs.len(), 3);
+        assert!(is_link_to(&jobs[1], "lib.so"));
+        assert!(matches!(&jobs[2], TransferJob::File(path) if path.ends_with("other.so")));
+
+        Ok(())
+    }
+}
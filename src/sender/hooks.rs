@@ -0,0 +1,87 @@
+use anyhow::anyhow;
+
+use crate::core::utils::shell_command;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SyncHookEvent {
+    /// About to scan and send the initial tree.
+    PreSync,
+    /// The initial transfer was sent.
+    Synced,
+    /// Watch mode was interrupted.
+    Shutdown,
+}
+
+impl SyncHookEvent {
+    fn name(self) -> &'static str {
+        match self {
+            SyncHookEvent::PreSync => "pre-sync",
+            SyncHookEvent::Synced => "sync",
+            SyncHookEvent::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Shell commands run around the initial transfer, e.g. to run formatters or generate artifacts
+/// before syncing, or to notify someone afterwards. Hooks run to completion before the sync goes
+/// on.
+#[derive(Debug, Default, Clone)]
+pub struct SyncHooks {
+    pub pre_sync: Option<String>,
+    /// Run after the initial transfer and on graceful shutdown.
+    pub post_sync: Option<String>,
+    /// Fail the sync when a hook fails, instead of warning.
+    pub abort_on_failure: bool,
+}
+
+impl SyncHooks {
+    pub async fn run(&self, event: SyncHookEvent) -> anyhow::Result<()> {
+        let command = match event {
+            SyncHookEvent::PreSync => &self.pre_sync,
+            SyncHookEvent::Synced | SyncHookEvent::Shutdown => &self.post_sync,
+        };
+        let Some(command) = command else {
+            return Ok(());
+        };
+
+        let status = shell_command(command)
+            .env("CAIMAN_EVENT", event.name())
+            .status()
+            .await;
+        let err = match status {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => anyhow!("{} hook {:?} failed with {}", event.name(), command, status),
+            Err(err) => anyhow!("could not run {} hook {:?}: {}", event.name(), command, err),
+        };
+
+        match self.abort_on_failure {
+            true => Err(err),
+            false => {
+                eprintln!("WARNING: {}", err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::test;
+
+    #[cfg(unix)]
+    #[test]
+    async fn test_failing_hooks_only_abort_when_asked_to() {
+        let mut hooks = SyncHooks {
+            pre_sync: Some("test \"$CAIMAN_EVENT\" = pre-sync".into()),
+            post_sync: Some("exit 3".into()),
+            abort_on_failure: false,
+        };
+        assert!(hooks.run(SyncHookEvent::PreSync).await.is_ok());
+        assert!(hooks.run(SyncHookEvent::Synced).await.is_ok());
+
+        hooks.abort_on_failure = true;
+        assert!(hooks.run(SyncHookEvent::PreSync).await.is_ok());
+        assert!(hooks.run(SyncHookEvent::Shutdown).await.is_err());
+    }
+}
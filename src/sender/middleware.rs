@@ -0,0 +1,52 @@
+use std::fmt::Debug;
+
+use crate::core::{message::SyncMessage, transfer::TransferJob};
+
+/// A stage in the sender's change pipeline. Jobs go through `on_job` before their payload is
+/// read, messages through `on_message` once it is loaded; returning `None` drops the change.
+pub trait Middleware: Send + Sync {
+    fn on_job(&self, job: TransferJob) -> Option<TransferJob> {
+        Some(job)
+    }
+
+    fn on_message(&self, message: SyncMessage) -> Option<SyncMessage> {
+        Some(message)
+    }
+}
+
+/// Runs middleware stages in the order they were added.
+#[derive(Default)]
+pub struct MiddlewareChain {
+    stages: Vec<Box<dyn Middleware>>,
+}
+
+impl Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MiddlewareChain")
+            .field("stages", &self.stages.len())
+            .finish()
+    }
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, stage: impl Middleware + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn on_job(&self, job: TransferJob) -> Option<TransferJob> {
+        self.stages
+            .iter()
+            .try_fold(job, |job, stage| stage.on_job(job))
+    }
+
+    pub fn on_message(&self, message: SyncMessage) -> Option<SyncMessage> {
+        self.stages
+            .iter()
+            .try_fold(message, |message, stage| stage.on_message(message))
+    }
+}
@@ -1,136 +1,1125 @@
+mod dedup;
+mod fanout;
+mod hardlinks;
+pub mod hooks;
+pub mod middleware;
+mod native_watcher;
+mod notify;
+mod outbox;
+mod queue;
+mod scheduler;
+mod sent_files;
+#[cfg(feature = "tui")]
+mod tui;
 mod watcher;
 
 use anyhow::{anyhow, bail, Context};
-use bytes::Bytes;
-use futures::stream::{SplitSink, StreamExt};
+use bytesize::ByteSize;
+use futures::stream::{SplitSink, SplitStream, StreamExt};
 use futures::SinkExt;
-use std::path::Path;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::{client_async, WebSocketStream};
 use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{header::AUTHORIZATION, HeaderName, HeaderValue, Uri};
 use tungstenite::Message;
 
-use crate::core::compression::compress_dir;
+use crate::core::activity::Activity;
+use crate::core::control::{Command, ControlAddr, ControlEvent, ControlServer, Controls};
+use crate::core::events::{Event, EventStream};
 use crate::core::file_change::{FileChange, SortedFileChanges};
-use crate::core::file_tree::FileTree;
-use crate::core::message::{FileChangeMessage, RequestMessage};
+use crate::core::file_tree::{root_checksum, FileTree, ScanOptions};
+use crate::core::file_tree_diff::TreeDiff;
+use crate::core::identity::Identities;
+use crate::core::keepalive::{DeadConnection, Keepalive, KeepaliveConfig};
+use crate::core::message::{
+    FileChangeMessage, Handshake, ReceiverMessage, Rejection, RequestMessage, SenderMessage,
+    SyncMessage, TreePage, CHECKSUMS_HEADER, DEDUP_HEADER, FILE_BATCH_HEADER, GZIP_ARCHIVES_HEADER,
+    HARDLINKS_HEADER, MAX_MESSAGE_SIZE_HEADER, OUT_DIR_HEADER, PROTOCOL_HEADER, PROTOCOL_VERSION,
+    QUICK_CHECK_HEADER, SPARSE_FILES_HEADER, SPECIALS_HEADER, SUBTREE_HASHES_HEADER,
+    TREE_PAGES_HEADER, TREE_PAGE_SIZE,
+};
+use crate::core::overlap::DirIdentity;
+use crate::core::policy::PolicyTable;
+use crate::core::profile;
+use crate::core::proxy::Proxy;
+use crate::core::roots::Roots;
+use crate::core::specials::SkippedSpecial;
+use crate::core::summary::Transfer;
+use crate::core::timeout::{with_timeout, TimedOut};
+use crate::core::tls::ClientTls;
+use crate::core::transfer::TransferJob;
+use crate::core::transport::{BoxedTransport, Loopback};
+use crate::core::utils::quoted;
+use crate::core::webhook::{WebhookEvent, Webhooks};
+pub use fanout::{sync_all, Fanout};
+use fanout::{FanoutShare, Following};
+use hooks::{SyncHookEvent, SyncHooks};
+use middleware::MiddlewareChain;
+use outbox::Outbox;
+use queue::ChangeQueue;
+use scheduler::TransferScheduler;
+pub use watcher::WatchBackend;
+pub use watcher::WatchmanOptions;
+use watcher::{Debouncer, UserSignal, UserSignals, WatchClocks, WatchEvent, Watcher};
 
-pub struct Sender<'command, P: AsRef<Path>> {
-    listener_addr: &'command str,
-    dir_path: P,
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Files still being written are tried this many more times, each waiting for them to settle,
+/// before being left for the next change to them.
+const MAX_SETTLE_RETRIES: usize = 5;
+
+/// Files the listener received corrupted are sent again at most this many times per session.
+const MAX_CHECKSUM_RETRIES: usize = 3;
+
+type WsStream = WebSocketStream<BoxedTransport>;
+type WsSink = SplitSink<WsStream, Message>;
+type WsSource = SplitStream<WsStream>;
+
+#[derive(Debug, Clone)]
+pub struct SenderOptions {
+    pub keepalive: KeepaliveConfig,
+    pub reconnect: bool,
+    pub timeout: Duration,
+    pub stall_warning: Duration,
+    pub jobs: usize,
+    pub checksum_interval: Option<Duration>,
+    pub verify_interval: Option<Duration>,
+    pub debounce: Duration,
+    /// Only send files whose size and mtime stay the same for this long, see
+    /// `TransferJob::settled`.
+    pub stability_window: Option<Duration>,
+    /// Where watch mode learns about changes from.
+    pub watcher: WatchBackend,
+    /// How watchman subscriptions hold back changes.
+    pub watchman: WatchmanOptions,
+    pub max_file_size: ByteSize,
+    /// Files smaller than this are packed together, for listeners accepting `FileBatch`.
+    pub batch_threshold: ByteSize,
+    /// Bounds the payloads read, compressed and queued for sending at once.
+    pub memory_limit: Option<ByteSize>,
+    pub scan: ScanOptions,
+    /// Quick check the initial tree, see `ScanOptions::quick_check`, with listeners accepting
+    /// `TimedFile` nodes.
+    pub quick_check: bool,
+    pub policies: PolicyTable,
+    pub middleware: Arc<MiddlewareChain>,
+    /// Presented to listeners serving several tenants, to pick which one to sync into.
+    pub key: Option<String>,
+    pub hooks: SyncHooks,
+    /// Serve `control::Command`s on this address.
+    pub control: Option<ControlAddr>,
+    /// Print the initial sync's summary as JSON.
+    pub json_summary: bool,
+    pub webhooks: Webhooks,
+    /// Pop desktop notifications when the connection drops, the sync fails or a large initial
+    /// sync completes.
+    pub notify: bool,
+    /// Report the session's events here.
+    pub events: Option<Arc<EventStream>>,
+    /// How to verify `wss://` listeners, and the client certificate to present them.
+    pub tls: Option<ClientTls>,
+    /// Authenticate the listener, and to it, with this identity.
+    pub identities: Option<Arc<Identities>>,
+    /// Reach the listener through this proxy.
+    pub proxy: Option<Proxy>,
+    /// Added to the websocket handshake's request, e.g. for a reverse proxy in front of the
+    /// listener.
+    pub headers: Vec<RequestHeader>,
+    /// Save watchman's clock of every root here once the changes up to it are sent, for the next
+    /// run to pick up from.
+    pub watch_clock: Option<PathBuf>,
+    /// Journal the watched changes not sent yet here, for the next session to send them, see
+    /// `ChangeQueue`.
+    pub queue: Option<PathBuf>,
+}
+
+impl Default for SenderOptions {
+    fn default() -> Self {
+        Self {
+            keepalive: KeepaliveConfig {
+                interval: Duration::from_secs(15),
+                timeout: Duration::from_secs(45),
+            },
+            reconnect: false,
+            timeout: Duration::from_secs(30),
+            stall_warning: Duration::from_secs(5),
+            jobs: 8,
+            checksum_interval: None,
+            verify_interval: None,
+            debounce: Duration::ZERO,
+            stability_window: None,
+            watcher: WatchBackend::default(),
+            watchman: Default::default(),
+            max_file_size: ByteSize::gib(1),
+            batch_threshold: ByteSize::kib(16),
+            memory_limit: None,
+            scan: ScanOptions::default(),
+            quick_check: false,
+            policies: PolicyTable::default(),
+            middleware: Default::default(),
+            key: None,
+            hooks: SyncHooks::default(),
+            control: None,
+            json_summary: false,
+            webhooks: Webhooks::default(),
+            notify: false,
+            events: None,
+            tls: None,
+            identities: None,
+            proxy: None,
+            headers: vec![],
+            watch_clock: None,
+            queue: None,
+        }
+    }
+}
+
+/// A header of the websocket handshake's request, as `<name>: <value>`.
+#[derive(Debug, Clone)]
+pub struct RequestHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl FromStr for RequestHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <name>: <value>, got '{}'", s))?;
+        let name = name
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid header name in '{}'", s))?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid header value in '{}'", s))?;
+
+        Ok(Self { name, value })
+    }
+}
+
+/// What the listener tells about itself in the handshake's response.
+struct Advertised {
+    /// The largest message it accepts, older listeners do not say.
+    max_message_size: Option<u64>,
+    /// Whether it accepts `FileChangeMessage::FileFromHash` changes.
+    dedup: bool,
+    /// Whether it accepts `FileChangeMessage::HardlinkCreated` changes.
+    hardlinks: bool,
+    /// Whether it accepts `FileChangeMessage::FileBatch` changes.
+    file_batch: bool,
+    /// Whether it accepts `FileChangeMessage::GzippedDirectoryCreated` changes.
+    gzip_archives: bool,
+    /// Whether it accepts `Handshake::PagedSync`.
+    tree_pages: bool,
+    /// Whether it accepts `FileTreeNodeType::HashedDir` nodes.
+    subtree_hashes: bool,
+    /// Whether it accepts `FileTreeNodeType::TimedFile` nodes.
+    quick_check: bool,
+    /// Whether it recreates the holes left out of the chunks of sparse files.
+    sparse_files: bool,
+    /// Whether it accepts `FileTreeNodeType::Fifo` nodes and `FileChangeMessage::FifoCreated`.
+    specials: bool,
+    /// Whether it verifies `FileChangeMessage::Checksummed` changes.
+    checksums: bool,
+    /// Where it writes, older listeners and those on other platforms do not say.
+    out_dir: Option<DirIdentity>,
+}
+
+/// Where the listener is: at a websocket address, or in this same process.
+enum Listener<'command> {
+    Remote(&'command str),
+    Loopback(Loopback),
+}
+
+/// What a sender is doing, as reported by its control endpoint.
+#[derive(Debug, Default, Serialize)]
+struct SyncStatus {
+    state: &'static str,
+    /// Watched changes held back while paused.
+    held_changes: usize,
+    batches_sent: u64,
+}
+
+pub struct Sender<'command> {
+    listener: Listener<'command>,
+    roots: Arc<Roots>,
+    options: SenderOptions,
+    controls: Arc<Controls>,
+    status: Arc<Mutex<SyncStatus>>,
+    activity: Arc<Mutex<Activity>>,
+    /// The clocks of the changes sent so far, `None` until the first session loads the saved ones.
+    watch_clocks: Mutex<Option<WatchClocks>>,
+    queue: Option<ChangeQueue>,
+    /// The scans and the watcher shared with the senders syncing to other listeners.
+    fanout: Option<FanoutShare>,
 }
 
-impl<'command, P: AsRef<Path>> Sender<'command, P> {
-    pub fn new(dir_path: P, listener_addr: &'command str) -> Self {
+impl<'command> Sender<'command> {
+    pub fn new(roots: Roots, listener_addr: &'command str, options: SenderOptions) -> Self {
+        Self {
+            listener: Listener::Remote(listener_addr),
+            roots: Arc::new(roots),
+            queue: options.queue.clone().map(ChangeQueue::new),
+            options,
+            controls: Default::default(),
+            status: Default::default(),
+            activity: Default::default(),
+            watch_clocks: Default::default(),
+            fanout: None,
+        }
+    }
+
+    /// Syncs to a receiver serving the other end of `loopback` in the same process.
+    pub fn with_loopback(roots: Roots, loopback: Loopback, options: SenderOptions) -> Self {
         Self {
-            listener_addr,
-            dir_path,
+            listener: Listener::Loopback(loopback),
+            roots: Arc::new(roots),
+            queue: options.queue.clone().map(ChangeQueue::new),
+            options,
+            controls: Default::default(),
+            status: Default::default(),
+            activity: Default::default(),
+            watch_clocks: Default::default(),
+            fanout: None,
         }
     }
 
+    /// Shares scans and the watcher with the other senders of `fanout`, see `Fanout`.
+    pub fn sharing(mut self, fanout: Arc<Fanout>) -> Self {
+        self.fanout = Some(FanoutShare::new(fanout));
+        self
+    }
+
+    /// What the sender is connected to and has done so far.
+    pub fn activity(&self) -> Activity {
+        self.activity.lock().unwrap().clone()
+    }
+
+    #[cfg(not(feature = "tui"))]
+    pub async fn start_tui(&self, _watch: bool) -> anyhow::Result<()> {
+        bail!("this build has no TUI support")
+    }
+
     pub async fn start(&self, watch: bool) -> anyhow::Result<()> {
-        let tree = FileTree::new(&self.dir_path).await?;
-        let request = self.listener_addr.into_client_request()?;
-        let (stream, _response) = connect_async(request).await?;
-        let (mut write, mut read) = stream.split();
+        let _control = self.spawn_control().await?;
+        loop {
+            let res = self.run_session(watch).await;
+            self.session_ended(&res).await;
+            match res {
+                Err(err) if self.options.reconnect && is_connection_error(&err) => {
+                    eprintln!(
+                        "Connection lost: {}\nReconnecting in {}",
+                        err,
+                        humantime::format_duration(RECONNECT_DELAY)
+                    );
+                    self.set_state("reconnecting");
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => (),
+                        _ = self.controls.shutdown_requested() => break Ok(()),
+                    }
+                }
+                res => break res,
+            }
+        }
+    }
 
-        let encoded = bincode::serialize(&tree)?;
-        println!("Sending initial directory state");
-        write.send(Message::Binary(encoded)).await?;
-        println!("Initial state sent, starting sync");
+    async fn spawn_control(&self) -> anyhow::Result<Option<ControlServer>> {
+        let Some(addr) = self.options.control.clone() else {
+            return Ok(None);
+        };
 
-        let files_req = read
-            .next()
-            .await
+        let listener = match &self.listener {
+            Listener::Remote(addr) => addr.to_string(),
+            Listener::Loopback(_) => "loopback".to_string(),
+        };
+        let sync_status = self.status.clone();
+        let activity = self.activity.clone();
+        let status = move || {
+            let mut status = serde_json::json!(*sync_status.lock().unwrap());
+            status["role"] = "sender".into();
+            status["listener"] = listener.clone().into();
+            status["activity"] = serde_json::json!(*activity.lock().unwrap());
+            status
+        };
+
+        Ok(Some(
+            ControlServer::spawn(addr, self.controls.clone(), status).await?,
+        ))
+    }
+
+    fn set_state(&self, state: &'static str) {
+        self.status.lock().unwrap().state = state;
+    }
+
+    async fn session_ended(&self, res: &anyhow::Result<()>) {
+        let (was_connected, error) = {
+            let mut activity = self.activity.lock().unwrap();
+            let was_connected = activity.connected;
+            activity.disconnected();
+            let error = res.as_ref().err().map(|err| format!("{:#}", err));
+            if let Some(error) = &error {
+                activity.error(error);
+            }
+            (was_connected, error)
+        };
+
+        if let Some(error) = &error {
+            self.emit(Event::Error {
+                error: error.clone(),
+            });
+        }
+        if was_connected {
+            self.emit(Event::Disconnected);
+        }
+        if let Some(error) = &error {
+            if self.options.notify {
+                match res.as_ref().is_err_and(is_connection_error) {
+                    true if was_connected => notify::show("Connection lost", error).await,
+                    true => (),
+                    false => notify::show("Sync failed", error).await,
+                }
+            }
+            self.post_webhook(WebhookEvent::Error(error.clone())).await;
+        }
+        if was_connected {
+            self.post_webhook(WebhookEvent::Disconnected).await;
+        }
+    }
+
+    async fn post_webhook(&self, event: WebhookEvent) {
+        let peer = self.activity.lock().unwrap().peer.clone();
+        self.options.webhooks.post(event, "sender", peer).await;
+    }
+
+    fn set_pending(&self, update: impl FnOnce(usize) -> usize) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.files_pending = update(activity.files_pending);
+    }
+
+    /// Records that one of the pending changes was sent.
+    fn sent(&self, message: &SenderMessage) {
+        {
+            let mut activity = self.activity.lock().unwrap();
+            let files = match message {
+                SenderMessage::Sync(SyncMessage {
+                    change: FileChangeMessage::FileBatch(_, packed),
+                    ..
+                }) => packed.files as usize,
+                _ => 1,
+            };
+            activity.files_pending = activity.files_pending.saturating_sub(files);
+            if let SenderMessage::Sync(message) = message {
+                activity.changed(&message.change);
+            }
+        }
+
+        if let SenderMessage::Sync(message) = message {
+            self.emit(Event::FileSynced {
+                change: message.change.label().to_owned(),
+                path: message.change.path().to_owned(),
+            });
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.options.events {
+            events.emit(&event);
+        }
+    }
+
+    /// Records that a batch of changes was sent.
+    fn batch_sent(&self) {
+        self.status.lock().unwrap().batches_sent += 1;
+        let mut activity = self.activity.lock().unwrap();
+        activity.files_pending = 0;
+        activity.synced();
+    }
+
+    /// Compares the local tree with the listener's without modifying either, printing the
+    /// differences. Returns whether the trees are identical.
+    pub async fn verify(&self) -> anyhow::Result<bool> {
+        let local_tree = self.roots.tree(self.options.scan).await?;
+        let (mut write, mut read, _) = self.connect(false).await?;
+
+        let encoded = bincode::serialize(&Handshake::Verify {
+            dests: self.roots.dests(),
+        })?;
+        self.send(&mut write, Message::Binary(encoded)).await?;
+
+        let ReceiverMessage::Tree(remote_tree) = self
+            .read_reply(&mut read, "waiting for the listener's directory state")
+            .await?
+        else {
+            bail!("incorrect reply received, expected the listener's directory state")
+        };
+        if !remote_tree.is_valid() {
+            bail!("invalid file tree received, aborting")
+        }
+
+        with_timeout(
+            self.options.timeout,
+            "closing the connection",
+            write.close(),
+        )
+        .await??;
+
+        let diff = TreeDiff::from(&remote_tree, &local_tree);
+        if diff.is_empty() {
+            println!("Directories are in sync");
+            return Ok(true);
+        }
+
+        println!("Directories differ\n{}", &diff);
+        Ok(false)
+    }
+
+    /// Connects to the listener, returning what it advertises. `quick_check` tells it the tree
+    /// about to be sent has `TimedFile` nodes, for it to scan its own alike.
+    async fn connect(&self, quick_check: bool) -> anyhow::Result<(WsSink, WsSource, Advertised)> {
+        let listener_addr = match &self.listener {
+            Listener::Remote(addr) => addr,
+            Listener::Loopback(_) => "ws://loopback",
+        };
+        let mut request = listener_addr.into_client_request()?;
+        if let Some(key) = &self.options.key {
+            let bearer = format!("Bearer {}", key).parse()?;
+            request.headers_mut().insert(AUTHORIZATION, bearer);
+        }
+        request
+            .headers_mut()
+            .insert(PROTOCOL_HEADER, HeaderValue::from(PROTOCOL_VERSION));
+        if quick_check {
+            request
+                .headers_mut()
+                .insert(QUICK_CHECK_HEADER, HeaderValue::from_static("1"));
+        }
+        // Appended, so that listeners still read the key from the first `Authorization` header.
+        for header in &self.options.headers {
+            request
+                .headers_mut()
+                .append(header.name.clone(), header.value.clone());
+        }
+        let name = request.uri().authority().map(ToString::to_string);
+        let exchange = match (&self.listener, &self.options.identities) {
+            (Listener::Remote(_), Some(identities)) => {
+                Some(identities.request(request.headers_mut()))
+            }
+            _ => None,
+        };
+
+        let connect = async {
+            let (stream, peer): (BoxedTransport, _) = match &self.listener {
+                Listener::Remote(_) => {
+                    connect_tcp(
+                        request.uri(),
+                        self.options.tls.as_ref(),
+                        self.options.proxy.as_ref(),
+                    )
+                    .await?
+                }
+                Listener::Loopback(loopback) => (loopback.connect()?, "loopback".to_string()),
+            };
+            anyhow::Ok((client_async(request, stream).await?, peer))
+        };
+        let ((mut stream, response), peer) =
+            with_timeout(self.options.timeout, "connecting to the listener", connect).await??;
+        let version = response.headers().get(PROTOCOL_HEADER);
+        if version.is_none_or(|version| version != PROTOCOL_VERSION.to_string().as_str()) {
+            let version = version.map(|version| String::from_utf8_lossy(version.as_bytes()));
+            bail!(
+                "the listener speaks protocol version {}, expected {}: run the same release on both ends",
+                version.as_deref().unwrap_or("none"),
+                PROTOCOL_VERSION
+            )
+        }
+        if let Some(exchange) = exchange {
+            let name = name.unwrap_or_default();
+            let signature = exchange.complete(&name, response.headers())?;
+            let send = stream.send(Message::binary(signature));
+            with_timeout(self.options.timeout, "authenticating to the listener", send).await??;
+        }
+        self.emit(Event::Connected { peer: peer.clone() });
+        self.activity.lock().unwrap().connected(peer);
+
+        let advertised = Advertised {
+            max_message_size: response
+                .headers()
+                .get(MAX_MESSAGE_SIZE_HEADER)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+            dedup: response.headers().contains_key(DEDUP_HEADER),
+            hardlinks: response.headers().contains_key(HARDLINKS_HEADER),
+            file_batch: response.headers().contains_key(FILE_BATCH_HEADER),
+            gzip_archives: response.headers().contains_key(GZIP_ARCHIVES_HEADER),
+            tree_pages: response.headers().contains_key(TREE_PAGES_HEADER),
+            subtree_hashes: response.headers().contains_key(SUBTREE_HASHES_HEADER),
+            quick_check: response.headers().contains_key(QUICK_CHECK_HEADER),
+            sparse_files: response.headers().contains_key(SPARSE_FILES_HEADER),
+            specials: response.headers().contains_key(SPECIALS_HEADER),
+            checksums: response.headers().contains_key(CHECKSUMS_HEADER),
+            out_dir: response
+                .headers()
+                .get(OUT_DIR_HEADER)
+                .and_then(|value| DirIdentity::from_header(value.to_str().ok()?)),
+        };
+        // Checked before anything is sent: a listener writing into a watched directory would be
+        // sent its own writes back, forever.
+        if let Some(out_dir) = &advertised.out_dir {
+            for root in self.roots.iter() {
+                if let Some(overlap) = out_dir.overlap(&root.path, self.options.scan) {
+                    bail!("{}", overlap.describe(&root.path));
+                }
+            }
+        }
+        let (write, read) = stream.split();
+        Ok((write, read, advertised))
+    }
+
+    async fn read_reply(
+        &self,
+        read: &mut WsSource,
+        operation: &'static str,
+    ) -> anyhow::Result<ReceiverMessage> {
+        with_timeout(self.options.timeout, operation, read.next())
+            .await?
             .ok_or(anyhow!("unexpected end of stream"))?
-            .map(|req| {
-                if let Message::Binary(files_req) = req {
-                    bincode::deserialize::<Vec<RequestMessage>>(&files_req)
-                        .context("deserializing the initial files request")
-                } else {
-                    bail!("incorrect file request received, expected binary message")
+            .map(|reply| match reply {
+                Message::Binary(reply) => bincode::deserialize::<ReceiverMessage>(&reply)
+                    .context("deserializing the listener's reply"),
+                Message::Close(Some(frame)) => {
+                    bail!("listener refused the session: {}", frame.reason)
                 }
-            })??;
+                _ => bail!("incorrect reply received, expected binary message"),
+            })?
+    }
 
-        self.handle_files_req(&mut write, files_req).await;
+    async fn run_session(&self, watch: bool) -> anyhow::Result<()> {
+        self.set_state("syncing");
+        let mut transfer = Transfer::start();
+        self.options.hooks.run(SyncHookEvent::PreSync).await?;
+        let scan = ScanOptions {
+            quick_check: self.options.quick_check,
+            ..self.options.scan
+        };
+        // Taken before the tree, so that the changes made while it is synced are sent after it.
+        let following = self.fanout.as_ref().map(FanoutShare::follow);
+        let since = match watch && self.options.watcher == WatchBackend::Watchman {
+            true if following.is_none() => self.watch_since().await?,
+            _ => WatchClocks::new(),
+        };
+        let mut tree = match (&self.fanout, &following) {
+            (Some(share), Some(following)) => {
+                share.fanout.tree(&self.roots, scan, following).await?
+            }
+            _ => self.roots.tree(scan).await?,
+        };
+        let (mut write, mut read, advertised) = self.connect(scan.quick_check).await?;
+        if scan.fifos && !advertised.specials {
+            bail!("the listener does not recreate FIFOs, start it with --specials too or sync without it")
+        }
+        // Listeners not accepting `TimedFile`s are sent hashes.
+        if scan.quick_check && !advertised.quick_check {
+            tree = self.roots.tree(self.options.scan).await?;
+        }
+        self.post_webhook(WebhookEvent::SyncStarted).await;
+        self.warn_specials(tree.specials().collect());
+
+        let mut scheduler = TransferScheduler::new(
+            self.roots.clone(),
+            &self.options,
+            advertised.max_message_size,
+        );
+        if advertised.dedup {
+            scheduler = scheduler.deduplicating();
+        }
+        if advertised.hardlinks {
+            scheduler = scheduler.linking();
+        }
+        if advertised.file_batch {
+            scheduler = scheduler.batching(self.options.batch_threshold.as_u64());
+        }
+        if !advertised.gzip_archives {
+            scheduler = scheduler.without_gzipped_archives();
+        }
+        if advertised.sparse_files {
+            scheduler = scheduler.preserving_holes();
+        }
+        if advertised.checksums {
+            scheduler = scheduler.checksumming();
+        }
+        scheduler.learn(&tree);
+        let tree = match advertised.subtree_hashes {
+            true => tree.with_subtree_hashes(),
+            false => tree,
+        };
+        println!("Sending initial directory state");
+        match advertised.tree_pages {
+            true => self.send_tree_pages(&mut write, tree).await?,
+            false => {
+                let encoded = bincode::serialize(&Handshake::Sync {
+                    dests: self.roots.dests(),
+                    tree,
+                })?;
+                self.send(&mut write, Message::Binary(encoded)).await?;
+            }
+        }
+        println!("Initial state sent, starting sync");
+
+        let files_req = self
+            .read_reply(&mut read, "waiting for the initial files request")
+            .await?;
+
+        let ReceiverMessage::Requests(files_req) = files_req else {
+            bail!("incorrect file request received, expected requested files")
+        };
+
+        let outbox = Outbox::new(write, &self.options, self.activity.clone());
+        self.handle_files_req(&outbox, &mut scheduler, files_req, Some(&mut transfer))
+            .await?;
         println!("Initial sync completed");
+        let summary = transfer.finish();
+        summary.print(self.options.json_summary);
+        if self.options.notify && notify::is_large(&summary) {
+            notify::show("Initial sync completed", &notify::describe(&summary)).await;
+        }
+        self.post_webhook(WebhookEvent::SyncCompleted(summary))
+            .await;
+        self.options.hooks.run(SyncHookEvent::Synced).await?;
+        self.send_queued(&outbox, &mut scheduler).await?;
 
         if watch {
             println!("Watching for changes");
-            self.watch_dir(&mut write).await?;
-        } else {
-            write.close().await?;
+            self.set_state("watching");
+            let watcher = following.and_then(Following::into_watcher);
+            self.watch_dir(&outbox, &mut read, &mut scheduler, &since, watcher)
+                .await?;
+            self.options.hooks.run(SyncHookEvent::Shutdown).await?;
+        }
+
+        outbox.close().await
+    }
+
+    /// Sends the initial tree as a `Handshake::PagedSync` followed by its `TreePage`s, so that
+    /// it is never encoded whole.
+    async fn send_tree_pages(&self, write: &mut WsSink, tree: FileTree) -> anyhow::Result<()> {
+        let encoded = bincode::serialize(&Handshake::PagedSync {
+            dests: self.roots.dests(),
+        })?;
+        self.send(write, Message::Binary(encoded)).await?;
+
+        let mut pages = tree.into_pages(TREE_PAGE_SIZE).peekable();
+        while let Some(nodes) = pages.next() {
+            let page = TreePage {
+                nodes,
+                last: pages.peek().is_none(),
+            };
+            self.send(write, Message::Binary(bincode::serialize(&page)?))
+                .await?;
         }
 
         Ok(())
     }
 
+    /// Sends a handshake, later messages go through the session's `Outbox`.
+    async fn send(&self, write: &mut WsSink, message: Message) -> anyhow::Result<()> {
+        let _span = profile::span("send", None);
+        self.activity.lock().unwrap().bytes_transferred += message.len() as u64;
+        with_timeout(
+            self.options.timeout,
+            "sending a message",
+            write.send(message),
+        )
+        .await??;
+
+        Ok(())
+    }
+
     async fn handle_files_req(
         &self,
-        write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+        outbox: &Outbox,
+        scheduler: &mut TransferScheduler,
         requests: Vec<RequestMessage>,
-    ) {
-        let mut handles = Vec::with_capacity(requests.len());
-        for request in requests {
-            match request {
-                RequestMessage::File(path) => {
-                    let file_path = self.dir_path.as_ref().join(&path);
-                    handles.push(tokio::spawn(async {
-                        let contents = tokio::fs::read(file_path).await.unwrap();
-                        let message = FileChangeMessage::FileEdited(path, Bytes::from(contents));
-
-                        bincode::serialize(&message).unwrap()
-                    }))
-                }
-                RequestMessage::Dir(path) => {
-                    let dir_path = self.dir_path.as_ref().join(&path);
-                    handles.push(tokio::spawn(async {
-                        let contents = compress_dir(dir_path).await;
+        mut transfer: Option<&mut Transfer>,
+    ) -> anyhow::Result<()> {
+        self.set_pending(|_| requests.len());
+        let jobs = requests.into_iter().map(TransferJob::from);
+        let mut messages = scheduler.unordered(jobs);
+        while let Some((message, reservation)) = messages.next().await {
+            if let (Some(transfer), SenderMessage::Sync(message)) = (&mut transfer, &message) {
+                transfer.count(&message.change);
+            }
+            outbox.send_reserved(&message, reservation).await?;
+            self.sent(&message);
+        }
+
+        drop(messages);
+        self.send_unsettled(outbox, scheduler, transfer).await?;
+        scheduler.forget_known();
+        self.warn_oversized(scheduler);
+        self.warn_specials(scheduler.take_specials());
+        self.batch_sent();
+
+        outbox.send(&SenderMessage::BatchEnd).await
+    }
 
-                        let contents = contents.unwrap();
-                        let message = FileChangeMessage::DirectoryCreated(path, contents);
+    /// Sends the files left out for still being written again, once they settle. Those still
+    /// changing after `MAX_SETTLE_RETRIES` tries are left out with a warning, for the watcher to
+    /// report their next change.
+    async fn send_unsettled(
+        &self,
+        outbox: &Outbox,
+        scheduler: &mut TransferScheduler,
+        mut transfer: Option<&mut Transfer>,
+    ) -> anyhow::Result<()> {
+        for _ in 0..MAX_SETTLE_RETRIES {
+            let unsettled = scheduler.take_unsettled();
+            if unsettled.is_empty() {
+                return Ok(());
+            }
 
-                        bincode::serialize(&message).unwrap()
-                    }))
+            let jobs = unsettled
+                .into_iter()
+                .map(|file| TransferJob::File(file.path));
+            let mut messages = scheduler.unordered(jobs);
+            while let Some((message, reservation)) = messages.next().await {
+                if let (Some(transfer), SenderMessage::Sync(message)) = (&mut transfer, &message) {
+                    transfer.count(&message.change);
                 }
+                outbox.send_reserved(&message, reservation).await?;
+                self.sent(&message);
             }
         }
 
-        for handle in handles {
-            let encoded = handle.await;
-            if let Ok(encoded) = encoded {
-                if let Err(err) = write.send(Message::Binary(encoded)).await {
-                    eprintln!("error occurred while sending message: {}", err);
+        let unsettled = scheduler.take_unsettled();
+        if !unsettled.is_empty() {
+            eprintln!(
+                "WARNING: skipped {} files still being written (see --stability-window):",
+                unsettled.len()
+            );
+            for file in unsettled {
+                eprintln!("  - {}", file);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn warn_oversized(&self, scheduler: &TransferScheduler) {
+        let oversized = scheduler.take_oversized();
+        if oversized.is_empty() {
+            return;
+        }
+
+        eprintln!(
+            "WARNING: skipped {} files larger than the {} in-memory limit (see --max-file-size):",
+            oversized.len(),
+            self.options.max_file_size
+        );
+        for file in oversized {
+            eprintln!("  - {}", file);
+        }
+    }
+
+    fn warn_specials(&self, specials: Vec<SkippedSpecial>) {
+        if specials.is_empty() {
+            return;
+        }
+
+        eprintln!(
+            "WARNING: skipped {} special files, which have no contents to send (see --specials for FIFOs):",
+            specials.len()
+        );
+        for special in specials {
+            eprintln!("  - {}", special);
+            self.emit(Event::SpecialFileSkipped {
+                path: special.path,
+                kind: special.kind,
+            });
+        }
+    }
+
+    async fn handle_receiver_message(
+        &self,
+        outbox: &Outbox,
+        scheduler: &mut TransferScheduler,
+        message: ReceiverMessage,
+    ) -> anyhow::Result<()> {
+        match message {
+            ReceiverMessage::Requests(requests) => {
+                self.handle_files_req(outbox, scheduler, requests, None)
+                    .await?;
+            }
+            ReceiverMessage::ChecksumsRequested => {
+                self.send_checksums(outbox).await?;
+            }
+            ReceiverMessage::SubtreesRequested(paths) => {
+                for path in paths {
+                    let tree = self.roots.subtree(&path, self.options.scan).await?;
+                    scheduler.learn(&tree);
+                    outbox.send(&SenderMessage::Subtree(path, tree)).await?;
                 }
             }
+            ReceiverMessage::Tree(_) => bail!("unexpected directory state received mid-session"),
+            ReceiverMessage::ChangeRejected(path, Rejection::MissingContents) => {
+                println!(
+                    "Sending {} in full, the listener has no file with the same contents anymore",
+                    quoted(&path)
+                );
+                scheduler.forget_sent();
+                let requests = vec![RequestMessage::File(path)];
+                self.handle_files_req(outbox, scheduler, requests, None)
+                    .await?;
+            }
+            ReceiverMessage::ChangeRejected(path, Rejection::ChecksumMismatch)
+                if scheduler.count_corrupted(&path) <= MAX_CHECKSUM_RETRIES =>
+            {
+                println!(
+                    "Sending {} again, the listener received it corrupted",
+                    quoted(&path)
+                );
+                let requests = vec![RequestMessage::File(path)];
+                self.handle_files_req(outbox, scheduler, requests, None)
+                    .await?;
+            }
+            ReceiverMessage::ChangeRejected(path, rejection) => {
+                let error = format!("the listener refused {}: {}", quoted(&path), rejection);
+                eprintln!("WARNING: {}", error);
+                self.activity.lock().unwrap().error(&error);
+                self.emit(Event::Error { error });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_checksums(&self, outbox: &Outbox) -> anyhow::Result<()> {
+        let tree = self.roots.tree(self.options.scan).await?;
+        let checksums = tree.top_level_checksums();
+        outbox.send(&SenderMessage::Checksums(checksums)).await
+    }
+
+    async fn send_root_checksum(&self, outbox: &Outbox) -> anyhow::Result<()> {
+        let tree = self.roots.tree(self.options.scan).await?;
+        let checksum = root_checksum(&tree.top_level_checksums());
+        outbox.send(&SenderMessage::RootChecksum(checksum)).await
+    }
+
+    async fn send_full_tree(
+        &self,
+        outbox: &Outbox,
+        scheduler: &TransferScheduler,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        self.emit(Event::ResyncStarted {
+            reason: reason.to_owned(),
+        });
+        let tree = self.roots.tree(self.options.scan).await?;
+        scheduler.learn(&tree);
+        outbox.send(&SenderMessage::FullTree(tree)).await
+    }
+
+    /// Sends the changes an earlier session journaled and did not send, with `--queue`.
+    async fn send_queued(
+        &self,
+        outbox: &Outbox,
+        scheduler: &mut TransferScheduler,
+    ) -> anyhow::Result<()> {
+        let Some(queue) = &self.queue else {
+            return Ok(());
+        };
+        let queued = queue.queued(&self.roots)?;
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        println!(
+            "Sending {} changes queued by an earlier session",
+            queued.len()
+        );
+        self.handle_file_changes(outbox, scheduler, queued).await?;
+        queue.clear()
+    }
+
+    /// The clocks to watch the roots since: the current ones, or for the first session those
+    /// saved by the previous run, so that the changes made while the sender was down are sent
+    /// as they happened, renames included, on top of the initial sync.
+    async fn watch_since(&self) -> anyhow::Result<WatchClocks> {
+        let mut since = watcher::clocks(&self.roots).await?;
+        let first = self.watch_clocks.lock().unwrap().is_none();
+        let saved = match &self.options.watch_clock {
+            Some(path) if first => watcher::load_clocks(path)?,
+            _ => WatchClocks::new(),
+        };
+        for (root, clock) in saved.iter() {
+            if let Some(since) = since.get_mut(root) {
+                *since = clock.clone();
+            }
+        }
+        self.watch_clocks
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| saved);
+
+        Ok(since)
+    }
+
+    /// Saves the clocks a batch brought up to date, with `--watch-clock`.
+    fn save_watch_clocks(&self, clocks: WatchClocks) -> anyhow::Result<()> {
+        let Some(path) = &self.options.watch_clock else {
+            return Ok(());
+        };
+        if clocks.is_empty() {
+            return Ok(());
         }
+
+        let mut saved = self.watch_clocks.lock().unwrap();
+        let saved = saved.get_or_insert_with(WatchClocks::new);
+        saved.extend(clocks);
+        watcher::save_clocks(path, saved)
     }
 
     async fn watch_dir(
         &self,
-        write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+        outbox: &Outbox,
+        read: &mut WsSource,
+        scheduler: &mut TransferScheduler,
+        since: &WatchClocks,
+        watcher: Option<Watcher>,
     ) -> anyhow::Result<()> {
-        let mut subscription = watcher::watch_dir(self.dir_path.as_ref()).await?;
+        let mut watcher = match watcher {
+            Some(watcher) => watcher,
+            None => {
+                Watcher::new(
+                    &self.roots,
+                    self.options.scan,
+                    self.options.watcher,
+                    &self.options.watchman,
+                    since,
+                )
+                .await?
+            }
+        };
+        let mut signals = UserSignals::new()?;
+        let mut keepalive = Keepalive::new(self.options.keepalive);
+        let mut checksum_ticker = self.options.checksum_interval.map(ticker);
+        let mut verify_ticker = self.options.verify_interval.map(ticker);
+        let mut debouncer = Debouncer::new(self.options.debounce);
+        let mut controls = self.controls.attach();
+        // Resyncs would send the changes held back while paused, so they wait for a resume.
+        let mut deferred_resync = false;
 
         loop {
+            let paused = controls.is_paused();
+            self.status.lock().unwrap().held_changes = match paused {
+                true => debouncer.len(),
+                false => 0,
+            };
+            self.set_pending(|_| debouncer.len());
+
             tokio::select! {
-                Ok(data) = subscription.next() => {
-                    let files = match data {
-                        watchman_client::SubscriptionData::FilesChanged(res) => res.files,
-                        _ => continue,
+                // Replies queued up while the session was busy sending are read before the
+                // keepalive decides whether the listener went silent.
+                biased;
+
+                message = read.next() => {
+                    let message = match message {
+                        Some(Ok(message)) => message,
+                        Some(Err(err)) => return Err(err.into()),
+                        None => bail!(tungstenite::Error::ConnectionClosed),
                     };
 
-                    if files.is_none() || files.as_ref().unwrap().is_empty() {
-                        continue;
+                    keepalive.seen();
+                    if let Message::Binary(bin) = message {
+                        let message: ReceiverMessage = bincode::deserialize(&bin)?;
+                        self.handle_receiver_message(outbox, scheduler, message).await?;
+                    }
+                }
+
+                event = watcher.next() => match event? {
+                    WatchEvent::Changes(files) => {
+                        if let Some(queue) = &self.queue {
+                            queue.push(&files)?;
+                        }
+                        debouncer.push(files);
+                    }
+                    WatchEvent::Clock(root, clock) => debouncer.clock(root, clock),
+                    WatchEvent::Resync(reason) if paused => {
+                        println!("{}, resyncing once resumed", reason);
+                        deferred_resync = true;
+                    }
+                    WatchEvent::Resync(reason) => {
+                        println!("{}, resyncing", reason);
+                        self.send_full_tree(outbox, scheduler, reason).await?;
                     }
+                },
 
-                    let files = files.unwrap();
-                    self.handle_file_changes(write, files).await;
+                (files, clocks) = debouncer.ready(), if !paused => {
+                    self.handle_file_changes(outbox, scheduler, files).await?;
+                    self.save_watch_clocks(clocks)?;
+                    if let Some(queue) = &self.queue {
+                        queue.clear()?;
+                    }
                 }
 
+                signal = signals.recv() => match signal {
+                    UserSignal::Resync => {
+                        println!("Resync requested");
+                        match paused {
+                            true => deferred_resync = true,
+                            false => self.send_full_tree(outbox, scheduler, "resync signal received").await?,
+                        }
+                    }
+                    // Announced by the controls, like pauses requested from `ctl`.
+                    UserSignal::TogglePause => {
+                        let command = match paused {
+                            true => Command::Resume,
+                            false => Command::Pause,
+                        };
+                        let _ = self.controls.run(command);
+                    }
+                },
+
+                event = controls.next() => match event {
+                    ControlEvent::Paused => println!("Paused, holding changes back"),
+                    ControlEvent::Resumed => {
+                        println!("Resumed, sending {} held changes", debouncer.len());
+                        if std::mem::take(&mut deferred_resync) {
+                            self.send_full_tree(outbox, scheduler, "resync held back while paused").await?;
+                        }
+                    }
+                    ControlEvent::Resync => {
+                        println!("Resync requested");
+                        self.send_full_tree(outbox, scheduler, "resync requested").await?;
+                    }
+                    ControlEvent::Shutdown => {
+                        println!("Exiting");
+                        break Ok(());
+                    }
+                },
+
+                // Checksums of a paused session would not match, and resync what it holds back.
+                _ = tick(&mut verify_ticker), if !paused => {
+                    self.send_root_checksum(outbox).await?;
+                }
+
+                _ = tick(&mut checksum_ticker), if !paused => {
+                    self.send_checksums(outbox).await?;
+                }
+
+                // Pings are sent by the outbox, this only checks that the listener is alive.
+                res = keepalive.tick() => res?,
+
                 _ = tokio::signal::ctrl_c() => {
                     println!("Exiting");
-                    write.close().await?;
                     break Ok(());
                 }
             }
@@ -139,15 +1128,86 @@ impl<'command, P: AsRef<Path>> Sender<'command, P> {
 
     async fn handle_file_changes(
         &self,
-        write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+        outbox: &Outbox,
+        scheduler: &mut TransferScheduler,
         files: Vec<FileChange>,
-    ) {
-        let mut changes = SortedFileChanges::from(self.dir_path.as_ref().to_owned(), files);
-        while let Some(message) = changes.next_message().await {
-            let encoded = bincode::serialize(&message).unwrap();
-            if let Err(err) = write.send(Message::Binary(encoded)).await {
-                eprintln!("error occurred while sending message: {}", err);
-            }
+    ) -> anyhow::Result<()> {
+        self.set_pending(|_| files.len());
+        let mut changes = SortedFileChanges::from(files);
+        let jobs = std::iter::from_fn(|| changes.next_job());
+        let mut messages = scheduler.ordered(jobs);
+        while let Some((message, reservation)) = messages.next().await {
+            outbox.send_reserved(&message, reservation).await?;
+            self.sent(&message);
         }
+
+        drop(messages);
+        self.send_unsettled(outbox, scheduler, None).await?;
+        self.warn_oversized(scheduler);
+        self.warn_specials(scheduler.take_specials());
+        self.batch_sent();
+
+        outbox.send(&SenderMessage::BatchEnd).await
     }
 }
+
+/// Connects to the listener at `uri` over TCP, through `proxy` if any, and over TLS for `wss://`
+/// addresses. Returns the connection along with the listener's address. Connection failures are
+/// reported as `tungstenite::Error`, like the other ones of the session, so that they count as a
+/// lost connection.
+async fn connect_tcp(
+    uri: &Uri,
+    tls: Option<&ClientTls>,
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<(BoxedTransport, String)> {
+    let (tls, default_port) = match (uri.scheme_str(), tls) {
+        (Some("ws"), _) => (None, 80),
+        (Some("wss"), Some(tls)) => (Some(tls), 443),
+        (Some("wss"), None) => bail!("{} uses TLS, which was not configured", uri),
+        _ => bail!(
+            "unsupported listener address {}, expected ws://<host>:<port> or wss://<host>:<port>",
+            uri
+        ),
+    };
+    let host = uri.host().context("listener address has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(default_port);
+    let stream = match proxy {
+        Some(proxy) => proxy.connect(host, port).await,
+        None => TcpStream::connect((host, port)).await,
+    }
+    .map_err(tungstenite::Error::Io)?;
+    let peer = match proxy {
+        Some(proxy) => format!("{}:{} via {}", host, port, proxy),
+        None => stream.peer_addr()?.to_string(),
+    };
+    let stream = match tls {
+        Some(tls) => tls
+            .connect(host, stream)
+            .await
+            .map_err(tungstenite::Error::Io)?,
+        None => Box::new(stream),
+    };
+
+    Ok((stream, peer))
+}
+
+fn ticker(period: Duration) -> Interval {
+    let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// Ticks an optional interval, never completing when it is disabled.
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.is::<DeadConnection>() || err.is::<TimedOut>() || err.is::<tungstenite::Error>()
+}
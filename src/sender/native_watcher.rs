@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use notify::{
+    event::{ModifyKind, RemoveKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _,
+};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+use watchman_client::prelude::*;
+
+use super::watcher::{mount, WatchEvent};
+use crate::core::{
+    file_change::FileChange, file_tree::ScanOptions, roots::SourceRoot,
+    wire_path::without_verbatim_prefix,
+};
+
+/// Watches a root with the platform's own notifications (ReadDirectoryChangesW on Windows,
+/// inotify on Linux, FSEvents on macOS) instead of watchman, reporting changes the way watchman
+/// would. Renames are reported as the removal of the old path and the creation of the new one,
+/// since not every platform pairs them up. A file is watched through its parent, like watchman.
+pub struct NativeWatch {
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    dir: PathBuf,
+    root: SourceRoot,
+    scan: ScanOptions,
+    /// The directories below `dir`, for removals to say what was removed.
+    dirs: BTreeSet<PathBuf>,
+    /// Every change gets its own inode number, so that none is taken for half of a rename.
+    next_ino: u64,
+}
+
+impl NativeWatch {
+    pub fn new(root: SourceRoot, scan: ScanOptions) -> anyhow::Result<Self> {
+        let (path, mode) = match (root.path.is_file(), root.path.parent()) {
+            (true, Some(parent)) => (parent, RecursiveMode::NonRecursive),
+            _ => (root.path.as_path(), RecursiveMode::Recursive),
+        };
+        let dir = without_verbatim_prefix(&path.canonicalize()?);
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .context("Could not set up the platform's file watcher")?;
+        watcher.watch(&dir, mode)?;
+
+        let dirs = match mode {
+            RecursiveMode::Recursive => WalkDir::new(&dir)
+                .into_iter()
+                .filter_entry(|entry| !scan.excludes(entry.path()))
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_dir())
+                .map(|entry| entry.into_path())
+                .collect(),
+            RecursiveMode::NonRecursive => BTreeSet::new(),
+        };
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            dir,
+            root,
+            scan,
+            dirs,
+            next_ino: 1,
+        })
+    }
+
+    /// Forwards the changes to `tx` until either side is dropped or the watcher fails.
+    pub async fn forward(mut self, tx: mpsc::Sender<anyhow::Result<WatchEvent>>) {
+        while let Some(event) = self.events.recv().await {
+            let event = match event {
+                Ok(event) if event.need_rescan() => {
+                    WatchEvent::Resync("The platform's file watcher lost track of changes")
+                }
+                Ok(event) => {
+                    let changes = self.changes(event);
+                    match mount(changes, &self.dir, &self.root, self.scan) {
+                        Ok(changes) if changes.is_empty() => continue,
+                        Ok(changes) => WatchEvent::Changes(changes),
+                        Err(err) => {
+                            let _ = tx.send(Err(err)).await;
+                            return;
+                        }
+                    }
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err.into())).await;
+                    return;
+                }
+            };
+
+            if tx.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// The changes an event reports, named like the paths of the event.
+    fn changes(&mut self, event: Event) -> Vec<FileChange> {
+        let (exists, is_new) = match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                (true, true)
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                (false, false)
+            }
+            // Also reported as a `From` and a `To`, where the platform pairs them up.
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => return vec![],
+            EventKind::Modify(_) => (true, false),
+            EventKind::Access(_) | EventKind::Any | EventKind::Other => return vec![],
+        };
+
+        let mut changes = vec![];
+        for path in event.paths {
+            let path = without_verbatim_prefix(&path);
+            if path == self.dir {
+                continue;
+            }
+            let metadata = std::fs::symlink_metadata(&path).ok();
+            // Like watchman's subscriptions, only regular files and directories are reported.
+            if metadata
+                .as_ref()
+                .is_some_and(|metadata| !metadata.is_file() && !metadata.is_dir())
+            {
+                continue;
+            }
+            let is_dir = match &metadata {
+                Some(metadata) => metadata.is_dir(),
+                None => {
+                    matches!(event.kind, EventKind::Remove(RemoveKind::Folder))
+                        || self.dirs.contains(&path)
+                }
+            };
+            match metadata.is_some() {
+                true if is_dir => {
+                    self.dirs.insert(path.clone());
+                }
+                true => (),
+                false => self.forget_dir(&path),
+            }
+
+            // New files are sent like edited ones, with their contents, and new directories whole.
+            let exists = exists && metadata.is_some();
+            changes.push(self.change(path, exists, is_new && is_dir, is_dir));
+        }
+
+        changes
+    }
+
+    #[allow(deprecated)]
+    fn change(&mut self, path: PathBuf, exists: bool, is_new: bool, is_dir: bool) -> FileChange {
+        let now = match exists {
+            true => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64),
+            false => 0,
+        };
+        self.next_ino += 1;
+
+        FileChange {
+            name: NameField::new(path),
+            exists: ExistsField::new(exists),
+            is_new: NewField::new(is_new),
+            ctime: CTimeField::new(now),
+            mtime: MTimeField::new(now),
+            typ: FileTypeField::new(match is_dir {
+                true => FileType::Directory,
+                false => FileType::Regular,
+            }),
+            ino: InodeNumberField::new(self.next_ino),
+        }
+    }
+
+    /// Forgets the directory at `path`, and those below it.
+    fn forget_dir(&mut self, path: &Path) {
+        let below: Vec<_> = self
+            .dirs
+            .range(path.to_path_buf()..)
+            .take_while(|dir| dir.starts_with(path))
+            .cloned()
+            .collect();
+        for dir in below {
+            self.dirs.remove(&dir);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use bytesize::ByteSize;
+
+use crate::core::summary::TransferSummary;
+
+/// Initial syncs taking at least this long, or transferring at least `LARGE_SYNC_BYTES`, pop a
+/// notification when they finish. Smaller ones are over before anyone looks away.
+const LARGE_SYNC_DURATION: Duration = Duration::from_secs(10);
+const LARGE_SYNC_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Whether `summary` is worth a notification.
+pub fn is_large(summary: &TransferSummary) -> bool {
+    summary.elapsed_secs >= LARGE_SYNC_DURATION.as_secs_f64() || summary.bytes >= LARGE_SYNC_BYTES
+}
+
+/// What the notification of a completed initial sync says.
+pub fn describe(summary: &TransferSummary) -> String {
+    format!(
+        "{} files and {} directories, {} in {:.0}s",
+        summary.files_transferred,
+        summary.dirs_transferred,
+        ByteSize::b(summary.bytes),
+        summary.elapsed_secs
+    )
+}
+
+/// Pops a desktop notification, warning when there is no notification service to show it, e.g.
+/// over SSH.
+#[cfg(feature = "notify")]
+pub async fn show(summary: &str, body: &str) {
+    let (summary, body) = (summary.to_owned(), body.to_owned());
+    let shown = tokio::task::spawn_blocking(move || {
+        notify_rust::Notification::new()
+            .appname("white-caiman")
+            .summary(&summary)
+            .body(&body)
+            .show()
+            .map(drop)
+    })
+    .await;
+
+    match shown {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => eprintln!("WARNING: could not show a desktop notification: {}", err),
+        Err(err) => eprintln!("WARNING: could not show a desktop notification: {}", err),
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+pub async fn show(_summary: &str, _body: &str) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_large_syncs_are_notified() {
+        let mut summary = TransferSummary {
+            bytes: 1024,
+            elapsed_secs: 0.5,
+            ..Default::default()
+        };
+        assert!(!is_large(&summary));
+
+        summary.elapsed_secs = 12.0;
+        assert!(is_large(&summary));
+
+        summary.elapsed_secs = 0.5;
+        summary.bytes = LARGE_SYNC_BYTES;
+        assert!(is_large(&summary));
+        assert_eq!(
+            describe(&summary),
+            "0 files and 0 directories, 100.0 MiB in 0s"
+        );
+    }
+}
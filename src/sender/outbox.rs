@@ -0,0 +1,268 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use tungstenite::Message;
+
+use super::SenderOptions;
+use crate::core::{
+    activity::Activity,
+    budget::Reservation,
+    message::{SenderMessage, MAX_FRAGMENT_SIZE},
+    profile,
+    timeout::{with_timeout, with_watchdog},
+};
+
+/// Writes to the listener from a dedicated task with two lanes. Control messages jump ahead of
+/// bulk ones, and bulk messages are split into fragments, so that pings and control messages
+/// never wait for more than one fragment of a large file. Pings are sent from the writer itself
+/// so they keep flowing while the session is busy producing bulk messages. Bulk messages keep
+/// their memory reservation until they are written.
+pub struct Outbox {
+    control: mpsc::UnboundedSender<Message>,
+    bulk: mpsc::Sender<(Bytes, Reservation)>,
+    writer: JoinHandle<()>,
+    failure: Arc<Mutex<Option<anyhow::Error>>>,
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl Outbox {
+    pub fn new<S>(sink: S, options: &SenderOptions, activity: Arc<Mutex<Activity>>) -> Self
+    where
+        S: Sink<Message, Error = tungstenite::Error> + Send + Unpin + 'static,
+    {
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let (bulk, bulk_rx) = mpsc::channel(1);
+        let failure = Arc::new(Mutex::new(None));
+        let writer = Writer {
+            sink,
+            control: control_rx,
+            bulk: bulk_rx,
+            timeout: options.timeout,
+            stall_warning: options.stall_warning,
+            ping_interval: options.keepalive.interval,
+            last_ping: Instant::now(),
+        };
+
+        let writer_failure = failure.clone();
+        let writer = tokio::spawn(async move {
+            if let Err(err) = writer.run().await {
+                *writer_failure.lock().unwrap() = Some(err);
+            }
+        });
+
+        Self {
+            control,
+            bulk,
+            writer,
+            failure,
+            activity,
+        }
+    }
+
+    /// Queues a message on the lane it belongs to, waiting for the writer to take bulk messages
+    /// so that at most one of them is held in memory at a time.
+    pub async fn send(&self, message: &SenderMessage) -> anyhow::Result<()> {
+        self.send_reserved(message, Reservation::default()).await
+    }
+
+    /// Like `send`, releasing `reservation` once the message is written.
+    pub async fn send_reserved(
+        &self,
+        message: &SenderMessage,
+        reservation: Reservation,
+    ) -> anyhow::Result<()> {
+        let encoded = bincode::serialize(message)?;
+        self.activity.lock().unwrap().bytes_transferred += encoded.len() as u64;
+        let sent = match message.is_control() {
+            true => self.control.send(Message::Binary(encoded)).is_ok(),
+            false => self
+                .bulk
+                .send((Bytes::from(encoded), reservation))
+                .await
+                .is_ok(),
+        };
+
+        match sent {
+            true => Ok(()),
+            false => Err(self.failure()),
+        }
+    }
+
+    /// Sends everything still queued, then closes the connection.
+    pub async fn close(self) -> anyhow::Result<()> {
+        drop(self.control);
+        drop(self.bulk);
+        self.writer.await?;
+
+        match self.failure.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn failure(&self) -> anyhow::Error {
+        self.failure
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| anyhow!(tungstenite::Error::ConnectionClosed))
+    }
+}
+
+struct Writer<S> {
+    sink: S,
+    control: mpsc::UnboundedReceiver<Message>,
+    bulk: mpsc::Receiver<(Bytes, Reservation)>,
+    timeout: Duration,
+    stall_warning: Duration,
+    ping_interval: Duration,
+    last_ping: Instant,
+}
+
+impl<S> Writer<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    async fn run(mut self) -> anyhow::Result<()> {
+        loop {
+            let ping_at = self.last_ping + self.ping_interval;
+            tokio::select! {
+                biased;
+
+                Some(message) = self.control.recv() => self.write(message).await?,
+                encoded = self.bulk.recv() => match encoded {
+                    Some((encoded, _reservation)) => self.write_bulk(encoded).await?,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(ping_at) => self.ping().await?,
+            }
+        }
+
+        with_timeout(self.timeout, "closing the connection", self.sink.close()).await??;
+        Ok(())
+    }
+
+    async fn write_bulk(&mut self, encoded: Bytes) -> anyhow::Result<()> {
+        let _span = profile::span("send", None);
+        if encoded.len() <= MAX_FRAGMENT_SIZE {
+            return self.write(Message::Binary(encoded.into())).await;
+        }
+
+        let mut fragments = encoded.chunks(MAX_FRAGMENT_SIZE).peekable();
+        while let Some(data) = fragments.next() {
+            let fragment = SenderMessage::Fragment {
+                data: encoded.slice_ref(data),
+                last: fragments.peek().is_none(),
+            };
+            self.write(Message::Binary(bincode::serialize(&fragment)?))
+                .await?;
+
+            while let Ok(message) = self.control.try_recv() {
+                self.write(message).await?;
+            }
+
+            if self.last_ping.elapsed() >= self.ping_interval {
+                self.ping().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        self.last_ping = Instant::now();
+        self.write(Message::Ping(Vec::new())).await
+    }
+
+    /// A receiver that stops reading applies backpressure here, so say so while waiting.
+    async fn write(&mut self, message: Message) -> anyhow::Result<()> {
+        with_watchdog(
+            self.timeout,
+            self.stall_warning,
+            "waiting for the receiver to consume a message",
+            |elapsed| {
+                eprintln!(
+                    "WARNING: the receiver has not consumed messages for {}, it may be stuck applying changes or out of disk space",
+                    humantime::format_duration(Duration::from_secs(elapsed.as_secs()))
+                )
+            },
+            self.sink.send(message),
+        )
+        .await??;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{channel::mpsc as sink, StreamExt};
+    use tokio::test;
+
+    #[test]
+    async fn test_control_messages_and_pings_go_out_between_fragments() -> anyhow::Result<()> {
+        // Holds a single message, so that the writer waits for each one to be read.
+        let (sink, mut written) = sink::channel(0);
+        let sink = sink.sink_map_err(|_| tungstenite::Error::ConnectionClosed);
+        let options = SenderOptions {
+            keepalive: crate::core::keepalive::KeepaliveConfig {
+                interval: Duration::from_millis(10),
+                timeout: Duration::from_secs(60),
+            },
+            ..Default::default()
+        };
+        let outbox = Outbox::new(sink, &options, Default::default());
+
+        let large = SenderMessage::Fragment {
+            data: Bytes::from(vec![7; 4 * MAX_FRAGMENT_SIZE]),
+            last: true,
+        };
+        outbox.send(&large).await?;
+        let Some(Message::Binary(first)) = written.next().await else {
+            panic!("expected the first fragment");
+        };
+        assert!(matches!(
+            bincode::deserialize(&first)?,
+            SenderMessage::Fragment { last: false, .. }
+        ));
+        outbox.send(&SenderMessage::Skipped(1)).await?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut rest = vec![];
+        while let Some(message) = written.next().await {
+            let last = match &message {
+                Message::Binary(encoded) => matches!(
+                    bincode::deserialize(encoded)?,
+                    SenderMessage::Fragment { last: true, .. }
+                ),
+                _ => false,
+            };
+            rest.push(message);
+            if last {
+                break;
+            }
+        }
+        let skipped = rest.iter().position(|message| match message {
+            Message::Binary(encoded) => {
+                matches!(bincode::deserialize(encoded), Ok(SenderMessage::Skipped(1)))
+            }
+            _ => false,
+        });
+        let ping = rest
+            .iter()
+            .position(|message| matches!(message, Message::Ping(_)));
+        let last = rest.len() - 1;
+        assert!(skipped.is_some_and(|skipped| skipped < last));
+        assert!(ping.is_some_and(|ping| ping < last));
+
+        let (closed, _) = tokio::join!(outbox.close(), written.collect::<Vec<_>>());
+        closed
+    }
+}
@@ -0,0 +1,186 @@
+#![allow(deprecated)]
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use watchman_client::prelude::*;
+
+use crate::core::{
+    file_change::FileChange,
+    records::{push_record, read_records},
+    roots::Roots,
+    utils::quoted,
+    wire_path,
+};
+
+/// A path the watcher reported a change of, and whether it was a directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct Queued(#[serde(with = "wire_path")] PathBuf, bool);
+
+/// The changes the watcher reported and the sender did not send yet, journaled to a file so that
+/// they are sent by the next session, even if the sender dies or loses the connection before
+/// sending them. Only their paths are kept: what is sent is their state once the next session
+/// sends them, which covers edits its initial sync cannot see, such as those keeping a file's
+/// size and mtime. The journal is emptied whenever every change reported so far was sent.
+#[derive(Debug)]
+pub struct ChangeQueue {
+    path: PathBuf,
+    /// The journal, opened on the first change queued.
+    file: Mutex<Option<File>>,
+}
+
+impl ChangeQueue {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: Default::default(),
+        }
+    }
+
+    /// Journals `changes` before they are sent.
+    pub fn push(&self, changes: &[FileChange]) -> anyhow::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let file = match &mut *file {
+            Some(file) => file,
+            None => file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .with_context(|| format!("opening {}", quoted(&self.path)))?,
+            ),
+        };
+
+        let mut buf = vec![];
+        for change in changes {
+            let is_dir = matches!(*change.typ, FileType::Directory);
+            push_record(&mut buf, &Queued(change.name.to_path_buf(), is_dir))?;
+        }
+        file.write_all(&buf)
+            .with_context(|| format!("writing to {}", quoted(&self.path)))
+    }
+
+    /// The changes left in the journal, as they are now: edits of the paths still there and
+    /// deletions of the others.
+    pub fn queued(&self, roots: &Roots) -> anyhow::Result<Vec<FileChange>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", quoted(&self.path))),
+        };
+
+        let mut queued = BTreeMap::new();
+        for Queued(path, is_dir) in read_records(&contents) {
+            queued.insert(path, is_dir);
+        }
+
+        let changes = queued
+            .into_iter()
+            .filter_map(|(path, was_dir)| {
+                let metadata = std::fs::symlink_metadata(roots.resolve(&path)?).ok();
+                let is_dir = metadata
+                    .as_ref()
+                    .map_or(was_dir, |metadata| metadata.is_dir());
+                Some((path, metadata.is_some(), is_dir))
+            })
+            .enumerate()
+            .map(|(ino, (path, exists, is_dir))| change(path, exists, is_dir, ino as u64 + 1))
+            .collect();
+        Ok(changes)
+    }
+
+    /// Empties the journal once every change it holds was sent.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        match &*file {
+            Some(file) => file.set_len(0),
+            None if self.path.exists() => {
+                OpenOptions::new().write(true).open(&self.path)?.set_len(0)
+            }
+            None => Ok(()),
+        }
+        .with_context(|| format!("emptying {}", quoted(&self.path)))?;
+        file.take();
+        Ok(())
+    }
+}
+
+/// A change sent with the path's contents, or its deletion, paired with no other: every change
+/// gets its own inode number, so that none is taken for half of a rename.
+fn change(path: PathBuf, exists: bool, is_dir: bool, ino: u64) -> FileChange {
+    FileChange {
+        name: NameField::new(path),
+        exists: ExistsField::new(exists),
+        is_new: NewField::new(false),
+        ctime: CTimeField::new(0),
+        mtime: MTimeField::new(0),
+        typ: FileTypeField::new(match is_dir {
+            true => FileType::Directory,
+            false => FileType::Regular,
+        }),
+        ino: InodeNumberField::new(ino),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_queued_changes_outlive_the_sender() -> anyhow::Result<()> {
+        let root = TempDir::new()?;
+        let dir = TempDir::new()?;
+        fs::write(root.path().join("edited.txt"), "edited")?;
+        fs::create_dir(root.path().join("docs"))?;
+        let roots = Roots::single(root.path());
+        let path = dir.path().join("queue");
+
+        let queue = ChangeQueue::new(path.clone());
+        assert!(queue.queued(&roots)?.is_empty());
+        queue.push(&[
+            change("edited.txt".into(), true, false, 1),
+            change("docs".into(), true, true, 2),
+        ])?;
+        queue.push(&[
+            change("edited.txt".into(), true, false, 3),
+            change("removed.txt".into(), true, false, 4),
+        ])?;
+        // A record cut short by a crash is left out.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[0, 0, 0, 0, 0, 0, 0, 9, 1])?;
+        drop(queue);
+
+        let queue = ChangeQueue::new(path);
+        let queued = queue.queued(&roots)?;
+        let described: Vec<_> = queued
+            .iter()
+            .map(|change| {
+                let is_dir = matches!(*change.typ, FileType::Directory);
+                (change.name.to_path_buf(), *change.exists, is_dir)
+            })
+            .collect();
+        assert_eq!(
+            described,
+            [
+                ("docs".into(), true, true),
+                ("edited.txt".into(), true, false),
+                ("removed.txt".into(), false, false),
+            ]
+        );
+        queue.clear()?;
+        assert!(queue.queued(&roots)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{stream, Stream, StreamExt};
+
+use super::{dedup::Dedup, hardlinks::Hardlinks, middleware::MiddlewareChain, SenderOptions};
+use crate::core::{
+    budget::{MemoryBudget, Reservation},
+    file_tree::{FileTree, ScanOptions},
+    message::{SenderMessage, SyncMessage},
+    policy::{Encoding, PolicyTable},
+    roots::Roots,
+    specials::SkippedSpecial,
+    transfer::{Oversized, SmallFilePacker, TransferJob, Unsettled},
+    utils::quoted,
+};
+
+/// Loads and encodes transfer jobs with at most `jobs` of them in flight and tags the resulting
+/// messages with sequence numbers and their causal dependencies. Jobs dropped after being numbered
+/// are reported as `SenderMessage::Skipped` so the receiver does not wait for them. Files over the
+/// in-memory limit are skipped and collected for `take_oversized`, special files for
+/// `take_specials`, and with a stability window files still being written for `take_unsettled`.
+/// Jobs whose message could exceed the receiver's `max_message_size`, or that the policy table
+/// chunks, are split first, and files identical to one already sent are deduplicated before that if
+/// the receiver accepts it, after hard links to a file already sent are turned into links. Sparse
+/// files are sent in chunks of their data if it accepts that. Small files are then packed together
+/// if it accepts that too, and files and chunks carry the checksum of their contents if it verifies
+/// them. With a memory budget, jobs wait for their payload to fit within it before being loaded,
+/// and messages hold their reservation until they are written.
+pub struct TransferScheduler {
+    roots: Arc<Roots>,
+    jobs: usize,
+    max_file_size: u64,
+    max_message_size: Option<u64>,
+    scan: ScanOptions,
+    policies: PolicyTable,
+    middleware: Arc<MiddlewareChain>,
+    next_id: u64,
+    causality: CausalIndex,
+    oversized: Arc<Mutex<Vec<Oversized>>>,
+    specials: Arc<Mutex<Vec<SkippedSpecial>>>,
+    stability_window: Option<Duration>,
+    unsettled: Arc<Mutex<Vec<Unsettled>>>,
+    dedup: Option<Arc<Mutex<Dedup>>>,
+    links: Option<Arc<Mutex<Hardlinks>>>,
+    batch_threshold: u64,
+    sparse: bool,
+    checksums: bool,
+    corrupted: HashMap<PathBuf, usize>,
+    budget: Option<MemoryBudget>,
+}
+
+impl TransferScheduler {
+    pub fn new(roots: Arc<Roots>, options: &SenderOptions, max_message_size: Option<u64>) -> Self {
+        Self {
+            roots,
+            jobs: options.jobs.max(1),
+            max_file_size: options.max_file_size.as_u64(),
+            max_message_size,
+            scan: options.scan,
+            policies: options.policies,
+            middleware: options.middleware.clone(),
+            next_id: 0,
+            causality: CausalIndex::default(),
+            oversized: Default::default(),
+            specials: Default::default(),
+            stability_window: options.stability_window,
+            unsettled: Default::default(),
+            dedup: None,
+            links: None,
+            batch_threshold: 0,
+            sparse: false,
+            checksums: false,
+            corrupted: HashMap::new(),
+            budget: options
+                .memory_limit
+                .map(|limit| MemoryBudget::new(limit.as_u64())),
+        }
+    }
+
+    /// Sends directory archives raw, for receivers that do not accept gzipped ones.
+    pub fn without_gzipped_archives(mut self) -> Self {
+        self.policies.archives = Encoding::Raw;
+        self
+    }
+
+    /// Packs files smaller than `threshold` bytes into `FileBatch` changes, none if it is 0.
+    pub fn batching(mut self, threshold: u64) -> Self {
+        self.batch_threshold = threshold;
+        self
+    }
+
+    /// Leaves the holes of sparse files out of the chunks sent, for receivers that recreate them.
+    pub fn preserving_holes(mut self) -> Self {
+        self.sparse = true;
+        self
+    }
+
+    /// Sends edited files and chunks as `Checksummed` changes, for receivers that verify them.
+    pub fn checksumming(mut self) -> Self {
+        self.checksums = true;
+        self
+    }
+
+    /// Sends files identical to one already sent as `FileFromHash` changes.
+    pub fn deduplicating(mut self) -> Self {
+        let dedup = Dedup::new(self.roots.clone(), self.scan);
+        self.dedup = Some(Arc::new(Mutex::new(dedup)));
+        self
+    }
+
+    /// Sends hard links to a file already sent as `HardlinkCreated` changes.
+    pub fn linking(mut self) -> Self {
+        let links = Hardlinks::new(self.roots.clone(), self.scan);
+        self.links = Some(Arc::new(Mutex::new(links)));
+        self
+    }
+
+    /// Learns the hashes and hard links of the files of a tree sent to the receiver, see
+    /// `Dedup::learn` and `Hardlinks::learn`.
+    pub fn learn(&self, tree: &FileTree) {
+        if let Some(dedup) = &self.dedup {
+            dedup.lock().unwrap().learn(tree);
+        }
+        if let Some(links) = &self.links {
+            links.lock().unwrap().learn(tree);
+        }
+    }
+
+    /// Forgets the hashes and links learnt once the requests they were for are scheduled.
+    pub fn forget_known(&self) {
+        if let Some(dedup) = &self.dedup {
+            dedup.lock().unwrap().forget_known();
+        }
+        if let Some(links) = &self.links {
+            links.lock().unwrap().forget_known();
+        }
+    }
+
+    /// Sends every file whole until it is sent again, when the receiver failed to copy or link
+    /// to one.
+    pub fn forget_sent(&self) {
+        if let Some(dedup) = &self.dedup {
+            dedup.lock().unwrap().forget_sent();
+        }
+        if let Some(links) = &self.links {
+            links.lock().unwrap().forget_sent();
+        }
+    }
+
+    /// Counts one more transfer of `path` the receiver found corrupted, returning how many it
+    /// found in this session.
+    pub fn count_corrupted(&mut self, path: &Path) -> usize {
+        let count = self.corrupted.entry(path.to_owned()).or_default();
+        *count += 1;
+        *count
+    }
+
+    /// Files skipped for being over the in-memory limit since the last call.
+    pub fn take_oversized(&self) -> Vec<Oversized> {
+        std::mem::take(&mut *self.oversized.lock().unwrap())
+    }
+
+    /// Special files skipped since the last call.
+    pub fn take_specials(&self) -> Vec<SkippedSpecial> {
+        std::mem::take(&mut *self.specials.lock().unwrap())
+    }
+
+    /// Files left out for still being written since the last call.
+    pub fn take_unsettled(&self) -> Vec<Unsettled> {
+        std::mem::take(&mut *self.unsettled.lock().unwrap())
+    }
+
+    /// Yields messages as soon as their payload is loaded, for batches of independent paths.
+    pub fn unordered<'a>(
+        &'a mut self,
+        jobs: impl IntoIterator<Item = TransferJob> + 'a,
+    ) -> impl Stream<Item = (SenderMessage, Reservation)> + 'a {
+        let concurrency = self.jobs;
+        self.spawn_all(jobs).buffer_unordered(concurrency)
+    }
+
+    /// Yields messages in the order the jobs were submitted, loading payloads ahead in parallel.
+    pub fn ordered<'a>(
+        &'a mut self,
+        jobs: impl IntoIterator<Item = TransferJob> + 'a,
+    ) -> impl Stream<Item = (SenderMessage, Reservation)> + 'a {
+        let concurrency = self.jobs;
+        self.spawn_all(jobs).buffered(concurrency)
+    }
+
+    fn spawn_all<'a>(
+        &'a mut self,
+        jobs: impl IntoIterator<Item = TransferJob> + 'a,
+    ) -> impl Stream<Item = impl std::future::Future<Output = (SenderMessage, Reservation)>> + 'a
+    {
+        let middleware = self.middleware.clone();
+        let (roots, max_file_size, scan) = (self.roots.clone(), self.max_file_size, self.scan);
+        let (max_message_size, sparse, policies) =
+            (self.max_message_size, self.sparse, self.policies);
+        let (links, dedup) = (self.links.clone(), self.dedup.clone());
+        let jobs = jobs
+            .into_iter()
+            .filter_map(move |job| middleware.on_job(job))
+            .flat_map(move |job| match &links {
+                Some(links) => links.lock().unwrap().link(job),
+                None => vec![job],
+            })
+            .flat_map(move |job| match &dedup {
+                Some(dedup) => dedup.lock().unwrap().dedupe(job),
+                None => vec![job],
+            })
+            .flat_map(
+                move |job| match (max_message_size, policies.min_chunk_size()) {
+                    (None, None) => vec![job],
+                    (max_message_size, _) => job.split(
+                        &roots,
+                        max_message_size.unwrap_or(u64::MAX),
+                        max_file_size,
+                        scan,
+                        sparse,
+                        &policies,
+                    ),
+                },
+            );
+        let jobs = SmallFilePacker::new(jobs, self.roots.clone(), self.batch_threshold);
+
+        // Reserving in job order, before spawning, so that a job never holds memory a message
+        // yielded before it waits for.
+        let (roots, budget) = (self.roots.clone(), self.budget.clone());
+        let reserved = stream::iter(jobs).then(move |job| {
+            let (roots, budget) = (roots.clone(), budget.clone());
+            Box::pin(async move {
+                let reservation = match budget {
+                    Some(budget) => {
+                        let size = job.estimated_size(&roots, budget.limit(), max_file_size, scan);
+                        budget.reserve(size).await
+                    }
+                    None => Reservation::default(),
+                };
+                (job, reservation)
+            })
+        });
+
+        reserved.map(move |(job, reservation)| {
+            let id = self.next_id;
+            self.next_id += 1;
+            let depends_on = self.causality.record(id, &job.paths());
+
+            let roots = self.roots.clone();
+            let max_file_size = self.max_file_size;
+            let scan = self.scan;
+            let policies = self.policies;
+            let middleware = self.middleware.clone();
+            let checksums = self.checksums;
+            let oversized = self.oversized.clone();
+            let specials = self.specials.clone();
+            let (stability_window, unsettled) = (self.stability_window, self.unsettled.clone());
+            let path = job.path().to_owned();
+            let handle = tokio::spawn(async move {
+                let job = match stability_window {
+                    Some(window) => {
+                        let (job, left_out) = job.settled(&roots, window).await;
+                        unsettled.lock().unwrap().extend(left_out);
+                        match job {
+                            Some(job) => job,
+                            None => return Ok(None),
+                        }
+                    }
+                    None => job,
+                };
+                let (change, left_out) = match job.load(&roots, max_file_size, scan).await {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        match err.downcast::<Oversized>() {
+                            Ok(file) => oversized.lock().unwrap().push(file),
+                            Err(err) => specials.lock().unwrap().push(err.downcast()?),
+                        }
+                        return Ok(None);
+                    }
+                };
+                oversized.lock().unwrap().extend(left_out);
+
+                let Some(message) = middleware.on_message(SyncMessage {
+                    id,
+                    depends_on,
+                    change,
+                }) else {
+                    return anyhow::Ok(None);
+                };
+
+                // Summed as the receiver decodes them, before being encoded.
+                let change = match checksums {
+                    true => message.change.checksummed(),
+                    false => message.change,
+                };
+                Ok(Some(SyncMessage {
+                    change: policies.encode(change)?,
+                    ..message
+                }))
+            });
+
+            async move {
+                let message = match handle.await {
+                    Ok(Ok(message)) => message,
+                    Ok(Err(err)) => {
+                        eprintln!("Skipping message {}: {:#}", id, err);
+                        None
+                    }
+                    Err(err) => {
+                        eprintln!("Loading {} panicked: {}", quoted(&path), err);
+                        None
+                    }
+                };
+
+                match message {
+                    Some(message) => (SenderMessage::Sync(message), reservation),
+                    None => (SenderMessage::Skipped(id), Reservation::default()),
+                }
+            }
+        })
+    }
+}
+
+/// Remembers, for every path touched during the session, the last message that touched it.
+#[derive(Default)]
+struct CausalIndex {
+    last: BTreeMap<PathBuf, u64>,
+}
+
+impl CausalIndex {
+    /// Records message `id` as the latest one touching `paths` and returns the earlier messages
+    /// it must be applied after, i.e. the latest ones touching the same paths, their ancestors
+    /// or their descendants.
+    fn record(&mut self, id: u64, paths: &[&Path]) -> Vec<u64> {
+        let mut depends_on = vec![];
+        for path in paths {
+            depends_on.extend(path.ancestors().filter_map(|path| self.last.get(path)));
+
+            // Descendants sort right after their ancestor. They can be forgotten once `id` is
+            // recorded since it already depends on them.
+            let descendants: Vec<_> = self
+                .last
+                .range(path.to_path_buf()..)
+                .skip_while(|(other, _)| other == path)
+                .take_while(|(other, _)| other.starts_with(path))
+                .map(|(other, id)| (other.clone(), *id))
+                .collect();
+            for (descendant, id) in descendants {
+                depends_on.push(id);
+                self.last.remove(&descendant);
+            }
+        }
+
+        for path in paths {
+            self.last.insert(path.to_path_buf(), id);
+        }
+
+        depends_on.sort_unstable();
+        depends_on.dedup();
+        depends_on
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_causal_dependencies() {
+        let mut index = CausalIndex::default();
+        let p = Path::new;
+
+        assert_eq!(index.record(0, &[p("a/b.txt")]), Vec::<u64>::new());
+        assert_eq!(index.record(1, &[p("c.txt")]), Vec::<u64>::new());
+        assert_eq!(index.record(2, &[p("a/b.txt")]), vec![0]);
+        assert_eq!(index.record(3, &[p("a"), p("d")]), vec![2]);
+        assert_eq!(index.record(4, &[p("a/e.txt")]), vec![3]);
+        assert_eq!(index.record(5, &[p("ab.txt")]), Vec::<u64>::new());
+        assert_eq!(index.record(6, &[p("d/f.txt"), p("c.txt")]), vec![1, 3]);
+    }
+}
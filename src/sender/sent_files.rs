@@ -0,0 +1,112 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::core::{file_tree::ScanOptions, roots::Roots, transfer::TransferJob};
+
+/// The files learnt from the trees sent to the receiver, which its requests will be about, by
+/// the key that files sent later are matched on.
+pub trait Known {
+    type Key: Clone + Eq + Hash + Debug;
+
+    /// Takes the key of the file at `path` out of the files learnt.
+    fn take(&mut self, path: &Path) -> Option<Self::Key>;
+
+    /// Whether a file below `dir` matches another one, among the files `sent` or yet to be.
+    fn matches_within(&self, dir: &Path, sent: &HashMap<Self::Key, PathBuf>) -> bool;
+}
+
+/// The files sent whole during the session, by key and by path, for the jobs of later files with
+/// the same key to be turned into references to them. Files are forgotten once overwritten, and
+/// directories holding files that match are sent entry by entry instead of as an archive.
+#[derive(Debug)]
+pub struct SentFiles<K: Known> {
+    roots: Arc<Roots>,
+    scan: ScanOptions,
+    pub known: K,
+    sent: HashMap<K::Key, PathBuf>,
+    sent_paths: BTreeMap<PathBuf, K::Key>,
+}
+
+impl<K: Known> SentFiles<K> {
+    pub fn new(roots: Arc<Roots>, scan: ScanOptions, known: K) -> Self {
+        Self {
+            roots,
+            scan,
+            known,
+            sent: Default::default(),
+            sent_paths: Default::default(),
+        }
+    }
+
+    /// Forgets the files sent, when the receiver could not reference one of them.
+    pub fn forget_sent(&mut self) {
+        self.sent.clear();
+        self.sent_paths.clear();
+    }
+
+    /// Called on every job in scheduling order, so that files changed, moved or deleted since
+    /// they were sent are not referenced anymore. `matched` makes the job of a file from its
+    /// path, its key and the path of the file sent with that key.
+    pub fn apply(
+        &mut self,
+        job: TransferJob,
+        matched: &impl Fn(PathBuf, K::Key, &Path) -> TransferJob,
+    ) -> Vec<TransferJob> {
+        for path in job.paths() {
+            self.overwritten(path);
+        }
+
+        match job {
+            TransferJob::File(path) => vec![self.apply_file(path, matched)],
+            TransferJob::Directory(path) if self.known.matches_within(&path, &self.sent) => {
+                TransferJob::Directory(path)
+                    .unpack(&self.roots, self.scan)
+                    .into_iter()
+                    .flat_map(|job| match job {
+                        TransferJob::Ready(_) => vec![job],
+                        job => self.apply(job, matched),
+                    })
+                    .collect()
+            }
+            job => vec![job],
+        }
+    }
+
+    fn apply_file(
+        &mut self,
+        path: PathBuf,
+        matched: &impl Fn(PathBuf, K::Key, &Path) -> TransferJob,
+    ) -> TransferJob {
+        let Some(key) = self.known.take(&path) else {
+            return TransferJob::File(path);
+        };
+        match self.sent.get(&key) {
+            Some(sent) => matched(path, key, sent),
+            None => {
+                self.sent.insert(key.clone(), path.clone());
+                self.sent_paths.insert(path.clone(), key);
+                TransferJob::File(path)
+            }
+        }
+    }
+
+    /// Drops the files sent at or below `path`.
+    fn overwritten(&mut self, path: &Path) {
+        let overwritten: Vec<_> = self
+            .sent_paths
+            .range(path.to_path_buf()..)
+            .take_while(|(other, _)| other.starts_with(path))
+            .map(|(other, _)| other.clone())
+            .collect();
+        for other in overwritten {
+            if let Some(key) = self.sent_paths.remove(&other) {
+                self.sent.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    collections::VecDeque,
+    io::{self, IsTerminal, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::bail;
+use bytesize::ByteSize;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute, terminal,
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, Paragraph, Sparkline},
+    Frame, Terminal,
+};
+use tokio::time::Instant;
+
+use super::Sender;
+use crate::core::{activity::Activity, control::Command};
+
+/// Output lines kept for the log pane, and printed again once the TUI exits.
+const LOG_LINES: usize = 500;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// Seconds of throughput kept for the graph.
+const GRAPH_SECS: usize = 300;
+
+type Lines = Arc<Mutex<VecDeque<String>>>;
+
+/// What the TUI shows, copied out of the sender at each redraw.
+struct Snapshot {
+    state: &'static str,
+    paused: bool,
+    activity: Activity,
+    log: Vec<String>,
+}
+
+/// Bytes sent per second, sampled from the activity's running total.
+struct Throughput {
+    samples: VecDeque<u64>,
+    last_total: u64,
+    last_sample: Instant,
+}
+
+enum Quit {
+    No,
+    /// Shutting down gracefully, the session ends on its own.
+    Requested,
+    Now,
+}
+
+impl Sender<'_> {
+    /// Runs `start`, showing a live view of the session instead of its output: the connection
+    /// state, the changes sent lately, the throughput, the pending changes and the output. Keys
+    /// pause, resume, resync and quit.
+    pub async fn start_tui(&self, watch: bool) -> anyhow::Result<()> {
+        if !io::stdout().is_terminal() {
+            bail!("the TUI needs a terminal, run without --tui when redirecting the output")
+        }
+
+        let (capture, lines) = Capture::start()?;
+        let res = self.run_tui(watch, capture.terminal()?, &lines).await;
+        drop(capture);
+
+        for line in lines.lock().unwrap().iter() {
+            println!("{}", line);
+        }
+        res
+    }
+
+    async fn run_tui(&self, watch: bool, output: impl Write, lines: &Lines) -> anyhow::Result<()> {
+        let mut terminal = Terminal::new(CrosstermBackend::new(output))?;
+        terminal::enable_raw_mode()?;
+        let _restore = RawMode;
+        execute!(
+            terminal.backend_mut(),
+            terminal::EnterAlternateScreen,
+            cursor::Hide
+        )?;
+        let res = self.show_session(watch, &mut terminal, lines).await;
+        execute!(
+            terminal.backend_mut(),
+            terminal::LeaveAlternateScreen,
+            cursor::Show
+        )?;
+
+        res
+    }
+
+    async fn show_session(
+        &self,
+        watch: bool,
+        terminal: &mut Terminal<CrosstermBackend<impl Write>>,
+        lines: &Lines,
+    ) -> anyhow::Result<()> {
+        let session = self.start(watch);
+        tokio::pin!(session);
+        let mut throughput = Throughput::new();
+        let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+        let mut quitting = false;
+        loop {
+            tokio::select! {
+                res = &mut session => break res,
+                _ = redraw.tick() => {
+                    match self.handle_keys(quitting, lines)? {
+                        Quit::No => (),
+                        Quit::Requested => quitting = true,
+                        Quit::Now => break Ok(()),
+                    }
+
+                    let snapshot = self.snapshot(lines);
+                    throughput.sample(snapshot.activity.bytes_transferred);
+                    terminal.draw(|frame| draw(frame, &snapshot, &throughput))?;
+                }
+            }
+        }
+    }
+
+    fn handle_keys(&self, quitting: bool, lines: &Lines) -> anyhow::Result<Quit> {
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            let command = match key.code {
+                KeyCode::Char('p') if self.controls.is_paused() => Command::Resume,
+                KeyCode::Char('p') => Command::Pause,
+                KeyCode::Char('r') => Command::Resync,
+                KeyCode::Char('q') | KeyCode::Esc => Command::Shutdown,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    Command::Shutdown
+                }
+                _ => continue,
+            };
+            if let Err(err) = self.controls.run(command) {
+                let command = format!("{:?}", command).to_lowercase();
+                push_line(lines, format!("Could not {}: {}", command, err));
+            }
+
+            if command == Command::Shutdown {
+                // Only watching or reconnecting sessions stop on request, others are cut short,
+                // as is a session asked to quit twice.
+                let state = self.status.lock().unwrap().state;
+                return Ok(
+                    match quitting || !matches!(state, "watching" | "reconnecting") {
+                        true => Quit::Now,
+                        false => Quit::Requested,
+                    },
+                );
+            }
+        }
+
+        Ok(Quit::No)
+    }
+
+    fn snapshot(&self, lines: &Lines) -> Snapshot {
+        Snapshot {
+            state: self.status.lock().unwrap().state,
+            paused: self.controls.is_paused(),
+            activity: self.activity.lock().unwrap().clone(),
+            log: lines.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(GRAPH_SECS),
+            last_total: 0,
+            last_sample: Instant::now(),
+        }
+    }
+
+    fn sample(&mut self, total: u64) {
+        let elapsed = self.last_sample.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+
+        if self.samples.len() == GRAPH_SECS {
+            self.samples.pop_front();
+        }
+        let sent = total.saturating_sub(self.last_total);
+        self.samples
+            .push_back((sent as f64 / elapsed.as_secs_f64()) as u64);
+        self.last_total = total;
+        self.last_sample = Instant::now();
+    }
+
+    fn current(&self) -> u64 {
+        self.samples.back().copied().unwrap_or_default()
+    }
+}
+
+fn draw(frame: &mut Frame, snapshot: &Snapshot, throughput: &Throughput) {
+    let [header, graph, body, keys] = Layout::vertical([
+        Constraint::Length(5),
+        Constraint::Length(6),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [changes, log] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+
+    let activity = &snapshot.activity;
+    let connection = match (activity.connected, &activity.peer) {
+        (true, Some(peer)) => Line::from(format!("Connected to {}", peer)).green(),
+        (false, Some(peer)) => Line::from(format!("Disconnected from {}", peer)).red(),
+        (_, None) => Line::from("Not connected").red(),
+    };
+    let state = match snapshot.paused {
+        true => format!("State: {} (paused)", snapshot.state),
+        false => format!("State: {}", snapshot.state),
+    };
+    let totals = format!(
+        "Pending: {}   Sent: {}   {}/s",
+        activity.files_pending,
+        ByteSize::b(activity.bytes_transferred),
+        ByteSize::b(throughput.current())
+    );
+    frame.render_widget(
+        Paragraph::new(vec![connection, Line::from(state), Line::from(totals)])
+            .block(Block::bordered().title(" white-caiman ")),
+        header,
+    );
+
+    // The newest samples that fit, right-aligned like a scrolling chart.
+    let width = graph.width.saturating_sub(2) as usize;
+    let samples: Vec<u64> = throughput
+        .samples
+        .iter()
+        .skip(throughput.samples.len().saturating_sub(width))
+        .copied()
+        .collect();
+    let peak = samples.iter().max().copied().unwrap_or_default();
+    frame.render_widget(
+        Sparkline::default()
+            .data(&samples)
+            .style(Style::default().fg(Color::Cyan))
+            .block(Block::bordered().title(format!(" Bytes/s, peak {}/s ", ByteSize::b(peak)))),
+        graph,
+    );
+
+    let items: Vec<ListItem> = activity
+        .recent_changes
+        .iter()
+        .rev()
+        .map(|change| {
+            // Only the time of the RFC 3339 timestamp.
+            let time = change.at.get(11..19).unwrap_or(&change.at);
+            ListItem::new(format!(
+                "{} {} {}",
+                time,
+                change.change,
+                change.path.display()
+            ))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Recently synced ")),
+        changes,
+    );
+
+    let height = log.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = snapshot.log[snapshot.log.len().saturating_sub(height)..]
+        .iter()
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Output ")),
+        log,
+    );
+
+    frame.render_widget(
+        Paragraph::new(" p pause/resume   r resync   q quit").dark_gray(),
+        keys,
+    );
+}
+
+fn push_line(lines: &Lines, line: String) {
+    let mut lines = lines.lock().unwrap();
+    if lines.len() == LOG_LINES {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+/// Leaves raw mode when dropped, even when the session failed.
+struct RawMode;
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Redirects the process's output into the TUI's log pane, so that it does not draw over the
+/// TUI. The output goes back to the terminal when dropped.
+#[cfg(unix)]
+struct Capture {
+    /// The original stdout and stderr.
+    saved: [std::os::fd::OwnedFd; 2],
+    reader: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl Capture {
+    fn start() -> anyhow::Result<(Self, Lines)> {
+        use std::io::{BufRead, BufReader};
+        use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let saved = [
+            io::stdout().as_fd().try_clone_to_owned()?,
+            io::stderr().as_fd().try_clone_to_owned()?,
+        ];
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(write.as_raw_fd(), fd) } < 0 {
+                let err = io::Error::last_os_error();
+                restore(&saved);
+                return Err(err.into());
+            }
+        }
+        drop(write);
+
+        let lines = Lines::default();
+        let captured = lines.clone();
+        let reader = std::thread::spawn(move || {
+            for line in BufReader::new(std::fs::File::from(read)).lines() {
+                match line {
+                    Ok(line) => push_line(&captured, line),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok((
+            Self {
+                saved,
+                reader: Some(reader),
+            },
+            lines,
+        ))
+    }
+
+    /// Where the TUI draws: the terminal that stdout was.
+    fn terminal(&self) -> io::Result<std::fs::File> {
+        Ok(std::fs::File::from(self.saved[0].try_clone()?))
+    }
+}
+
+#[cfg(unix)]
+fn restore(saved: &[std::os::fd::OwnedFd; 2]) {
+    use std::os::fd::AsRawFd;
+
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    for (saved, fd) in saved.iter().zip([libc::STDOUT_FILENO, libc::STDERR_FILENO]) {
+        unsafe { libc::dup2(saved.as_raw_fd(), fd) };
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Capture {
+    fn drop(&mut self) {
+        restore(&self.saved);
+        // Restoring closed the last write end of the pipe, so the reader is at its end.
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Output is not captured on this platform, and may draw over the TUI.
+#[cfg(not(unix))]
+struct Capture;
+
+#[cfg(not(unix))]
+impl Capture {
+    fn start() -> anyhow::Result<(Self, Lines)> {
+        Ok((Self, Lines::default()))
+    }
+
+    fn terminal(&self) -> io::Result<io::Stdout> {
+        Ok(io::stdout())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::message::FileChangeMessage;
+    use ratatui::backend::TestBackend;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_view_shows_session() -> anyhow::Result<()> {
+        let mut activity = Activity::default();
+        activity.connected("127.0.0.1:8080");
+        activity.files_pending = 3;
+        activity.changed(&FileChangeMessage::FileDeleted(PathBuf::from("src/old.rs")));
+        let snapshot = Snapshot {
+            state: "watching",
+            paused: true,
+            activity,
+            log: vec!["Watching for changes".to_owned()],
+        };
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 20))?;
+        terminal.draw(|frame| draw(frame, &snapshot, &Throughput::new()))?;
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+
+        assert!(screen.contains("Connected to 127.0.0.1:8080"));
+        assert!(screen.contains("State: watching (paused)"));
+        assert!(screen.contains("Pending: 3"));
+        assert!(screen.contains("deleted src/old.rs"));
+        assert!(screen.contains("Watching for changes"));
+
+        Ok(())
+    }
+}
@@ -1,31 +1,651 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::core::file_change::FileChange;
-use anyhow::Context;
-use watchman_client::{CanonicalPath, Connector, Subscription};
+use crate::core::{
+    file_change::FileChange,
+    file_tree::ScanOptions,
+    roots::{Roots, SourceRoot},
+    utils::quoted,
+};
+use anyhow::{anyhow, bail, ensure, Context};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinSet,
+    time::Instant,
+};
+use watchman_client::{CanonicalPath, Connector, Subscription, SubscriptionData};
 
 use watchman_client::prelude::*;
 
-pub async fn watch_dir(path: &Path) -> anyhow::Result<Subscription<FileChange>> {
-    let client = Connector::new().connect().await.context(
-        "Could not connect to watchman server, make sure it is installed on your system",
-    )?;
+use super::native_watcher::NativeWatch;
 
-    let path = CanonicalPath::canonicalize(path)?;
-    let resolved = client.resolve_root(path).await?;
+/// Where watch mode learns about changes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchBackend {
+    /// A watchman server, which must be installed and running.
+    Watchman,
+    /// The platform's own notifications, see `NativeWatch`.
+    Native,
+}
+
+/// Watchman is the default except on Windows, where it is seldom installed.
+impl Default for WatchBackend {
+    fn default() -> Self {
+        match cfg!(windows) {
+            true => WatchBackend::Native,
+            false => WatchBackend::Watchman,
+        }
+    }
+}
+
+impl FromStr for WatchBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "watchman" => Ok(WatchBackend::Watchman),
+            "native" => Ok(WatchBackend::Native),
+            _ => Err(format!(
+                "unknown watcher '{}', expected watchman or native",
+                s
+            )),
+        }
+    }
+}
+
+/// How watchman subscriptions hold back changes, so that large operations such as checkouts
+/// are reported as one batch rather than as thousands of interleaved ones.
+#[derive(Debug, Clone, Default)]
+pub struct WatchmanOptions {
+    /// States asserted by other watchman clients (e.g. `hg.update`, asserted by Mercurial's
+    /// fsmonitor) during which watchman holds changes back, reporting them at once when the
+    /// state is left.
+    pub defer: Vec<&'static str>,
+    /// How long a subscription must go without reporting changes for them to be forwarded, the
+    /// changes reported meanwhile merged into one batch. Unlike the debounce window, there is no
+    /// limit to how long they may be held back.
+    pub settle: Duration,
+}
+
+/// Watchman's clock of each root, by its source path: where a subscription picks up from.
+pub type WatchClocks = BTreeMap<PathBuf, Clock>;
+
+async fn connect() -> anyhow::Result<Client> {
+    Connector::new()
+        .connect()
+        .await
+        .context("Could not connect to watchman server, make sure it is installed on your system")
+}
+
+/// The clock of every root, once watchman has seen the changes made so far, so that subscribing
+/// since them reports every change made afterwards.
+pub async fn clocks(roots: &Roots) -> anyhow::Result<WatchClocks> {
+    let client = connect().await?;
+    let mut clocks = WatchClocks::new();
+    for root in roots.iter() {
+        let (resolved, ..) = resolve(&client, &root.path).await?;
+        let clock = client.clock(&resolved, SyncTimeout::Default).await?;
+        clocks.insert(root.path.clone(), Clock::Spec(clock));
+    }
+
+    Ok(clocks)
+}
+
+/// Reads the clocks saved by `save_clocks`, none if there are none yet.
+pub fn load_clocks(path: &Path) -> anyhow::Result<WatchClocks> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("reading watchman clocks from {}", quoted(path))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(WatchClocks::new()),
+        Err(err) => Err(err).with_context(|| format!("reading {}", quoted(path))),
+    }
+}
+
+/// Saves `clocks` to `path`, replacing what it held at once.
+pub fn save_clocks(path: &Path, clocks: &WatchClocks) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, serde_json::to_vec(clocks)?)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .with_context(|| format!("saving watchman clocks to {}", quoted(path)))
+}
+
+/// Subscribes to the changes below `path`, those made since `since` if given. Watchman only
+/// watches directories, so a file is watched through its parent, with changes named relative to
+/// the parent. Also returns the canonical path of the watched directory.
+pub async fn watch_dir(
+    path: &Path,
+    since: Option<Clock>,
+    watchman: &WatchmanOptions,
+) -> anyhow::Result<(Subscription<FileChange>, PathBuf)> {
+    let client = connect().await?;
+    let (resolved, dir, expression) = resolve(&client, path).await?;
     let (subscription, _) = client
         .subscribe::<FileChange>(
             &resolved,
             SubscribeRequest {
+                since,
                 empty_on_fresh_instance: true,
-                expression: Some(Expr::Any(vec![
-                    Expr::FileType(FileType::Regular),
-                    Expr::FileType(FileType::Directory),
-                ])),
+                expression: Some(expression),
+                defer: watchman.defer.clone(),
                 ..Default::default()
             },
         )
         .await?;
 
-    Ok(subscription)
+    Ok((subscription, dir))
+}
+
+/// The root watchman watches `path` through, the canonical path of the watched directory, and
+/// the expression matching the changes to report.
+async fn resolve(client: &Client, path: &Path) -> anyhow::Result<(ResolvedRoot, PathBuf, Expr)> {
+    let (path, expression) = match (path.is_file(), path.parent(), path.file_name()) {
+        (true, Some(parent), Some(name)) => (
+            parent,
+            Expr::All(vec![
+                Expr::FileType(FileType::Regular),
+                Expr::Name(NameTerm {
+                    paths: vec![name.into()],
+                    wholename: false,
+                }),
+            ]),
+        ),
+        _ => (
+            path,
+            Expr::Any(vec![
+                Expr::FileType(FileType::Regular),
+                Expr::FileType(FileType::Directory),
+            ]),
+        ),
+    };
+
+    let path = CanonicalPath::canonicalize(path)?;
+    let dir = path.clone().into_path_buf();
+    // A directory inside an existing watch is watched through that watch's root, with names
+    // relative to the directory only if watchman resolved it to the same path.
+    let resolved = client.resolve_root(path).await?;
+    ensure!(
+        resolved.path() == dir,
+        "watchman resolved {} to {} under the watch root {}, changes would be named against the wrong directory",
+        quoted(&dir),
+        quoted(&resolved.path()),
+        quoted(resolved.project_root())
+    );
+
+    Ok((resolved, dir, expression))
+}
+
+/// Makes a name reported by watchman relative to the watched `dir`. Names are relative to it
+/// already, unless the server ignored the subscription's relative root and named them against
+/// the watch root, either absolutely or with a path leading out of `dir`.
+fn relativize(name: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    let relative = match name.strip_prefix(dir) {
+        Ok(relative) => relative,
+        Err(_) if name.is_absolute() => {
+            bail!(
+                "watchman reported {}, outside of the watched directory {}",
+                quoted(name),
+                quoted(dir)
+            )
+        }
+        Err(_) => name,
+    };
+
+    let escapes = relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        bail!(
+            "watchman reported {} relative to another directory than the watched {}",
+            quoted(name),
+            quoted(dir)
+        )
+    }
+
+    Ok(relative.to_owned())
+}
+
+/// What the user asked for by signaling the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserSignal {
+    /// SIGUSR1: compare the whole tree with the listener again.
+    Resync,
+    /// SIGUSR2: pause if running, resume if paused.
+    TogglePause,
+}
+
+/// Lets the user ask for a full resync by sending SIGUSR1 to the sender, and pause or resume it
+/// with SIGUSR2. Never fires on platforms without Unix signals.
+pub struct UserSignals {
+    #[cfg(unix)]
+    resync: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    toggle_pause: tokio::signal::unix::Signal,
+}
+
+impl UserSignals {
+    pub fn new() -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Self {
+            #[cfg(unix)]
+            resync: signal(SignalKind::user_defined1())?,
+            #[cfg(unix)]
+            toggle_pause: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    /// Cancel safe, so it can be used as a `tokio::select!` branch.
+    pub async fn recv(&mut self) -> UserSignal {
+        #[cfg(unix)]
+        tokio::select! {
+            Some(()) = self.resync.recv() => return UserSignal::Resync,
+            Some(()) = self.toggle_pause.recv() => return UserSignal::TogglePause,
+            else => (),
+        }
+
+        std::future::pending().await
+    }
+}
+
+/// A batch is released at the latest this many windows after its first change, so that a
+/// continuous stream of changes cannot hold it back forever.
+const MAX_DEBOUNCE_WINDOWS: u32 = 10;
+
+/// Holds back file changes until none arrived for the debounce window, then releases them as one
+/// batch, coalesced per path by `SortedFileChanges`.
+pub struct Debouncer {
+    window: Duration,
+    pending: Vec<FileChange>,
+    /// The clocks reported after the pending changes, which the batch brings up to date.
+    clocks: WatchClocks,
+    first_at: Instant,
+    last_at: Instant,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: vec![],
+            clocks: WatchClocks::new(),
+            first_at: Instant::now(),
+            last_at: Instant::now(),
+        }
+    }
+
+    /// Changes waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn push(&mut self, changes: Vec<FileChange>) {
+        if self.pending.is_empty() {
+            self.first_at = Instant::now();
+        }
+
+        self.last_at = Instant::now();
+        self.pending.extend(changes);
+    }
+
+    pub fn clock(&mut self, root: PathBuf, clock: Clock) {
+        self.clocks.insert(root, clock);
+    }
+
+    /// Waits until the pending batch is due and takes it, along with the clocks it brings up to
+    /// date once sent. Cancel safe, so it can be used as a `tokio::select!` branch.
+    pub async fn ready(&mut self) -> (Vec<FileChange>, WatchClocks) {
+        if self.pending.is_empty() {
+            return std::future::pending().await;
+        }
+
+        let deadline =
+            (self.last_at + self.window).min(self.first_at + self.window * MAX_DEBOUNCE_WINDOWS);
+        tokio::time::sleep_until(deadline).await;
+
+        (
+            std::mem::take(&mut self.pending),
+            std::mem::take(&mut self.clocks),
+        )
+    }
+}
+
+/// What watching the roots reports, with names relative to the mounted tree.
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Changes(Vec<FileChange>),
+    /// Watchman reported the changes of the root with this source path up to this clock.
+    Clock(PathBuf, Clock),
+    /// Watchman may have missed changes, so the whole tree must be diffed again.
+    Resync(&'static str),
+}
+
+/// Watches every root with its own subscription, resubscribing when watchman cancels one, or
+/// follows a `SharedWatcher`.
+pub struct Watcher {
+    events: Events,
+    _subscriptions: JoinSet<()>,
+}
+
+enum Events {
+    Own(mpsc::Receiver<anyhow::Result<WatchEvent>>),
+    Shared(broadcast::Receiver<SharedEvent>),
+}
+
+/// What a `SharedWatcher` reports, its failure shared by everyone following it.
+pub type SharedEvent = Result<WatchEvent, Arc<anyhow::Error>>;
+
+/// Events a follower of a `SharedWatcher` may fall behind by before it must resync.
+const SHARED_EVENTS: usize = 4096;
+
+/// A `Watcher` followed by several sessions, e.g. of senders syncing the same roots to several
+/// listeners, each getting the events reported after it subscribed.
+pub struct SharedWatcher {
+    events: broadcast::Sender<SharedEvent>,
+    _watcher: JoinSet<()>,
+}
+
+impl SharedWatcher {
+    pub async fn new(
+        roots: &Roots,
+        scan: ScanOptions,
+        backend: WatchBackend,
+        watchman: &WatchmanOptions,
+    ) -> anyhow::Result<Self> {
+        let mut watcher = Watcher::new(roots, scan, backend, watchman, &WatchClocks::new()).await?;
+        let (events, _) = broadcast::channel(SHARED_EVENTS);
+        let tx = events.clone();
+        let mut forwarder = JoinSet::new();
+        forwarder.spawn(async move {
+            loop {
+                let event = watcher.next().await.map_err(Arc::new);
+                let failed = event.is_err();
+                // Sessions come and go, nobody may be following at times.
+                let _ = tx.send(event);
+                if failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            events,
+            _watcher: forwarder,
+        })
+    }
+
+    /// A watcher reporting the changes fed to it with `report` instead of watching the roots.
+    pub fn fed() -> Self {
+        let (events, _) = broadcast::channel(SHARED_EVENTS);
+        Self {
+            events,
+            _watcher: JoinSet::new(),
+        }
+    }
+
+    pub fn report(&self, changes: Vec<FileChange>) {
+        // Sessions come and go, nobody may be following at times.
+        let _ = self.events.send(Ok(WatchEvent::Changes(changes)));
+    }
+
+    /// A watcher reporting the events from now on, holding them until they are taken with
+    /// `Watcher::next`.
+    pub fn follow(&self) -> Watcher {
+        Watcher {
+            events: Events::Shared(self.events.subscribe()),
+            _subscriptions: JoinSet::new(),
+        }
+    }
+}
+
+impl Watcher {
+    /// Watches the roots, with watchman since their clock in `since` for those it has one of.
+    pub async fn new(
+        roots: &Roots,
+        scan: ScanOptions,
+        backend: WatchBackend,
+        watchman: &WatchmanOptions,
+        since: &WatchClocks,
+    ) -> anyhow::Result<Self> {
+        let (tx, events) = mpsc::channel(64);
+        let mut subscriptions = JoinSet::new();
+        for root in roots.iter() {
+            match backend {
+                WatchBackend::Watchman => {
+                    let since = since.get(&root.path).cloned();
+                    let resumed = since.is_some();
+                    let (subscription, dir) = watch_dir(&root.path, since, watchman).await?;
+                    let root = root.clone();
+                    let watchman = watchman.clone();
+                    let tx = tx.clone();
+                    subscriptions.spawn(forward(
+                        subscription,
+                        dir,
+                        root,
+                        scan,
+                        watchman,
+                        resumed,
+                        tx,
+                    ));
+                }
+                WatchBackend::Native => {
+                    let watch = NativeWatch::new(root.clone(), scan)?;
+                    subscriptions.spawn(watch.forward(tx.clone()));
+                }
+            }
+        }
+
+        Ok(Self {
+            events: Events::Own(events),
+            _subscriptions: subscriptions,
+        })
+    }
+
+    /// Cancel safe, so it can be used as a `tokio::select!` branch.
+    pub async fn next(&mut self) -> anyhow::Result<WatchEvent> {
+        match &mut self.events {
+            Events::Own(events) => match events.recv().await {
+                Some(event) => event,
+                None => std::future::pending().await,
+            },
+            Events::Shared(events) => match events.recv().await {
+                Ok(Ok(event)) => Ok(event),
+                Ok(Err(err)) => Err(anyhow!("{:#}", err)),
+                Err(broadcast::error::RecvError::Lagged(_)) => Ok(WatchEvent::Resync(
+                    "Fell behind the changes watched for every listener",
+                )),
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            },
+        }
+    }
+}
+
+/// Forwards the changes of a subscription, and the clock they go up to, once it went without
+/// changes for the settle period. Subscriptions without a clock to start from begin with a fresh
+/// instance result, which only matters afterwards, while those `resumed` from a clock only get one
+/// if watchman lost track of changes since.
+async fn forward(
+    mut subscription: Subscription<FileChange>,
+    mut dir: PathBuf,
+    root: SourceRoot,
+    scan: ScanOptions,
+    watchman: WatchmanOptions,
+    resumed: bool,
+    tx: mpsc::Sender<anyhow::Result<WatchEvent>>,
+) {
+    let mut subscribed = resumed;
+    // The changes reported less than the settle period apart, with the clock of the last ones.
+    let mut held: Option<(Vec<FileChange>, Clock)> = None;
+    loop {
+        let next = match held.is_some() {
+            true => tokio::time::timeout(watchman.settle, subscription.next()).await,
+            false => Ok(subscription.next().await),
+        };
+        let Ok(next) = next else {
+            let (files, clock) = held.take().expect("only settling with changes held");
+            match report(&tx, &root, files, clock).await {
+                true => continue,
+                false => return,
+            }
+        };
+
+        let settling =
+            matches!(&next, Ok(SubscriptionData::FilesChanged(res)) if !res.is_fresh_instance);
+        if let (false, Some((files, clock))) = (settling, held.take()) {
+            if !report(&tx, &root, files, clock).await {
+                return;
+            }
+        }
+
+        let event = match next {
+            Ok(SubscriptionData::FilesChanged(res)) if res.is_fresh_instance => {
+                let was_subscribed = std::mem::replace(&mut subscribed, true);
+                match was_subscribed {
+                    true => WatchEvent::Resync("Watchman lost track of changes"),
+                    false => WatchEvent::Clock(root.path.clone(), res.clock),
+                }
+            }
+            Ok(SubscriptionData::FilesChanged(res)) => {
+                subscribed = true;
+                let files = match mount(res.files.unwrap_or_default(), &dir, &root, scan) {
+                    Ok(files) => files,
+                    Err(err) => {
+                        let _ = tx.send(Err(err)).await;
+                        return;
+                    }
+                };
+                let mut batch = held.take().map_or(vec![], |(files, _)| files);
+                batch.extend(files);
+                if !watchman.settle.is_zero() {
+                    held = Some((batch, res.clock));
+                } else if !report(&tx, &root, batch, res.clock).await {
+                    return;
+                }
+                continue;
+            }
+            Ok(SubscriptionData::StateEnter { state_name, .. })
+                if watchman.defer.contains(&state_name.as_str()) =>
+            {
+                println!(
+                    "{} started in {}, holding back changes until it ends",
+                    state_name,
+                    quoted(&root.path)
+                );
+                continue;
+            }
+            Ok(SubscriptionData::Canceled) => match watch_dir(&root.path, None, &watchman).await {
+                Ok((resubscribed, resubscribed_dir)) => {
+                    subscription = resubscribed;
+                    dir = resubscribed_dir;
+                    subscribed = false;
+                    WatchEvent::Resync("Watchman subscription canceled, resubscribed")
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    return;
+                }
+            },
+            Ok(_) => continue,
+            Err(err) => {
+                let _ = tx.send(Err(err.into())).await;
+                return;
+            }
+        };
+
+        if tx.send(Ok(event)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends the changes of a subscription, if any, then the clock they go up to. False once the
+/// watcher is dropped.
+async fn report(
+    tx: &mpsc::Sender<anyhow::Result<WatchEvent>>,
+    root: &SourceRoot,
+    files: Vec<FileChange>,
+    clock: Clock,
+) -> bool {
+    if !files.is_empty() && tx.send(Ok(WatchEvent::Changes(files))).await.is_err() {
+        return false;
+    }
+    let clock = WatchEvent::Clock(root.path.clone(), clock);
+    tx.send(Ok(clock)).await.is_ok()
+}
+
+/// Names changes reported below the watched `dir` against the mounted tree, leaving out the
+/// excluded ones. A file root is watched through its parent, and only ever edited or deleted.
+pub fn mount(
+    mut files: Vec<FileChange>,
+    dir: &Path,
+    root: &SourceRoot,
+    scan: ScanOptions,
+) -> anyhow::Result<Vec<FileChange>> {
+    for file in files.iter_mut() {
+        *file.name = relativize(&file.name, dir)?;
+    }
+    files.retain(|file| !scan.excludes_within(&file.name));
+
+    let file_name = match root.path.is_file() {
+        true => root.path.file_name().map(PathBuf::from),
+        false => None,
+    };
+    if let Some(file_name) = &file_name {
+        files.retain(|file| *file.name == *file_name);
+    }
+
+    for file in files.iter_mut() {
+        *file.name = match file_name {
+            Some(_) => root.dest.clone(),
+            None => root.dest.join(&*file.name),
+        };
+        *file.is_new &= file_name.is_none();
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks_are_saved_and_loaded() -> anyhow::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("clocks.json");
+        assert!(load_clocks(&path)?.is_empty());
+
+        let clock = Clock::Spec(ClockSpec::StringClock("c:1700000000:42:1:7".into()));
+        let clocks = WatchClocks::from([(PathBuf::from("/home/user/project"), clock)]);
+        save_clocks(&path, &clocks)?;
+        assert_eq!(
+            serde_json::to_string(&load_clocks(&path)?)?,
+            serde_json::to_string(&clocks)?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relativize() -> anyhow::Result<()> {
+        let dir = Path::new("/home/user/project/src");
+        assert_eq!(
+            relativize(Path::new("lib/mod.rs"), dir)?,
+            Path::new("lib/mod.rs")
+        );
+        assert_eq!(
+            relativize(Path::new("/home/user/project/src/lib/mod.rs"), dir)?,
+            Path::new("lib/mod.rs")
+        );
+        assert!(relativize(Path::new("/home/user/project/README.md"), dir).is_err());
+        assert!(relativize(Path::new("../README.md"), dir).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,185 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    file_tree::{FileTree, ScanOptions},
+    file_tree_diff::TreeDiff,
+    utils::quoted,
+    wire_path,
+};
+
+/// Starts every snapshot file, followed by the format version and the gzipped snapshot.
+const MAGIC: &[u8; 8] = b"CAIMANTR";
+const VERSION: u8 = 1;
+
+/// A directory's `FileTree`, saved to compare it offline with another state of it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    #[serde(with = "wire_path")]
+    pub dir: PathBuf,
+    pub taken_at: SystemTime,
+    /// Files were not hashed, so edits keeping a file's size do not show in diffs.
+    pub size_only: bool,
+    pub tree: FileTree,
+}
+
+impl Snapshot {
+    pub async fn take(dir: &Path, options: ScanOptions) -> anyhow::Result<Self> {
+        if !dir.is_dir() {
+            bail!("{} is not a directory", quoted(dir));
+        }
+
+        Ok(Self {
+            dir: dir.canonicalize()?,
+            taken_at: SystemTime::now(),
+            size_only: options.size_only,
+            tree: FileTree::new_with(dir, options).await?,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path).with_context(|| format!("creating {}", quoted(path)))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        bincode::serialize_into(&mut encoder, self)?;
+        encoder.finish()?.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", quoted(path)))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0; MAGIC.len() + 1];
+        if reader.read_exact(&mut header).is_err() || &header[..MAGIC.len()] != MAGIC {
+            bail!("{} is not a snapshot", quoted(path));
+        }
+        if header[MAGIC.len()] != VERSION {
+            bail!(
+                "{} is a snapshot of version {}, only version {} is supported",
+                quoted(path),
+                header[MAGIC.len()],
+                VERSION
+            );
+        }
+
+        bincode::deserialize_from(GzDecoder::new(reader))
+            .with_context(|| format!("reading {}", quoted(path)))
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} at {}",
+            quoted(&self.dir),
+            humantime::format_rfc3339_seconds(self.taken_at)
+        )
+    }
+}
+
+/// What changed from the `before` snapshot to the `after` one.
+pub struct SnapshotDiff<'snapshot> {
+    before: &'snapshot Snapshot,
+    after: &'snapshot Snapshot,
+    diff: TreeDiff<'snapshot>,
+}
+
+impl<'snapshot> SnapshotDiff<'snapshot> {
+    pub fn new(before: &'snapshot Snapshot, after: &'snapshot Snapshot) -> Self {
+        Self {
+            before,
+            after,
+            diff: TreeDiff::from(&before.tree, &after.tree),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diff.is_empty()
+    }
+}
+
+fn write_paths(f: &mut std::fmt::Formatter<'_>, title: &str, paths: &[&Path]) -> std::fmt::Result {
+    write!(f, "\n{}:", title)?;
+    for path in paths {
+        write!(f, "\n  - {}", quoted(path))?;
+    }
+
+    Ok(())
+}
+
+impl Display for SnapshotDiff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "From {}\nto {}",
+            self.before.describe(),
+            self.after.describe()
+        )?;
+        if self.before.size_only || self.after.size_only {
+            write!(
+                f,
+                "\nFiles were compared by size only, edits keeping their size are not shown"
+            )?;
+        }
+        if self.is_empty() {
+            return write!(f, "\nNo differences");
+        }
+
+        write_paths(f, "Created Directories", self.diff.created_dirs())?;
+        write_paths(f, "Created Files", self.diff.created_files())?;
+        write_paths(f, "Deleted Directories", self.diff.deleted_dirs())?;
+        write_paths(f, "Deleted Files", self.diff.deleted_files())?;
+        write_paths(f, "Edited Files", self.diff.edited_files())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::test;
+
+    #[test]
+    async fn test_snapshots_round_trip_and_diff() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("logs"))?;
+        std::fs::write(dir.path().join("logs/app.log"), "started")?;
+        std::fs::write(dir.path().join("config.toml"), "port = 80")?;
+        std::fs::write(dir.path().join("old.txt"), "old")?;
+        let snapshots = TempDir::new()?;
+        let path = snapshots.path().join("before.caiman");
+        Snapshot::take(dir.path(), ScanOptions::default())
+            .await?
+            .write(&path)?;
+        let before = Snapshot::read(&path)?;
+
+        std::fs::remove_dir_all(dir.path().join("logs"))?;
+        std::fs::remove_file(dir.path().join("old.txt"))?;
+        std::fs::write(dir.path().join("config.toml"), "port = 81")?;
+        std::fs::write(dir.path().join("new.txt"), "new")?;
+        let after = Snapshot::take(dir.path(), ScanOptions::default()).await?;
+
+        let diff = SnapshotDiff::new(&before, &after);
+        assert_eq!(diff.diff.created_files(), [Path::new("new.txt")]);
+        assert_eq!(diff.diff.deleted_dirs(), [Path::new("logs")]);
+        assert_eq!(diff.diff.deleted_files(), [Path::new("old.txt")]);
+        assert_eq!(diff.diff.edited_files(), [Path::new("config.toml")]);
+        assert!(SnapshotDiff::new(&after, &after).is_empty());
+
+        std::fs::write(&path, "not a snapshot")?;
+        assert!(Snapshot::read(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+pub mod s3;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures::{stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::{
+    core::{
+        file_tree::{FileTree, FileTreeNodeType, ScanOptions},
+        utils::quoted,
+    },
+    receiver::{excluding_partials, Applied, Receiver, ReceiverOptions},
+};
+
+pub use s3::S3;
+
+/// Where a listener's output directory is mirrored to, as objects named after the paths of the
+/// files, relative to the output directory and with slashes between their components. There
+/// are no directories, only keys sharing a prefix.
+pub trait Storage: Display + Send + Sync {
+    /// The objects whose keys start with `prefix`, e.g. a directory's path and a slash.
+    fn list(&self, prefix: &str) -> impl Future<Output = anyhow::Result<Vec<StoredObject>>> + Send;
+
+    /// Stores the file at `path` as `key`, replacing the object there if any.
+    fn put(&self, key: &str, path: &Path) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Stores a copy of the object at `from` as `to`, without transferring it again.
+    fn copy(&self, from: &str, to: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Removes the object at `key`, if there is one.
+    fn delete(&self, key: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// A listener mirroring its output directory to a `Storage`, e.g. an S3 bucket. The storage is
+/// caught up with the output directory on startup, then each batch the listener applies is
+/// mirrored once applied: files are uploaded or deleted, renamed ones copied, and directories
+/// received whole compared with what is stored under them. Changes made in the output directory
+/// by other means are only mirrored by the next catch-up.
+pub struct Target<S> {
+    port: u32,
+    out_dir: PathBuf,
+    storage: S,
+    receiver: ReceiverOptions,
+}
+
+impl<S: Storage> Target<S> {
+    pub fn new(
+        port: u32,
+        out_dir: impl Into<PathBuf>,
+        storage: S,
+        receiver: ReceiverOptions,
+    ) -> Self {
+        Self {
+            port,
+            out_dir: out_dir.into(),
+            storage,
+            receiver,
+        }
+    }
+
+    /// Listens, and mirrors meanwhile, until the listener stops. Changes applied during the
+    /// catch-up are mirrored once it is done.
+    pub async fn start(mut self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.out_dir)
+            .await
+            .with_context(|| format!("creating {}", quoted(&self.out_dir)))?;
+
+        let (report, mut applied) = mpsc::unbounded_channel();
+        self.receiver.applied = Some(report);
+        let replica = Replica {
+            out_dir: self.out_dir.clone(),
+            storage: self.storage,
+            scan: ScanOptions {
+                quick_check: true,
+                ..excluding_partials(self.receiver.scan)
+            },
+            jobs: self.receiver.jobs,
+        };
+        let receiver = Receiver::new(self.port, &self.out_dir, self.receiver);
+        let mirror = async {
+            println!("Catching up {}", replica.storage);
+            let (uploaded, deleted) = replica
+                .reconcile(Path::new(""))
+                .await
+                .with_context(|| format!("catching up {}", replica.storage))?;
+            println!(
+                "Caught up {}: {} objects uploaded, {} deleted",
+                replica.storage, uploaded, deleted
+            );
+
+            // The receiver reports until it stops.
+            while let Some(applied) = applied.recv().await {
+                replica.mirror(applied).await;
+            }
+            Ok(())
+        };
+
+        tokio::select! {
+            res = receiver.start() => res,
+            res = mirror => res,
+        }
+    }
+}
+
+/// The output directory's copy in a `Storage`.
+struct Replica<S> {
+    out_dir: PathBuf,
+    storage: S,
+    /// Quick checks, for files to be compared by size and mtime.
+    scan: ScanOptions,
+    jobs: usize,
+}
+
+impl<S: Storage> Replica<S> {
+    /// Mirrors what a batch applied, in order. Failures are reported, and left for the next
+    /// catch-up to mirror.
+    async fn mirror(&self, applied: Vec<Applied>) {
+        for applied in applied {
+            let res = match &applied {
+                Applied::File(path) => self.mirror_file(path).await,
+                Applied::Directory(path) => self.reconcile(path).await.map(drop),
+                Applied::Renamed(from, to) => self.rename(from, to).await,
+            };
+            if let Err(err) = res {
+                eprintln!("Could not mirror a change to {}: {:#}", self.storage, err);
+            }
+        }
+    }
+
+    async fn mirror_file(&self, path: &Path) -> anyhow::Result<()> {
+        let key = key_of(path)?;
+        let local = self.out_dir.join(path);
+        match tokio::fs::metadata(&local).await {
+            Ok(metadata) if metadata.is_dir() => self.reconcile(path).await.map(drop),
+            Ok(_) => self.storage.put(&key, &local).await,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.storage.delete(&key).await
+            }
+            Err(err) => Err(err).with_context(|| format!("reading {}", quoted(&local))),
+        }
+    }
+
+    /// Moves the objects of a renamed file or directory by copying them, which is cheaper than
+    /// uploading them again.
+    async fn rename(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let (from_key, to_key) = (key_of(from)?, key_of(to)?);
+        if self.out_dir.join(to).is_file() {
+            // The object is uploaded again if it cannot be copied, e.g. if it was never stored.
+            if self.storage.copy(&from_key, &to_key).await.is_err() {
+                self.storage.put(&to_key, &self.out_dir.join(to)).await?;
+            }
+            return self.storage.delete(&from_key).await;
+        }
+
+        let (from_prefix, to_prefix) = (dir_prefix(&from_key), dir_prefix(&to_key));
+        for object in self.storage.list(&from_prefix).await? {
+            let moved = format!("{}{}", to_prefix, &object.key[from_prefix.len()..]);
+            self.storage.copy(&object.key, &moved).await?;
+            self.storage.delete(&object.key).await?;
+        }
+        // Renamed directories may have been edited since, and renamed files be gone.
+        self.reconcile(to).await?;
+        self.storage.delete(&from_key).await
+    }
+
+    /// Uploads the files under `dir` that are not stored as they are, and deletes the objects
+    /// under it that have no file anymore, returning how many were. Files are compared by size,
+    /// and taken as changed if modified after their object was stored.
+    async fn reconcile(&self, dir: &Path) -> anyhow::Result<(usize, usize)> {
+        let local = self.scan(dir).await?;
+        let prefix = dir_prefix(&key_of(dir)?);
+        let mut stored: BTreeMap<_, _> = self
+            .storage
+            .list(&prefix)
+            .await?
+            .into_iter()
+            .map(|object| (object.key.clone(), object))
+            .collect();
+
+        let mut uploads = vec![];
+        for (key, (size, mtime)) in local {
+            let object = stored.remove(&key);
+            let is_stored = object.is_some_and(|object| {
+                object.size == size && mtime.is_none_or(|mtime| mtime <= object.modified)
+            });
+            if !is_stored {
+                uploads.push(key);
+            }
+        }
+        let deletions: Vec<_> = stored.into_keys().collect();
+        let counts = (uploads.len(), deletions.len());
+
+        let uploads = uploads.into_iter().map(|key| async move {
+            let res = self.storage.put(&key, &self.out_dir.join(&key)).await;
+            res.with_context(|| format!("uploading {}", key))
+        });
+        let deletions = deletions.into_iter().map(|key| async move {
+            let res = self.storage.delete(&key).await;
+            res.with_context(|| format!("deleting {}", key))
+        });
+        let results: Vec<_> = stream::iter(uploads)
+            .buffer_unordered(self.jobs)
+            .chain(stream::iter(deletions).buffer_unordered(self.jobs))
+            .collect()
+            .await;
+        for err in results.into_iter().filter_map(Result::err) {
+            eprintln!("Could not mirror to {}: {:#}", self.storage, err);
+        }
+
+        Ok(counts)
+    }
+
+    /// The files under `dir`, by key, with their size and, unless scanned by size only, mtime.
+    async fn scan(
+        &self,
+        dir: &Path,
+    ) -> anyhow::Result<BTreeMap<String, (u64, Option<SystemTime>)>> {
+        let local = self.out_dir.join(dir);
+        if !local.is_dir() {
+            return Ok(BTreeMap::new());
+        }
+
+        let mut files = BTreeMap::new();
+        for node in FileTree::new_with(&local, self.scan).await?.iter() {
+            let (size, mtime) = match node.typ {
+                FileTreeNodeType::TimedFile { size, mtime } => {
+                    (size, Some(UNIX_EPOCH + Duration::from_secs(mtime)))
+                }
+                FileTreeNodeType::File { size, .. } => (size, None),
+                _ => continue,
+            };
+            let path = dir.join(&node.path);
+            match key_of(&path) {
+                Ok(key) => files.insert(key, (size, mtime)),
+                Err(err) => {
+                    eprintln!("Not mirroring {}: {:#}", quoted(&path), err);
+                    continue;
+                }
+            };
+        }
+        Ok(files)
+    }
+}
+
+/// The key of the file at `path`, relative to the output directory.
+fn key_of(path: &Path) -> anyhow::Result<String> {
+    let components = path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("{} is not valid UTF-8, as keys must be", quoted(path)))?;
+    Ok(components.join("/"))
+}
+
+/// What the keys of the objects under the directory at `key` start with.
+fn dir_prefix(key: &str) -> String {
+    match key {
+        "" => String::new(),
+        key => format!("{}/", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, sync::Mutex};
+    use tempfile::TempDir;
+    use tokio::test;
+
+    /// Objects kept in memory, with their contents.
+    #[derive(Default)]
+    struct Memory {
+        objects: Mutex<BTreeMap<String, (StoredObject, Vec<u8>)>>,
+        puts: Mutex<Vec<String>>,
+    }
+
+    impl Display for Memory {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "memory")
+        }
+    }
+
+    impl Memory {
+        fn contents(&self) -> BTreeMap<String, String> {
+            let objects = self.objects.lock().unwrap();
+            objects
+                .iter()
+                .map(|(key, (_, contents))| (key.clone(), String::from_utf8_lossy(contents).into()))
+                .collect()
+        }
+
+        fn store(&self, key: &str, contents: Vec<u8>) {
+            let object = StoredObject {
+                key: key.to_owned(),
+                size: contents.len() as u64,
+                modified: SystemTime::now(),
+            };
+            let mut objects = self.objects.lock().unwrap();
+            objects.insert(key.to_owned(), (object, contents));
+        }
+    }
+
+    impl Storage for Memory {
+        async fn list(&self, prefix: &str) -> anyhow::Result<Vec<StoredObject>> {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects
+                .values()
+                .filter(|(object, _)| object.key.starts_with(prefix))
+                .map(|(object, _)| object.clone())
+                .collect())
+        }
+
+        async fn put(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+            self.store(key, fs::read(path)?);
+            self.puts.lock().unwrap().push(key.to_owned());
+            Ok(())
+        }
+
+        async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+            let contents = self
+                .objects
+                .lock()
+                .unwrap()
+                .get(from)
+                .map(|(_, contents)| contents.clone());
+            self.store(to, contents.context("no such object")?);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    async fn test_storage_mirrors_the_output_directory() -> anyhow::Result<()> {
+        let out_dir = TempDir::new()?;
+        fs::create_dir_all(out_dir.path().join("docs/guide"))?;
+        fs::write(out_dir.path().join("docs/guide/intro.md"), "intro")?;
+        fs::write(out_dir.path().join("README.md"), "readme")?;
+        let replica = Replica {
+            out_dir: out_dir.path().to_owned(),
+            storage: Memory::default(),
+            scan: ScanOptions {
+                quick_check: true,
+                ..Default::default()
+            },
+            jobs: 4,
+        };
+        replica.storage.store("README.md", b"readme".to_vec());
+        replica.storage.store("stale.txt", b"stale".to_vec());
+
+        // Stored objects of the same size, stored after the file was modified, are kept.
+        assert_eq!(replica.reconcile(Path::new("")).await?, (1, 1));
+        assert_eq!(
+            *replica.storage.puts.lock().unwrap(),
+            ["docs/guide/intro.md"]
+        );
+        let expected = [("README.md", "readme"), ("docs/guide/intro.md", "intro")];
+        assert_eq!(
+            replica.storage.contents(),
+            expected
+                .map(|(key, contents)| (key.to_owned(), contents.to_owned()))
+                .into()
+        );
+
+        fs::rename(
+            out_dir.path().join("docs/guide"),
+            out_dir.path().join("docs/manual"),
+        )?;
+        fs::write(out_dir.path().join("docs/manual/setup.md"), "setup")?;
+        fs::remove_file(out_dir.path().join("README.md"))?;
+        fs::write(out_dir.path().join("notes.txt"), "notes")?;
+        replica
+            .mirror(vec![
+                Applied::Renamed("docs/guide".into(), "docs/manual".into()),
+                Applied::File("README.md".into()),
+                Applied::File("notes.txt".into()),
+            ])
+            .await;
+        assert_eq!(
+            replica.storage.contents(),
+            [
+                ("docs/manual/intro.md", "intro"),
+                ("docs/manual/setup.md", "setup"),
+                ("notes.txt", "notes"),
+            ]
+            .map(|(key, contents)| (key.to_owned(), contents.to_owned()))
+            .into()
+        );
+
+        Ok(())
+    }
+}